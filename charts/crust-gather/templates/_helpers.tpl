@@ -96,6 +96,17 @@ Return the name of the OCI auth secret, if configured.
 {{- end -}}
 {{- end }}
 
+{{/*
+Return the name of the S3 credentials secret, if configured.
+*/}}
+{{- define "crust-gather.s3SecretName" -}}
+{{- if .Values.s3.existingSecret -}}
+{{- .Values.s3.existingSecret -}}
+{{- else if .Values.s3.accessKeyId -}}
+{{- printf "%s-s3-auth" (include "crust-gather.fullname" .) | trunc 63 | trimSuffix "-" -}}
+{{- end -}}
+{{- end }}
+
 {{/*
 Return the name of the CA secret, if configured.
 */}}
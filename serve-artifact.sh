@@ -1,4 +1,4 @@
-#!/usr/bin/env -S nix shell nixpkgs#bash nixpkgs#curl nixpkgs#unzip nixpkgs#mktemp github:crust-gather/crust-gather --command bash
+#!/usr/bin/env -S nix shell nixpkgs#bash github:crust-gather/crust-gather --command bash
 
 
 usage() {
@@ -54,24 +54,10 @@ if [[ -z ${OWNER} ]] || [[ -z ${REPO} ]] || [[ -z ${ARTIFACT_ID} ]] && [[ -z ${U
 fi
 
 if [[ -n ${URI} ]] then
-    # Extract owner and repo using regex
-    OWNER=$(echo "$URI" | cut -d\/ -f4)
-    REPO=$(echo "$URI" | cut -d\/ -f5)
-
-    # Extract artifact ID
-    ARTIFACT_ID=${URI##*/}
-
-    # Split the artifact ID to get the actual ID (everything after the last '/')
-    ARTIFACT_ID=${ARTIFACT_ID##*/}
-    ARTIFACT_ID=${ARTIFACT_ID%%/*}
+    ARTIFACT="${URI}"
+else
+    ARTIFACT="${OWNER}/${REPO}/${ARTIFACT_ID}"
 fi
 
-artifact_download="https://api.github.com/repos/${OWNER}/${REPO}/actions/artifacts/${ARTIFACT_ID}/zip"
-echo "Downloading from $artifact_download"
-
-tmp=$(mktemp -d)
-curl -H "Accept: application/vnd.github+json" -H "Authorization: token ${GITHUB_TOKEN}" -L  ${artifact_download} --output ${tmp}/artifact.zip
-unzip ${tmp}/artifact.zip -d ${tmp}
-
 echo "Serving on ${SOCKET:-0.0.0.0:9095}..."
-kubectl-crust-gather serve -a ${tmp} ${SOCKET}
+kubectl-crust-gather serve --github-artifact "${ARTIFACT}" ${SOCKET}
@@ -1,9 +1,13 @@
 use std::{
     fs::{self, File},
-    sync::Arc,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
 };
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use clap::{ArgAction, Parser, Subcommand};
 use k8s_openapi::serde::{Deserialize, Serialize};
 use kube::{
@@ -28,22 +32,38 @@ use crate::{
         selector::{Annotations, Labels, Selector},
     },
     gather::{
+        compression::LayerCompression,
         config::{
             Config, ConfigFromConfigMap, GatherMode, KubeconfigFile, KubeconfigSecretLabel,
             KubeconfigSecretNamespaceName, RunDuration, Secrets, SecretsFile,
         },
+        docker_auth::DockerConfig,
+        encryption::{Decryption, Encryption},
+        ignore::{IgnoreRule, IgnoreRules},
+        list::{Lister, print_snapshots},
         log::HostLog,
+        manifest::{Verifier, signing_key},
+        pseudonym::{MappingFile, Pseudonymizer},
+        pull::Puller,
+        redact::{Detector, RedactMode, RedactPattern, Redaction},
+        s3::{S3Location, S3Settings},
         server::Server,
-        writer::{Archive, Encoding, Writer},
+        writer::{
+            Archive, DEFAULT_OCI_MAX_LAYER_SIZE, DEFAULT_OCI_MAX_LAYERS, Encoding, LayerPacking,
+            Writer, WriterHandle,
+        },
     },
     mcp_server,
 };
 
 pub const DEFAULT_OCI_BUFFER_SIZE: usize = 32;
+pub const DEFAULT_AUTO_REDACT_MIN_LENGTH: usize = 8;
+
+/// Set while stdout is owned by the MCP transport or a streamed archive, so logs go to stderr.
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
 
-use tracing_subscriber::Layer;
 use tracing_subscriber::filter::EnvFilter;
-use tracing_subscriber::fmt;
+use tracing_subscriber::fmt::{self, writer::EitherWriter};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt as _;
 
@@ -62,12 +82,14 @@ pub struct Cli {
 
 impl Cli {
     pub fn init(&self) {
-        let fmt_layer = if matches!(self.command, Commands::Mcp) {
-            // The MCP transport owns stdout, so logs must go to stderr in this mode.
-            fmt::layer().with_writer(std::io::stderr).boxed()
-        } else {
-            fmt::layer().boxed()
-        };
+        LOG_TO_STDERR.store(
+            matches!(self.command, Commands::Mcp) || self.command.streams_stdout(),
+            Ordering::Relaxed,
+        );
+        let fmt_layer = fmt::layer().with_writer(|| match LOG_TO_STDERR.load(Ordering::Relaxed) {
+            true => EitherWriter::A(std::io::stderr()),
+            false => EitherWriter::B(std::io::stdout()),
+        });
 
         tracing_subscriber::registry()
             .with(fmt_layer)
@@ -96,12 +118,18 @@ pub enum Commands {
 
     /// Record resource changes over time from the Kubernetes cluster using the provided
     /// filtering options like namespaces, kinds, etc to include/exclude.
+    ///
+    /// Patches and keyframes are written next to the collected objects, so only directory
+    /// archives, optionally uploaded to S3, can be recorded.
     Record {
         #[command(flatten)]
         config: GatherCommands,
     },
 
     /// Record resource changes over time from the Kubernetes cluster using the provided config file
+    ///
+    /// Patches and keyframes are written next to the collected objects, so only directory
+    /// archives, optionally uploaded to S3, can be recorded.
     RecordFromConfig {
         #[command(flatten)]
         source: ConfigSource,
@@ -117,11 +145,52 @@ pub enum Commands {
         serve: Server,
     },
 
+    /// Verify the archive files against the archive manifest and its signature.
+    Verify {
+        #[command(flatten)]
+        verify: Verifier,
+    },
+
+    /// List snapshots in an OCI repository with their collection annotations.
+    Ls {
+        #[command(flatten)]
+        ls: Lister,
+    },
+
+    /// Pull an OCI archive into a local archive, which can be served offline.
+    Pull {
+        #[command(flatten)]
+        pull: Puller,
+    },
+
     /// Start the MCP server over stdio.
     Mcp,
 }
 
 impl Commands {
+    /// Returns true when the archive is streamed to stdout with "--file=-",
+    /// or the served kubeconfig is printed with "--kubeconfig-output=-". The file of
+    /// a config map is only known once it is loaded, so it is assumed to be stdout until
+    /// the config is merged.
+    fn streams_stdout(&self) -> bool {
+        let file = match self {
+            Commands::Serve { serve } => return serve.streams_stdout(),
+            Commands::Collect { config } | Commands::Record { config } => &config.settings.file,
+            Commands::CollectFromConfig { source, overrides }
+            | Commands::RecordFromConfig { source, overrides } => {
+                match (&overrides.file, &source.config) {
+                    (Some(_), _) => &overrides.file,
+                    (None, Some(config)) => &config.settings.file,
+                    (None, None) => return true,
+                }
+            }
+            Commands::Pull { pull } => return pull.streams_stdout(),
+            _ => return false,
+        };
+
+        file.as_ref().is_some_and(Archive::is_stdout)
+    }
+
     pub async fn run(self) -> anyhow::Result<()> {
         match self {
             Commands::Collect { config } => {
@@ -132,18 +201,26 @@ impl Commands {
                     .await
             }
             Commands::CollectFromConfig { source, overrides } => {
-                source
+                let config = source
                     .gather(overrides.origin_client().await?)
                     .await?
-                    .merge(overrides)
-                    .load()
-                    .await?
-                    .collect()
-                    .await
+                    .merge(overrides);
+                config.route_logs();
+                config.load().await?.collect().await
             }
             Commands::Serve { serve } => {
                 serve.get_api().await?.serve().await.map_err(|e| anyhow!(e))
             }
+            Commands::Verify { verify } => {
+                let verification = verify.run().await?;
+                println!("{verification}");
+                verification.ensure_valid()
+            }
+            Commands::Ls { ls } => {
+                print_snapshots(&ls.run().await?);
+                Ok(())
+            }
+            Commands::Pull { pull } => pull.run().await,
             Commands::Mcp => mcp_server::run().await,
             Commands::Record { config } => {
                 let config = GatherCommands {
@@ -161,6 +238,7 @@ impl Commands {
                     .gather(overrides.origin_client().await?)
                     .await?
                     .merge(overrides);
+                config.route_logs();
                 let config = GatherCommands {
                     mode: GatherMode::Record,
                     ..config
@@ -263,6 +341,14 @@ pub struct GatherCommands {
 }
 
 impl GatherCommands {
+    /// Sends logs to stderr only when the merged config streams the archive to stdout.
+    fn route_logs(&self) {
+        LOG_TO_STDERR.store(
+            self.settings.file.as_ref().is_some_and(Archive::is_stdout),
+            Ordering::Relaxed,
+        );
+    }
+
     pub fn merge(&self, other: GatherSettings) -> Self {
         Self {
             mode: self.mode.clone(),
@@ -284,13 +370,61 @@ impl GatherSettings {
                 .or(self.insecure_skip_tls_verify),
             file: other.file.or(self.file.clone()),
             encoding: other.encoding.or(self.encoding.clone()),
+            oci_layer_compression: other.oci_layer_compression.or(self.oci_layer_compression),
+            oci_max_layers: other.oci_max_layers.or(self.oci_max_layers),
+            oci_max_layer_size: other.oci_max_layer_size.or(self.oci_max_layer_size),
+            base: other.base.or(self.base.clone()),
             oci: other.oci.merge(self.oci.clone()),
+            s3_upload: other.s3_upload.or(self.s3_upload.clone()),
+            s3: self.s3.merge(other.s3),
             secrets: if other.secrets.is_empty() {
                 self.secrets.clone()
             } else {
                 other.secrets
             },
             secrets_file: other.secrets_file.or(self.secrets_file.clone()),
+            auto_redact_secrets: other.auto_redact_secrets || self.auto_redact_secrets,
+            auto_redact_min_length: other.auto_redact_min_length.or(self.auto_redact_min_length),
+            ignore_fields: if other.ignore_fields.is_empty() {
+                self.ignore_fields.clone()
+            } else {
+                other.ignore_fields
+            },
+            disable_default_ignore_fields: other.disable_default_ignore_fields
+                || self.disable_default_ignore_fields,
+            redact_secrets: other.redact_secrets.or(self.redact_secrets),
+            redact_fields: if other.redact_fields.is_empty() {
+                self.redact_fields.clone()
+            } else {
+                other.redact_fields
+            },
+            detectors: if other.detectors.is_empty() {
+                self.detectors.clone()
+            } else {
+                other.detectors
+            },
+            redact_patterns: if other.redact_patterns.is_empty() {
+                self.redact_patterns.clone()
+            } else {
+                other.redact_patterns
+            },
+            pseudonymize_key: other.pseudonymize_key.or(self.pseudonymize_key.clone()),
+            pseudonymize_domains: if other.pseudonymize_domains.is_empty() {
+                self.pseudonymize_domains.clone()
+            } else {
+                other.pseudonymize_domains
+            },
+            pseudonym_mapping: other.pseudonym_mapping.or(self.pseudonym_mapping.clone()),
+            pseudonym_mapping_recipient: other
+                .pseudonym_mapping_recipient
+                .or(self.pseudonym_mapping_recipient.clone()),
+            encrypt_recipients: if other.encrypt_recipients.is_empty() {
+                self.encrypt_recipients.clone()
+            } else {
+                other.encrypt_recipients
+            },
+            encrypt_passphrase: other.encrypt_passphrase.or(self.encrypt_passphrase.clone()),
+            sign_key: other.sign_key.or(self.sign_key.clone()),
             duration: other.duration.or(self.duration),
             systemd_units: if other.systemd_units.is_empty() {
                 self.systemd_units.clone()
@@ -329,9 +463,11 @@ pub struct GatherSettings {
 
     /// The output file path.
     /// Defaults to a new archive with name "crust-gather".
+    /// Use "-" to stream a gzip, zstd or xz compressed tar to stdout, logs are written to stderr.
     ///
     /// Example:
     ///     --file=./artifacts
+    ///     --file=- > crust-gather.tar.gz
     #[arg(short, long, value_name = "PATH")]
     #[serde(default)]
     pub file: Option<Archive>,
@@ -340,15 +476,73 @@ pub struct GatherSettings {
     /// By default there is no encoding and data is written to the filesystem.
     /// The available options are:
     /// - gzip: GZip encoded tar.
+    /// - zstd: Zstandard encoded tar, with an optional level from 1 to 22. Defaults to 3.
+    /// - xz: XZ encoded tar, with an optional preset from 0 to 9. Defaults to 6.
     /// - zip: ZIP encoded.
     ///
     /// Example:
     ///     --encoding=zip
-    #[arg(short, long, value_enum)]
+    ///     --encoding=zstd:19
+    #[arg(short, long, value_name = "ENCODING",
+        value_parser = |arg: &str| -> anyhow::Result<Encoding> {Encoding::try_from(arg)})]
     #[serde(default)]
     #[arg(conflicts_with = "reference")]
     pub encoding: Option<Encoding>,
 
+    /// Compression of the OCI image layers.
+    /// The available options are:
+    /// - gzip: GZip compressed and base64 encoded layers, readable by all versions.
+    /// - zstd: Zstandard compressed layers with a crust-gather layer media type.
+    ///
+    /// Example:
+    ///     --oci-layer-compression=zstd
+    #[arg(long, value_enum, value_name = "COMPRESSION", requires = "reference")]
+    #[serde(default)]
+    pub oci_layer_compression: Option<LayerCompression>,
+
+    /// Maximum number of layers in the OCI image. Collection fails when the archive
+    /// requires more layers, as some registries reject large images.
+    /// Defaults to 1000.
+    ///
+    /// Example:
+    ///     --oci-max-layers=100
+    #[arg(long, value_name = "LAYERS", requires = "reference",
+        value_parser = |arg: &str| -> anyhow::Result<usize> {
+        let value = arg.parse::<usize>()?;
+        anyhow::ensure!(value >= 2, "an OCI archive requires at least 2 layers");
+        Ok(value)
+    })]
+    #[serde(default)]
+    pub oci_max_layers: Option<usize>,
+
+    /// Uncompressed size in MiB after which an OCI layer is closed and a new one is started.
+    /// Files are packed into layers per namespace, with logs bundled into tar layers, and
+    /// split into layers in path order once the collection is finished, so unchanged
+    /// files produce the same layers in every snapshot. Defaults to 64.
+    ///
+    /// Example:
+    ///     --oci-max-layer-size=256
+    #[arg(long, value_name = "MIB", requires = "reference",
+        value_parser = |arg: &str| -> anyhow::Result<usize> {
+        let value = arg.parse::<usize>()?;
+        anyhow::ensure!(value >= 1, "layer size must be at least 1 MiB");
+        Ok(value)
+    })]
+    #[serde(default)]
+    pub oci_max_layer_size: Option<usize>,
+
+    /// Previous snapshot in the same registry. Unchanged layers are mounted from the base
+    /// image instead of being uploaded again. Layers already present in the destination
+    /// repository are always reused. Encrypted archives use a new random layer key and
+    /// age encryption is randomized, so their layers never match and are always uploaded.
+    ///
+    /// Example:
+    ///     --base=ghcr.io/org/snapshots:previous
+    #[arg(long, value_name = "REFERENCE", requires = "reference",
+        value_parser = |arg: &str| -> anyhow::Result<OCIReference> {Ok(arg.try_into()?)})]
+    #[serde(default)]
+    pub base: Option<OCIReference>,
+
     /// OCI destination for crust gather collection. Stores cluster state in the provided image reference
     /// with optional authentication.
     #[clap(flatten)]
@@ -356,6 +550,22 @@ pub struct GatherSettings {
     #[serde(default)]
     pub oci: OCISettings,
 
+    /// S3 destination for the collection, as "s3://bucket/prefix". The archive is written
+    /// to "--file" and uploaded once finished, as a single object with an encoding,
+    /// or file by file under the archive name otherwise.
+    ///
+    /// Example:
+    ///     --s3-upload=s3://snapshots/prod --encoding=zstd
+    #[arg(long, value_name = "LOCATION", conflicts_with = "reference",
+        value_parser = |arg: &str| -> anyhow::Result<S3Location> {S3Location::try_from(arg)})]
+    #[serde(default)]
+    pub s3_upload: Option<S3Location>,
+
+    /// S3-compatible storage settings for "--s3-upload", under "s3" in the gather config.
+    #[command(flatten)]
+    #[serde(default)]
+    pub s3: S3Settings,
+
     /// Secret environment variable name with data to exclude in the collected artifacts.
     /// Can be specified multiple times to exclude multiple values.
     ///
@@ -381,6 +591,173 @@ pub struct GatherSettings {
     #[serde(default)]
     pub secrets_file: Option<SecretsFile>,
 
+    /// Collect values of all Secrets in the selected scope before collection and
+    /// strip them from every collected file, including container and host logs.
+    /// The number of replacements per file is stored in "redactions.json".
+    ///
+    /// Example:
+    ///     --auto-redact-secrets
+    #[arg(long)]
+    #[serde(default)]
+    pub auto_redact_secrets: bool,
+
+    /// Minimum length of a Secret value to be redacted with "--auto-redact-secrets".
+    /// Shorter values are skipped, as they are likely to match unrelated data.
+    /// Defaults to 8.
+    ///
+    /// Example:
+    ///     --auto-redact-min-length=12
+    #[arg(long, value_name = "LENGTH")]
+    #[serde(default)]
+    pub auto_redact_min_length: Option<usize>,
+
+    /// Redact the data of collected Secret objects, keeping keys, value sizes and metadata.
+    /// Values in the "last-applied-configuration" annotation are redacted as well.
+    /// The available modes are:
+    /// - hash: replace values with their HMAC-SHA256 digest under a random key per
+    ///   collection, truncated to the original size.
+    /// - marker: replace values with a fixed "<redacted>" marker.
+    ///
+    /// Example:
+    ///     --redact-secrets
+    ///     --redact-secrets=marker
+    #[arg(long, value_enum, value_name = "MODE", num_args = 0..=1, default_missing_value = "hash")]
+    #[serde(default)]
+    pub redact_secrets: Option<RedactMode>,
+
+    /// Field to redact in collected objects, using the same rule format as "--ignore-field".
+    /// Values are replaced according to the "--redact-secrets" mode, or hashed by default.
+    /// Can be specified multiple times.
+    ///
+    /// Example:
+    ///     --redact-field=example.com/Database:/spec/password
+    #[arg(long = "redact-field", value_name = "RULE", action = ArgAction::Append,
+        value_parser = |arg: &str| -> anyhow::Result<IgnoreRule> {Ok(IgnoreRule::try_from(arg)?)})]
+    #[serde(default)]
+    pub redact_fields: Vec<IgnoreRule>,
+
+    /// Built-in secret detector to apply on all collected data, including logs.
+    /// Matches are replaced with a named marker, like "<redacted:jwt>".
+    /// Can be specified multiple times.
+    ///
+    /// Example:
+    ///     --detector=jwt --detector=pem
+    ///     --detector=all
+    #[arg(long = "detector", value_name = "DETECTOR", value_enum, action = ArgAction::Append)]
+    #[serde(default)]
+    pub detectors: Vec<Detector>,
+
+    /// Named regular expression to redact in all collected data, including logs.
+    /// Matches are replaced with "<redacted:NAME>". If the expression has a named
+    /// group "secret", only the group is replaced. Can be specified multiple times.
+    ///
+    /// Example:
+    ///     --redact-pattern='password=password=(?P<secret>\S+)'
+    #[arg(long = "redact-pattern", value_name = "NAME=REGEX", action = ArgAction::Append,
+        value_parser = |arg: &str| -> anyhow::Result<RedactPattern> {Ok(RedactPattern::try_from(arg)?)})]
+    #[serde(default)]
+    pub redact_patterns: Vec<RedactPattern>,
+
+    /// Key for deterministic pseudonymization of namespace names, node names, IPv4 and IPv6
+    /// addresses and domain names. Setting the key enables pseudonymization of object content, archive
+    /// paths and logs. The same key always produces the same pseudonyms, so objects can be
+    /// correlated across files and collections.
+    ///
+    /// Example:
+    ///     CRUST_GATHER_PSEUDONYMIZE_KEY=my-key
+    ///     --pseudonymize-key=my-key
+    #[arg(
+        long,
+        value_name = "KEY",
+        env = "CRUST_GATHER_PSEUDONYMIZE_KEY",
+        hide_env_values = true
+    )]
+    #[serde(default)]
+    pub pseudonymize_key: Option<String>,
+
+    /// Domain to pseudonymize, including all subdomains. Can be specified multiple times.
+    ///
+    /// Example:
+    ///     --pseudonymize-domain=corp.example.com
+    #[arg(long = "pseudonymize-domain", value_name = "DOMAIN", action = ArgAction::Append)]
+    #[serde(default)]
+    pub pseudonymize_domains: Vec<String>,
+
+    /// Path to store the mapping of pseudonyms to original values, encrypted with age
+    /// for the "--pseudonym-mapping-recipient". The file is stored outside of the archive
+    /// and can be decrypted with "age --decrypt -i key.txt".
+    ///
+    /// Example:
+    ///     --pseudonym-mapping=mapping.json.age
+    #[arg(long, value_name = "PATH", requires = "pseudonym_mapping_recipient")]
+    #[serde(default)]
+    pub pseudonym_mapping: Option<PathBuf>,
+
+    /// The age X25519 public key used to encrypt the pseudonym mapping.
+    ///
+    /// Example:
+    ///     --pseudonym-mapping-recipient=age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p
+    #[arg(long, value_name = "RECIPIENT", requires = "pseudonym_mapping")]
+    #[serde(default)]
+    pub pseudonym_mapping_recipient: Option<String>,
+
+    /// The age X25519 public key to encrypt the archive for. Applies to the zip, gzip,
+    /// zstd, xz and OCI encodings. Can be specified multiple times to encrypt for multiple recipients.
+    /// The archive can be served with "serve --identity".
+    ///
+    /// Example:
+    ///     --encrypt-recipient=age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p
+    #[arg(long = "encrypt-recipient", value_name = "RECIPIENT", action = ArgAction::Append,
+        conflicts_with = "encrypt_passphrase")]
+    #[serde(default)]
+    pub encrypt_recipients: Vec<String>,
+
+    /// Passphrase to encrypt the archive with. Applies to all encodings except the plain path.
+    /// The archive can be served with "serve --decrypt-passphrase".
+    ///
+    /// Example:
+    ///     CRUST_GATHER_ENCRYPT_PASSPHRASE=passphrase
+    #[arg(
+        long,
+        value_name = "PASSPHRASE",
+        env = "CRUST_GATHER_ENCRYPT_PASSPHRASE",
+        hide_env_values = true
+    )]
+    #[serde(default)]
+    pub encrypt_passphrase: Option<String>,
+
+    /// Path to an ed25519 private key in PKCS#8 PEM format, used to sign the archive manifest.
+    /// The key can be generated with "openssl genpkey -algorithm ed25519".
+    ///
+    /// Example:
+    ///     --sign-key=key.pem
+    #[arg(long, value_name = "PATH")]
+    #[serde(default)]
+    pub sign_key: Option<PathBuf>,
+
+    /// Field to ignore when recording patches in record mode. Changes to ignored fields
+    /// alone do not produce a patch. Can be specified multiple times.
+    ///
+    /// The rule is a JSON pointer with optional glob wildcards, optionally prefixed with
+    /// a group and kind in the "--include-group" format, separated by ":".
+    /// By default resourceVersion, managedFields, condition heartbeats and Lease renewTime are ignored.
+    ///
+    /// Example:
+    ///     --ignore-field=/status/conditions/*/lastTransitionTime
+    ///     --ignore-field=apps/Deployment:/status/observedGeneration
+    #[arg(long = "ignore-field", value_name = "RULE", action = ArgAction::Append,
+        value_parser = |arg: &str| -> anyhow::Result<IgnoreRule> {Ok(IgnoreRule::try_from(arg)?)})]
+    #[serde(default)]
+    pub ignore_fields: Vec<IgnoreRule>,
+
+    /// Disable the default set of ignored fields in record mode.
+    ///
+    /// Example:
+    ///     --disable-default-ignore-fields
+    #[arg(long)]
+    #[serde(default)]
+    pub disable_default_ignore_fields: bool,
+
     /// The duration to run the collection for.
     /// Defaults to 60 seconds.
     ///
@@ -412,7 +789,9 @@ pub struct GatherSettings {
 #[derive(Parser, Clone, Default, Deserialize, Debug, schemars::JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub struct OCISettings {
-    /// Token to use for the registry authentication
+    /// Token to use for the registry authentication.
+    /// Without a token or username, credentials are taken from the docker config
+    /// "~/.docker/config.json" or "$DOCKER_CONFIG/config.json" and its credential helpers.
     #[arg(short, long, env = "OCI_AUTH_TOKEN")]
     #[arg(conflicts_with = "regular")]
     #[serde(default)]
@@ -434,12 +813,19 @@ pub struct OCISettings {
     #[serde(default)]
     pub ca_file: Option<Certificate>,
 
-    /// OCI Image reference
-    #[arg(short, long, value_parser = |arg: &str| -> anyhow::Result<OCIReference> {Ok(arg.try_into()?)})]
-    #[serde(default)]
-    pub reference: Option<OCIReference>,
-
-    /// Maximum number of OCI layers processed concurrently
+    /// OCI Image reference. The serve command accepts multiple references, other
+    /// commands work on a single image.
+    ///
+    /// Example:
+    ///     --reference=ghcr.io/org/snapshot:before --reference=ghcr.io/org/snapshot:after
+    #[arg(id = "reference", short, long = "reference", value_name = "REFERENCE",
+        value_parser = |arg: &str| -> anyhow::Result<OCIReference> {Ok(arg.try_into()?)})]
+    #[serde(default, rename = "reference", deserialize_with = "one_or_many")]
+    #[schemars(rename = "reference", with = "Vec<OCIReference>")]
+    pub references: Vec<OCIReference>,
+
+    /// Maximum number of OCI layers processed concurrently. Pushed layers are also limited
+    /// to 256 MiB of uncompressed data in flight.
     #[arg(short, long, value_parser = |arg: &str| -> anyhow::Result<usize> {
         let value = arg.parse::<u64>()?;
         anyhow::ensure!(value >= 1, "buffer size must be at least 1");
@@ -447,6 +833,12 @@ pub struct OCISettings {
     }, default_value_t = DEFAULT_OCI_BUFFER_SIZE)]
     #[serde(default = "default_oci_buffer_size")]
     pub buffer_size: usize,
+
+    /// Registry credentials, resolved once for the settings.
+    #[arg(skip)]
+    #[serde(skip)]
+    #[schemars(skip)]
+    auth: Arc<tokio::sync::OnceCell<RegistryAuth>>,
 }
 
 #[derive(
@@ -541,12 +933,36 @@ impl OCISettings {
             regular: self.regular.clone().or(other.regular),
             insecure: self.insecure || other.insecure,
             ca_file: self.ca_file.clone().or(other.ca_file),
-            reference: self.reference.clone().or(other.reference),
+            references: match self.references.is_empty() {
+                true => other.references,
+                false => self.references.clone(),
+            },
             buffer_size: if self.buffer_size == DEFAULT_OCI_BUFFER_SIZE {
                 other.buffer_size
             } else {
                 self.buffer_size
             },
+            auth: Default::default(),
+        }
+    }
+
+    /// Returns the image reference for commands working on a single image.
+    pub fn reference(&self) -> anyhow::Result<Option<&OCIReference>> {
+        match self.references.as_slice() {
+            [] => Ok(None),
+            [reference] => Ok(Some(reference)),
+            _ => Err(anyhow!(
+                "multiple references are only supported by the serve command"
+            )),
+        }
+    }
+
+    /// Returns the settings for one of the references.
+    pub fn with_reference(&self, reference: OCIReference) -> Self {
+        Self {
+            references: vec![reference],
+            auth: Default::default(),
+            ..self.clone()
         }
     }
 
@@ -568,15 +984,54 @@ impl OCISettings {
         config
     }
 
-    pub fn to_auth(&self) -> RegistryAuth {
-        let mut auth = RegistryAuth::Anonymous;
+    /// Returns the registry credentials. Without a token or username, credentials for
+    /// the reference registry are taken from the docker config and its credential helpers.
+    /// The credentials are resolved on the first call and reused afterwards.
+    pub async fn to_auth(&self) -> RegistryAuth {
         if let Some(token) = self.token.as_ref() {
-            auth = RegistryAuth::Bearer(token.clone())
+            return RegistryAuth::Bearer(token.clone());
         } else if let Some(up) = self.regular.as_ref() {
-            auth = RegistryAuth::Basic(up.username.clone(), up.password.clone())
+            return RegistryAuth::Basic(up.username.clone(), up.password.clone());
         }
 
-        auth
+        self.auth
+            .get_or_init(|| async {
+                let settings = self.clone();
+                tokio::task::spawn_blocking(move || settings.docker_auth())
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .flatten()
+                    .unwrap_or_else(|error| {
+                        tracing::warn!(
+                            %error,
+                            "Unable to read registry credentials from docker config"
+                        );
+                        None
+                    })
+                    .unwrap_or(RegistryAuth::Anonymous)
+            })
+            .await
+            .clone()
+    }
+
+    /// Returns the docker config credentials of the mirror registry, or the registry.
+    fn docker_auth(&self) -> anyhow::Result<Option<RegistryAuth>> {
+        let (Some(reference), Some(config)) = (self.reference()?, DockerConfig::load()?) else {
+            return Ok(None);
+        };
+
+        for registry in reference
+            .mirror_registry
+            .iter()
+            .chain([&reference.registry])
+        {
+            if let Some(auth) = config.auth(registry)? {
+                tracing::debug!(registry, "Using registry credentials from docker config");
+                return Ok(Some(auth));
+            }
+        }
+
+        Ok(None)
     }
 }
 
@@ -632,8 +1087,22 @@ impl GatherSettings {
         client.map_err(|e| anyhow::anyhow!("Failed to initialize client from kubeconfig: {e}"))
     }
 
+    /// Returns the name of the collected cluster from the kubeconfig current context.
+    /// Clusters accessed with a kubeconfig from a secret are not named.
+    pub fn cluster_name(&self) -> Option<String> {
+        if self.kubeconfig_secret.is_some() {
+            return None;
+        }
+
+        match &self.kubeconfig {
+            Some(kubeconfig) => kubeconfig.cluster_name(),
+            None => KubeconfigFile::infer_file().ok()?.cluster_name(),
+        }
+    }
+
     pub async fn to_writer(&self) -> anyhow::Result<Writer> {
-        let encoding = if let Some(reference) = self.oci.reference.as_ref() {
+        let reference = self.oci.reference()?;
+        let encoding = if let Some(reference) = reference {
             &Encoding::Oci(reference.clone().into())
         } else if let Some(encoding) = self.encoding.as_ref() {
             encoding
@@ -641,14 +1110,14 @@ impl GatherSettings {
             &Encoding::Path
         };
 
-        let client_config = if self.oci.reference.as_ref().is_some() {
+        let client_config = if reference.is_some() {
             Some(self.oci.to_client_config())
         } else {
             None
         };
 
-        let auth = if self.oci.reference.as_ref().is_some() {
-            Some(self.oci.to_auth())
+        let auth = if reference.is_some() {
+            Some(self.oci.to_auth().await)
         } else {
             None
         };
@@ -660,7 +1129,53 @@ impl GatherSettings {
             auth,
             self.oci.buffer_size,
         )
-        .await
+        .await?
+        .with_layer_compression(self.oci_layer_compression)
+        .with_cluster_name(self.cluster_name())
+        .with_private_summary(self.pseudonymize_key.is_some())
+        .with_layer_packing(LayerPacking {
+            max_layers: self.oci_max_layers.unwrap_or(DEFAULT_OCI_MAX_LAYERS),
+            max_layer_size: self
+                .oci_max_layer_size
+                .map_or(DEFAULT_OCI_MAX_LAYER_SIZE, |size| size * 1024 * 1024),
+        })
+        .with_encryption(Encryption::new(
+            self.encrypt_passphrase.clone(),
+            &self.encrypt_recipients,
+        )?)?
+        .with_base(self.base.clone().map(Into::into))
+        .await?
+        .with_s3(self.s3_upload.clone(), &self.s3)
+    }
+}
+
+#[derive(Parser, Clone, Default, Deserialize)]
+pub struct DecryptionSettings {
+    /// Path to an age identity file, used to decrypt encrypted archives.
+    ///
+    /// Example:
+    ///     --identity=key.txt
+    #[arg(long, value_name = "PATH", conflicts_with = "decrypt_passphrase")]
+    #[serde(default)]
+    pub identity: Option<PathBuf>,
+
+    /// Passphrase used to decrypt encrypted archives.
+    ///
+    /// Example:
+    ///     CRUST_GATHER_DECRYPT_PASSPHRASE=passphrase
+    #[arg(
+        long,
+        value_name = "PASSPHRASE",
+        env = "CRUST_GATHER_DECRYPT_PASSPHRASE",
+        hide_env_values = true
+    )]
+    #[serde(default)]
+    pub decrypt_passphrase: Option<String>,
+}
+
+impl DecryptionSettings {
+    pub fn to_decryption(&self) -> anyhow::Result<Option<Decryption>> {
+        Decryption::new(self.decrypt_passphrase.clone(), self.identity.as_deref())
     }
 }
 
@@ -668,6 +1183,25 @@ const fn default_oci_buffer_size() -> usize {
     DEFAULT_OCI_BUFFER_SIZE
 }
 
+/// Accepts a single reference, as written by configs before multiple references were
+/// supported, or a list of references.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<OCIReference>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(OCIReference),
+        Many(Vec<OCIReference>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(reference) => vec![reference],
+        OneOrMany::Many(references) => references,
+    })
+}
+
 #[derive(Parser, Clone, Default, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct DebugPod {
@@ -999,6 +1533,12 @@ impl GatherCommands {
     }
 
     pub async fn load(&self) -> anyhow::Result<Config> {
+        if matches!(self.mode, GatherMode::Record)
+            && self.settings.file.as_ref().is_some_and(Archive::is_stdout)
+        {
+            bail!("record writes patches next to the collected objects, --file=- is not supported");
+        }
+
         let env_secrets: Secrets = self.settings.secrets.clone().into();
         let mut secrets: Secrets = match self.settings.secrets_file.clone() {
             Some(file) => file.clone().try_into()?,
@@ -1007,13 +1547,74 @@ impl GatherCommands {
 
         secrets.0.extend(env_secrets.0.into_iter());
 
+        let client = self.client().await?;
+        let filter: Arc<FilterGroup> = Arc::new(self.into());
+        if self.settings.auto_redact_secrets {
+            let min_length = self
+                .settings
+                .auto_redact_min_length
+                .unwrap_or(DEFAULT_AUTO_REDACT_MIN_LENGTH);
+            secrets.0.extend(
+                Secrets::from_cluster(client.clone(), &filter, min_length)
+                    .await?
+                    .0,
+            );
+        }
+
+        let mut ignore = match self.settings.disable_default_ignore_fields {
+            true => IgnoreRules::default(),
+            false => IgnoreRules::defaults(),
+        };
+        ignore.0.extend(self.settings.ignore_fields.clone());
+
+        let pseudonymizer = match &self.settings.pseudonymize_key {
+            Some(key) => {
+                let mapping_file = match (
+                    &self.settings.pseudonym_mapping,
+                    &self.settings.pseudonym_mapping_recipient,
+                ) {
+                    (Some(path), Some(recipient)) => Some(MappingFile {
+                        path: path.clone(),
+                        recipient: recipient
+                            .parse()
+                            .map_err(|e| anyhow!("Invalid pseudonym mapping recipient: {e}"))?,
+                    }),
+                    _ => None,
+                };
+
+                Pseudonymizer::from_cluster(
+                    client.clone(),
+                    key,
+                    self.settings.pseudonymize_domains.clone(),
+                    mapping_file,
+                )
+                .await?
+            }
+            None => Pseudonymizer::default(),
+        };
+
+        let redaction = Redaction::new(
+            self.settings.redact_secrets,
+            self.settings.redact_fields.clone(),
+        )
+        .with_patterns(
+            self.settings
+                .detectors
+                .iter()
+                .flat_map(|detector| Vec::<RedactPattern>::from(*detector))
+                .chain(self.settings.redact_patterns.clone())
+                .collect(),
+        )
+        .with_pseudonymizer(pseudonymizer);
         let writer: Writer = self.settings.to_writer().await?;
 
         Ok(Config {
-            client: self.client().await?,
-            filter: Arc::new(self.into()),
-            writer: writer.into(),
+            client,
+            filter,
+            writer: WriterHandle::from(writer).with_redaction(secrets.clone(), redaction.clone()),
             secrets,
+            ignore,
+            redaction,
             mode: self.mode.clone(),
             additional_logs: self
                 .additional_logs
@@ -1026,6 +1627,12 @@ impl GatherCommands {
             duration: self.settings.duration.unwrap_or_default(),
             systemd_units: self.settings.systemd_units.clone(),
             debug_pod: self.settings.debug_pod.clone(),
+            signing_key: self
+                .settings
+                .sign_key
+                .as_deref()
+                .map(signing_key)
+                .transpose()?,
             disable_additional_logs: self.additional_logs.disable,
             skip_logs_collection: self
                 .filter
@@ -1684,4 +2291,43 @@ mod tests {
             GatherCommands::try_from(tmp_dir.path().join("invalid.yaml").to_str().unwrap());
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_streams_stdout() {
+        let settings = |file: Option<&str>| GatherSettings {
+            file: file.map(Into::into),
+            ..Default::default()
+        };
+        let command = |config: Option<&str>, config_map: bool, file: Option<&str>| {
+            Commands::CollectFromConfig {
+                source: ConfigSource {
+                    config: (!config_map).then(|| GatherCommands {
+                        settings: settings(config),
+                        ..Default::default()
+                    }),
+                    config_map: config_map.then(|| "crust-gather-config".to_string().into()),
+                },
+                overrides: settings(file),
+            }
+        };
+
+        assert!(command(Some("-"), false, None).streams_stdout());
+        assert!(!command(None, false, None).streams_stdout());
+        assert!(command(None, false, Some("-")).streams_stdout());
+        assert!(!command(Some("-"), false, Some("out.zip")).streams_stdout());
+        // The file of a config map is only known once it is loaded.
+        assert!(command(None, true, None).streams_stdout());
+    }
+
+    #[tokio::test]
+    async fn test_record_rejects_stdout() {
+        let mut config = GatherCommands {
+            mode: GatherMode::Record,
+            ..Default::default()
+        };
+        config.settings.file = Some("-".into());
+
+        let error = config.load().await.err().unwrap();
+        assert!(error.to_string().contains("--file=-"));
+    }
 }
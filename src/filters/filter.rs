@@ -52,13 +52,14 @@ where
     }
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Serialize)]
 pub struct FilterList(pub Vec<FilterType>);
 
 #[derive(Default)]
 pub struct FilterGroup(pub Vec<FilterList>);
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum FilterType {
     NamespaceExclude(Vec<Namespace<Exclude>>),
     NamespaceInclude(Vec<Namespace<Include>>),
@@ -0,0 +1,495 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::SystemTime,
+};
+
+use anyhow::{Context as _, bail};
+use clap::Parser;
+use oci_client::{Client, Reference, manifest::OciDescriptor, secrets::RegistryAuth};
+use serde::Deserialize;
+use tempfile::NamedTempFile;
+use walkdir::WalkDir;
+
+use super::manifest::digest;
+
+/// Default size limit of the blob cache in MiB.
+pub const DEFAULT_CACHE_SIZE: u64 = 2048;
+
+#[derive(Parser, Clone, Default, Deserialize)]
+pub struct CacheSettings {
+    /// Directory of the OCI blob cache, shared across runs and image references.
+    /// Downloaded GitHub artifacts and remote archives are kept in its "github" and
+    /// "http" subdirectories.
+    /// Defaults to "$XDG_CACHE_HOME/crust-gather/blobs" or "~/.cache/crust-gather/blobs".
+    ///
+    /// Example:
+    ///     --cache-dir=/tmp/crust-gather-cache
+    #[arg(long, value_name = "PATH")]
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Size limit of the cache in MiB, including downloaded archives. Least recently
+    /// used blobs and archives are removed once the limit is reached. Defaults to 2048.
+    ///
+    /// Example:
+    ///     --cache-size=512
+    #[arg(long, value_name = "MIB")]
+    #[serde(default)]
+    pub cache_size: Option<u64>,
+
+    /// Disable the OCI blob cache on disk. Blobs are downloaded on every run.
+    ///
+    /// Example:
+    ///     --no-cache
+    #[arg(long, conflicts_with_all = ["cache_dir", "cache_size"])]
+    #[serde(default)]
+    pub no_cache: bool,
+}
+
+impl CacheSettings {
+    pub fn to_cache(&self) -> anyhow::Result<Option<BlobCache>> {
+        let Some(dir) = self.dir() else {
+            return Ok(None);
+        };
+
+        let size = self.cache_size.unwrap_or(DEFAULT_CACHE_SIZE) * 1024 * 1024;
+        Ok(Some(BlobCache::new(dir, size)?))
+    }
+
+    /// Returns the cache directory, or None when caching is disabled.
+    pub fn dir(&self) -> Option<PathBuf> {
+        if self.no_cache {
+            return None;
+        }
+
+        match &self.cache_dir {
+            Some(dir) => Some(dir.clone()),
+            None => match (env::var_os("XDG_CACHE_HOME"), env::var_os("HOME")) {
+                (Some(cache), _) => Some(PathBuf::from(cache)),
+                (None, Some(home)) => Some(PathBuf::from(home).join(".cache")),
+                (None, None) => {
+                    tracing::warn!("No cache directory found, the blob cache is disabled");
+                    None
+                }
+            }
+            .map(|dir| dir.join("crust-gather").join("blobs")),
+        }
+    }
+}
+
+/// `BlobCache` stores OCI blobs on disk by digest, as they are stored in the registry.
+/// Encrypted layers stay encrypted in the cache. Downloaded archives are stored next to
+/// the blobs and share the size limit. The least recently used files are removed once
+/// the cache exceeds its size limit.
+///
+/// Sizes and the order of use are kept in an index, loaded from the directory once and
+/// ordered by modification time, which is updated on every read for later runs. Blobs are
+/// checked against their digest when they are stored, and when read after their size or
+/// modification time changed. File operations run on the blocking thread pool.
+#[derive(Clone)]
+pub struct BlobCache {
+    dir: PathBuf,
+    max_size: u64,
+    index: Arc<Mutex<Index>>,
+}
+
+/// Cached files with their size and last use.
+#[derive(Default)]
+struct Index {
+    loaded: bool,
+    files: HashMap<PathBuf, CachedFile>,
+    // Files by their last use, least recently used first.
+    lru: BTreeMap<u64, PathBuf>,
+    size: u64,
+    clock: u64,
+}
+
+struct CachedFile {
+    len: u64,
+    used: u64,
+    // Size and modification time of the file when its content was last checked against
+    // the digest.
+    verified: Option<(u64, SystemTime)>,
+}
+
+impl BlobCache {
+    pub fn new(dir: PathBuf, max_size: u64) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("unable to create cache directory {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            max_size,
+            index: Default::default(),
+        })
+    }
+
+    /// Returns the blob from the cache, or downloads and caches it.
+    pub async fn fetch(
+        &self,
+        client: &Client,
+        reference: &Reference,
+        auth: &RegistryAuth,
+        descriptor: &OciDescriptor,
+    ) -> anyhow::Result<Vec<u8>> {
+        if let Some(data) = self.get(&descriptor.digest).await {
+            return Ok(data);
+        }
+
+        client
+            .store_auth_if_needed(reference.resolve_registry(), auth)
+            .await;
+        let mut data = Vec::with_capacity(descriptor.size as usize);
+        client.pull_blob(reference, descriptor, &mut data).await?;
+        self.insert(&descriptor.digest, data).await
+    }
+
+    /// Returns true when the blob is in the cache.
+    pub async fn contains(&self, digest: &str) -> bool {
+        let Some(path) = self.path(digest) else {
+            return false;
+        };
+        self.blocking(move |cache| cache.index().files.contains_key(&path))
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Reads the blob, marking it as recently used. Blobs not matching the digest are removed.
+    pub async fn get(&self, digest: &str) -> Option<Vec<u8>> {
+        let path = self.path(digest)?;
+        let digest = digest.to_string();
+        self.blocking(move |cache| cache.read_blob(&digest, &path))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Path of a downloaded archive in the "kind" subdirectory, under the digest of the
+    /// key it was downloaded from.
+    pub fn download_path(&self, kind: &str, key: &str, name: &str) -> PathBuf {
+        let key = digest(key.as_bytes());
+        self.dir
+            .join(kind)
+            .join(key.trim_start_matches("sha256:"))
+            .join(name)
+    }
+
+    /// Opens a downloaded archive from the cache, marking it as recently used.
+    pub async fn open_download(&self, path: &Path) -> Option<File> {
+        let path = path.to_path_buf();
+        self.blocking(move |cache| {
+            let file = File::open(&path).ok()?;
+            cache.touch(&path, false);
+            Some(file)
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    /// Moves the downloaded archive into the cache, and evicts least recently used files
+    /// over the size limit. The archive itself is kept until the next insert.
+    pub async fn insert_download(&self, file: NamedTempFile, path: &Path) -> anyhow::Result<File> {
+        let path = path.to_path_buf();
+        self.blocking(move |cache| {
+            let file = file.persist(&path)?;
+            cache.add(&path, file.metadata()?.len(), None);
+            cache.evict(&path)?;
+            Ok(file)
+        })
+        .await?
+    }
+
+    /// Stores the blob after checking it against the digest, and evicts least recently
+    /// used blobs over the size limit. Returns the stored data.
+    pub async fn insert(&self, digest: &str, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if self::digest(&data) != digest {
+            bail!("blob does not match its digest {digest}");
+        }
+        let Some(path) = self.path(digest) else {
+            tracing::debug!(digest, "Unsupported digest, blob is not cached");
+            return Ok(data);
+        };
+
+        self.blocking(move |cache| {
+            let dir = path.parent().unwrap_or(&cache.dir);
+            fs::create_dir_all(dir)?;
+            let mut file = NamedTempFile::new_in(dir)?;
+            std::io::Write::write_all(&mut file, &data)?;
+            let file = file.persist(&path)?;
+            let metadata = file.metadata()?;
+            let verified = metadata.modified().ok().map(|time| (metadata.len(), time));
+            cache.add(&path, metadata.len(), verified);
+            cache.evict(&path)?;
+            Ok(data)
+        })
+        .await?
+    }
+
+    /// Runs the file operations on the blocking thread pool.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&Self) -> T + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let cache = self.clone();
+        Ok(tokio::task::spawn_blocking(move || operation(&cache)).await?)
+    }
+
+    /// Reads the blob, checking it against the digest unless it is unchanged since
+    /// the last check.
+    fn read_blob(&self, digest: &str, path: &Path) -> Option<Vec<u8>> {
+        let verified = self.index().files.get(path).and_then(|file| file.verified);
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => {
+                self.remove(path);
+                return None;
+            }
+        };
+
+        let current = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .map(|time| (data.len() as u64, time));
+        if (current.is_none() || verified != current) && self::digest(&data) != digest {
+            tracing::warn!(digest, "Removing corrupted blob from the cache");
+            let _ = fs::remove_file(path);
+            self.remove(path);
+            return None;
+        }
+
+        self.touch(path, true);
+        Some(data)
+    }
+
+    /// Path of the blob in the cache, for sha256 digests only.
+    fn path(&self, digest: &str) -> Option<PathBuf> {
+        let hex = digest.strip_prefix("sha256:")?;
+        match hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            true => Some(self.dir.join("sha256").join(hex)),
+            false => None,
+        }
+    }
+
+    /// Returns the index, loading it from the cache directory on first use. Files being
+    /// downloaded are not counted.
+    fn index(&self) -> MutexGuard<'_, Index> {
+        let mut index = self.index.lock().unwrap_or_else(PoisonError::into_inner);
+        if index.loaded {
+            return index;
+        }
+
+        let mut files: Vec<(SystemTime, u64, PathBuf)> = WalkDir::new(&self.dir)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with(".tmp"))
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                metadata.is_file().then(|| {
+                    (
+                        metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                        metadata.len(),
+                        entry.into_path(),
+                    )
+                })
+            })
+            .collect();
+        files.sort();
+        for (_, len, path) in files {
+            index.add(path, len, None);
+        }
+        index.loaded = true;
+        index
+    }
+
+    fn add(&self, path: &Path, len: u64, verified: Option<(u64, SystemTime)>) {
+        self.index().add(path.to_path_buf(), len, verified);
+    }
+
+    fn remove(&self, path: &Path) {
+        self.index().remove(path);
+    }
+
+    /// Marks the file as recently used, and updates its modification time, which orders
+    /// the files of the next run. Verified files are recorded as unchanged since the check.
+    fn touch(&self, path: &Path, verified: bool) {
+        if let Err(error) = File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+        {
+            tracing::debug!(%error, path = %path.display(), "Unable to update cache access time");
+        }
+
+        let Ok(metadata) = fs::metadata(path) else {
+            return;
+        };
+        let verified = match verified {
+            true => metadata.modified().ok().map(|time| (metadata.len(), time)),
+            false => None,
+        };
+        self.add(path, metadata.len(), verified);
+    }
+
+    /// Removes the least recently used blobs and archives over the size limit, except
+    /// the just inserted file.
+    fn evict(&self, keep: &Path) -> anyhow::Result<()> {
+        let mut index = self.index();
+        let mut kept = None;
+        while index.size > self.max_size
+            && let Some((used, path)) = index.lru.pop_first()
+        {
+            if path == keep {
+                kept = Some((used, path));
+                continue;
+            }
+
+            tracing::debug!(path = %path.display(), "Evicting file from the cache");
+            if let Some(file) = index.files.remove(&path) {
+                index.size -= file.len;
+            }
+            match fs::remove_file(&path) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+                _ => (),
+            }
+            // Archives are stored in a directory per download key.
+            if let Some(parent) = path.parent()
+                && parent.parent() != Some(self.dir.as_path())
+            {
+                let _ = fs::remove_dir(parent);
+            }
+        }
+        if let Some((used, path)) = kept {
+            index.lru.insert(used, path);
+        }
+
+        Ok(())
+    }
+}
+
+impl Index {
+    /// Adds or updates the file as the most recently used one.
+    fn add(&mut self, path: PathBuf, len: u64, verified: Option<(u64, SystemTime)>) {
+        self.remove(&path);
+        self.clock += 1;
+        self.size += len;
+        self.lru.insert(self.clock, path.clone());
+        self.files.insert(
+            path,
+            CachedFile {
+                len,
+                used: self.clock,
+                verified,
+            },
+        );
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(file) = self.files.remove(path) {
+            self.size -= file.len;
+            self.lru.remove(&file.used);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write as _, time::Duration};
+
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::BlobCache;
+    use crate::gather::manifest::digest;
+
+    #[tokio::test]
+    async fn test_blob_cache_eviction() {
+        let tmp_dir = TempDir::new().unwrap();
+        let cache = BlobCache::new(tmp_dir.path().to_path_buf(), 12).unwrap();
+
+        let blobs = [b"first".as_slice(), b"second", b"third"];
+        let digests = blobs.map(digest);
+        assert!(cache.insert(&digests[0], b"other".to_vec()).await.is_err());
+
+        cache.insert(&digests[0], blobs[0].to_vec()).await.unwrap();
+        cache.insert(&digests[1], blobs[1].to_vec()).await.unwrap();
+        assert_eq!(cache.get(&digests[0]).await.as_deref(), Some(blobs[0]));
+
+        // The least recently used blob is evicted, the first blob was read after the second.
+        cache.insert(&digests[2], blobs[2].to_vec()).await.unwrap();
+        assert!(cache.contains(&digests[0]).await);
+        assert!(!cache.contains(&digests[1]).await);
+        assert!(cache.contains(&digests[2]).await);
+
+        let path = tmp_dir
+            .path()
+            .join("sha256")
+            .join(digests[2].trim_start_matches("sha256:"));
+        fs::write(path, "corrupted").unwrap();
+        assert_eq!(cache.get(&digests[2]).await, None);
+        assert!(!cache.contains(&digests[2]).await);
+    }
+
+    #[tokio::test]
+    async fn test_blob_cache_download_eviction() {
+        let tmp_dir = TempDir::new().unwrap();
+        let cache = BlobCache::new(tmp_dir.path().to_path_buf(), 12).unwrap();
+
+        let blob = digest(b"first");
+        cache.insert(&blob, b"first".to_vec()).await.unwrap();
+
+        // Downloads count against the size limit and evict older blobs.
+        let insert = async |key: &str, data: &[u8]| {
+            let path = cache.download_path("http", key, "snapshot.zip");
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let mut file = NamedTempFile::new_in(path.parent().unwrap()).unwrap();
+            file.write_all(data).unwrap();
+            cache.insert_download(file, &path).await.unwrap();
+            path
+        };
+        let first = insert("https://example.com/a.zip", b"archives").await;
+        assert!(!cache.contains(&blob).await);
+        assert!(cache.open_download(&first).await.is_some());
+
+        // The inserted download is kept even over the size limit.
+        let second = insert("https://example.com/b.zip", b"larger archive").await;
+        assert!(!first.exists());
+        assert!(!first.parent().unwrap().exists());
+        assert!(second.exists());
+    }
+
+    #[tokio::test]
+    async fn test_blob_cache_reload() {
+        let tmp_dir = TempDir::new().unwrap();
+        let blobs = [b"first".as_slice(), b"second", b"third"];
+        let digests = blobs.map(digest);
+
+        let cache = BlobCache::new(tmp_dir.path().to_path_buf(), 12).unwrap();
+        cache.insert(&digests[0], blobs[0].to_vec()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        cache.insert(&digests[1], blobs[1].to_vec()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        cache.get(&digests[0]).await.unwrap();
+
+        // A new cache loads the sizes and the order of use from the directory.
+        let cache = BlobCache::new(tmp_dir.path().to_path_buf(), 12).unwrap();
+        cache.insert(&digests[2], blobs[2].to_vec()).await.unwrap();
+        assert!(cache.contains(&digests[0]).await);
+        assert!(!cache.contains(&digests[1]).await);
+
+        // Blobs not checked by this cache are checked against the digest on read.
+        let path = tmp_dir
+            .path()
+            .join("sha256")
+            .join(digests[0].trim_start_matches("sha256:"));
+        fs::write(&path, "fifth").unwrap();
+        let cache = BlobCache::new(tmp_dir.path().to_path_buf(), 12).unwrap();
+        assert_eq!(cache.get(&digests[0]).await, None);
+        assert!(!path.exists());
+        assert_eq!(cache.get(&digests[2]).await.as_deref(), Some(blobs[2]));
+    }
+}
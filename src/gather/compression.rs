@@ -0,0 +1,207 @@
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+};
+
+use anyhow::bail;
+use clap::ValueEnum;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use lzma_rust2::{XzOptions, XzReader, XzWriter};
+use oci_client::manifest::IMAGE_LAYER_MEDIA_TYPE;
+use serde::{Deserialize, Serialize};
+
+pub const GZIP_EXTENSION: &str = "tar.gz";
+pub const ZSTD_EXTENSION: &str = "tar.zst";
+pub const XZ_EXTENSION: &str = "tar.xz";
+pub const ZIP_EXTENSION: &str = "zip";
+
+/// Default zstd level for archives, which favors speed on large collections.
+pub const DEFAULT_ZSTD_LEVEL: i32 = zstd::DEFAULT_COMPRESSION_LEVEL;
+
+/// Default xz preset for archives.
+pub const DEFAULT_XZ_LEVEL: u32 = 6;
+
+/// Media type of zstd compressed OCI layers. Layers hold a single file or a YAML list of
+/// resources rather than a tar, so the media type is specific to crust-gather.
+pub const LAYER_ZSTD_MEDIA_TYPE: &str = "application/vnd.crust-gather.layer.v1+zstd";
+
+/// `TarEncoder` compresses the tar stream of a single file archive, or of an archive
+/// streamed to stdout.
+pub enum TarEncoder<W: Write = File> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(Box<XzWriter<W>>),
+}
+
+impl<W: Write> TarEncoder<W> {
+    pub fn gzip(file: W) -> Self {
+        Self::Gzip(GzEncoder::new(file, Compression::default()))
+    }
+
+    pub fn zstd(file: W, level: i32) -> anyhow::Result<Self> {
+        Ok(Self::Zstd(zstd::Encoder::new(file, level)?))
+    }
+
+    pub fn xz(file: W, level: u32) -> anyhow::Result<Self> {
+        Ok(Self::Xz(Box::new(XzWriter::new(
+            file,
+            XzOptions::with_preset(level),
+        )?)))
+    }
+
+    /// Extension of the archive file, without the leading dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Gzip(_) => GZIP_EXTENSION,
+            Self::Zstd(_) => ZSTD_EXTENSION,
+            Self::Xz(_) => XZ_EXTENSION,
+        }
+    }
+
+    /// Writes the compression trailer and returns the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+            Self::Xz(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for TarEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::Xz(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Returns true when the file name is a compressed tar archive.
+pub fn is_tar(name: &str) -> bool {
+    [GZIP_EXTENSION, ZSTD_EXTENSION, XZ_EXTENSION]
+        .iter()
+        .any(|extension| name.ends_with(&format!(".{extension}")))
+}
+
+/// Returns a reader with the decompressed tar stream, choosing the decoder from the file name.
+pub fn tar_decoder<'a>(name: &str, reader: impl Read + 'a) -> anyhow::Result<Box<dyn Read + 'a>> {
+    Ok(if name.ends_with(&format!(".{GZIP_EXTENSION}")) {
+        Box::new(GzDecoder::new(reader))
+    } else if name.ends_with(&format!(".{ZSTD_EXTENSION}")) {
+        Box::new(zstd::Decoder::new(reader)?)
+    } else if name.ends_with(&format!(".{XZ_EXTENSION}")) {
+        Box::new(XzReader::new(reader, true))
+    } else {
+        bail!("unsupported tar archive: {name}")
+    })
+}
+
+/// Compression of OCI layers.
+/// - gzip: gzip compressed and base64 encoded, readable by all crust-gather versions.
+/// - zstd: zstd compressed, stored with the crust-gather zstd layer media type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LayerCompression {
+    #[default]
+    Gzip,
+    Zstd,
+}
+
+impl LayerCompression {
+    pub fn from_media_type(media_type: &str) -> Self {
+        match media_type {
+            LAYER_ZSTD_MEDIA_TYPE => Self::Zstd,
+            _ => Self::Gzip,
+        }
+    }
+
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            Self::Gzip => IMAGE_LAYER_MEDIA_TYPE,
+            Self::Zstd => LAYER_ZSTD_MEDIA_TYPE,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Self::Gzip => {
+                let mut enc = GzEncoder::new(vec![], Compression::best());
+                enc.write_all(data)?;
+                enc.finish()?
+            }
+            Self::Zstd => zstd::encode_all(data, DEFAULT_ZSTD_LEVEL)?,
+        })
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut objects = vec![];
+        match self {
+            Self::Gzip => GzDecoder::new(data).read_to_end(&mut objects)?,
+            Self::Zstd => zstd::Decoder::new(data)?.read_to_end(&mut objects)?,
+        };
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_tar_roundtrip() {
+        let tmp_dir = TempDir::new().unwrap();
+        for encoder in [
+            TarEncoder::gzip as fn(File) -> TarEncoder<File>,
+            |file| TarEncoder::zstd(file, DEFAULT_ZSTD_LEVEL).unwrap(),
+            |file| TarEncoder::xz(file, DEFAULT_XZ_LEVEL).unwrap(),
+        ] {
+            let path = tmp_dir.path().join("archive");
+            let encoder = encoder(File::create(&path).unwrap());
+            let path = path.with_extension(encoder.extension());
+            std::fs::rename(tmp_dir.path().join("archive"), &path).unwrap();
+
+            let mut builder = tar::Builder::new(encoder);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(4);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, "file", b"data".as_slice())
+                .unwrap();
+            builder.into_inner().unwrap().finish().unwrap();
+
+            let name = path.to_string_lossy();
+            assert!(is_tar(&name));
+            let mut archive =
+                tar::Archive::new(tar_decoder(&name, File::open(&path).unwrap()).unwrap());
+            let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+            let mut data = String::new();
+            entry.read_to_string(&mut data).unwrap();
+            assert_eq!(data, "data");
+        }
+    }
+
+    #[test]
+    fn test_layer_compression() {
+        for compression in [LayerCompression::Gzip, LayerCompression::Zstd] {
+            let compressed = compression.compress(b"layer").unwrap();
+            assert_eq!(
+                LayerCompression::from_media_type(compression.media_type()),
+                compression
+            );
+            assert_eq!(compression.decompress(&compressed).unwrap(), b"layer");
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Display;
 use std::fs::File;
 use std::path::{Path, PathBuf};
@@ -8,22 +9,23 @@ use std::{env, fs};
 use anyhow::{self, bail};
 use base64::prelude::*;
 use duration_string::DurationString;
+use ed25519_dalek::SigningKey;
 use futures::future::join_all;
-use k8s_openapi::api::core::v1::{ConfigMap, Event, Node, Pod, Secret};
+use k8s_openapi::api::core::v1::{ConfigMap, Event, Namespace, Node, Pod, Secret};
+use k8s_openapi::serde_json;
 use kube::api::ListParams;
 use kube::config::Kubeconfig;
-use kube::core::ApiResource;
 use kube::core::discovery::verbs::{LIST, WATCH};
+use kube::core::{ApiResource, GroupVersionKind};
 use kube::{Api, Client, ResourceExt, discovery};
 use serde::Deserialize;
 use serde::de::DeserializeOwned;
 use serde_saphyr::ser_options;
-use tokio::sync::Mutex;
 use tokio::time::timeout;
 use tracing::instrument;
 
 use crate::cli::DebugPod;
-use crate::filters::filter::FilterGroup;
+use crate::filters::filter::{Filter as _, FilterGroup};
 use crate::scanners::dynamic::Dynamic;
 use crate::scanners::events::Events;
 use crate::scanners::host_logs::HostLogs;
@@ -32,11 +34,33 @@ use crate::scanners::interface::Collect;
 use crate::scanners::logs::{LogSelection, Logs};
 use crate::scanners::versions::Versions;
 
-use super::representation::{CustomLog, NamespaceName, Representation};
-use super::writer::Writer;
+use super::ignore::IgnoreRules;
+use super::redact::Redaction;
+use super::representation::{ArchivePath, CustomLog, NamespaceName, Representation};
+use super::writer::WriterHandle;
 
 #[derive(Default, Clone, Debug)]
-pub struct Secrets(pub Vec<String>);
+pub struct Secrets(pub Vec<String>, Replacements);
+
+/// `Replacements` counts secret replacements made per archive file, shared between scanners.
+#[derive(Default, Clone, Debug)]
+pub struct Replacements(Arc<std::sync::Mutex<BTreeMap<String, usize>>>);
+
+impl Replacements {
+    fn add(&self, path: String, count: usize) {
+        *self
+            .0
+            .lock()
+            .expect("replacements lock poisoned")
+            .entry(path)
+            .or_default() += count;
+    }
+
+    /// Returns the number of replacements made per archive file.
+    pub fn counts(&self) -> BTreeMap<String, usize> {
+        self.0.lock().expect("replacements lock poisoned").clone()
+    }
+}
 
 #[derive(Default, Clone, Deserialize)]
 pub struct SecretsFile(pub PathBuf);
@@ -44,15 +68,134 @@ pub struct SecretsFile(pub PathBuf);
 impl Secrets {
     /// Replaces any secrets in representation data with xxx.
     pub fn strip(&self, repr: &Representation) -> Representation {
-        let mut data = repr.data().to_string();
+        let (data, count) = self.replace(repr.data());
+        self.count(repr, count);
+        repr.clone().with_data(data.as_str())
+    }
+
+    /// Strips the secrets from the representation between the structural redaction and the
+    /// text redaction. Secret objects keep their value lengths, while secret values elsewhere
+    /// are found before names and addresses in them are pseudonymized. Replacements are
+    /// counted under the redacted archive path.
+    pub fn strip_and_redact(&self, repr: &Representation, redaction: &Redaction) -> Representation {
+        let repr = redaction.redact_object(repr);
+        let (data, count) = self.replace(repr.data());
+        let repr = redaction.redact_text(repr.with_data(data.as_str()));
+        self.count(&repr, count);
+        repr
+    }
+
+    fn replace(&self, data: &str) -> (String, usize) {
+        let mut data = data.to_string();
+        let mut count = 0;
         for secret in &self.0 {
-            data = data.replace(secret.as_str(), "xxx");
             let b64 = BASE64_STANDARD.encode(secret);
-            data = data.replace(b64.as_str(), "xxx");
-            data = data.replace(BASE64_STANDARD.encode(b64).as_str(), "xxx");
+            for value in [secret.clone(), BASE64_STANDARD.encode(&b64), b64] {
+                count += data.matches(value.as_str()).count();
+                data = data.replace(value.as_str(), "xxx");
+            }
         }
 
-        repr.clone().with_data(data.as_str())
+        (data, count)
+    }
+
+    fn count(&self, repr: &Representation, count: usize) {
+        if count > 0 {
+            self.1
+                .add(String::try_from(repr.path()).unwrap_or_default(), count);
+        }
+    }
+
+    /// Returns the replacement counts collected while stripping representations.
+    pub fn replacements(&self) -> &Replacements {
+        &self.1
+    }
+
+    /// Collects decoded values of all Secrets accepted by the filter, which are at least
+    /// `min_length` long, so they can be stripped from any other collected data.
+    #[instrument(skip_all, err)]
+    pub async fn from_cluster(
+        client: Client,
+        filter: &FilterGroup,
+        min_length: usize,
+    ) -> anyhow::Result<Self> {
+        let gvk = GroupVersionKind::gvk("", "v1", "Secret");
+        let mut values = BTreeSet::new();
+        for secret in Self::list_secrets(client)
+            .await?
+            .iter()
+            .filter(|secret| filter.filter(&gvk, *secret))
+        {
+            let data = secret.data.iter().flatten().map(|(_, v)| v.0.clone());
+            let string_data = secret
+                .string_data
+                .iter()
+                .flatten()
+                .map(|(_, v)| v.clone().into_bytes());
+            for value in data.chain(string_data) {
+                let Ok(value) = String::from_utf8(value) else {
+                    continue;
+                };
+                for value in [value.trim().to_string(), value] {
+                    if value.len() >= min_length {
+                        values.insert(value);
+                    }
+                }
+            }
+        }
+
+        // Longer values are replaced first, so values containing others are fully stripped.
+        let mut values: Vec<String> = values.into_iter().collect();
+        values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+        tracing::info!(
+            count = values.len(),
+            "Collected secret values for redaction"
+        );
+
+        Ok(Self(values, Default::default()))
+    }
+
+    /// Lists Secrets cluster-wide, falling back to listing them per namespace when the
+    /// collector is not allowed to list them cluster-wide. Namespaces where listing is
+    /// forbidden are skipped with a warning.
+    async fn list_secrets(client: Client) -> anyhow::Result<Vec<Secret>> {
+        let params = ListParams::default();
+        match Api::<Secret>::all(client.clone()).list(&params).await {
+            Ok(secrets) => return Ok(secrets.items),
+            Err(kube::Error::Api(status)) if status.code == 403 => {
+                tracing::warn!("Not allowed to list Secrets cluster-wide, listing per namespace")
+            }
+            Err(error) => return Err(error.into()),
+        }
+
+        let namespaces = match Api::<Namespace>::all(client.clone())
+            .list_metadata(&params)
+            .await
+        {
+            Ok(namespaces) => namespaces.items,
+            Err(kube::Error::Api(status)) if status.code == 403 => {
+                tracing::warn!("Not allowed to list namespaces, no Secret values are redacted");
+                return Ok(vec![]);
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut secrets = vec![];
+        for namespace in namespaces {
+            let namespace = namespace.name_any();
+            match Api::<Secret>::namespaced(client.clone(), &namespace)
+                .list(&params)
+                .await
+            {
+                Ok(list) => secrets.extend(list.items),
+                Err(kube::Error::Api(status)) if status.code == 403 => {
+                    tracing::warn!(namespace, "Not allowed to list Secrets, skipping namespace")
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        Ok(secrets)
     }
 }
 
@@ -64,6 +207,7 @@ impl From<Vec<String>> for Secrets {
                 .map(|s| env::var(s).unwrap_or_default())
                 .filter(|s| !s.is_empty())
                 .collect(),
+            Default::default(),
         )
     }
 }
@@ -78,6 +222,7 @@ impl TryFrom<SecretsFile> for Secrets {
                 .lines()
                 .map(Into::into)
                 .collect(),
+            Default::default(),
         ))
     }
 }
@@ -151,6 +296,18 @@ impl KubeconfigFile {
         Ok(self)
     }
 
+    /// Returns the cluster name of the current context.
+    pub fn cluster_name(&self) -> Option<String> {
+        let current = self.0.current_context.as_ref()?;
+        self.0
+            .contexts
+            .iter()
+            .find(|context| &context.name == current)?
+            .context
+            .as_ref()
+            .map(|context| context.cluster.clone())
+    }
+
     pub fn infer_file() -> anyhow::Result<Self> {
         Ok(Self(Kubeconfig::read()?))
     }
@@ -360,13 +517,16 @@ pub enum GatherMode {
 pub struct Config {
     pub client: Client,
     pub filter: Arc<FilterGroup>,
-    pub writer: Arc<Mutex<Writer>>,
+    pub writer: WriterHandle,
     pub secrets: Secrets,
+    pub ignore: IgnoreRules,
+    pub redaction: Redaction,
     pub mode: GatherMode,
     pub additional_logs: Vec<CustomLog>,
     pub duration: RunDuration,
     pub systemd_units: Vec<String>,
     pub debug_pod: DebugPod,
+    pub signing_key: Option<SigningKey>,
 
     pub disable_additional_logs: bool,
     pub skip_logs_collection: bool,
@@ -419,12 +579,43 @@ impl Config {
         self.finish().await
     }
 
+    // Collection parameters recorded in the archive manifest.
+    fn parameters(&self) -> serde_json::Value {
+        serde_json::json!({
+            "mode": match self.mode {
+                GatherMode::Collect => "collect",
+                GatherMode::Record => "record",
+            },
+            "duration": self.duration.to_string(),
+            "filters": self.filter.0,
+            "systemdUnits": self.systemd_units,
+            "skipLogsCollection": self.skip_logs_collection,
+            "skipEventsCollection": self.skip_events_collection,
+        })
+    }
+
     async fn finish(&self) -> anyhow::Result<()> {
         let writer = &self.writer.clone();
-        writer.lock().await.finish_oci().await?;
-        writer.lock().await.finish_gzip()?;
-        drop(writer.lock().await);
-        Ok(())
+        let replacements = self.secrets.replacements().counts();
+        if !replacements.is_empty() {
+            tracing::info!(
+                files = replacements.len(),
+                replacements = replacements.values().sum::<usize>(),
+                "Redacted secret values"
+            );
+            writer
+                .store(
+                    Representation::new()
+                        .with_path(ArchivePath::Custom("redactions.json".into()))
+                        .with_data(&serde_json::to_string_pretty(&replacements)?),
+                )
+                .await?;
+        }
+        self.redaction.pseudonymizer().save_mapping()?;
+        writer
+            .store_manifest(self.parameters(), self.signing_key.clone())
+            .await?;
+        writer.finish().await
     }
 
     async fn iterate_until_completion(&self, collectables: impl Iterator<Item = Collectable>) {
@@ -523,7 +714,10 @@ mod tests {
     use crate::{
         cli::DEFAULT_OCI_BUFFER_SIZE,
         filters::filter::{FilterList, Include},
-        gather::writer::{Archive, Encoding},
+        gather::{
+            redact::RedactMode,
+            writer::{Archive, Encoding, Writer},
+        },
     };
 
     use crate::filters::namespace::Namespace;
@@ -576,6 +770,48 @@ mod tests {
         assert_eq!(result.data(), "omit xxx string");
     }
 
+    #[test]
+    fn test_strip_secrets_replacements() {
+        unsafe { env::set_var("REPLACED_KEY", "password") };
+
+        let secrets: Secrets = vec!["REPLACED_KEY".to_string()].into();
+        let repr = |path: &str, data: &str| {
+            Representation::new()
+                .with_path(ArchivePath::Custom(path.into()))
+                .with_data(data)
+        };
+        secrets.strip(&repr("a.log", "password and cGFzc3dvcmQ="));
+        secrets.clone().strip(&repr("a.log", "password"));
+        secrets.strip(&repr("b.log", "nothing"));
+
+        assert_eq!(
+            secrets.replacements().counts(),
+            BTreeMap::from([("a.log".to_string(), 3)])
+        );
+    }
+
+    #[test]
+    fn test_strip_and_redact_secret_lengths() {
+        unsafe { env::set_var("REDACTED_KEY", "hunter2") };
+
+        let secrets: Secrets = vec!["REDACTED_KEY".to_string()].into();
+        let repr = Representation::new()
+            .with_path(ArchivePath::Custom("secret.yaml".into()))
+            .with_data(&format!(
+                "apiVersion: v1\nkind: Secret\nmetadata:\n  name: creds\ndata:\n  password: {}\n",
+                BASE64_STANDARD.encode("hunter2"),
+            ));
+        let redaction = Redaction::new(Some(RedactMode::Hash), vec![]);
+        let result: serde_json::Value =
+            serde_saphyr::from_str(secrets.strip_and_redact(&repr, &redaction).data()).unwrap();
+
+        // The value is redacted structurally, keeping the length of the original secret.
+        let password = BASE64_STANDARD
+            .decode(result["data"]["password"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(password.len(), "hunter2".len());
+    }
+
     #[test]
     fn test_strip_secrets_from_file() {
         let data = "omit password string with ip 10.10.10.10".to_string();
@@ -614,6 +850,9 @@ mod tests {
             .expect("failed to create builder")
             .into(),
             secrets: Default::default(),
+            ignore: Default::default(),
+            redaction: Default::default(),
+            signing_key: None,
             mode: GatherMode::Collect,
             duration: "10s".try_into().unwrap(),
             additional_logs: Default::default(),
@@ -656,6 +895,9 @@ mod tests {
             duration: "1m".try_into().unwrap(),
             mode: GatherMode::Collect,
             secrets: Default::default(),
+            ignore: Default::default(),
+            redaction: Default::default(),
+            signing_key: None,
             additional_logs: Default::default(),
             systemd_units: Default::default(),
             debug_pod: Default::default(),
@@ -694,6 +936,9 @@ mod tests {
             duration: "1m".try_into().unwrap(),
             mode: GatherMode::Collect,
             secrets: Default::default(),
+            ignore: Default::default(),
+            redaction: Default::default(),
+            signing_key: None,
             additional_logs: Default::default(),
             systemd_units: Default::default(),
             debug_pod: Default::default(),
@@ -0,0 +1,225 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::Write as _,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context as _, bail};
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use oci_client::secrets::RegistryAuth;
+use serde::Deserialize;
+
+/// Registry host names of Docker Hub, which is stored under the legacy index URL.
+const DOCKER_HUB: [&str; 4] = [
+    "docker.io",
+    "index.docker.io",
+    "registry-1.docker.io",
+    "https://index.docker.io/v1/",
+];
+
+/// Username returned by credential helpers for identity tokens, which are refresh
+/// tokens for the registry token endpoint.
+const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+
+/// Registry credentials from the docker `config.json`.
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+    #[serde(default)]
+    creds_store: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+struct AuthEntry {
+    #[serde(default)]
+    auth: Option<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    registrytoken: Option<String>,
+    #[serde(default)]
+    identitytoken: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+impl DockerConfig {
+    /// Loads the config from "$DOCKER_CONFIG/config.json" or "~/.docker/config.json".
+    pub fn load() -> anyhow::Result<Option<Self>> {
+        let dir = match (env::var_os("DOCKER_CONFIG"), env::var_os("HOME")) {
+            (Some(dir), _) => PathBuf::from(dir),
+            (None, Some(home)) => PathBuf::from(home).join(".docker"),
+            (None, None) => return Ok(None),
+        };
+        let path = dir.join("config.json");
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let data = fs::read(&path)?;
+        Ok(Some(serde_json::from_slice(&data).with_context(|| {
+            format!("invalid docker config {}", path.display())
+        })?))
+    }
+
+    /// Returns the credentials for the registry. Credential helpers take precedence over
+    /// the credentials store and the inline auth entries, as in docker.
+    pub fn auth(&self, registry: &str) -> anyhow::Result<Option<RegistryAuth>> {
+        let keys = Self::keys(registry);
+        if let Some(helper) = keys.iter().find_map(|key| self.cred_helpers.get(key)) {
+            return Self::helper_auth(helper, &keys[0]);
+        }
+        if let Some(store) = &self.creds_store
+            && let Some(auth) = Self::helper_auth(store, &keys[0])?
+        {
+            return Ok(Some(auth));
+        }
+
+        let Some(entry) = self.auths.iter().find_map(|(key, entry)| {
+            keys.contains(&Self::normalize(key).to_string())
+                .then_some(entry)
+        }) else {
+            return Ok(None);
+        };
+        if let Some(token) = &entry.registrytoken {
+            return Ok(Some(RegistryAuth::Bearer(token.clone())));
+        }
+        if entry.identitytoken.is_some() {
+            bail!(
+                "docker config identity token for {registry} is not supported, use --token or --username instead"
+            );
+        }
+        if let (Some(username), Some(password)) = (&entry.username, &entry.password) {
+            return Ok(Some(RegistryAuth::Basic(
+                username.clone(),
+                password.clone(),
+            )));
+        }
+        let Some(auth) = &entry.auth else {
+            return Ok(None);
+        };
+        let auth = String::from_utf8(BASE64_STANDARD.decode(auth)?)?;
+        let Some((username, password)) = auth.split_once(':') else {
+            bail!("docker config auth for {registry} is not in the username:password format");
+        };
+        Ok(Some(RegistryAuth::Basic(
+            username.to_string(),
+            password.to_string(),
+        )))
+    }
+
+    /// Returns the config keys of the registry, the first key is passed to credential helpers.
+    fn keys(registry: &str) -> Vec<String> {
+        let registry = Self::normalize(registry);
+        match DOCKER_HUB.contains(&registry) {
+            true => DOCKER_HUB.iter().rev().map(ToString::to_string).collect(),
+            false => vec![registry.to_string()],
+        }
+    }
+
+    /// Strips the scheme and path from config keys, which may be stored as URLs.
+    fn normalize(key: &str) -> &str {
+        if DOCKER_HUB.contains(&key) {
+            return key;
+        }
+        let key = key
+            .strip_prefix("https://")
+            .or_else(|| key.strip_prefix("http://"))
+            .unwrap_or(key);
+        key.split('/').next().unwrap_or(key)
+    }
+
+    /// Runs "docker-credential-<helper> get" for the registry. Missing credentials
+    /// are not an error, identity tokens are rejected.
+    fn helper_auth(helper: &str, registry: &str) -> anyhow::Result<Option<RegistryAuth>> {
+        let program = format!("docker-credential-{helper}");
+        let mut child = Command::new(&program)
+            .arg("get")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("unable to run {program}"))?;
+        child
+            .stdin
+            .take()
+            .context("credential helper stdin is not available")?
+            .write_all(registry.as_bytes())?;
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            tracing::debug!(
+                registry,
+                output = %String::from_utf8_lossy(&output.stdout).trim(),
+                "No credentials from {program}"
+            );
+            return Ok(None);
+        }
+
+        let credentials: HelperCredentials = serde_json::from_slice(&output.stdout)
+            .with_context(|| format!("invalid output of {program}"))?;
+        if credentials.username == IDENTITY_TOKEN_USERNAME {
+            bail!(
+                "identity token from {program} for {registry} is not supported, use --token or --username instead"
+            );
+        }
+
+        Ok(Some(RegistryAuth::Basic(
+            credentials.username,
+            credentials.secret,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_client::secrets::RegistryAuth;
+
+    use super::DockerConfig;
+
+    #[test]
+    fn test_docker_config_auths() {
+        let config: DockerConfig = serde_json::from_str(
+            r#"{
+                "auths": {
+                    "https://index.docker.io/v1/": {"auth": "dXNlcjpwYXNz"},
+                    "ghcr.io": {"username": "user", "password": "token"},
+                    "https://registry.example.com/v2/": {"registrytoken": "bearer"},
+                    "acr.example.com": {"auth": "PHRva2VuPjo=", "identitytoken": "refresh"},
+                    "empty.example.com": {}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let basic = |username: &str, password: &str| {
+            Some(RegistryAuth::Basic(username.into(), password.into()))
+        };
+        for (registry, expected) in [
+            ("docker.io", basic("user", "pass")),
+            ("ghcr.io", basic("user", "token")),
+            (
+                "registry.example.com",
+                Some(RegistryAuth::Bearer("bearer".into())),
+            ),
+            ("empty.example.com", None),
+            ("quay.io", None),
+        ] {
+            assert_eq!(config.auth(registry).unwrap(), expected, "{registry}");
+        }
+        assert!(config.auth("acr.example.com").is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    iter,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use age::{
+    Decryptor, Encryptor,
+    secrecy::{ExposeSecret as _, SecretString},
+    stream::StreamWriter,
+    x25519,
+};
+use anyhow::{Context as _, bail};
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+
+/// Magic prefix of the age file format.
+const AGE_HEADER: &[u8] = b"age-encryption.org/v1";
+
+/// Extension appended to encrypted archive files.
+pub const ENCRYPTED_EXTENSION: &str = "age";
+
+/// Returns true when the data is in the age encrypted format.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(AGE_HEADER)
+}
+
+/// `Encryption` encrypts archives with the age format, either for a passphrase
+/// or for a list of X25519 public key recipients.
+///
+/// Encrypted zip and gzip archives can be decrypted with the `age` CLI.
+#[derive(Clone)]
+pub enum Encryption {
+    Passphrase(String),
+    Recipients(Vec<x25519::Recipient>),
+}
+
+impl Encryption {
+    pub fn new(passphrase: Option<String>, recipients: &[String]) -> anyhow::Result<Option<Self>> {
+        match (passphrase, recipients) {
+            (Some(_), [_, ..]) => bail!("Passphrase and recipients can't be used together"),
+            (Some(passphrase), []) => Ok(Some(Self::Passphrase(passphrase))),
+            (None, []) => Ok(None),
+            (None, recipients) => Ok(Some(Self::Recipients(
+                recipients
+                    .iter()
+                    .map(|recipient| {
+                        x25519::Recipient::from_str(recipient)
+                            .map_err(|e| anyhow::anyhow!("Invalid recipient {recipient}: {e}"))
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            ))),
+        }
+    }
+
+    fn encryptor(&self) -> anyhow::Result<Encryptor> {
+        Ok(match self {
+            Encryption::Passphrase(passphrase) => {
+                Encryptor::with_user_passphrase(SecretString::from(passphrase.clone()))
+            }
+            Encryption::Recipients(recipients) => {
+                Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))?
+            }
+        })
+    }
+
+    pub fn encrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut encrypted = vec![];
+        let mut writer = self.encryptor()?.wrap_output(&mut encrypted)?;
+        writer.write_all(data)?;
+        writer.finish()?;
+        Ok(encrypted)
+    }
+
+    /// Generates a random key for the archive content and wraps it for the configured
+    /// passphrase or recipients. Encrypting every OCI layer with a passphrase directly
+    /// would run the key derivation for each layer, so the layers use the generated key
+    /// and only the wrapped key requires the passphrase. The key is new for every archive
+    /// and age encryption is randomized, so encrypted layers never match the blobs of a
+    /// previous snapshot and are always uploaded.
+    pub fn layer_key(&self) -> anyhow::Result<(x25519::Recipient, String)> {
+        let identity = x25519::Identity::generate();
+        let wrapped = self.encrypt(identity.to_string().expose_secret().as_bytes())?;
+        Ok((identity.to_public(), BASE64_STANDARD.encode(wrapped)))
+    }
+}
+
+/// `ArchiveFile` is the file of a single file archive. Encrypted archives are written
+/// through the age encryptor into a file with the ".age" extension, so the plaintext
+/// archive never reaches the disk.
+pub enum ArchiveFile {
+    Plain(File),
+    Encrypted(StreamWriter<File>),
+}
+
+impl ArchiveFile {
+    /// Creates the archive file, encrypted when the encryption is set.
+    pub fn create(path: &Path, encryption: Option<&Encryption>) -> anyhow::Result<Self> {
+        let Some(encryption) = encryption else {
+            return Ok(Self::Plain(File::create(path)?));
+        };
+
+        Ok(Self::Encrypted(
+            encryption
+                .encryptor()?
+                .wrap_output(File::create(encrypted_path(path))?)?,
+        ))
+    }
+
+    /// Writes the encryption trailer and returns the underlying file.
+    pub fn finish(self) -> io::Result<File> {
+        match self {
+            Self::Plain(file) => Ok(file),
+            Self::Encrypted(writer) => writer.finish(),
+        }
+    }
+}
+
+impl Write for ArchiveFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(file) => file.write(buf),
+            Self::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => file.flush(),
+            Self::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Returns the path of the encrypted file, with the ".age" extension appended.
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    let mut encrypted = path.as_os_str().to_owned();
+    encrypted.push(format!(".{ENCRYPTED_EXTENSION}"));
+    encrypted.into()
+}
+
+/// Encrypts the data for the layer key.
+pub fn encrypt_layer(recipient: &x25519::Recipient, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    Ok(age::encrypt(recipient, data)?)
+}
+
+/// `Decryption` holds the passphrase or identities to read encrypted archives.
+#[derive(Clone)]
+pub enum Decryption {
+    Passphrase(String),
+    Identities(Arc<Vec<x25519::Identity>>),
+}
+
+impl Decryption {
+    pub fn new(
+        passphrase: Option<String>,
+        identity: Option<&Path>,
+    ) -> anyhow::Result<Option<Self>> {
+        match (passphrase, identity) {
+            (Some(_), Some(_)) => bail!("Passphrase and identity file can't be used together"),
+            (Some(passphrase), None) => Ok(Some(Self::Passphrase(passphrase))),
+            (None, Some(path)) => Ok(Some(Self::from_identity_file(path)?)),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Reads X25519 identities from an age identity file, as generated by `age-keygen`.
+    pub fn from_identity_file(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open identity file {}", path.display()))?;
+        let identities = BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                x25519::Identity::from_str(&line)
+                    .map_err(|e| anyhow::anyhow!("Invalid identity in {}: {e}", path.display()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if identities.is_empty() {
+            bail!("No identities found in {}", path.display());
+        }
+
+        Ok(Self::Identities(Arc::new(identities)))
+    }
+
+    /// Returns a reader with the decrypted content of the input.
+    pub fn reader<R: BufRead>(&self, input: R) -> anyhow::Result<impl Read> {
+        let decryptor = Decryptor::new_buffered(input)?;
+        Ok(match self {
+            Decryption::Passphrase(passphrase) => decryptor
+                .decrypt(iter::once(&age::scrypt::Identity::new(SecretString::from(
+                    passphrase.clone(),
+                )) as &dyn age::Identity))?,
+            Decryption::Identities(identities) => {
+                decryptor.decrypt(identities.iter().map(|i| i as &dyn age::Identity))?
+            }
+        })
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut decrypted = vec![];
+        self.reader(data)?.read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+
+    /// Unwraps the layer key stored in the OCI manifest config.
+    pub fn layer_key(&self, wrapped: &str) -> anyhow::Result<Self> {
+        let key = self
+            .decrypt(&BASE64_STANDARD.decode(wrapped)?)
+            .context("failed to decrypt the archive key")?;
+        let identity = x25519::Identity::from_str(std::str::from_utf8(&key)?)
+            .map_err(|e| anyhow::anyhow!("Invalid archive key: {e}"))?;
+        Ok(Self::Identities(Arc::new(vec![identity])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_encrypt_recipients() {
+        let identity = x25519::Identity::generate();
+        let encryption = Encryption::new(None, &[identity.to_public().to_string()])
+            .unwrap()
+            .unwrap();
+
+        let encrypted = encryption.encrypt(b"data").unwrap();
+        assert!(is_encrypted(&encrypted));
+
+        let decryption = Decryption::Identities(Arc::new(vec![identity]));
+        assert_eq!(decryption.decrypt(&encrypted).unwrap(), b"data");
+
+        let other = Decryption::Identities(Arc::new(vec![x25519::Identity::generate()]));
+        assert!(other.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_layer_key_passphrase() {
+        let encryption = Encryption::new(Some("secret".into()), &[])
+            .unwrap()
+            .unwrap();
+        let (recipient, wrapped) = encryption.layer_key().unwrap();
+        let layer = encrypt_layer(&recipient, b"layer").unwrap();
+
+        let decryption = Decryption::Passphrase("secret".into())
+            .layer_key(&wrapped)
+            .unwrap();
+        assert_eq!(decryption.decrypt(&layer).unwrap(), b"layer");
+        assert!(
+            Decryption::Passphrase("wrong".into())
+                .layer_key(&wrapped)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_archive_file() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("archive.tar.gz");
+
+        let identity = x25519::Identity::generate();
+        let identity_file = tmp_dir.path().join("key.txt");
+        fs::write(
+            &identity_file,
+            format!(
+                "# public key: {}\n{}\n",
+                identity.to_public(),
+                identity.to_string().expose_secret()
+            ),
+        )
+        .unwrap();
+
+        let encryption = Encryption::Recipients(vec![identity.to_public()]);
+        let mut file = ArchiveFile::create(&path, Some(&encryption)).unwrap();
+        file.write_all(b"archive").unwrap();
+        file.finish().unwrap();
+        assert!(!path.exists());
+
+        let encrypted = fs::read(tmp_dir.path().join("archive.tar.gz.age")).unwrap();
+        assert!(is_encrypted(&encrypted));
+        let decryption = Decryption::new(None, Some(&identity_file))
+            .unwrap()
+            .unwrap();
+        let mut data = vec![];
+        decryption
+            .reader(Cursor::new(encrypted))
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"archive");
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    fmt::Display,
+    fs::{self, File},
+};
+
+use anyhow::{Context as _, bail};
+use clap::Parser;
+use reqwest::header::{ACCEPT, USER_AGENT};
+use serde::Deserialize;
+use tempfile::NamedTempFile;
+
+use super::{
+    blob_cache::BlobCache,
+    http::{HTTP_USER_AGENT, write_response},
+    storage::ZipState,
+};
+
+/// Default GitHub API URL, GitHub Enterprise Server uses "https://<host>/api/v3".
+pub const DEFAULT_GITHUB_API_URL: &str = "https://api.github.com";
+
+#[derive(Parser, Clone, Default, Deserialize)]
+pub struct GithubSettings {
+    /// GitHub Actions artifact with crust-gather snapshots to serve, as the artifact URL
+    /// or "owner/repo/artifact_id". Every snapshot in the artifact is served in its own
+    /// context, read from the downloaded zip without extracting it.
+    ///
+    /// Example:
+    ///     --github-artifact=https://github.com/owner/repo/actions/runs/8923331571/artifacts/1467008322
+    #[arg(long, value_name = "ARTIFACT",
+        value_parser = |arg: &str| -> anyhow::Result<GithubArtifact> {GithubArtifact::try_from(arg)})]
+    #[serde(default)]
+    pub github_artifact: Vec<GithubArtifact>,
+
+    /// Token to download GitHub artifacts with.
+    #[arg(
+        long,
+        env = "GITHUB_TOKEN",
+        hide_env_values = true,
+        value_name = "TOKEN"
+    )]
+    #[serde(default)]
+    pub github_token: Option<String>,
+
+    /// GitHub API URL. Defaults to "https://api.github.com".
+    ///
+    /// Example:
+    ///     --github-api-url=https://github.example.com/api/v3
+    #[arg(long, env = "GITHUB_API_URL", value_name = "URL")]
+    #[serde(default)]
+    pub github_api_url: Option<String>,
+}
+
+/// GitHub Actions artifact, identified by its repository and id.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct GithubArtifact {
+    pub owner: String,
+    pub repo: String,
+    pub id: u64,
+}
+
+impl TryFrom<&str> for GithubArtifact {
+    type Error = anyhow::Error;
+
+    /// Parses "owner/repo/id", the artifact URL of a workflow run
+    /// "https://github.com/owner/repo/actions/runs/<run>/artifacts/<id>",
+    /// or the API URL "https://api.github.com/repos/owner/repo/actions/artifacts/<id>".
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let path = match value.split_once("://") {
+            Some((_, url)) => url.split_once('/').map_or("", |(_, path)| path),
+            None => value,
+        };
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        let (owner, repo, id) = match segments.as_slice() {
+            [owner, repo, id] => (owner, repo, id),
+            ["repos", owner, repo, "actions", "artifacts", id, ..]
+            | [owner, repo, "actions", "runs", _, "artifacts", id, ..] => (owner, repo, id),
+            _ => bail!(
+                "invalid GitHub artifact {value}, expected an artifact URL or owner/repo/artifact_id"
+            ),
+        };
+
+        Ok(Self {
+            owner: owner.to_string(),
+            repo: repo.to_string(),
+            id: id
+                .parse()
+                .with_context(|| format!("invalid GitHub artifact id {id}"))?,
+        })
+    }
+}
+
+impl TryFrom<String> for GithubArtifact {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.as_str().try_into()
+    }
+}
+
+impl Display for GithubArtifact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}/{}", self.owner, self.repo, self.id)
+    }
+}
+
+impl GithubSettings {
+    /// Opens the artifact zip. Artifacts are immutable, so they are downloaded once into
+    /// the cache and reused on later runs. Artifacts are cached under their API URL, to
+    /// keep artifacts of GitHub Enterprise Server apart. Without a cache the zip is kept
+    /// in an anonymous temporary file.
+    pub async fn open(
+        &self,
+        artifact: &GithubArtifact,
+        cache: Option<&BlobCache>,
+    ) -> anyhow::Result<ZipState> {
+        let url = self.artifact_url(artifact);
+        let cached = cache.map(|cache| {
+            let path = cache.download_path("github", &url, &format!("{}.zip", artifact.id));
+            (cache, path)
+        });
+
+        let cached_file = match &cached {
+            Some((cache, path)) => cache.open_download(path).await,
+            None => None,
+        };
+        let file = match (&cached, cached_file) {
+            (Some((_, path)), Some(file)) => {
+                tracing::info!(%artifact, path = %path.display(), "Using cached GitHub artifact");
+                file
+            }
+            (Some((cache, path)), None) => {
+                let dir = path.parent().context("invalid artifact cache path")?;
+                fs::create_dir_all(dir)?;
+                let mut file = NamedTempFile::new_in(dir)?;
+                self.download(artifact, &url, file.as_file_mut()).await?;
+                cache.insert_download(file, path).await?
+            }
+            (None, _) => {
+                let mut file = tempfile::tempfile()?;
+                self.download(artifact, &url, &mut file).await?;
+                file
+            }
+        };
+
+        ZipState::new(artifact.to_string(), file)
+            .with_context(|| format!("GitHub artifact {artifact} is not a zip file"))
+    }
+
+    /// Returns the API URL of the artifact zip.
+    fn artifact_url(&self, artifact: &GithubArtifact) -> String {
+        let api = self
+            .github_api_url
+            .as_deref()
+            .unwrap_or(DEFAULT_GITHUB_API_URL)
+            .trim_end_matches('/');
+        format!(
+            "{api}/repos/{}/{}/actions/artifacts/{}/zip",
+            artifact.owner, artifact.repo, artifact.id
+        )
+    }
+
+    /// Streams the artifact zip into the file. The API redirects to a short-lived
+    /// storage URL on another host, the token is not sent with the redirect.
+    async fn download(
+        &self,
+        artifact: &GithubArtifact,
+        url: &str,
+        file: &mut File,
+    ) -> anyhow::Result<()> {
+        tracing::info!(%artifact, url, "Downloading GitHub artifact");
+
+        let mut request = reqwest::Client::new()
+            .get(url)
+            .header(ACCEPT, "application/vnd.github+json")
+            .header(USER_AGENT, HTTP_USER_AGENT);
+        if let Some(token) = &self.github_token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            bail!(
+                "unable to download GitHub artifact {artifact}: {}{}",
+                response.status(),
+                match self.github_token {
+                    Some(_) => "",
+                    None => ", set GITHUB_TOKEN to download artifacts",
+                }
+            );
+        }
+
+        write_response(response, file).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Write as _},
+        path::PathBuf,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use actix_web::{App, HttpRequest, HttpResponse, HttpServer, web};
+    use k8s_openapi::serde_json::Value;
+    use tempfile::TempDir;
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    use super::{GithubArtifact, GithubSettings};
+    use crate::gather::{
+        blob_cache::BlobCache,
+        manifest::{Manifest, digest, verify_storage},
+        representation::ArchivePath,
+        storage::Storage,
+    };
+
+    #[test]
+    fn test_github_artifact_parse() {
+        let expected = GithubArtifact {
+            owner: "rancher-sandbox".into(),
+            repo: "cluster-api-provider-rke2".into(),
+            id: 1467008322,
+        };
+        for value in [
+            "rancher-sandbox/cluster-api-provider-rke2/1467008322",
+            "https://github.com/rancher-sandbox/cluster-api-provider-rke2/actions/runs/8923331571/artifacts/1467008322",
+            "https://api.github.com/repos/rancher-sandbox/cluster-api-provider-rke2/actions/artifacts/1467008322/zip",
+        ] {
+            assert_eq!(
+                GithubArtifact::try_from(value).unwrap(),
+                expected,
+                "{value}"
+            );
+        }
+        assert!(GithubArtifact::try_from("owner/repo").is_err());
+        assert!(GithubArtifact::try_from("owner/repo/latest").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_github_artifact_download() {
+        let (manifest, _) = Manifest::new(
+            Value::Null,
+            [("version.yaml".to_string(), digest(b"{}"))].into(),
+        )
+        .sign(None)
+        .unwrap();
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for (path, data) in [
+            ("cluster-a/crust-gather/version.yaml", "{}"),
+            (
+                "cluster-a/crust-gather/cluster/v1/node/a.yaml",
+                "kind: Node",
+            ),
+            ("cluster-b/crust-gather/version.yaml", "{}"),
+            ("cluster-b/crust-gather/manifest.json", &manifest),
+            ("logs/build.log", "done"),
+        ] {
+            zip.start_file(path, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+        }
+        let data = zip.finish().unwrap().into_inner();
+
+        // The API checks the token and redirects to the artifact storage.
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let state = (data, downloads.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .route(
+                    "/repos/owner/repo/actions/artifacts/1/zip",
+                    web::get().to(|request: HttpRequest| async move {
+                        match request.headers().get("authorization") {
+                            Some(token) if token == "Bearer token" => HttpResponse::Found()
+                                .insert_header(("location", "/storage/1.zip"))
+                                .finish(),
+                            _ => HttpResponse::Unauthorized().finish(),
+                        }
+                    }),
+                )
+                .route(
+                    "/storage/1.zip",
+                    web::get().to(|state: web::Data<(Vec<u8>, Arc<AtomicUsize>)>| async move {
+                        state.1.fetch_add(1, Ordering::SeqCst);
+                        HttpResponse::Ok().body(state.0.clone())
+                    }),
+                )
+        })
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let address = server.addrs()[0];
+        let handle = server.run();
+        let stop = handle.handle();
+        tokio::spawn(handle);
+
+        let tmp_dir = TempDir::new().unwrap();
+        let cache = BlobCache::new(tmp_dir.path().to_path_buf(), 1024 * 1024).unwrap();
+        let artifact = GithubArtifact::try_from("owner/repo/1").unwrap();
+        let mut settings = GithubSettings {
+            github_artifact: vec![artifact.clone()],
+            github_token: None,
+            github_api_url: Some(format!("http://{address}")),
+        };
+        assert!(settings.open(&artifact, Some(&cache)).await.is_err());
+
+        settings.github_token = Some("token".into());
+        for _ in 0..2 {
+            let zip = Arc::new(settings.open(&artifact, Some(&cache)).await.unwrap());
+            let archives: Vec<PathBuf> = zip.archives().iter().map(|a| a.path()).collect();
+            assert_eq!(
+                archives,
+                [
+                    PathBuf::from("cluster-a/crust-gather"),
+                    PathBuf::from("cluster-b/crust-gather")
+                ]
+            );
+
+            let storage = Storage::Zip(zip.clone());
+            let node = zip.archives()[0].join(ArchivePath::Custom("cluster/v1/node/*.yaml".into()));
+            let paths = storage.matching_paths(node).unwrap();
+            assert_eq!(paths.len(), 1);
+            assert_eq!(
+                storage.read_raw(paths[0].clone()).await.unwrap(),
+                "kind: Node"
+            );
+
+            // Only the snapshot with a manifest passes verification.
+            assert!(
+                verify_storage(&storage, &archives[0], None, 4)
+                    .await
+                    .is_err()
+            );
+            let verification = verify_storage(&storage, &archives[1], None, 4)
+                .await
+                .unwrap();
+            assert!(verification.is_valid());
+        }
+        assert_eq!(downloads.load(Ordering::SeqCst), 1, "artifact is cached");
+
+        // Artifacts of another GitHub instance are not taken from the cache.
+        settings.github_api_url = Some(format!("http://{address}/api/v3"));
+        assert!(settings.open(&artifact, Some(&cache)).await.is_err());
+
+        stop.stop(false).await;
+    }
+}
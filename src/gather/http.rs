@@ -0,0 +1,686 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write as _},
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context as _, bail};
+use futures::TryStreamExt as _;
+use reqwest::{
+    Response, StatusCode,
+    header::{CONTENT_RANGE, ETAG, LAST_MODIFIED, RANGE, USER_AGENT},
+};
+use tempfile::{NamedTempFile, TempDir};
+use thiserror::Error;
+
+use super::{
+    blob_cache::BlobCache,
+    compression::ZIP_EXTENSION,
+    encryption::Decryption,
+    storage::{Storage, ZipState},
+};
+
+/// Size of the file tail requested first, which holds the zip central directory of
+/// most archives.
+const TAIL_SIZE: u64 = 1024 * 1024;
+
+/// Size of the ranges requested for zip entries which are not found in the central
+/// directory.
+const FETCH_SIZE: u64 = 256 * 1024;
+
+/// Limit of fetched entry ranges kept in memory, the central directory is always kept.
+const MAX_FETCHED_SIZE: usize = 64 * 1024 * 1024;
+
+pub const HTTP_USER_AGENT: &str = concat!("crust-gather/", env!("CARGO_PKG_VERSION"));
+
+/// Returns true when the archive location is an HTTP(S) URL.
+pub fn is_url(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Archive served from an HTTP(S) URL.
+pub enum RemoteArchive {
+    /// Zip file read with range requests, or downloaded when the server does not
+    /// support them.
+    Zip(Box<ZipState>),
+    /// Tar or encrypted archive, downloaded once and extracted.
+    Unpacked(TempDir),
+}
+
+impl RemoteArchive {
+    /// Opens the archive at the URL. Zip files are read lazily, other archives are
+    /// downloaded into the cache and reused on later runs.
+    pub async fn open(
+        url: &str,
+        cache: Option<&BlobCache>,
+        decryption: Option<&Decryption>,
+    ) -> anyhow::Result<Self> {
+        Self::open_as(url, url, None, cache, decryption).await
+    }
+
+    /// Opens the archive at the URL under the source name, which is used in logs and
+    /// errors instead of the URL. Downloads are cached under the cache key, which must
+    /// identify the version of the file. Without a key, the version is taken from the
+    /// ETag or Last-Modified header of the URL.
+    pub async fn open_as(
+        source: &str,
+        url: &str,
+        cache_key: Option<&str>,
+        cache: Option<&BlobCache>,
+        decryption: Option<&Decryption>,
+    ) -> anyhow::Result<Self> {
+        let client = reqwest::Client::new();
+        let name = source
+            .split(['?', '#'])
+            .next()
+            .and_then(|path| path.rsplit('/').next())
+            .filter(|name| !name.is_empty())
+            .with_context(|| format!("{source} does not name an archive file"))?;
+
+        if name.ends_with(&format!(".{ZIP_EXTENSION}")) {
+            if let Some(source) = HttpSource::open(client.clone(), source, url).await? {
+                tracing::info!(
+                    source = source.source,
+                    size = source.reader.len,
+                    "Reading zip archive with range requests"
+                );
+                return Ok(Self::Zip(Box::new(ZipState::remote(source).await?)));
+            }
+            tracing::info!(
+                source,
+                "Server does not support range requests, downloading archive"
+            );
+        }
+
+        let (path, _download_dir) = download(&client, source, url, name, cache_key, cache).await?;
+        if name.ends_with(&format!(".{ZIP_EXTENSION}")) {
+            return Ok(Self::Zip(Box::new(ZipState::new(
+                source.to_string(),
+                File::open(&path)?,
+            )?)));
+        }
+
+        match Storage::unpack(&path, decryption)? {
+            Some(dir) => Ok(Self::Unpacked(dir)),
+            None => bail!("{source} is not a zip, tar or encrypted archive"),
+        }
+    }
+}
+
+/// Downloads the file into the cache, or a temporary directory which is returned
+/// along with the path.
+async fn download(
+    client: &reqwest::Client,
+    source: &str,
+    url: &str,
+    name: &str,
+    cache_key: Option<&str>,
+    cache: Option<&BlobCache>,
+) -> anyhow::Result<(PathBuf, Option<TempDir>)> {
+    let cache = match (cache, cache_key) {
+        (Some(cache), Some(key)) => Some((cache, key.to_string())),
+        (Some(cache), None) => {
+            let key = versioned_key(client, url).await;
+            if key.is_none() {
+                tracing::info!(
+                    source,
+                    "Server returns no ETag or Last-Modified header, the download is not cached"
+                );
+            }
+            key.map(|key| (cache, key))
+        }
+        (None, _) => None,
+    };
+    let (path, temp_dir) = match &cache {
+        Some((cache, key)) => (cache.download_path("http", key, name), None),
+        None => {
+            let temp_dir = TempDir::new()?;
+            (temp_dir.path().join(name), Some(temp_dir))
+        }
+    };
+
+    if let Some((cache, _)) = cache
+        && cache.open_download(&path).await.is_some()
+    {
+        tracing::info!(source, path = %path.display(), "Using cached archive");
+        return Ok((path, temp_dir));
+    }
+
+    tracing::info!(source, "Downloading archive");
+    let dir = path.parent().context("invalid archive cache path")?;
+    fs::create_dir_all(dir)?;
+    let mut file = NamedTempFile::new_in(dir)?;
+    let response = client
+        .get(url)
+        .header(USER_AGENT, HTTP_USER_AGENT)
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("unable to download {source}"))?;
+    write_response(response, file.as_file_mut()).await?;
+    match cache {
+        Some((cache, _)) => cache.insert_download(file, &path).await?,
+        None => file.persist(&path)?,
+    };
+
+    Ok((path, temp_dir))
+}
+
+/// Returns the cache key of the URL, versioned by the ETag or Last-Modified header
+/// of a HEAD request, so changed files are downloaded again.
+async fn versioned_key(client: &reqwest::Client, url: &str) -> Option<String> {
+    let response = client
+        .head(url)
+        .header(USER_AGENT, HTTP_USER_AGENT)
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?;
+    let headers = response.headers();
+    let version = headers
+        .get(ETAG)
+        .or_else(|| headers.get(LAST_MODIFIED))?
+        .to_str()
+        .ok()?;
+    Some(format!("{url}#{version}"))
+}
+
+/// Streams the response body into the file.
+pub async fn write_response(response: Response, file: &mut File) -> anyhow::Result<()> {
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.try_next().await? {
+        file.write_all(&chunk)?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Remote file read with HTTP range requests.
+#[derive(Clone)]
+pub struct HttpSource {
+    client: reqwest::Client,
+    pub source: String,
+    url: String,
+    pub reader: RangeReader,
+}
+
+impl HttpSource {
+    /// Requests the tail of the file, which also returns its size. Returns None when
+    /// the server does not support range requests.
+    async fn open(
+        client: reqwest::Client,
+        source: &str,
+        url: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let response = client
+            .get(url)
+            .header(USER_AGENT, HTTP_USER_AGENT)
+            .header(RANGE, format!("bytes=-{TAIL_SIZE}"))
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("unable to read {source}"))?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Ok(None);
+        }
+
+        // Content-Range: bytes <start>-<end>/<size>
+        let range = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|range| range.to_str().ok())
+            .and_then(|range| range.strip_prefix("bytes "))
+            .and_then(|range| range.split_once('/'))
+            .and_then(|(range, size)| {
+                let (start, _) = range.split_once('-')?;
+                Some((start.parse::<u64>().ok()?, size.parse::<u64>().ok()?))
+            });
+        let Some((start, size)) = range else {
+            return Ok(None);
+        };
+
+        let reader = RangeReader::new(size);
+        reader.insert(start, response.bytes().await?.to_vec(), true)?;
+        Ok(Some(Self {
+            client,
+            source: source.to_string(),
+            url: url.to_string(),
+            reader,
+        }))
+    }
+
+    /// Fetches the range starting at the offset, to retry a read which failed with
+    /// `MissingRange`.
+    pub async fn fetch(&self, offset: u64) -> anyhow::Result<()> {
+        self.fetch_range(offset..offset.saturating_add(FETCH_SIZE))
+            .await
+    }
+
+    /// Fetches the range with a single request, limited to the end of the file.
+    pub async fn fetch_range(&self, range: Range<u64>) -> anyhow::Result<()> {
+        let (offset, end) = (range.start, range.end.min(self.reader.len));
+        if offset >= end {
+            bail!("offset {offset} is outside of {}", self.source);
+        }
+
+        tracing::debug!(source = self.source, offset, end, "Fetching range");
+        let response = self
+            .client
+            .get(&self.url)
+            .header(USER_AGENT, HTTP_USER_AGENT)
+            .header(RANGE, format!("bytes={offset}-{}", end - 1))
+            .send()
+            .await?
+            .error_for_status()?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            bail!("{} does not support range requests anymore", self.source);
+        }
+
+        let data = response.bytes().await?.to_vec();
+        if data.is_empty() {
+            bail!("empty range at offset {offset} of {}", self.source);
+        }
+        self.reader.insert(offset, data, false)
+    }
+}
+
+/// Error returned by `RangeReader` for reads of ranges which are not fetched yet.
+#[derive(Error, Debug)]
+#[error("range at offset {0} is not fetched")]
+pub struct MissingRange(pub u64);
+
+impl MissingRange {
+    /// Returns the offset of the missing range when it caused the error.
+    pub fn find(error: &anyhow::Error) -> Option<u64> {
+        error.chain().find_map(|cause| {
+            let error = cause.downcast_ref::<io::Error>()?.get_ref()?;
+            error.downcast_ref::<Self>().map(|missing| missing.0)
+        })
+    }
+}
+
+/// Reader over the fetched ranges of a remote file. Reads outside of them fail with
+/// `MissingRange`, so the caller can fetch the range and retry.
+#[derive(Clone)]
+pub struct RangeReader {
+    len: u64,
+    position: u64,
+    ranges: Arc<Mutex<Ranges>>,
+}
+
+#[derive(Default)]
+struct Ranges {
+    chunks: BTreeMap<u64, Vec<u8>>,
+    // Fetched ranges which are not pinned, oldest first.
+    fetched: VecDeque<u64>,
+    fetched_size: usize,
+}
+
+impl RangeReader {
+    fn new(len: u64) -> Self {
+        Self {
+            len,
+            position: 0,
+            ranges: Default::default(),
+        }
+    }
+
+    /// Adds a fetched range. Ranges which are not pinned are dropped, oldest first,
+    /// once they exceed the memory limit.
+    fn insert(&self, start: u64, data: Vec<u8>, pinned: bool) -> anyhow::Result<()> {
+        let mut ranges = self
+            .ranges
+            .lock()
+            .map_err(|_| anyhow::anyhow!("range lock is poisoned"))?;
+        if !pinned {
+            ranges.fetched.push_back(start);
+            ranges.fetched_size += data.len();
+        }
+        ranges.chunks.insert(start, data);
+
+        while ranges.fetched_size > MAX_FETCHED_SIZE && ranges.fetched.len() > 1 {
+            let Some(start) = ranges.fetched.pop_front() else {
+                break;
+            };
+            if let Some(data) = ranges.chunks.remove(&start) {
+                ranges.fetched_size -= data.len();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns true when the range is within a single fetched range.
+    pub fn contains(&self, range: &Range<u64>) -> bool {
+        self.ranges.lock().is_ok_and(|ranges| {
+            ranges.get(range.start).is_some_and(|data| {
+                data.len() as u64 >= range.end.min(self.len).saturating_sub(range.start)
+            })
+        })
+    }
+}
+
+impl Ranges {
+    fn get(&self, position: u64) -> Option<&[u8]> {
+        self.chunks
+            .range(..=position)
+            .rev()
+            .find_map(|(start, data)| {
+                data.get((position - start) as usize..)
+                    .filter(|data| !data.is_empty())
+            })
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let ranges = self
+            .ranges
+            .lock()
+            .map_err(|_| io::Error::other("range lock is poisoned"))?;
+        let Some(data) = ranges.get(self.position) else {
+            return Err(io::Error::other(MissingRange(self.position)));
+        };
+
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for RangeReader {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Read as _, Seek as _, SeekFrom, Write as _},
+        net::SocketAddr,
+        path::PathBuf,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use actix_web::{App, HttpRequest, HttpResponse, HttpServer, dev::ServerHandle, web};
+    use tempfile::TempDir;
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    use super::{MissingRange, RangeReader, RemoteArchive};
+    use crate::gather::{
+        blob_cache::BlobCache,
+        representation::ArchivePath,
+        storage::{Storage, ZipState},
+    };
+
+    #[test]
+    fn test_range_reader() {
+        let mut reader = RangeReader::new(10);
+        reader.insert(6, b"6789".to_vec(), true).unwrap();
+        reader.seek(SeekFrom::End(-4)).unwrap();
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "6789");
+
+        reader.seek(SeekFrom::Start(2)).unwrap();
+        let error = anyhow::Error::from(reader.read(&mut [0; 4]).unwrap_err());
+        assert_eq!(MissingRange::find(&error), Some(2));
+
+        reader.insert(2, b"2345".to_vec(), false).unwrap();
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "23456789");
+
+        // A range ending at the position does not hide an earlier range covering it.
+        let mut reader = RangeReader::new(10);
+        reader.insert(0, b"0123456789".to_vec(), true).unwrap();
+        reader.insert(3, b"34".to_vec(), false).unwrap();
+        reader.seek(SeekFrom::Start(5)).unwrap();
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "56789");
+    }
+
+    /// Serves the zip file with "bytes=-<suffix>" and "bytes=<start>-<end>" ranges,
+    /// counting requests.
+    fn range_server(data: Vec<u8>) -> (SocketAddr, Arc<AtomicUsize>, ServerHandle) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let state = (data, requests.clone());
+        let server = HttpServer::new(move || {
+            App::new().app_data(web::Data::new(state.clone())).route(
+                "/snapshot.zip",
+                web::get().to(
+                    |request: HttpRequest,
+                     state: web::Data<(Vec<u8>, Arc<AtomicUsize>)>| async move {
+                        state.1.fetch_add(1, Ordering::SeqCst);
+                        let data = &state.0;
+                        let range = request
+                            .headers()
+                            .get("range")
+                            .and_then(|range| range.to_str().ok())
+                            .and_then(|range| range.strip_prefix("bytes="))
+                            .and_then(|range| range.split_once('-'));
+                        let (start, end) = match range {
+                            Some(("", suffix)) => {
+                                (data.len() - suffix.parse::<usize>().unwrap(), data.len())
+                            }
+                            Some((start, end)) => {
+                                (start.parse().unwrap(), end.parse::<usize>().unwrap() + 1)
+                            }
+                            None => return HttpResponse::Ok().body(data.clone()),
+                        };
+                        HttpResponse::PartialContent()
+                            .insert_header((
+                                "content-range",
+                                format!("bytes {start}-{}/{}", end - 1, data.len()),
+                            ))
+                            .body(data[start..end].to_vec())
+                    },
+                ),
+            )
+        })
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let address = server.addrs()[0];
+        let handle = server.run();
+        let stop = handle.handle();
+        tokio::spawn(handle);
+
+        (address, requests, stop)
+    }
+
+    #[tokio::test]
+    async fn test_remote_zip_archive() {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        let padding = "x".repeat(2 * 1024 * 1024);
+        for (path, data) in [
+            ("snapshot/version.yaml", "{}"),
+            ("snapshot/cluster/v1/node/a.yaml", "kind: Node"),
+            ("snapshot/padding.txt", padding.as_str()),
+        ] {
+            let options =
+                SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+            zip.start_file(path, options).unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+        }
+        let data = zip.finish().unwrap().into_inner();
+
+        let (address, requests, stop) = range_server(data);
+
+        let cache = TempDir::new().unwrap();
+        let blob_cache = BlobCache::new(cache.path().to_path_buf(), 1024 * 1024).unwrap();
+        let url = format!("http://{address}/snapshot.zip");
+        let RemoteArchive::Zip(zip) = RemoteArchive::open(&url, Some(&blob_cache), None)
+            .await
+            .unwrap()
+        else {
+            panic!("zip archive is not read with range requests");
+        };
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let zip: Arc<ZipState> = Arc::from(zip);
+        let archives = zip.archives();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(archives[0].path(), PathBuf::from("snapshot"));
+
+        let storage = Storage::Zip(zip.clone());
+        let node = archives[0].join(ArchivePath::Custom("cluster/v1/node/a.yaml".into()));
+        assert_eq!(storage.read_raw(node).await.unwrap(), "kind: Node");
+        assert_eq!(
+            requests.load(Ordering::SeqCst),
+            2,
+            "entry is fetched on demand"
+        );
+
+        // Entries larger than the fetch size are fetched with a single request.
+        let padding_path = archives[0].join(ArchivePath::Custom("padding.txt".into()));
+        assert_eq!(
+            storage.read_raw(padding_path).await.unwrap().len(),
+            padding.len()
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Concurrent reads use their own copy of the archive.
+        let version = archives[0].join(ArchivePath::Custom("version.yaml".into()));
+        let node = archives[0].join(ArchivePath::Custom("cluster/v1/node/a.yaml".into()));
+        let padding_path = archives[0].join(ArchivePath::Custom("padding.txt".into()));
+        let (version, node, padding_data) = tokio::join!(
+            storage.read_raw(version),
+            storage.read_raw(node),
+            storage.read_raw(padding_path)
+        );
+        assert_eq!(version.unwrap(), "{}");
+        assert_eq!(node.unwrap(), "kind: Node");
+        assert_eq!(padding_data.unwrap(), padding);
+
+        // Nothing is downloaded into the cache for range requests.
+        assert!(!cache.path().join("http").exists());
+
+        stop.stop(false).await;
+    }
+
+    #[tokio::test]
+    async fn test_remote_zip_central_directory() {
+        // A central directory larger than the fetched tail.
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        zip.start_file("snapshot/version.yaml", SimpleFileOptions::default())
+            .unwrap();
+        for i in 0..20_000 {
+            zip.start_file(
+                format!("snapshot/cluster/v1/configmap/{i:05}.yaml"),
+                SimpleFileOptions::default(),
+            )
+            .unwrap();
+            zip.write_all(format!("name: {i}").as_bytes()).unwrap();
+        }
+        let data = zip.finish().unwrap().into_inner();
+
+        let (address, requests, stop) = range_server(data);
+        let RemoteArchive::Zip(zip) =
+            RemoteArchive::open(&format!("http://{address}/snapshot.zip"), None, None)
+                .await
+                .unwrap()
+        else {
+            panic!("zip archive is not read with range requests");
+        };
+        assert_eq!(
+            requests.load(Ordering::SeqCst),
+            2,
+            "central directory is fetched with a single request"
+        );
+
+        let storage = Storage::Zip(Arc::from(zip));
+        let path = PathBuf::from("snapshot/cluster/v1/configmap/00042.yaml");
+        assert_eq!(storage.read_raw(path).await.unwrap(), "name: 42");
+
+        stop.stop(false).await;
+    }
+
+    #[tokio::test]
+    async fn test_download_revalidated() {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        zip.start_file("snapshot/version.yaml", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"{}").unwrap();
+        let data = zip.finish().unwrap().into_inner();
+
+        // Ignores ranges, and versions the file with an ETag. Only full downloads are counted.
+        let version = Arc::new(AtomicUsize::new(1));
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let state = (data, version.clone(), downloads.clone());
+        let server = HttpServer::new(move || {
+            App::new().app_data(web::Data::new(state.clone())).route(
+                "/snapshot.zip",
+                web::route().to(
+                    |request: HttpRequest,
+                     state: web::Data<(Vec<u8>, Arc<AtomicUsize>, Arc<AtomicUsize>)>| async move {
+                        let etag = format!("\"{}\"", state.1.load(Ordering::SeqCst));
+                        if request.method() == "GET" {
+                            if !request.headers().contains_key("range") {
+                                state.2.fetch_add(1, Ordering::SeqCst);
+                            }
+                            return HttpResponse::Ok()
+                                .insert_header(("etag", etag))
+                                .body(state.0.clone());
+                        }
+                        HttpResponse::Ok().insert_header(("etag", etag)).finish()
+                    },
+                ),
+            )
+        })
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let address = server.addrs()[0];
+        let handle = server.run();
+        let stop = handle.handle();
+        tokio::spawn(handle);
+
+        let cache = TempDir::new().unwrap();
+        let blob_cache = BlobCache::new(cache.path().to_path_buf(), 1024 * 1024).unwrap();
+        let url = format!("http://{address}/snapshot.zip");
+        for _ in 0..2 {
+            let RemoteArchive::Zip(zip) = RemoteArchive::open(&url, Some(&blob_cache), None)
+                .await
+                .unwrap()
+            else {
+                panic!("zip archive is not opened");
+            };
+            assert_eq!(zip.archives()[0].path(), PathBuf::from("snapshot"));
+        }
+        assert_eq!(
+            downloads.load(Ordering::SeqCst),
+            1,
+            "cached archive is used"
+        );
+
+        // A changed file is downloaded again.
+        version.store(2, Ordering::SeqCst);
+        RemoteArchive::open(&url, Some(&blob_cache), None)
+            .await
+            .unwrap();
+        assert_eq!(downloads.load(Ordering::SeqCst), 2);
+
+        stop.stop(false).await;
+    }
+}
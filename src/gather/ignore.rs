@@ -0,0 +1,329 @@
+use std::fmt::Display;
+
+use glob::{MatchOptions, Pattern};
+use k8s_openapi::serde_json::Value;
+use kube::core::GroupVersionKind;
+use serde::Deserialize;
+
+use crate::{filters::group::GroupRegex, scanners::interface::UPDATED_ANNOTATION};
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Fields which are ignored by default when recording patches. These are updated
+/// continuously by the cluster and carry no meaningful change for the replay.
+const DEFAULT_RULES: &[&str] = &[
+    "/metadata/resourceVersion",
+    "/metadata/managedFields",
+    "/status/conditions/*/lastHeartbeatTime",
+    "^coordination.k8s.io$/^Lease$:/spec/renewTime",
+];
+
+/// `IgnoreRule` selects fields in a resource which should not produce a patch
+/// when changed during recording.
+///
+/// The rule is written as `[<group>/<kind>:]<path>`, where the optional group
+/// and kind follow the `--include-group` syntax and the path is a JSON pointer
+/// which may contain glob wildcards. `*` matches a single path segment, while
+/// `**` matches any number of segments.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IgnoreRule {
+    group: Option<GroupRegex>,
+    path: Pattern,
+}
+
+impl IgnoreRule {
+    fn applies(&self, gvk: &GroupVersionKind) -> bool {
+        self.group.as_ref().is_none_or(|group| group.matches(gvk))
+    }
+}
+
+impl TryFrom<&str> for IgnoreRule {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let (group, path) = match value.split_once(":/") {
+            Some((group, path)) => (Some(group.try_into()?), format!("/{path}")),
+            None => (None, value.to_string()),
+        };
+
+        if !path.starts_with('/') {
+            anyhow::bail!("Ignore rule path must be a JSON pointer starting with '/': {value}");
+        }
+
+        Ok(Self {
+            group,
+            path: Pattern::new(&path)?,
+        })
+    }
+}
+
+impl TryFrom<String> for IgnoreRule {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::try_from(value.as_str())
+    }
+}
+
+impl Display for IgnoreRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.group {
+            Some(group) => write!(f, "{group}:{}", self.path),
+            None => write!(f, "{}", self.path),
+        }
+    }
+}
+
+/// `IgnoreRules` is a set of rules applied to objects before they are diffed in record mode.
+#[derive(Clone, Debug, Default)]
+pub struct IgnoreRules(pub Vec<IgnoreRule>);
+
+impl IgnoreRules {
+    /// Returns the default rules, ignoring resource versions, managed fields, heartbeats and lease renewals.
+    pub fn defaults() -> Self {
+        Self(
+            DEFAULT_RULES
+                .iter()
+                .map(|rule| IgnoreRule::try_from(*rule).expect("valid default rule"))
+                .collect(),
+        )
+    }
+
+    /// Returns the object with all ignored fields removed.
+    pub fn apply(&self, mut value: Value) -> Value {
+        self.strip(&mut value);
+        value
+    }
+
+    /// Removes all ignored fields from the object. Array elements are replaced with `null`
+    /// instead of being removed, so the indexes of the remaining elements stay stable.
+    pub fn strip(&self, value: &mut Value) {
+        visit(value, &self.0, &mut |_| false);
+    }
+}
+
+impl From<Vec<IgnoreRule>> for IgnoreRules {
+    fn from(value: Vec<IgnoreRule>) -> Self {
+        Self(value)
+    }
+}
+
+/// Checks whether the objects differ in anything except the update timestamp annotation,
+/// which is set on every watch event.
+pub fn has_changes(original: &Value, updated: &Value) -> bool {
+    without_update_timestamp(original) != without_update_timestamp(updated)
+}
+
+fn without_update_timestamp(value: &Value) -> Value {
+    let mut value = value.clone();
+    if let Some(metadata) = value.get_mut("metadata").and_then(Value::as_object_mut)
+        && let Some(annotations) = metadata
+            .get_mut("annotations")
+            .and_then(Value::as_object_mut)
+    {
+        annotations.remove(UPDATED_ANNOTATION);
+        if annotations.is_empty() {
+            metadata.remove("annotations");
+        }
+    }
+
+    value
+}
+
+fn gvk(value: &Value) -> Option<GroupVersionKind> {
+    let api_version = value.get("apiVersion")?.as_str()?;
+    let kind = value.get("kind")?.as_str()?;
+    let (group, version) = match api_version.split_once('/') {
+        Some((group, version)) => (group, version),
+        None => ("", api_version),
+    };
+
+    Some(GroupVersionKind::gvk(group, version, kind))
+}
+
+/// Calls `on_match` for every field of the object selected by the rules applying to its kind.
+/// The callback returns whether the field should be kept. Removed array elements are replaced
+/// with `null`, so the indexes of the remaining elements stay stable.
+pub(crate) fn visit<'a>(
+    value: &mut Value,
+    rules: impl IntoIterator<Item = &'a IgnoreRule>,
+    on_match: &mut impl FnMut(&mut Value) -> bool,
+) {
+    let Some(gvk) = gvk(value) else {
+        return;
+    };
+
+    let patterns: Vec<&Pattern> = rules
+        .into_iter()
+        .filter(|rule| rule.applies(&gvk))
+        .map(|rule| &rule.path)
+        .collect();
+
+    if !patterns.is_empty() {
+        visit_value(value, &mut String::new(), &patterns, on_match);
+    }
+}
+
+fn visit_value(
+    value: &mut Value,
+    pointer: &mut String,
+    patterns: &[&Pattern],
+    on_match: &mut impl FnMut(&mut Value) -> bool,
+) {
+    let len = pointer.len();
+    let matches = |pointer: &str| {
+        patterns
+            .iter()
+            .any(|p| p.matches_with(pointer, MATCH_OPTIONS))
+    };
+    match value {
+        Value::Object(map) => {
+            map.retain(|key, child| {
+                pointer.push('/');
+                pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+                let keep = match matches(pointer) {
+                    true => on_match(child),
+                    false => {
+                        visit_value(child, pointer, patterns, on_match);
+                        true
+                    }
+                };
+                pointer.truncate(len);
+                keep
+            });
+        }
+        Value::Array(items) => {
+            for (index, child) in items.iter_mut().enumerate() {
+                pointer.push('/');
+                pointer.push_str(&index.to_string());
+                match matches(pointer) {
+                    true if !on_match(child) => *child = Value::Null,
+                    true => (),
+                    false => visit_value(child, pointer, patterns, on_match),
+                }
+                pointer.truncate(len);
+            }
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_rule() {
+        let rule = IgnoreRule::try_from("apps/Deployment:/status/*").unwrap();
+        assert!(rule.applies(&GroupVersionKind::gvk("apps", "v1", "Deployment")));
+        assert!(!rule.applies(&GroupVersionKind::gvk("", "v1", "Pod")));
+
+        let rule = IgnoreRule::try_from("/metadata/resourceVersion").unwrap();
+        assert!(rule.applies(&GroupVersionKind::gvk("", "v1", "Pod")));
+
+        assert!(IgnoreRule::try_from("metadata/resourceVersion").is_err());
+    }
+
+    #[test]
+    fn test_strip_defaults() {
+        let mut node = json!({
+            "apiVersion": "v1",
+            "kind": "Node",
+            "metadata": {
+                "name": "node",
+                "resourceVersion": "1",
+                "managedFields": [{"manager": "kubelet"}],
+            },
+            "status": {
+                "conditions": [
+                    {"type": "Ready", "status": "True", "lastHeartbeatTime": "now"},
+                    {"type": "DiskPressure", "status": "False", "lastHeartbeatTime": "now"},
+                ],
+            },
+        });
+
+        IgnoreRules::defaults().strip(&mut node);
+
+        assert_eq!(
+            node,
+            json!({
+                "apiVersion": "v1",
+                "kind": "Node",
+                "metadata": {"name": "node"},
+                "status": {
+                    "conditions": [
+                        {"type": "Ready", "status": "True"},
+                        {"type": "DiskPressure", "status": "False"},
+                    ],
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_strip_per_kind() {
+        let rules = IgnoreRules::defaults();
+
+        let mut lease = json!({
+            "apiVersion": "coordination.k8s.io/v1",
+            "kind": "Lease",
+            "spec": {"holderIdentity": "a", "renewTime": "now"},
+        });
+        rules.strip(&mut lease);
+        assert_eq!(lease["spec"], json!({"holderIdentity": "a"}));
+
+        let mut other = json!({
+            "apiVersion": "example.com/v1",
+            "kind": "Lease",
+            "spec": {"renewTime": "now"},
+        });
+        rules.strip(&mut other);
+        assert_eq!(other["spec"], json!({"renewTime": "now"}));
+    }
+
+    #[test]
+    fn test_strip_escaped_and_array() {
+        let rules: IgnoreRules = vec![
+            "/metadata/annotations/example.com~1*".try_into().unwrap(),
+            "/spec/items/1".try_into().unwrap(),
+            "/status/**".try_into().unwrap(),
+        ]
+        .into();
+
+        let mut obj = json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": {"annotations": {"example.com/a": "1", "other/b": "2"}},
+            "spec": {"items": [1, 2, 3]},
+            "status": {"a": {"b": 1}},
+        });
+        rules.strip(&mut obj);
+
+        assert_eq!(obj["metadata"]["annotations"], json!({"other/b": "2"}));
+        assert_eq!(obj["spec"]["items"], json!([1, null, 3]));
+        assert_eq!(obj["status"], json!({}));
+    }
+
+    #[test]
+    fn test_has_changes() {
+        let original = json!({"metadata": {"name": "a"}, "data": {"a": "1"}});
+        let updated = json!({
+            "metadata": {"name": "a", "annotations": {UPDATED_ANNOTATION: "now"}},
+            "data": {"a": "1"},
+        });
+        assert!(!has_changes(&original, &updated));
+
+        let updated = json!({
+            "metadata": {"name": "a", "annotations": {UPDATED_ANNOTATION: "now"}},
+            "data": {"a": "2"},
+        });
+        assert!(has_changes(&original, &updated));
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    fs::{self, File},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use kube::config::{Kubeconfig, NamedCluster};
+use tempfile::NamedTempFile;
+
+/// Time to wait for the lock of a kubeconfig held by another process.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Kubeconfig receiving the contexts of the served archives.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KubeconfigTarget {
+    /// Contexts are merged into an existing kubeconfig, such as "~/.kube/config". The file
+    /// is locked while it is updated, so several servers can share it.
+    Merge(PathBuf),
+    /// A standalone kubeconfig is written to the path and removed once serving stops.
+    Standalone(PathBuf),
+    /// A standalone kubeconfig is printed to stdout.
+    Stdout,
+}
+
+impl KubeconfigTarget {
+    /// Returns the target for the "--kubeconfig" and "--kubeconfig-output" flags. Without
+    /// either, contexts are merged into "$KUBECONFIG" or "~/.kube/config".
+    pub fn new(kubeconfig: Option<PathBuf>, output: Option<PathBuf>) -> anyhow::Result<Self> {
+        Ok(match (kubeconfig, output) {
+            (_, Some(output)) if output.as_os_str() == "-" => Self::Stdout,
+            (_, Some(output)) => Self::Standalone(output),
+            (Some(kubeconfig), None) => Self::Merge(kubeconfig),
+            (None, None) => Self::Merge(match std::env::var("KUBECONFIG") {
+                Ok(kubeconfig) => kubeconfig.into(),
+                Err(_) => PathBuf::from(std::env::var("HOME")?).join(".kube/config"),
+            }),
+        })
+    }
+
+    /// Adds the served contexts and makes the first one current. Returns the current
+    /// context of a merged kubeconfig, which is restored once serving stops.
+    pub async fn install(&self, served: &Kubeconfig) -> anyhow::Result<Option<String>> {
+        let (target, served) = (self.clone(), served.clone());
+        tokio::task::spawn_blocking(move || target.add_contexts(&served)).await?
+    }
+
+    /// Removes the served contexts. Entries replaced by another server in the meantime
+    /// are kept, and the previous context is only restored while a served one is current.
+    pub async fn uninstall(
+        &self,
+        served: &Kubeconfig,
+        previous_context: Option<String>,
+    ) -> anyhow::Result<()> {
+        let (target, served) = (self.clone(), served.clone());
+        tokio::task::spawn_blocking(move || target.remove_contexts(&served, previous_context))
+            .await?
+    }
+
+    /// Writes the served contexts, waiting for the lock of a merged kubeconfig.
+    fn add_contexts(&self, served: &Kubeconfig) -> anyhow::Result<Option<String>> {
+        match self {
+            Self::Merge(path) => {
+                let _lock = KubeconfigLock::acquire(path)?;
+                let mut config = read(path)?;
+                let previous_context = config.current_context.take();
+                let names: Vec<String> = served.contexts.iter().map(|c| c.name.clone()).collect();
+                remove_named(&mut config, &names);
+                config.clusters.extend(served.clusters.clone());
+                config.contexts.extend(served.contexts.clone());
+                config.auth_infos.extend(served.auth_infos.clone());
+                config.current_context = served.current_context.clone();
+                write(path, &config)?;
+                Ok(previous_context)
+            }
+            Self::Standalone(path) => {
+                write(path, served)?;
+                tracing::info!(path = %path.display(), "Wrote kubeconfig for the served archives");
+                Ok(None)
+            }
+            Self::Stdout => {
+                serde_saphyr::to_io_writer(&mut io::stdout(), served)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Removes the served contexts, waiting for the lock of a merged kubeconfig.
+    fn remove_contexts(
+        &self,
+        served: &Kubeconfig,
+        previous_context: Option<String>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::Merge(path) => {
+                let _lock = KubeconfigLock::acquire(path)?;
+                let mut config = read(path)?;
+                let names = owned_names(&config, served);
+                remove_named(&mut config, &names);
+
+                let current = config.current_context.as_ref();
+                if !config.contexts.iter().any(|c| Some(&c.name) == current) {
+                    config.current_context = match config
+                        .contexts
+                        .iter()
+                        .find(|c| Some(&c.name) == previous_context.as_ref())
+                    {
+                        Some(context) => Some(context.name.clone()),
+                        None => config.contexts.first().map(|c| c.name.clone()),
+                    };
+                }
+                write(path, &config)
+            }
+            Self::Standalone(path) => match fs::remove_file(path) {
+                Err(error) if error.kind() != ErrorKind::NotFound => Err(error.into()),
+                _ => Ok(()),
+            },
+            Self::Stdout => Ok(()),
+        }
+    }
+}
+
+/// Returns the names of the served clusters which still point to the served server.
+/// Entries replaced by another server with the same name are left to that server.
+fn owned_names(config: &Kubeconfig, served: &Kubeconfig) -> Vec<String> {
+    let server = |cluster: &NamedCluster| cluster.cluster.as_ref()?.server.clone();
+    served
+        .clusters
+        .iter()
+        .filter(|served| {
+            config
+                .clusters
+                .iter()
+                .any(|cluster| cluster.name == served.name && server(cluster) == server(served))
+        })
+        .map(|served| served.name.clone())
+        .collect()
+}
+
+/// Removes the clusters, contexts and users with the names.
+fn remove_named(config: &mut Kubeconfig, names: &[String]) {
+    config.clusters.retain(|c| !names.contains(&c.name));
+    config.contexts.retain(|c| !names.contains(&c.name));
+    config.auth_infos.retain(|ai| !names.contains(&ai.name));
+}
+
+fn read(path: &Path) -> anyhow::Result<Kubeconfig> {
+    match File::open(path) {
+        Ok(file) => serde_saphyr::from_reader(file)
+            .with_context(|| format!("unable to parse kubeconfig {}", path.display())),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(Kubeconfig::default()),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Replaces the kubeconfig with a renamed temporary file, so readers never see a
+/// partially written file. Symlinked kubeconfigs are replaced at their target.
+fn write(path: &Path, config: &Kubeconfig) -> anyhow::Result<()> {
+    let path = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+
+    let mut file = NamedTempFile::new_in(dir)?;
+    serde_saphyr::to_io_writer(&mut file, config)?;
+    if let Ok(metadata) = fs::metadata(&path) {
+        fs::set_permissions(file.path(), metadata.permissions())?;
+    }
+    file.persist(&path)?;
+    Ok(())
+}
+
+/// Lock file next to the kubeconfig, following the "<kubeconfig>.lock" convention
+/// used by kubectl. Removed when dropped.
+struct KubeconfigLock(PathBuf);
+
+impl KubeconfigLock {
+    fn acquire(path: &Path) -> anyhow::Result<Self> {
+        let mut lock = path.as_os_str().to_owned();
+        lock.push(".lock");
+        let lock = PathBuf::from(lock);
+        if let Some(dir) = lock.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+
+        let start = Instant::now();
+        loop {
+            match File::options().write(true).create_new(true).open(&lock) {
+                Ok(_) => return Ok(Self(lock)),
+                Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+                    if start.elapsed() > LOCK_TIMEOUT {
+                        anyhow::bail!(
+                            "kubeconfig is locked by another process, remove {} if it is stale",
+                            lock.display()
+                        );
+                    }
+                    sleep(LOCK_RETRY_INTERVAL);
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+}
+
+impl Drop for KubeconfigLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use kube::config::{Cluster, Kubeconfig, NamedCluster, NamedContext};
+    use tempfile::TempDir;
+
+    use super::{KubeconfigTarget, read};
+
+    fn served(name: &str, port: u16) -> Kubeconfig {
+        Kubeconfig {
+            current_context: Some(name.into()),
+            clusters: vec![NamedCluster {
+                name: name.into(),
+                cluster: Some(Cluster {
+                    server: Some(format!("http://127.0.0.1:{port}/{name}")),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            contexts: vec![NamedContext {
+                name: name.into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_kubeconfig_merge() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config");
+        let mut user = served("kind", 6443);
+        user.current_context = Some("kind".into());
+        fs::write(&path, serde_saphyr::to_string(&user).unwrap()).unwrap();
+
+        let target = &KubeconfigTarget::Merge(path.clone());
+        let (a, b) = (served("a", 9095), served("b", 9096));
+
+        // Concurrent servers merge their contexts without losing each other's.
+        let (previous_a, previous_b) = tokio::join!(target.install(&a), target.install(&b));
+        let previous = [previous_a.unwrap(), previous_b.unwrap()];
+        assert!(previous.contains(&Some("kind".into())));
+        let config = read(&path).unwrap();
+        let mut names: Vec<_> = config.contexts.iter().map(|c| c.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["a", "b", "kind"]);
+        assert!(!dir.path().join("config.lock").exists());
+
+        // A context of the same name from another server is not removed.
+        target.install(&served("a", 9097)).await.unwrap();
+        target.uninstall(&a, Some("kind".into())).await.unwrap();
+        let config = read(&path).unwrap();
+        assert_eq!(config.contexts.len(), 3);
+
+        target
+            .uninstall(&served("a", 9097), Some("kind".into()))
+            .await
+            .unwrap();
+        target.uninstall(&b, Some("kind".into())).await.unwrap();
+        let config = read(&path).unwrap();
+        assert_eq!(config.contexts.len(), 1);
+        assert_eq!(config.current_context.as_deref(), Some("kind"));
+    }
+
+    #[tokio::test]
+    async fn test_kubeconfig_standalone() {
+        let dir = TempDir::new().unwrap();
+        let user = dir.path().join("config");
+        fs::write(&user, "current-context: kind\n").unwrap();
+        let path = dir.path().join("served.yaml");
+
+        let target = KubeconfigTarget::new(Some(user.clone()), Some(path.clone())).unwrap();
+        assert_eq!(target, KubeconfigTarget::Standalone(path.clone()));
+        let a = served("a", 9095);
+        assert_eq!(target.install(&a).await.unwrap(), None);
+        assert_eq!(read(&path).unwrap().current_context.as_deref(), Some("a"));
+
+        target.uninstall(&a, None).await.unwrap();
+        assert!(!path.exists());
+        assert_eq!(
+            fs::read_to_string(&user).unwrap(),
+            "current-context: kind\n"
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::BTreeMap;
+
+use anyhow::bail;
+use clap::Parser;
+use futures::{StreamExt as _, future, stream};
+use oci_client::{Client, Reference};
+
+use crate::cli::OCISettings;
+
+use super::writer::{
+    CLUSTER_ANNOTATION, CREATED_ANNOTATION, FILTERS_ANNOTATION, OCI_ARTIFACT_TYPE,
+    SERVER_VERSION_ANNOTATION, VERSION_ANNOTATION,
+};
+
+/// Number of tags requested per page from the registry.
+const TAGS_PAGE_SIZE: usize = 100;
+
+#[derive(Parser, Clone, Default)]
+pub struct Lister {
+    /// OCI repository to list snapshots in. The tag of the reference is ignored.
+    #[clap(flatten)]
+    oci: OCISettings,
+}
+
+/// Snapshot tag with the annotations of its manifest, empty for other images.
+#[derive(Debug, PartialEq)]
+pub struct Snapshot {
+    pub tag: String,
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl Lister {
+    /// Lists the tags in the repository with the crust-gather annotations of their
+    /// manifests, newest snapshots first. Archive layers are not pulled, and tags without
+    /// an image manifest, like image indexes, are skipped.
+    pub async fn run(&self) -> anyhow::Result<Vec<Snapshot>> {
+        let Some(reference) = self.oci.reference()? else {
+            bail!("missing reference");
+        };
+        let reference: Reference = reference.clone().into();
+        let client = Client::new(self.oci.to_client_config());
+        let auth = self.oci.to_auth().await;
+
+        let mut tags: Vec<String> = vec![];
+        loop {
+            let page = client
+                .list_tags(
+                    &reference,
+                    &auth,
+                    Some(TAGS_PAGE_SIZE),
+                    tags.last().map(String::as_str),
+                )
+                .await?;
+            // Registries may return shorter pages before the end, so only an empty page,
+            // or one not moving past the last tag, ends the listing.
+            if page.tags.is_empty() || page.tags.last() == tags.last() {
+                break;
+            }
+            tags.extend(page.tags);
+        }
+
+        let mut snapshots: Vec<Snapshot> = stream::iter(tags)
+            .map(|tag| {
+                let (client, auth, reference) = (client.clone(), auth.clone(), &reference);
+                async move {
+                    let reference = Reference::with_tag(
+                        reference.registry().to_string(),
+                        reference.repository().to_string(),
+                        tag.clone(),
+                    );
+                    let manifest = match client.pull_image_manifest(&reference, &auth).await {
+                        Ok((manifest, _)) => manifest,
+                        Err(error) => {
+                            tracing::warn!(tag, "Skipping tag without an image manifest: {error}");
+                            return None;
+                        }
+                    };
+                    let annotations = match manifest.artifact_type.as_deref() {
+                        Some(OCI_ARTIFACT_TYPE) => manifest.annotations.unwrap_or_default(),
+                        _ => BTreeMap::new(),
+                    };
+                    Some(Snapshot { tag, annotations })
+                }
+            })
+            .buffer_unordered(self.oci.buffer_size)
+            .filter_map(future::ready)
+            .collect()
+            .await;
+
+        snapshots.sort_by(|a, b| {
+            b.annotation(CREATED_ANNOTATION)
+                .cmp(a.annotation(CREATED_ANNOTATION))
+                .then_with(|| a.tag.cmp(&b.tag))
+        });
+        Ok(snapshots)
+    }
+}
+
+impl Snapshot {
+    fn annotation(&self, key: &str) -> &str {
+        self.annotations.get(key).map_or("", String::as_str)
+    }
+}
+
+/// Prints snapshots as a table. Tags pushed by older versions, or other images in the
+/// repository have no annotations.
+pub fn print_snapshots(snapshots: &[Snapshot]) {
+    let columns = [
+        CREATED_ANNOTATION,
+        CLUSTER_ANNOTATION,
+        SERVER_VERSION_ANNOTATION,
+        VERSION_ANNOTATION,
+        FILTERS_ANNOTATION,
+    ];
+    let rows: Vec<Vec<&str>> = snapshots
+        .iter()
+        .map(|snapshot| {
+            let mut row = vec![snapshot.tag.as_str()];
+            row.extend(columns.map(|key| snapshot.annotation(key)));
+            row
+        })
+        .collect();
+
+    let header = vec![
+        "TAG",
+        "CREATED",
+        "CLUSTER",
+        "SERVER VERSION",
+        "CRUST-GATHER",
+        "FILTERS",
+    ];
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| {
+            rows.iter()
+                .chain([&header])
+                .filter_map(|row| row.get(column).map(|cell| cell.len()))
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    for row in [&header].into_iter().chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        println!("{}", line.join("   ").trim_end());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use actix_web::{App, HttpResponse, HttpServer, web};
+    use clap::Parser as _;
+    use k8s_openapi::serde_json::{self, json};
+    use oci_client::manifest::OciImageManifest;
+
+    use super::{CREATED_ANNOTATION, Lister, OCI_ARTIFACT_TYPE, Snapshot};
+
+    /// Number of tags the mock registry returns per page, regardless of the requested size.
+    const PAGE_SIZE: usize = 2;
+
+    /// Serves the tags list in short pages and the manifests of the tags. The "index"
+    /// tag is an image index, which has no image manifest.
+    fn start_registry() -> (String, actix_web::dev::ServerHandle) {
+        type Query = web::Query<HashMap<String, String>>;
+
+        let server = HttpServer::new(|| {
+            App::new()
+                .route("/v2/", web::get().to(HttpResponse::Ok))
+                .route(
+                    "/v2/{repo}/tags/list",
+                    web::get().to(|path: web::Path<String>, query: Query| async move {
+                        let tags: Vec<&str> = ["a", "b", "c", "d", "index"]
+                            .into_iter()
+                            .filter(|tag| query.get("last").is_none_or(|last| *tag > last.as_str()))
+                            .take(PAGE_SIZE)
+                            .collect();
+                        HttpResponse::Ok().json(json!({"name": path.into_inner(), "tags": tags}))
+                    }),
+                )
+                .route(
+                    "/v2/{repo}/manifests/{tag}",
+                    web::get().to(|path: web::Path<(String, String)>| async move {
+                        let (_, tag) = path.into_inner();
+                        if tag == "index" {
+                            return HttpResponse::Ok()
+                                .content_type("application/vnd.oci.image.index.v1+json")
+                                .json(json!({
+                                    "schemaVersion": 2,
+                                    "mediaType": "application/vnd.oci.image.index.v1+json",
+                                    "manifests": [],
+                                }));
+                        }
+
+                        let manifest = match tag.as_str() {
+                            "c" => OciImageManifest::default(),
+                            _ => OciImageManifest {
+                                artifact_type: Some(OCI_ARTIFACT_TYPE.to_string()),
+                                annotations: Some(BTreeMap::from([(
+                                    CREATED_ANNOTATION.to_string(),
+                                    format!("2026-01-0{}", tag.as_bytes()[0] - b'a' + 1),
+                                )])),
+                                ..Default::default()
+                            },
+                        };
+                        HttpResponse::Ok()
+                            .content_type("application/vnd.oci.image.manifest.v1+json")
+                            .body(serde_json::to_vec(&manifest).unwrap())
+                    }),
+                )
+        })
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let address = server.addrs()[0].to_string();
+        let server = server.run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        (address, handle)
+    }
+
+    #[tokio::test]
+    async fn test_list_snapshots() {
+        let (address, stop) = start_registry();
+        let lister = Lister::try_parse_from([
+            "ls",
+            "--insecure",
+            "--token=token",
+            &format!("--reference={address}/snapshots"),
+        ])
+        .unwrap();
+
+        // Tags are listed past the short pages, and the image index is skipped.
+        let snapshots = lister.run().await.unwrap();
+        let created = |day: usize| {
+            BTreeMap::from([(CREATED_ANNOTATION.to_string(), format!("2026-01-0{day}"))])
+        };
+        assert_eq!(
+            snapshots,
+            vec![
+                Snapshot {
+                    tag: "d".to_string(),
+                    annotations: created(4),
+                },
+                Snapshot {
+                    tag: "b".to_string(),
+                    annotations: created(2),
+                },
+                Snapshot {
+                    tag: "a".to_string(),
+                    annotations: created(1),
+                },
+                Snapshot {
+                    tag: "c".to_string(),
+                    annotations: BTreeMap::new(),
+                },
+            ]
+        );
+
+        stop.stop(false).await;
+    }
+}
@@ -0,0 +1,606 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fmt::Display,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, bail};
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use ed25519_dalek::{
+    Signature, Signer as _, SigningKey, Verifier as _, VerifyingKey,
+    pkcs8::{DecodePrivateKey as _, DecodePublicKey as _},
+};
+use futures::{StreamExt as _, TryStreamExt as _, stream};
+use k8s_openapi::serde_json::{self, Value};
+use oci_client::Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use walkdir::WalkDir;
+
+use crate::cli::{DecryptionSettings, OCISettings};
+
+use super::{
+    compression::{ZIP_EXTENSION, is_tar, tar_decoder},
+    encryption::ENCRYPTED_EXTENSION,
+    pull::files_by_layer,
+    storage::{Descriptor, OCIState, Storage},
+};
+
+pub const MANIFEST_PATH: &str = "manifest.json";
+pub const SIGNATURE_PATH: &str = "manifest.json.sig";
+
+/// Returns the sha256 digest of the data in the OCI digest format.
+pub fn digest(data: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(data)))
+}
+
+/// `Manifest` records the digest of every file stored in the archive, along with
+/// the tool version and the collection parameters.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Manifest {
+    pub version: String,
+    pub created: DateTime<Utc>,
+    #[serde(default)]
+    pub parameters: Value,
+    pub files: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    pub fn new(parameters: Value, files: BTreeMap<String, String>) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            created: Utc::now(),
+            parameters,
+            files,
+        }
+    }
+
+    /// Computes digests for all files in the archive directory, except the manifest itself.
+    pub fn files_in(root: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+        let mut files = BTreeMap::new();
+        for entry in WalkDir::new(root).into_iter() {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let path = entry
+                .path()
+                .strip_prefix(root)?
+                .to_string_lossy()
+                .to_string();
+            if path == MANIFEST_PATH || path == SIGNATURE_PATH {
+                continue;
+            }
+
+            files.insert(path, digest(&fs::read(entry.path())?));
+        }
+
+        Ok(files)
+    }
+
+    /// Serializes the manifest, returning the content and the optional detached signature.
+    pub fn sign(&self, key: Option<&SigningKey>) -> anyhow::Result<(String, Option<String>)> {
+        let data = serde_json::to_string_pretty(self)?;
+        let signature = key.map(|key| BASE64_STANDARD.encode(key.sign(data.as_bytes()).to_bytes()));
+        Ok((data, signature))
+    }
+}
+
+/// Reads an ed25519 private key in the PKCS#8 PEM format, as generated by
+/// `openssl genpkey -algorithm ed25519`.
+pub fn signing_key(path: &Path) -> anyhow::Result<SigningKey> {
+    SigningKey::from_pkcs8_pem(&fs::read_to_string(path)?)
+        .with_context(|| format!("failed to read ed25519 private key {}", path.display()))
+}
+
+/// Reads an ed25519 public key in the SPKI PEM format, as generated by `openssl pkey -pubout`.
+pub fn verifying_key(path: &Path) -> anyhow::Result<VerifyingKey> {
+    VerifyingKey::from_public_key_pem(&fs::read_to_string(path)?)
+        .with_context(|| format!("failed to read ed25519 public key {}", path.display()))
+}
+
+/// Result of an archive verification.
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub files: usize,
+    pub mismatched: Vec<String>,
+    pub missing: Vec<String>,
+    pub unexpected: Vec<String>,
+    pub signed: bool,
+}
+
+impl Verification {
+    pub fn is_valid(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty() && self.unexpected.is_empty()
+    }
+
+    /// Returns an error describing the failures, if the archive is not valid.
+    pub fn ensure_valid(&self) -> anyhow::Result<()> {
+        match self.is_valid() {
+            true => Ok(()),
+            false => bail!("archive verification failed: {self}"),
+        }
+    }
+}
+
+impl Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_valid() {
+            let signed = if self.signed { ", signature valid" } else { "" };
+            return write!(f, "{} files verified{signed}", self.files);
+        }
+
+        for (kind, paths) in [
+            ("modified", &self.mismatched),
+            ("missing", &self.missing),
+            ("unexpected", &self.unexpected),
+        ] {
+            for path in paths {
+                writeln!(f, "{kind}: {path}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Parser, Clone, Default)]
+pub struct Verifier {
+    /// The archive to verify: a directory, a zip, or a gzip, zstd or xz compressed tar archive.
+    /// Encrypted archives are decrypted with "--identity" or "--decrypt-passphrase".
+    ///
+    /// Example:
+    ///     --archive=./crust-gather.tar.gz
+    #[arg(short, long, value_name = "PATH", conflicts_with = "reference")]
+    archive: Option<PathBuf>,
+
+    /// OCI source of the archive to verify.
+    #[clap(flatten)]
+    oci: OCISettings,
+
+    /// Decryption options for encrypted archives.
+    #[clap(flatten)]
+    decryption: DecryptionSettings,
+
+    /// Path to the ed25519 public key in PEM format to verify the manifest signature with.
+    /// When provided, the manifest must be signed.
+    ///
+    /// Example:
+    ///     --verify-key=key.pub
+    #[arg(long, value_name = "PATH")]
+    verify_key: Option<PathBuf>,
+}
+
+impl Verifier {
+    pub async fn run(&self) -> anyhow::Result<Verification> {
+        let key = self.verify_key.as_deref().map(verifying_key).transpose()?;
+        let decryption = self.decryption.to_decryption()?;
+
+        if let Some(reference) = self.oci.reference()? {
+            let state = OCIState::pull(
+                Client::new(self.oci.to_client_config()),
+                reference.clone().into(),
+                self.oci.to_auth().await,
+                decryption,
+                None,
+            )
+            .await?;
+            return verify_oci(state, key.as_ref(), self.oci.buffer_size).await;
+        }
+
+        let Some(archive) = &self.archive else {
+            bail!("either --archive or --reference is required");
+        };
+
+        let is_encrypted = archive.extension() == Some(OsStr::new(ENCRYPTED_EXTENSION));
+        match (is_encrypted, decryption) {
+            (false, _) => verify_archive(archive, key.as_ref()),
+            (true, None) => {
+                bail!("archive is encrypted, provide --identity or --decrypt-passphrase")
+            }
+            (true, Some(decryption)) => {
+                let Some(unpacked) = Storage::unpack(archive, Some(&decryption))? else {
+                    bail!("unable to decrypt {}", archive.display());
+                };
+                let root = fs::read_dir(unpacked.path())?
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("archive {} is empty", archive.display()))??;
+                verify_archive(&root.path(), key.as_ref())
+            }
+        }
+    }
+}
+
+/// Checks the manifest signature, and the files against the digests in the manifest.
+/// The signature is required when a key is provided.
+fn check(
+    manifest: &[u8],
+    signature: Option<&[u8]>,
+    key: Option<&VerifyingKey>,
+    files: BTreeMap<String, String>,
+) -> anyhow::Result<Verification> {
+    let signed = match (key, signature) {
+        (Some(key), Some(signature)) => {
+            let signature = Signature::from_slice(
+                &BASE64_STANDARD.decode(String::from_utf8_lossy(signature).trim())?,
+            )?;
+            key.verify(manifest, &signature)
+                .context("manifest signature is invalid")?;
+            true
+        }
+        (Some(_), None) => bail!("manifest is not signed"),
+        (None, Some(_)) => {
+            tracing::warn!("Manifest is signed, but no key is provided to verify the signature");
+            false
+        }
+        (None, None) => false,
+    };
+
+    let manifest: Manifest = serde_json::from_slice(manifest).context("invalid manifest")?;
+    let mut verification = Verification {
+        files: manifest.files.len(),
+        signed,
+        ..Default::default()
+    };
+
+    for (path, expected) in &manifest.files {
+        match files.get(path) {
+            Some(actual) if actual == expected => {}
+            Some(_) => verification.mismatched.push(path.clone()),
+            None => verification.missing.push(path.clone()),
+        }
+    }
+
+    verification.unexpected = files
+        .into_keys()
+        .filter(|path| !manifest.files.contains_key(path))
+        .collect();
+
+    Ok(verification)
+}
+
+/// Verifies an archive directory, zip or compressed tar archive against its manifest.
+pub fn verify_archive(path: &Path, key: Option<&VerifyingKey>) -> anyhow::Result<Verification> {
+    let name = path.to_string_lossy();
+    let mut files = BTreeMap::new();
+    let mut manifest = None;
+    let mut signature = None;
+    let mut add = |path: String, data: Vec<u8>| match path.as_str() {
+        MANIFEST_PATH => manifest = Some(data),
+        SIGNATURE_PATH => signature = Some(data),
+        _ => {
+            files.insert(path, digest(&data));
+        }
+    };
+
+    if path.is_dir() {
+        for entry in WalkDir::new(path).into_iter() {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                add(
+                    entry
+                        .path()
+                        .strip_prefix(path)?
+                        .to_string_lossy()
+                        .to_string(),
+                    fs::read(entry.path())?,
+                );
+            }
+        }
+    } else if is_tar(&name) {
+        let mut archive = tar::Archive::new(tar_decoder(&name, File::open(path)?)?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+
+            let entry_path = strip_root(&entry.path()?);
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+            add(entry_path, data);
+        }
+    } else if name.ends_with(&format!(".{ZIP_EXTENSION}")) {
+        let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            if !entry.is_file() {
+                continue;
+            }
+
+            let entry_path = strip_root(Path::new(entry.name()));
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+            add(entry_path, data);
+        }
+    } else {
+        bail!("unsupported archive format: {name}");
+    }
+
+    let Some(manifest) = manifest else {
+        bail!("archive {name} contains no {MANIFEST_PATH}");
+    };
+
+    check(&manifest, signature.as_deref(), key, files)
+}
+
+/// Verifies an OCI archive. Every layer is checked against its digest, and every file
+/// in the image is checked against the manifest, so layers added after signing are
+/// reported as unexpected files.
+pub async fn verify_oci(
+    state: OCIState,
+    key: Option<&VerifyingKey>,
+    buffer_size: usize,
+) -> anyhow::Result<Verification> {
+    let Some(manifest_path) = state
+        .index
+        .keys()
+        .find(|path| path.file_name().is_some_and(|name| name == MANIFEST_PATH))
+        .cloned()
+    else {
+        bail!("OCI archive contains no {MANIFEST_PATH} layer");
+    };
+    let root = manifest_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    let layers: Vec<_> = state
+        .index
+        .iter()
+        .filter_map(|(path, descriptor)| match descriptor {
+            Descriptor::OciDescriptor(layer) => Some((path.clone(), layer.clone())),
+            Descriptor::ListOciDescriptor(..) => None,
+        })
+        .collect();
+    stream::iter(layers)
+        .map(|(path, layer)| {
+            let state = state.clone();
+            async move {
+                let mut data = Vec::with_capacity(layer.size as usize);
+                state
+                    .client
+                    .pull_blob(&state.reference, &layer, &mut data)
+                    .await?;
+                match digest(&data) == layer.digest {
+                    true => Ok(()),
+                    false => bail!("layer {} does not match its digest", path.display()),
+                }
+            }
+        })
+        .buffer_unordered(buffer_size)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    // Every layer is pulled once, and split into the files packed into it.
+    let mut manifest = None;
+    let mut signature = None;
+    let mut files = BTreeMap::new();
+    let mut layers = stream::iter(files_by_layer(&state).into_values())
+        .map(|paths| {
+            let state = &state;
+            async move {
+                let Some(first) = paths.first() else {
+                    return anyhow::Ok(vec![]);
+                };
+                let layer = state.pull_layer(first).await?;
+                paths
+                    .into_iter()
+                    .map(|path| {
+                        let data = state.layer_file(&layer, &path)?.to_vec();
+                        Ok((path, data))
+                    })
+                    .collect()
+            }
+        })
+        .buffer_unordered(buffer_size);
+    while let Some(layer_files) = layers.try_next().await? {
+        for (path, data) in layer_files {
+            let relative = path.strip_prefix(&root).unwrap_or(&path).to_string_lossy();
+            match relative.as_ref() {
+                MANIFEST_PATH => manifest = Some(data),
+                SIGNATURE_PATH => signature = Some(data),
+                relative => {
+                    files.insert(relative.to_string(), digest(&data));
+                }
+            }
+        }
+    }
+    let Some(manifest) = manifest else {
+        bail!("OCI archive contains no {} layer", manifest_path.display());
+    };
+
+    check(&manifest, signature.as_deref(), key, files)
+}
+
+/// Verifies a snapshot read through a remote storage, like the entries of a zip file
+/// or S3 objects. Every file under the snapshot root is read and checked against the manifest.
+pub async fn verify_storage(
+    storage: &Storage,
+    root: &Path,
+    key: Option<&VerifyingKey>,
+    buffer_size: usize,
+) -> anyhow::Result<Verification> {
+    let pattern = format!("{}/*", glob::Pattern::escape(&root.to_string_lossy()));
+    let paths = storage
+        .matching_paths(PathBuf::from(pattern))?
+        .into_iter()
+        .filter_map(|path| {
+            let relative = path.strip_prefix(root).ok()?.to_string_lossy().to_string();
+            Some((relative, path))
+        });
+
+    let mut manifest = None;
+    let mut signature = None;
+    let mut files = BTreeMap::new();
+    let mut reads = stream::iter(paths)
+        .map(|(relative, path)| {
+            let storage = storage.clone();
+            async move { anyhow::Ok((relative, storage.read_raw(path).await?)) }
+        })
+        .buffer_unordered(buffer_size);
+    while let Some((relative, data)) = reads.try_next().await? {
+        match relative.as_str() {
+            MANIFEST_PATH => manifest = Some(data),
+            SIGNATURE_PATH => signature = Some(data),
+            _ => {
+                files.insert(relative, digest(data.as_bytes()));
+            }
+        }
+    }
+
+    let Some(manifest) = manifest else {
+        bail!("archive {} contains no {MANIFEST_PATH}", root.display());
+    };
+
+    check(
+        manifest.as_bytes(),
+        signature.as_deref().map(str::as_bytes),
+        key,
+        files,
+    )
+}
+
+// Zip and tar archives store files under a root directory named after the archive.
+fn strip_root(path: &Path) -> String {
+    path.components()
+        .skip(1)
+        .collect::<PathBuf>()
+        .to_string_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::pkcs8::{
+        EncodePrivateKey as _, EncodePublicKey as _, spki::der::pem::LineEnding,
+    };
+    use std::sync::Arc;
+
+    use tempfile::TempDir;
+    use zip::write::SimpleFileOptions;
+
+    use crate::gather::storage::ZipState;
+
+    use super::*;
+
+    fn write_archive(root: &Path, key: Option<&SigningKey>) {
+        fs::create_dir_all(root.join("cluster")).unwrap();
+        fs::write(root.join("version.yaml"), "version").unwrap();
+        fs::write(root.join("cluster/node.yaml"), "node").unwrap();
+
+        let manifest = Manifest::new(Value::Null, Manifest::files_in(root).unwrap());
+        let (data, signature) = manifest.sign(key).unwrap();
+        fs::write(root.join(MANIFEST_PATH), data).unwrap();
+        if let Some(signature) = signature {
+            fs::write(root.join(SIGNATURE_PATH), signature).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_verify_dir() {
+        let tmp_dir = TempDir::new().unwrap();
+        write_archive(tmp_dir.path(), None);
+
+        let verification = verify_archive(tmp_dir.path(), None).unwrap();
+        assert!(verification.is_valid());
+        assert_eq!(verification.files, 2);
+
+        fs::write(tmp_dir.path().join("cluster/node.yaml"), "changed").unwrap();
+        fs::remove_file(tmp_dir.path().join("version.yaml")).unwrap();
+        fs::write(tmp_dir.path().join("extra.yaml"), "extra").unwrap();
+
+        let verification = verify_archive(tmp_dir.path(), None).unwrap();
+        assert!(!verification.is_valid());
+        assert_eq!(verification.mismatched, vec!["cluster/node.yaml"]);
+        assert_eq!(verification.missing, vec!["version.yaml"]);
+        assert_eq!(verification.unexpected, vec!["extra.yaml"]);
+    }
+
+    #[tokio::test]
+    async fn test_verify_storage() {
+        let tmp_dir = TempDir::new().unwrap();
+        let root = tmp_dir.path().join("snapshots/crust-gather");
+        write_archive(&root, None);
+
+        let path = tmp_dir.path().join("archive.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        for entry in WalkDir::new(&root) {
+            let entry = entry.unwrap();
+            if entry.file_type().is_file() {
+                let name = entry.path().strip_prefix(tmp_dir.path()).unwrap();
+                zip.start_file(name.to_string_lossy(), SimpleFileOptions::default())
+                    .unwrap();
+                std::io::Write::write_all(&mut zip, &fs::read(entry.path()).unwrap()).unwrap();
+            }
+        }
+        zip.finish().unwrap();
+
+        let zip = ZipState::new("archive.zip".into(), File::open(&path).unwrap()).unwrap();
+        let archives = zip.archives();
+        let storage = Storage::Zip(Arc::new(zip));
+        let root = archives[0].path();
+        assert_eq!(root, Path::new("snapshots/crust-gather"));
+
+        let verification = verify_storage(&storage, &root, None, 4).await.unwrap();
+        assert!(verification.is_valid());
+        assert_eq!(verification.files, 2);
+
+        let key = SigningKey::from_bytes(&[7; 32]).verifying_key();
+        assert!(
+            verify_storage(&storage, &root, Some(&key), 4)
+                .await
+                .is_err()
+        );
+        assert!(
+            verify_storage(&storage, Path::new("snapshots"), None, 4)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let tmp_dir = TempDir::new().unwrap();
+        let key = SigningKey::from_bytes(&[7; 32]);
+        write_archive(tmp_dir.path(), Some(&key));
+
+        let keys_dir = TempDir::new().unwrap();
+        let private = keys_dir.path().join("key.pem");
+        let public = keys_dir.path().join("key.pub");
+        fs::write(
+            &private,
+            key.to_pkcs8_pem(LineEnding::LF).unwrap().as_bytes(),
+        )
+        .unwrap();
+        fs::write(
+            &public,
+            key.verifying_key()
+                .to_public_key_pem(LineEnding::LF)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(signing_key(&private).unwrap(), key);
+
+        let public = verifying_key(&public).unwrap();
+        let verification = verify_archive(tmp_dir.path(), Some(&public)).unwrap();
+        assert!(verification.is_valid() && verification.signed);
+
+        let other = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        assert!(verify_archive(tmp_dir.path(), Some(&other)).is_err());
+
+        let manifest = fs::read_to_string(tmp_dir.path().join(MANIFEST_PATH)).unwrap();
+        fs::write(
+            tmp_dir.path().join(MANIFEST_PATH),
+            manifest.replace("version.yaml", "other.yaml"),
+        )
+        .unwrap();
+        assert!(verify_archive(tmp_dir.path(), Some(&public)).is_err());
+    }
+}
@@ -1,9 +1,25 @@
+pub mod blob_cache;
+pub mod compression;
 pub mod config;
+pub mod docker_auth;
+pub mod encryption;
+pub mod github;
+pub mod http;
+pub mod ignore;
+pub mod kubeconfig;
+pub mod list;
 pub mod log;
+pub mod manifest;
 pub mod printers;
+pub mod pseudonym;
+pub mod pull;
+pub mod rbac;
 pub mod reader;
+pub mod redact;
 pub mod representation;
+pub mod s3;
 pub mod selector;
 pub mod server;
 pub mod storage;
+pub mod tls;
 pub mod writer;
@@ -0,0 +1,726 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use hmac::{Hmac, KeyInit as _, Mac as _};
+use k8s_openapi::{
+    api::core::v1::{Namespace, Node},
+    serde_json::{self, Value},
+};
+use kube::{Api, Client, ResourceExt as _, api::ListParams};
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::instrument;
+
+use super::representation::Representation;
+
+/// Namespaces which exist in every cluster and carry no customer information.
+const BUILTIN_NAMESPACES: &[&str] = &["default", "kube-system", "kube-public", "kube-node-lease"];
+
+/// Archive files with API discovery data, which only holds group and resource names.
+const DISCOVERY_DOCUMENTS: &[&str] = &["api.json", "apis.json", "version.yaml"];
+
+/// Object fields holding a namespace name.
+const NAMESPACE_FIELDS: &[&str] = &["namespace", "kubernetes.io/metadata.name"];
+
+/// Object fields holding API groups, which are never pseudonymized.
+const GROUP_FIELDS: &[&str] = &["apiVersion", "apiGroup", "apiGroups", "group"];
+
+/// Rounds of the Feistel network permuting IPv4 addresses.
+const FEISTEL_ROUNDS: u8 = 8;
+
+/// Words, dotted names and IPv4 addresses, or colon separated IPv6 address candidates,
+/// which start with a hex group so Rust-like paths such as "serde::de" are not matched.
+static TOKEN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(concat!(
+        r"[0-9A-Fa-f]{1,4}:(?:[0-9A-Fa-f]{0,4}:){1,6}(?:[0-9A-Fa-f]{1,4}\b)?",
+        r"|[A-Za-z0-9](?:[A-Za-z0-9_.-]*[A-Za-z0-9])?",
+    ))
+    .expect("valid token pattern")
+});
+
+/// Category of a pseudonymized value. Each category is mapped with its own HMAC domain,
+/// so equal values in different categories produce unrelated pseudonyms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Namespace,
+    Node,
+    Ip,
+    Dns,
+}
+
+impl Display for Category {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Category::Namespace => write!(f, "namespace"),
+            Category::Node => write!(f, "node"),
+            Category::Ip => write!(f, "ip"),
+            Category::Dns => write!(f, "dns"),
+        }
+    }
+}
+
+/// Destination for the mapping of pseudonyms to the original values. The mapping is
+/// encrypted to an age X25519 recipient and can be decrypted with the `age` CLI.
+#[derive(Clone, Debug)]
+pub struct MappingFile {
+    pub path: PathBuf,
+    pub recipient: age::x25519::Recipient,
+}
+
+/// `Pseudonymizer` deterministically replaces namespace names, node names, IPv4 and IPv6
+/// addresses and DNS names under the configured domains with keyed HMAC based pseudonyms.
+///
+/// The same value always produces the same pseudonym for the same key, so relationships
+/// between objects, events and logs are preserved across the archive and between collections.
+/// Names carry 64 bits of the HMAC, so collisions are negligible, and IPv4 addresses are
+/// mapped by a keyed permutation, so they never collide regardless of the collection order.
+#[derive(Clone, Default)]
+pub struct Pseudonymizer(Option<Arc<State>>);
+
+struct State {
+    // HMAC keyed once, cloned for every pseudonym.
+    mac: Hmac<Sha256>,
+    namespaces: BTreeSet<String>,
+    nodes: BTreeSet<String>,
+    domains: Vec<String>,
+    mapping_file: Option<MappingFile>,
+    mapping: Mutex<BTreeMap<Category, BTreeMap<String, String>>>,
+}
+
+impl std::fmt::Debug for Pseudonymizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pseudonymizer")
+            .field("enabled", &self.0.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Pseudonymizer {
+    pub fn new(
+        key: &str,
+        namespaces: impl IntoIterator<Item = String>,
+        nodes: impl IntoIterator<Item = String>,
+        domains: Vec<String>,
+        mapping_file: Option<MappingFile>,
+    ) -> Self {
+        Self(Some(Arc::new(State {
+            mac: Hmac::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size"),
+            namespaces: namespaces
+                .into_iter()
+                .filter(|ns| !BUILTIN_NAMESPACES.contains(&ns.as_str()))
+                .collect(),
+            nodes: nodes.into_iter().collect(),
+            domains: domains
+                .into_iter()
+                .map(|domain| domain.trim_matches('.').to_lowercase())
+                .collect(),
+            mapping_file,
+            mapping: Default::default(),
+        })))
+    }
+
+    /// Lists namespaces and nodes in the cluster, to recognize their names in the collected data.
+    #[instrument(skip_all, err)]
+    pub async fn from_cluster(
+        client: Client,
+        key: &str,
+        domains: Vec<String>,
+        mapping_file: Option<MappingFile>,
+    ) -> anyhow::Result<Self> {
+        let namespaces = Api::<Namespace>::all(client.clone())
+            .list_metadata(&ListParams::default())
+            .await?;
+        let nodes = Api::<Node>::all(client)
+            .list_metadata(&ListParams::default())
+            .await?;
+
+        Ok(Self::new(
+            key,
+            namespaces.iter().map(|ns| ns.name_any()),
+            nodes.iter().map(|node| node.name_any()),
+            domains,
+            mapping_file,
+        ))
+    }
+
+    /// Replaces known names and addresses in the representation data and path.
+    ///
+    /// Collected objects only have namespace names replaced in known fields, so API groups
+    /// matching a namespace name stay intact. Node names are replaced wherever they appear
+    /// as whole words, also as the suffix of names like the "kube-apiserver-<node>" static
+    /// pods. Other files, like logs, have namespace names replaced as whole words too.
+    /// Discovery documents are left unchanged.
+    pub fn apply(&self, repr: &Representation) -> Representation {
+        let Some(state) = &self.0 else {
+            return repr.clone();
+        };
+
+        let path = String::try_from(repr.path()).unwrap_or_default();
+        if DISCOVERY_DOCUMENTS.contains(&path.as_str()) {
+            return repr.clone();
+        }
+
+        let object = path
+            .ends_with(".yaml")
+            .then(|| serde_saphyr::from_str::<Value>(repr.data()).ok())
+            .flatten()
+            .filter(|value| value.get("apiVersion").is_some() && value.get("kind").is_some());
+        let data = match object {
+            Some(mut object) => {
+                state.replace_object(&mut object);
+                match serde_saphyr::to_string(&object) {
+                    Ok(data) => data,
+                    Err(_) => state.replace_text(repr.data()),
+                }
+            }
+            None => state.replace_text(repr.data()),
+        };
+
+        repr.clone()
+            .with_path(repr.path().map_path(|path| state.replace_path(path)))
+            .with_data(&data)
+    }
+
+    /// Replaces known names and addresses in free text.
+    pub fn apply_text(&self, data: &str) -> String {
+        match &self.0 {
+            Some(state) => state.replace_text(data),
+            None => data.to_string(),
+        }
+    }
+
+    /// Writes the encrypted mapping of pseudonyms to the original values, if configured.
+    pub fn save_mapping(&self) -> anyhow::Result<()> {
+        let Some(state) = &self.0 else {
+            return Ok(());
+        };
+        let Some(mapping_file) = &state.mapping_file else {
+            return Ok(());
+        };
+
+        let mapping = serde_json::to_vec_pretty(&*state.mapping.lock().expect("mapping lock"))?;
+        fs::write(
+            &mapping_file.path,
+            age::encrypt(&mapping_file.recipient, &mapping)?,
+        )?;
+        tracing::info!(path = %mapping_file.path.display(), "Stored encrypted pseudonym mapping");
+
+        Ok(())
+    }
+}
+
+impl State {
+    /// Replaces the namespace segment and the resource name segment of archive paths,
+    /// like "namespaces/<namespace>/<group-version>/<kind>/<name>.yaml", and node names
+    /// in the segments below the kind. Group and kind segments are kept as they are.
+    fn replace_path(&self, path: &Path) -> PathBuf {
+        let mut components: Vec<String> = path
+            .iter()
+            .map(|component| component.to_string_lossy().into_owned())
+            .collect();
+
+        let kind = match components.first().map(String::as_str) {
+            Some("namespaces") if components.len() > 1 => {
+                if self.namespaces.contains(&components[1]) {
+                    components[1] = self.pseudonym(Category::Namespace, &components[1]);
+                }
+                3
+            }
+            Some("cluster") => 2,
+            _ => return path.to_path_buf(),
+        };
+
+        for i in kind + 1..components.len() {
+            let name = &components[i];
+            let (stem, extension) = match name.strip_suffix(".yaml") {
+                Some(stem) => (stem, ".yaml"),
+                None => (name.as_str(), ""),
+            };
+            let stem = match self.categorize_resource(&components[kind], stem) {
+                Some(category) if i == kind + 1 => self.pseudonym(category, stem),
+                _ => self.replace_node_names(stem),
+            };
+            components[i] = format!("{stem}{extension}");
+        }
+
+        components.iter().collect()
+    }
+
+    /// Categorizes a resource name. Node names are replaced for resources of any kind,
+    /// as leases and host log pods are named after their node.
+    fn categorize_resource(&self, kind: &str, name: &str) -> Option<Category> {
+        if self.nodes.contains(name) {
+            return Some(Category::Node);
+        }
+
+        (kind.eq_ignore_ascii_case("namespace") && self.namespaces.contains(name))
+            .then_some(Category::Namespace)
+    }
+
+    /// Replaces namespace names in the known fields of the object, and node names and
+    /// addresses in all values except API groups and versions.
+    fn replace_object(&self, object: &mut Value) {
+        let kind = object
+            .get("kind")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        if let Some(Value::String(name)) = object.pointer_mut("/metadata/name")
+            && let Some(category) = self.categorize_resource(&kind, name)
+        {
+            *name = self.pseudonym(category, name);
+        }
+
+        self.replace_value(None, object);
+    }
+
+    fn replace_value(&self, key: Option<&str>, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if !GROUP_FIELDS.contains(&key.as_str()) {
+                        self.replace_value(Some(key), value);
+                    }
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    self.replace_value(key, item);
+                }
+            }
+            Value::String(data) => {
+                *data = match key {
+                    Some(key)
+                        if NAMESPACE_FIELDS.contains(&key) && self.namespaces.contains(data) =>
+                    {
+                        self.pseudonym(Category::Namespace, data)
+                    }
+                    _ => self.replace_tokens(data, false),
+                };
+            }
+            _ => (),
+        }
+    }
+
+    fn replace_text(&self, data: &str) -> String {
+        self.replace_tokens(data, true)
+    }
+
+    /// Replaces addresses, domains, node names and service DNS names in the data. Namespace
+    /// names are replaced as whole words only when `names` is set.
+    fn replace_tokens(&self, data: &str, names: bool) -> String {
+        TOKEN
+            .replace_all(data, |captures: &Captures| {
+                let token = &captures[0];
+                match self.categorize(token, names) {
+                    Some(category) => self.pseudonym(category, token),
+                    // Namespaces in service DNS names, like "web.customer.svc.cluster.local".
+                    // Colon separated tokens which are not IPv6 addresses, like "a:b:c".
+                    None if token.contains(':') => token
+                        .split(':')
+                        .map(|part| self.replace_tokens(part, names))
+                        .collect::<Vec<_>>()
+                        .join(":"),
+                    None if token.contains(".svc") => {
+                        let mut labels: Vec<String> = token.split('.').map(Into::into).collect();
+                        for i in 0..labels.len().saturating_sub(1) {
+                            if labels[i + 1] == "svc" && self.namespaces.contains(&labels[i]) {
+                                labels[i] = self.pseudonym(Category::Namespace, &labels[i]);
+                            }
+                        }
+                        labels.join(".")
+                    }
+                    None => self.replace_node_names(token),
+                }
+            })
+            .into_owned()
+    }
+
+    /// Replaces node names within the token, which start it or follow a "-" or ".", and end
+    /// it or are followed by a ".". Covers names like "kube-apiserver-worker-1" of static
+    /// pods, and host names like "worker-1.ec2.internal".
+    fn replace_node_names(&self, token: &str) -> String {
+        let starts = std::iter::once(0).chain(token.match_indices(['-', '.']).map(|(i, _)| i + 1));
+        for start in starts {
+            let rest = &token[start..];
+            let ends = rest.match_indices('.').map(|(i, _)| i).chain([rest.len()]);
+            // The longest name, as node names may be dotted host names.
+            let Some(end) = ends.filter(|end| self.nodes.contains(&rest[..*end])).last() else {
+                continue;
+            };
+            return format!(
+                "{}{}{}",
+                &token[..start],
+                self.pseudonym(Category::Node, &rest[..end]),
+                self.replace_node_names(&rest[end..])
+            );
+        }
+        token.to_string()
+    }
+
+    fn categorize(&self, token: &str, names: bool) -> Option<Category> {
+        if let Ok(ip) = Ipv4Addr::from_str(token) {
+            return is_pseudonymized(ip).then_some(Category::Ip);
+        }
+        if let Ok(ip) = Ipv6Addr::from_str(token) {
+            return (!ip.is_loopback() && !ip.is_unspecified()).then_some(Category::Ip);
+        }
+
+        if self.nodes.contains(token) {
+            return Some(Category::Node);
+        }
+        if names && self.namespaces.contains(token) {
+            return Some(Category::Namespace);
+        }
+
+        let lowercase = token.to_lowercase();
+        self.domains
+            .iter()
+            .any(|domain| lowercase == *domain || lowercase.ends_with(&format!(".{domain}")))
+            .then_some(Category::Dns)
+    }
+
+    fn pseudonym(&self, category: Category, value: &str) -> String {
+        self.mapping
+            .lock()
+            .expect("mapping lock")
+            .entry(category)
+            .or_default()
+            .entry(value.to_string())
+            .or_insert_with(|| self.derive(category, value))
+            .clone()
+    }
+
+    fn derive(&self, category: Category, value: &str) -> String {
+        if category == Category::Ip
+            && let Ok(ip) = Ipv4Addr::from_str(value)
+        {
+            return self.permute(ip).to_string();
+        }
+
+        let digest = self.hmac(&[category.to_string().as_bytes(), value.as_bytes()]);
+        match category {
+            Category::Ip => {
+                let mut octets = [0xfd; 16];
+                octets[1..].copy_from_slice(&digest[..15]);
+                Ipv6Addr::from(octets).to_string()
+            }
+            Category::Dns => format!("host-{}.example", hex::encode(&digest[..8])),
+            Category::Namespace => format!("ns-{}", hex::encode(&digest[..8])),
+            Category::Node => format!("node-{}", hex::encode(&digest[..8])),
+        }
+    }
+
+    /// Maps the address with a keyed Feistel network over the IPv4 address space. Results
+    /// which are not pseudonymized themselves, like loopback addresses, are permuted again,
+    /// so distinct addresses always get distinct pseudonyms.
+    fn permute(&self, ip: Ipv4Addr) -> Ipv4Addr {
+        let mut value = u32::from(ip);
+        loop {
+            let (mut left, mut right) = ((value >> 16) as u16, value as u16);
+            for round in 0..FEISTEL_ROUNDS {
+                let digest = self.hmac(&[b"ip", &[round], &right.to_be_bytes()]);
+                (left, right) = (right, left ^ u16::from_be_bytes([digest[0], digest[1]]));
+            }
+            value = (u32::from(left) << 16) | u32::from(right);
+            if is_pseudonymized(Ipv4Addr::from(value)) {
+                return Ipv4Addr::from(value);
+            }
+        }
+    }
+
+    /// HMAC of the parts, separated by a NUL byte.
+    fn hmac(&self, parts: &[&[u8]]) -> Vec<u8> {
+        let mut mac = self.mac.clone();
+        for (i, part) in parts.iter().enumerate() {
+            if i > 0 {
+                mac.update(b"\0");
+            }
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// Returns true for addresses replaced by pseudonyms, all except loopback, unspecified and
+/// broadcast addresses.
+fn is_pseudonymized(ip: Ipv4Addr) -> bool {
+    !ip.is_loopback() && !ip.is_unspecified() && !ip.is_broadcast()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use crate::gather::representation::ArchivePath;
+
+    use super::*;
+
+    fn pseudonymizer(key: &str, mapping_file: Option<MappingFile>) -> Pseudonymizer {
+        Pseudonymizer::new(
+            key,
+            vec!["customer".to_string(), "default".to_string()],
+            vec!["worker-1".to_string()],
+            vec!["corp.example.com".to_string()],
+            mapping_file,
+        )
+    }
+
+    #[test]
+    fn test_pseudonymize_consistent() {
+        let repr = Representation::new()
+            .with_path(ArchivePath::Namespaced(
+                "namespaces/customer/v1/pod/web.yaml".into(),
+            ))
+            .with_data("namespace: customer\nnodeName: worker-1\nhostIP: 192.168.1.10\nhost: db.corp.example.com\nsvc: web.customer.svc\nother: default customers 127.0.0.1");
+
+        let first = pseudonymizer("key", None).apply(&repr);
+        let second = pseudonymizer("key", None).apply(&repr);
+        assert_eq!(first.data(), second.data());
+        assert_eq!(first.path(), second.path());
+
+        let data = first.data();
+        assert!(!data.contains("customer\n"));
+        assert!(!data.contains("web.customer.svc"));
+        assert!(!data.contains("worker-1"));
+        assert!(!data.contains("192.168.1.10"));
+        assert!(!data.contains("corp.example.com"));
+        assert!(data.contains("default customers 127.0.0.1"));
+
+        let ns = data
+            .lines()
+            .next()
+            .unwrap()
+            .trim_start_matches("namespace: ");
+        assert!(ns.starts_with("ns-"));
+        assert!(data.contains(&format!("web.{ns}.svc")));
+        assert_eq!(
+            String::try_from(first.path()).unwrap(),
+            format!("namespaces/{ns}/v1/pod/web.yaml")
+        );
+
+        let other = pseudonymizer("other", None).apply(&repr);
+        assert_ne!(first.data(), other.data());
+    }
+
+    #[test]
+    fn test_pseudonymize_api_groups() {
+        let pseudonymizer = Pseudonymizer::new(
+            "key",
+            vec!["monitoring".to_string(), "cert-manager".to_string()],
+            vec![],
+            vec![],
+            None,
+        );
+        let object = serde_json::json!({
+            "apiVersion": "monitoring.coreos.com/v1",
+            "kind": "ServiceMonitor",
+            "metadata": {
+                "name": "web",
+                "namespace": "monitoring",
+                "ownerReferences": [{"apiVersion": "cert-manager.io/v1", "kind": "Issuer", "name": "ca"}],
+            },
+            "spec": {"namespaceSelector": {"matchNames": ["monitoring"]}, "group": "monitoring"},
+        });
+        let repr = Representation::new()
+            .with_path(ArchivePath::Namespaced(
+                "namespaces/monitoring/monitoring.coreos.com-v1/servicemonitor/web.yaml".into(),
+            ))
+            .with_data(&serde_saphyr::to_string(&object).unwrap());
+
+        let pseudonymized = pseudonymizer.apply(&repr);
+        let value: Value = serde_saphyr::from_str(pseudonymized.data()).unwrap();
+        let ns = value["metadata"]["namespace"].as_str().unwrap();
+        assert!(ns.starts_with("ns-"));
+        assert_eq!(value["apiVersion"], "monitoring.coreos.com/v1");
+        assert_eq!(
+            value["metadata"]["ownerReferences"][0]["apiVersion"],
+            "cert-manager.io/v1"
+        );
+        assert_eq!(value["spec"]["group"], "monitoring");
+        assert_eq!(
+            String::try_from(pseudonymized.path()).unwrap(),
+            format!("namespaces/{ns}/monitoring.coreos.com-v1/servicemonitor/web.yaml")
+        );
+
+        let namespace = Representation::new()
+            .with_path(ArchivePath::Cluster(
+                "cluster/v1/namespace/monitoring.yaml".into(),
+            ))
+            .with_data("apiVersion: v1\nkind: Namespace\nmetadata:\n  name: monitoring\n");
+        let pseudonymized = pseudonymizer.apply(&namespace);
+        assert_eq!(
+            String::try_from(pseudonymized.path()).unwrap(),
+            format!("cluster/v1/namespace/{ns}.yaml")
+        );
+        assert!(pseudonymized.data().contains(&format!("name: {ns}")));
+
+        let log = pseudonymizer
+            .apply(&Representation::new().with_data("issuer cert-manager.io/v1 in cert-manager"));
+        assert!(log.data().starts_with("issuer cert-manager.io/v1 in ns-"));
+
+        let apis = Representation::new()
+            .with_path(ArchivePath::Custom("apis.json".into()))
+            .with_data(r#"{"name": "monitoring"}"#);
+        assert_eq!(pseudonymizer.apply(&apis).data(), apis.data());
+    }
+
+    #[test]
+    fn test_pseudonymize_ip_collisions() {
+        let reversed = pseudonymizer("key", None);
+        let pseudonymizer = pseudonymizer("key", None);
+        let ips: Vec<String> = (0..16)
+            .flat_map(|c| (0..=255).map(move |d| format!("172.16.{c}.{d}")))
+            .collect();
+        let pseudonyms: Vec<String> = ips.iter().map(|ip| pseudonymizer.apply_text(ip)).collect();
+        assert_eq!(pseudonyms.iter().collect::<BTreeSet<_>>().len(), ips.len());
+        assert!(
+            pseudonyms
+                .iter()
+                .all(|ip| is_pseudonymized(Ipv4Addr::from_str(ip).unwrap()))
+        );
+
+        // Pseudonyms don't depend on the order the addresses are seen in.
+        for (ip, pseudonym) in ips.iter().zip(&pseudonyms).rev() {
+            assert_eq!(&reversed.apply_text(ip), pseudonym);
+        }
+    }
+
+    #[test]
+    fn test_pseudonymize_events() {
+        let pseudonymizer = pseudonymizer("key", None);
+        let node = pseudonymizer.apply_text("worker-1");
+        let object = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Event",
+            "metadata": {"name": "web.17f", "namespace": "customer"},
+            "message": "Successfully assigned customer/web to worker-1",
+            "source": {"component": "kubelet", "host": "worker-1"},
+            "reportingInstance": "worker-1",
+            "involvedObject": {"kind": "Node", "name": "worker-1.ec2.internal"},
+        });
+        let repr = Representation::new()
+            .with_path(ArchivePath::Namespaced(
+                "namespaces/customer/v1/event/web.17f.yaml".into(),
+            ))
+            .with_data(&serde_saphyr::to_string(&object).unwrap());
+
+        let pseudonymized = pseudonymizer.apply(&repr);
+        assert!(!pseudonymized.data().contains("worker-1"));
+        let value: Value = serde_saphyr::from_str(pseudonymized.data()).unwrap();
+        assert_eq!(
+            value["message"],
+            format!("Successfully assigned customer/web to {node}")
+        );
+        assert_eq!(value["source"]["host"], node.as_str());
+        assert_eq!(value["reportingInstance"], node.as_str());
+        assert_eq!(
+            value["involvedObject"]["name"],
+            format!("{node}.ec2.internal")
+        );
+
+        let status = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Node",
+            "metadata": {"name": "worker-1"},
+            "status": {"addresses": [
+                {"type": "Hostname", "address": "worker-1"},
+                {"type": "InternalIP", "address": "10.0.0.5"},
+            ]},
+        });
+        let repr = Representation::new()
+            .with_path(ArchivePath::Cluster("cluster/v1/node/worker-1.yaml".into()))
+            .with_data(&serde_saphyr::to_string(&status).unwrap());
+        let pseudonymized = pseudonymizer.apply(&repr);
+        assert!(!pseudonymized.data().contains("worker-1"));
+        assert!(!pseudonymized.data().contains("10.0.0.5"));
+        assert_eq!(
+            String::try_from(pseudonymized.path()).unwrap(),
+            format!("cluster/v1/node/{node}.yaml")
+        );
+    }
+
+    #[test]
+    fn test_pseudonymize_static_pods() {
+        let pseudonymizer = pseudonymizer("key", None);
+        let node = pseudonymizer.apply_text("worker-1");
+        let repr = Representation::new()
+            .with_path(ArchivePath::Namespaced(
+                "namespaces/kube-system/v1/pod/kube-apiserver-worker-1.yaml".into(),
+            ))
+            .with_data(
+                "apiVersion: v1\nkind: Pod\nmetadata:\n  name: kube-apiserver-worker-1\n  namespace: kube-system\n",
+            );
+
+        let pseudonymized = pseudonymizer.apply(&repr);
+        assert!(
+            pseudonymized
+                .data()
+                .contains(&format!("name: kube-apiserver-{node}"))
+        );
+        assert_eq!(
+            String::try_from(pseudonymized.path()).unwrap(),
+            format!("namespaces/kube-system/v1/pod/kube-apiserver-{node}.yaml")
+        );
+
+        let log = pseudonymizer.apply_text("etcd-worker-1 and worker-10 on worker-1");
+        assert_eq!(log, format!("etcd-{node} and worker-10 on {node}"));
+    }
+
+    #[test]
+    fn test_pseudonymize_ipv6() {
+        let pseudonymizer = pseudonymizer("key", None);
+        let data = pseudonymizer.apply_text(
+            "pod fd00:10:244::5 via 2001:db8::1, local ::1 at 12:34:56 in serde::de a:b:customer",
+        );
+
+        assert!(!data.contains("fd00:10:244::5"));
+        assert!(!data.contains("2001:db8::1"));
+        assert!(data.contains("local ::1 at 12:34:56 in serde::de a:b:ns-"));
+        let ip = data.split_whitespace().nth(1).unwrap();
+        assert!(Ipv6Addr::from_str(ip).unwrap().octets()[0] == 0xfd);
+        assert_eq!(
+            data,
+            pseudonymizer.apply_text(
+                "pod fd00:10:244::5 via 2001:db8::1, local ::1 at 12:34:56 in serde::de a:b:customer"
+            )
+        );
+    }
+
+    #[test]
+    fn test_disabled() {
+        let repr = Representation::new().with_data("worker-1 10.0.0.1");
+        assert_eq!(Pseudonymizer::default().apply(&repr).data(), repr.data());
+    }
+
+    #[test]
+    fn test_save_mapping() {
+        let tmp_dir = TempDir::new().unwrap();
+        let identity = age::x25519::Identity::generate();
+        let path = tmp_dir.path().join("mapping.age");
+        let pseudonymizer = pseudonymizer(
+            "key",
+            Some(MappingFile {
+                path: path.clone(),
+                recipient: identity.to_public(),
+            }),
+        );
+
+        let pseudonymized = pseudonymizer.apply(&Representation::new().with_data("worker-1"));
+        pseudonymizer.save_mapping().unwrap();
+
+        let mapping: BTreeMap<Category, BTreeMap<String, String>> =
+            serde_json::from_slice(&age::decrypt(&identity, &fs::read(path).unwrap()).unwrap())
+                .unwrap();
+        assert_eq!(mapping[&Category::Node]["worker-1"], pseudonymized.data());
+    }
+}
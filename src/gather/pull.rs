@@ -0,0 +1,250 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, bail};
+use clap::Parser;
+use futures::{StreamExt as _, TryStreamExt as _, stream};
+use oci_client::{Client, Reference};
+
+use crate::cli::{DecryptionSettings, OCISettings};
+
+use super::{
+    blob_cache::CacheSettings,
+    representation::{ArchivePath, Representation},
+    storage::{Descriptor, OCIState},
+    writer::{Archive, Encoding, Writer, WriterHandle},
+};
+
+#[derive(Parser, Clone, Default)]
+pub struct Puller {
+    /// OCI source of the archive to pull.
+    #[clap(flatten)]
+    oci: OCISettings,
+
+    /// Decryption options for encrypted archives.
+    #[clap(flatten)]
+    decryption: DecryptionSettings,
+
+    /// Disk cache for OCI blobs, shared with the OCI server.
+    #[clap(flatten)]
+    cache: CacheSettings,
+
+    /// The output file path.
+    /// Defaults to a new archive with name "crust-gather".
+    /// Use "-" to stream a gzip, zstd or xz compressed tar to stdout.
+    ///
+    /// Example:
+    ///     --file=./artifacts
+    #[arg(short, long, value_name = "PATH")]
+    file: Option<Archive>,
+
+    /// Encoding for the output file.
+    /// By default there is no encoding and data is written to the filesystem.
+    /// The available options are "path", "gzip", "zstd", "xz" and "zip".
+    ///
+    /// Example:
+    ///     --encoding=gzip
+    #[arg(short, long, value_name = "ENCODING",
+        value_parser = |arg: &str| -> anyhow::Result<Encoding> {Encoding::try_from(arg)})]
+    encoding: Option<Encoding>,
+}
+
+impl Puller {
+    /// Returns true when the archive is streamed to stdout with "--file=-".
+    pub fn streams_stdout(&self) -> bool {
+        self.file.as_ref().is_some_and(Archive::is_stdout)
+    }
+
+    /// Pulls all files of the OCI archive and writes them into the output archive.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let Some(reference) = self.oci.reference()? else {
+            bail!("missing reference");
+        };
+        let reference: Reference = reference.clone().into();
+        let archive = self.file.clone().unwrap_or_default();
+        let encoding = self.encoding.clone().unwrap_or(Encoding::Path);
+        if matches!(encoding, Encoding::Path) && archive.path().exists() {
+            bail!("archive {} already exists", archive.path().display());
+        }
+
+        let state = OCIState::pull(
+            Client::new(self.oci.to_client_config()),
+            reference.clone(),
+            self.oci.to_auth().await,
+            self.decryption.to_decryption()?,
+            self.cache.to_cache()?,
+        )
+        .await?;
+        let layers = files_by_layer(&state);
+        let root = archive_root(&state)?;
+
+        let writer: WriterHandle =
+            Writer::new(&archive, &encoding, None, None, self.oci.buffer_size)
+                .await?
+                .into();
+
+        // Every layer is pulled once, and split into the files packed into it.
+        stream::iter(layers.into_values())
+            .map(|paths| {
+                let (state, writer, root) = (&state, writer.clone(), root.clone());
+                async move {
+                    let Some(first) = paths.first() else {
+                        return Ok(());
+                    };
+                    let layer = state.pull_layer(first).await?;
+                    for path in paths {
+                        let data = String::from_utf8(state.layer_file(&layer, &path)?.to_vec())?;
+                        let relative = path.strip_prefix(&root)?.to_path_buf();
+                        tracing::debug!(path = %relative.display(), "Pulled file");
+                        writer
+                            .store(
+                                Representation::new()
+                                    .with_path(ArchivePath::Custom(relative))
+                                    .with_data(&data),
+                            )
+                            .await
+                            .with_context(|| format!("unable to write {}", path.display()))?;
+                    }
+                    anyhow::Ok(())
+                }
+            })
+            .buffer_unordered(self.oci.buffer_size)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        writer.finish().await?;
+        tracing::info!(archive = %archive.path().display(), "Pulled {reference}");
+        Ok(())
+    }
+}
+
+/// Groups archive files by the digest of the layer holding them. Packed layers and the
+/// index are not archive files, they are split into the files referencing them.
+pub fn files_by_layer(state: &OCIState) -> BTreeMap<String, Vec<PathBuf>> {
+    let packed: HashSet<&str> = state
+        .index
+        .values()
+        .filter_map(|descriptor| match descriptor {
+            Descriptor::ListOciDescriptor(layer, ..) => Some(layer.digest.as_str()),
+            Descriptor::OciDescriptor(_) => None,
+        })
+        .collect();
+
+    let mut layers: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for (path, descriptor) in state.index.iter() {
+        let is_file = match descriptor {
+            Descriptor::ListOciDescriptor(..) => true,
+            Descriptor::OciDescriptor(layer) => {
+                !packed.contains(layer.digest.as_str()) && path != Path::new("index.yaml")
+            }
+        };
+        if is_file {
+            layers
+                .entry(descriptor.digest.clone())
+                .or_default()
+                .push(path.clone());
+        }
+    }
+
+    layers
+}
+
+/// Returns the archive directory of the snapshot, which holds the "version.yaml" file.
+fn archive_root(state: &OCIState) -> anyhow::Result<PathBuf> {
+    state
+        .index
+        .keys()
+        .find(|path| path.file_name().is_some_and(|name| name == "version.yaml"))
+        .and_then(|path| path.parent())
+        .map(Path::to_path_buf)
+        .context("OCI image is not a crust-gather archive, it contains no version.yaml")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use oci_client::{Client, manifest::OciDescriptor, secrets::RegistryAuth};
+
+    use super::{archive_root, files_by_layer};
+    use crate::gather::storage::{Descriptor, OCIState};
+
+    #[test]
+    fn test_files_by_layer() {
+        let layer = |digest: &str| OciDescriptor {
+            digest: digest.to_string(),
+            ..Default::default()
+        };
+        let index: HashMap<PathBuf, Descriptor> = [
+            ("index.yaml", Descriptor::OciDescriptor(layer("index"))),
+            (
+                "snapshot/.layers/cluster-0.yaml",
+                Descriptor::OciDescriptor(layer("pack")),
+            ),
+            (
+                "snapshot/cluster/v1/node/a.yaml",
+                Descriptor::ListOciDescriptor(layer("pack"), 0, 1),
+            ),
+            (
+                "snapshot/cluster/v1/node/b.yaml",
+                Descriptor::ListOciDescriptor(layer("pack"), 1, 2),
+            ),
+            (
+                "snapshot/version.yaml",
+                Descriptor::OciDescriptor(layer("version")),
+            ),
+        ]
+        .into_iter()
+        .map(|(path, descriptor)| (PathBuf::from(path), descriptor))
+        .collect();
+        let state = OCIState {
+            reference: "localhost:5000/crust-gather:test".parse().unwrap(),
+            auth: RegistryAuth::Anonymous,
+            client: Client::default(),
+            config: Default::default(),
+            index: Arc::new(index),
+            decryption: None,
+            cache: None,
+        };
+
+        let mut layers = files_by_layer(&state);
+        layers.get_mut("pack").unwrap().sort();
+        assert_eq!(
+            layers,
+            [
+                (
+                    "pack".to_string(),
+                    vec![
+                        PathBuf::from("snapshot/cluster/v1/node/a.yaml"),
+                        PathBuf::from("snapshot/cluster/v1/node/b.yaml"),
+                    ]
+                ),
+                (
+                    "version".to_string(),
+                    vec![PathBuf::from("snapshot/version.yaml")]
+                ),
+            ]
+            .into()
+        );
+        assert_eq!(archive_root(&state).unwrap(), PathBuf::from("snapshot"));
+
+        // Files are split from their pulled layer.
+        let file = |layer: &'static [u8], path: &str| state.layer_file(layer, Path::new(path));
+        assert_eq!(
+            file(b"ab", "snapshot/cluster/v1/node/a.yaml").unwrap(),
+            b"a"
+        );
+        assert_eq!(
+            file(b"ab", "snapshot/cluster/v1/node/b.yaml").unwrap(),
+            b"b"
+        );
+        assert_eq!(file(b"{}", "snapshot/version.yaml").unwrap(), b"{}");
+        assert!(file(b"a", "snapshot/cluster/v1/node/b.yaml").is_err());
+    }
+}
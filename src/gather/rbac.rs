@@ -0,0 +1,671 @@
+use std::collections::HashMap;
+
+use actix_web::http::header::HeaderMap;
+use k8s_openapi::api::{
+    authorization::v1::{
+        NonResourceAttributes, NonResourceRule, ResourceAttributes, ResourceRule,
+        SubjectAccessReviewStatus, SubjectRulesReviewStatus,
+    },
+    rbac::v1::{ClusterRole, ClusterRoleBinding, PolicyRule, Role, RoleBinding, RoleRef, Subject},
+};
+use kube::{
+    ResourceExt as _,
+    core::{Selector, SelectorExt as _},
+};
+
+use super::tls::{CLIENT_GROUP, CLIENT_USER, ClientCertificate};
+
+/// Members of the group are allowed everything, like the API server's privileged group
+/// authorizer does before RBAC is consulted.
+const PRIVILEGED_GROUP: &str = "system:masters";
+
+const IMPERSONATE_USER: &str = "Impersonate-User";
+const IMPERSONATE_GROUP: &str = "Impersonate-Group";
+
+const ALL_AUTHENTICATED: &str = "system:authenticated";
+const ALL_UNAUTHENTICATED: &str = "system:unauthenticated";
+const ANONYMOUS: &str = "system:anonymous";
+const SERVICE_ACCOUNT_PREFIX: &str = "system:serviceaccount:";
+
+/// User a review is evaluated for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserInfo {
+    pub name: String,
+    pub groups: Vec<String>,
+}
+
+impl UserInfo {
+    /// Returns the authenticated user: the subject of the verified client certificate, or
+    /// the privileged user of the generated kubeconfig for bearer token and unauthenticated
+    /// requests.
+    pub fn requester(certificate: Option<&ClientCertificate>) -> Self {
+        let Some(certificate) = certificate else {
+            return Self {
+                name: CLIENT_USER.into(),
+                groups: vec![CLIENT_GROUP.into(), ALL_AUTHENTICATED.into()],
+            };
+        };
+
+        let mut groups = certificate.groups.clone();
+        if !groups.iter().any(|group| group == ALL_AUTHENTICATED) {
+            groups.push(ALL_AUTHENTICATED.into());
+        }
+        Self {
+            name: certificate.user.clone(),
+            groups,
+        }
+    }
+
+    pub fn is_privileged(&self) -> bool {
+        self.groups.iter().any(|group| group == PRIVILEGED_GROUP)
+    }
+}
+
+/// User and groups requested with "kubectl --as" and "--as-group".
+#[derive(Clone, Debug, PartialEq)]
+pub struct Impersonation {
+    pub user: String,
+    pub groups: Vec<String>,
+}
+
+impl Impersonation {
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let user = headers.get(IMPERSONATE_USER)?.to_str().ok()?;
+        Some(Self {
+            user: user.to_string(),
+            groups: headers
+                .get_all(IMPERSONATE_GROUP)
+                .filter_map(|group| group.to_str().ok())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+
+    /// Returns the impersonated user with the groups the API server adds: the service
+    /// account groups when no group is given, and "system:authenticated" or
+    /// "system:unauthenticated".
+    pub fn user_info(&self) -> UserInfo {
+        let mut groups = self.groups.clone();
+        if groups.is_empty()
+            && let Some((namespace, _)) = self.service_account()
+        {
+            groups.push("system:serviceaccounts".into());
+            groups.push(format!("system:serviceaccounts:{namespace}"));
+        }
+
+        let implicit = match self.user.as_str() {
+            ANONYMOUS => ALL_UNAUTHENTICATED,
+            _ => ALL_AUTHENTICATED,
+        };
+        if !groups
+            .iter()
+            .any(|group| group == implicit || group == ALL_UNAUTHENTICATED)
+        {
+            groups.push(implicit.into());
+        }
+
+        UserInfo {
+            name: self.user.clone(),
+            groups,
+        }
+    }
+
+    /// Returns the namespace and name of an impersonated service account.
+    fn service_account(&self) -> Option<(&str, &str)> {
+        self.user
+            .strip_prefix(SERVICE_ACCOUNT_PREFIX)
+            .and_then(|account| account.split_once(':'))
+    }
+}
+
+/// Role or cluster role binding.
+struct Binding {
+    kind: &'static str,
+    name: String,
+    namespace: Option<String>,
+    role_ref: RoleRef,
+    subjects: Vec<Subject>,
+}
+
+impl Binding {
+    fn applies_to(&self, subject: &Subject, user: &UserInfo) -> bool {
+        match subject.kind.as_str() {
+            "User" => subject.name == user.name,
+            "Group" => user.groups.contains(&subject.name),
+            "ServiceAccount" => match subject.namespace.as_ref().or(self.namespace.as_ref()) {
+                Some(namespace) if !namespace.is_empty() => {
+                    user.name == format!("{SERVICE_ACCOUNT_PREFIX}{namespace}:{}", subject.name)
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Describes the binding like the RBAC authorizer does in its reasons.
+    fn describe(&self, subject: &Subject) -> String {
+        let name = match &self.namespace {
+            Some(namespace) => format!("{}/{namespace}", self.name),
+            None => self.name.clone(),
+        };
+        let subject_name = match (subject.kind.as_str(), &subject.namespace, &self.namespace) {
+            ("ServiceAccount", Some(namespace), _) | ("ServiceAccount", None, Some(namespace)) => {
+                format!("{}/{namespace}", subject.name)
+            }
+            _ => subject.name.clone(),
+        };
+        format!(
+            "{} {name:?} of {} {:?} to {} {subject_name:?}",
+            self.kind, self.role_ref.kind, self.role_ref.name, subject.kind
+        )
+    }
+}
+
+/// RBAC authorizer over the Roles, ClusterRoles and bindings collected in a snapshot.
+#[derive(Default)]
+pub struct Authorizer {
+    cluster_roles: HashMap<String, Vec<PolicyRule>>,
+    roles: HashMap<(String, String), Vec<PolicyRule>>,
+    bindings: Vec<Binding>,
+}
+
+impl Authorizer {
+    /// Rules of aggregated ClusterRoles are combined from the ClusterRoles matching their
+    /// selectors, in case the snapshot was collected before the controller updated them.
+    pub fn new(
+        cluster_roles: Vec<ClusterRole>,
+        roles: Vec<Role>,
+        cluster_role_bindings: Vec<ClusterRoleBinding>,
+        role_bindings: Vec<RoleBinding>,
+    ) -> Self {
+        let aggregated = cluster_roles
+            .iter()
+            .map(|role| {
+                let mut rules = role.rules.clone().unwrap_or_default();
+                let selectors = role
+                    .aggregation_rule
+                    .iter()
+                    .flat_map(|aggregation| aggregation.cluster_role_selectors.iter().flatten())
+                    .filter_map(|selector| Selector::try_from(selector.clone()).ok());
+                for selector in selectors {
+                    for other in &cluster_roles {
+                        if other.name_any() == role.name_any() || !selector.matches(other.labels())
+                        {
+                            continue;
+                        }
+                        for rule in other.rules.iter().flatten() {
+                            if !rules.contains(rule) {
+                                rules.push(rule.clone());
+                            }
+                        }
+                    }
+                }
+                (role.name_any(), rules)
+            })
+            .collect();
+
+        let roles = roles
+            .into_iter()
+            .map(|role| {
+                let key = (role.namespace().unwrap_or_default(), role.name_any());
+                (key, role.rules.unwrap_or_default())
+            })
+            .collect();
+
+        let bindings = cluster_role_bindings
+            .into_iter()
+            .map(|binding| Binding {
+                kind: "ClusterRoleBinding",
+                name: binding.name_any(),
+                namespace: None,
+                role_ref: binding.role_ref,
+                subjects: binding.subjects.unwrap_or_default(),
+            })
+            .chain(role_bindings.into_iter().map(|binding| Binding {
+                kind: "RoleBinding",
+                name: binding.name_any(),
+                namespace: binding.namespace(),
+                role_ref: binding.role_ref,
+                subjects: binding.subjects.unwrap_or_default(),
+            }))
+            .collect();
+
+        Self {
+            cluster_roles: aggregated,
+            roles,
+            bindings,
+        }
+    }
+
+    /// Returns the rules bound to the user in the namespace, with the binding granting
+    /// them. Cluster-scoped requests have an empty namespace and only get ClusterRoleBindings.
+    fn bound_rules<'a>(
+        &'a self,
+        user: &'a UserInfo,
+        namespace: &'a str,
+    ) -> impl Iterator<Item = (&'a Binding, &'a Subject, &'a [PolicyRule])> {
+        self.bindings
+            .iter()
+            .filter(move |binding| {
+                binding
+                    .namespace
+                    .as_deref()
+                    .is_none_or(|binding_namespace| binding_namespace == namespace)
+            })
+            .filter_map(move |binding| {
+                let subject = binding
+                    .subjects
+                    .iter()
+                    .find(|subject| binding.applies_to(subject, user))?;
+                let rules = match (binding.role_ref.kind.as_str(), &binding.namespace) {
+                    ("ClusterRole", _) => self.cluster_roles.get(&binding.role_ref.name),
+                    ("Role", Some(namespace)) => self
+                        .roles
+                        .get(&(namespace.clone(), binding.role_ref.name.clone())),
+                    _ => None,
+                }?;
+                Some((binding, subject, rules.as_slice()))
+            })
+    }
+
+    /// Evaluates a SubjectAccessReview for the user.
+    pub fn review(
+        &self,
+        user: &UserInfo,
+        resource: Option<&ResourceAttributes>,
+        non_resource: Option<&NonResourceAttributes>,
+    ) -> SubjectAccessReviewStatus {
+        if user.is_privileged() {
+            return SubjectAccessReviewStatus {
+                allowed: true,
+                ..Default::default()
+            };
+        }
+
+        let namespace = resource
+            .and_then(|resource| resource.namespace.as_deref())
+            .unwrap_or_default();
+        let reason = self
+            .bound_rules(user, namespace)
+            .find(|(_, _, rules)| {
+                rules.iter().any(|rule| match (resource, non_resource) {
+                    (Some(resource), _) => resource_allows(rule, resource),
+                    (None, Some(non_resource)) => non_resource_allows(rule, non_resource),
+                    (None, None) => false,
+                })
+            })
+            .map(|(binding, subject, _)| format!("RBAC: allowed by {}", binding.describe(subject)));
+
+        SubjectAccessReviewStatus {
+            allowed: reason.is_some(),
+            reason,
+            ..Default::default()
+        }
+    }
+
+    /// Checks the "impersonate" permissions the API server requires for impersonation:
+    /// on the user or service account, and on every requested group.
+    pub fn can_impersonate(&self, requester: &UserInfo, impersonation: &Impersonation) -> bool {
+        let attributes = |resource: &str, name: &str, namespace: Option<&str>| ResourceAttributes {
+            verb: Some("impersonate".into()),
+            group: Some(String::new()),
+            resource: Some(resource.into()),
+            name: Some(name.into()),
+            namespace: namespace.map(Into::into),
+            ..Default::default()
+        };
+        let user = match impersonation.service_account() {
+            Some((namespace, name)) => attributes("serviceaccounts", name, Some(namespace)),
+            None => attributes("users", &impersonation.user, None),
+        };
+
+        std::iter::once(user)
+            .chain(
+                impersonation
+                    .groups
+                    .iter()
+                    .map(|group| attributes("groups", group, None)),
+            )
+            .all(|attributes| self.review(requester, Some(&attributes), None).allowed)
+    }
+
+    /// Lists the rules of the user in the namespace for a SelfSubjectRulesReview.
+    pub fn rules(&self, user: &UserInfo, namespace: &str) -> SubjectRulesReviewStatus {
+        let mut status = SubjectRulesReviewStatus::default();
+        if user.is_privileged() {
+            status.resource_rules.push(ResourceRule {
+                verbs: vec!["*".into()],
+                api_groups: Some(vec!["*".into()]),
+                resources: Some(vec!["*".into()]),
+                ..Default::default()
+            });
+            status.non_resource_rules.push(NonResourceRule {
+                verbs: vec!["*".into()],
+                non_resource_urls: Some(vec!["*".into()]),
+            });
+            return status;
+        }
+
+        for (_, _, rules) in self.bound_rules(user, namespace) {
+            for rule in rules {
+                if rule.resources.as_ref().is_some_and(|r| !r.is_empty()) {
+                    status.resource_rules.push(ResourceRule {
+                        verbs: rule.verbs.clone(),
+                        api_groups: rule.api_groups.clone(),
+                        resources: rule.resources.clone(),
+                        resource_names: rule.resource_names.clone(),
+                    });
+                }
+                if rule
+                    .non_resource_urls
+                    .as_ref()
+                    .is_some_and(|u| !u.is_empty())
+                {
+                    status.non_resource_rules.push(NonResourceRule {
+                        verbs: rule.verbs.clone(),
+                        non_resource_urls: rule.non_resource_urls.clone(),
+                    });
+                }
+            }
+        }
+        status
+    }
+}
+
+fn matches(values: &[String], value: &str) -> bool {
+    values.iter().any(|v| v == "*" || v == value)
+}
+
+fn resource_allows(rule: &PolicyRule, attributes: &ResourceAttributes) -> bool {
+    let verb = attributes.verb.as_deref().unwrap_or_default();
+    let group = attributes.group.as_deref().unwrap_or_default();
+    let resource = attributes.resource.as_deref().unwrap_or_default();
+    let subresource = attributes.subresource.as_deref().unwrap_or_default();
+    let name = attributes.name.as_deref().unwrap_or_default();
+    let combined = match subresource {
+        "" => resource.to_string(),
+        subresource => format!("{resource}/{subresource}"),
+    };
+
+    matches(&rule.verbs, verb)
+        && matches(rule.api_groups.as_deref().unwrap_or_default(), group)
+        && rule.resources.iter().flatten().any(|rule_resource| {
+            rule_resource == "*"
+                || *rule_resource == combined
+                || (!subresource.is_empty()
+                    && rule_resource.strip_prefix("*/") == Some(subresource))
+        })
+        && match rule.resource_names.as_deref() {
+            None | Some([]) => true,
+            Some(names) => names.iter().any(|n| n == name),
+        }
+}
+
+fn non_resource_allows(rule: &PolicyRule, attributes: &NonResourceAttributes) -> bool {
+    let verb = attributes.verb.as_deref().unwrap_or_default();
+    let path = attributes.path.as_deref().unwrap_or_default();
+
+    matches(&rule.verbs, verb)
+        && rule.non_resource_urls.iter().flatten().any(|url| {
+            url == "*"
+                || url == path
+                || url
+                    .strip_suffix('*')
+                    .is_some_and(|prefix| path.starts_with(prefix))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use k8s_openapi::api::{
+        authorization::v1::{NonResourceAttributes, ResourceAttributes},
+        rbac::v1::{ClusterRole, ClusterRoleBinding, Role, RoleBinding},
+    };
+    use serde::Deserialize;
+
+    use super::{Authorizer, Impersonation, UserInfo};
+    use crate::gather::tls::ClientCertificate;
+
+    const RBAC: &str = r#"
+clusterRoles:
+- metadata: {name: view, labels: {}}
+  aggregationRule:
+    clusterRoleSelectors:
+    - matchLabels: {rbac.example.com/aggregate-to-view: "true"}
+  rules:
+  - {apiGroups: [""], resources: [pods, pods/log], verbs: [get, list]}
+- metadata: {name: view-widgets, labels: {rbac.example.com/aggregate-to-view: "true"}}
+  rules:
+  - {apiGroups: [example.com], resources: [widgets], verbs: [get]}
+- metadata: {name: health}
+  rules:
+  - {nonResourceURLs: ["/healthz/*"], verbs: [get]}
+- metadata: {name: node-reader}
+  rules:
+  - {apiGroups: [""], resources: [nodes], verbs: [get]}
+- metadata: {name: impersonate-jane}
+  rules:
+  - {apiGroups: [""], resources: [users], resourceNames: [jane], verbs: [impersonate]}
+roles:
+- metadata: {name: config, namespace: app}
+  rules:
+  - {apiGroups: [""], resources: [configmaps], resourceNames: [settings], verbs: [update]}
+  - {apiGroups: [""], resources: ["*/status"], verbs: [patch]}
+clusterRoleBindings:
+- metadata: {name: health}
+  roleRef: {apiGroup: rbac.authorization.k8s.io, kind: ClusterRole, name: health}
+  subjects:
+  - {apiGroup: rbac.authorization.k8s.io, kind: Group, name: "system:authenticated"}
+- metadata: {name: nodes}
+  roleRef: {apiGroup: rbac.authorization.k8s.io, kind: ClusterRole, name: node-reader}
+  subjects:
+  - {kind: User, name: jane}
+- metadata: {name: impersonate-jane}
+  roleRef: {apiGroup: rbac.authorization.k8s.io, kind: ClusterRole, name: impersonate-jane}
+  subjects:
+  - {kind: User, name: ops-bot}
+roleBindings:
+- metadata: {name: view, namespace: app}
+  roleRef: {apiGroup: rbac.authorization.k8s.io, kind: ClusterRole, name: view}
+  subjects:
+  - {kind: ServiceAccount, name: deployer}
+- metadata: {name: config, namespace: app}
+  roleRef: {apiGroup: rbac.authorization.k8s.io, kind: Role, name: config}
+  subjects:
+  - {kind: Group, name: "system:serviceaccounts:app"}
+"#;
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Objects {
+        cluster_roles: Vec<ClusterRole>,
+        roles: Vec<Role>,
+        cluster_role_bindings: Vec<ClusterRoleBinding>,
+        role_bindings: Vec<RoleBinding>,
+    }
+
+    fn authorizer() -> Authorizer {
+        let objects: Objects = serde_saphyr::from_str(RBAC).unwrap();
+        Authorizer::new(
+            objects.cluster_roles,
+            objects.roles,
+            objects.cluster_role_bindings,
+            objects.role_bindings,
+        )
+    }
+
+    fn impersonation(user: &str, groups: &[&str]) -> Impersonation {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("impersonate-user"),
+            HeaderValue::from_str(user).unwrap(),
+        );
+        for group in groups {
+            headers.append(
+                HeaderName::from_static("impersonate-group"),
+                HeaderValue::from_str(group).unwrap(),
+            );
+        }
+        Impersonation::from_headers(&headers).unwrap()
+    }
+
+    fn impersonate(user: &str, groups: &[&str]) -> UserInfo {
+        impersonation(user, groups).user_info()
+    }
+
+    /// Reviews "verb group/resource/subresource name" in the namespace.
+    fn can_i(user: &UserInfo, verb: &str, resource: &str, name: &str, namespace: &str) -> bool {
+        let (group, resource) = resource.split_once('/').unwrap_or_default();
+        let (resource, subresource) = match resource.split_once('/') {
+            Some((resource, subresource)) => (resource, Some(subresource.into())),
+            None => (resource, None),
+        };
+        let attributes = ResourceAttributes {
+            verb: Some(verb.into()),
+            group: Some(group.into()),
+            resource: Some(resource.into()),
+            subresource,
+            name: Some(name.into()),
+            namespace: Some(namespace.into()),
+            ..Default::default()
+        };
+        authorizer().review(user, Some(&attributes), None).allowed
+    }
+
+    #[test]
+    fn test_impersonated_user() {
+        let account = impersonate("system:serviceaccount:app:deployer", &[]);
+        assert_eq!(
+            account.groups,
+            [
+                "system:serviceaccounts",
+                "system:serviceaccounts:app",
+                "system:authenticated"
+            ]
+        );
+        assert_eq!(
+            impersonate("system:serviceaccount:app:deployer", &["ops"]).groups,
+            ["ops", "system:authenticated"]
+        );
+        assert_eq!(
+            impersonate("system:anonymous", &[]).groups,
+            ["system:unauthenticated"]
+        );
+
+        assert!(Impersonation::from_headers(&HeaderMap::new()).is_none());
+        let requester = UserInfo::requester(None);
+        assert!(requester.is_privileged());
+        assert!(can_i(&requester, "delete", "/secrets", "", "kube-system"));
+    }
+
+    #[test]
+    fn test_client_certificate_user() {
+        let generated = UserInfo::requester(Some(&ClientCertificate {
+            user: "crust-gather".into(),
+            groups: vec!["system:masters".into()],
+        }));
+        assert!(generated.is_privileged());
+
+        let requester = UserInfo::requester(Some(&ClientCertificate {
+            user: "ops-bot".into(),
+            groups: vec!["ops".into()],
+        }));
+        assert_eq!(requester.name, "ops-bot");
+        assert_eq!(requester.groups, ["ops", "system:authenticated"]);
+        assert!(!requester.is_privileged());
+        assert!(!can_i(&requester, "get", "/nodes", "", ""));
+
+        let authorizer = authorizer();
+        assert!(authorizer.can_impersonate(&requester, &impersonation("jane", &[])));
+        assert!(!authorizer.can_impersonate(&requester, &impersonation("bob", &[])));
+        assert!(
+            !authorizer.can_impersonate(&requester, &impersonation("jane", &["system:masters"]))
+        );
+        assert!(authorizer.can_impersonate(&generated, &impersonation("bob", &["system:masters"])));
+    }
+
+    #[test]
+    fn test_review() {
+        let account = impersonate("system:serviceaccount:app:deployer", &[]);
+        assert!(can_i(&account, "list", "/pods", "", "app"));
+        assert!(can_i(&account, "get", "/pods/log", "web", "app"));
+        assert!(!can_i(&account, "get", "/pods/exec", "web", "app"));
+        assert!(!can_i(&account, "list", "/pods", "", "other"));
+        assert!(!can_i(&account, "list", "/pods", "", ""));
+        assert!(!can_i(&account, "delete", "/pods", "web", "app"));
+
+        // Rules of ClusterRoles aggregated into "view".
+        assert!(can_i(&account, "get", "example.com/widgets", "a", "app"));
+        assert!(!can_i(&account, "get", "/widgets", "a", "app"));
+
+        // Role bound to the service account group, with resource names and subresources.
+        assert!(can_i(&account, "update", "/configmaps", "settings", "app"));
+        assert!(!can_i(&account, "update", "/configmaps", "other", "app"));
+        assert!(can_i(&account, "patch", "/pods/status", "web", "app"));
+        assert!(!can_i(&account, "patch", "/pods", "web", "app"));
+        assert!(!can_i(
+            &account,
+            "patch",
+            "apps/deployments/status",
+            "web",
+            "app"
+        ));
+
+        let jane = impersonate("jane", &[]);
+        assert!(can_i(&jane, "get", "/nodes", "node-1", ""));
+        assert!(!can_i(&jane, "list", "/pods", "", "app"));
+
+        let status = authorizer().review(
+            &jane,
+            None,
+            Some(&NonResourceAttributes {
+                verb: Some("get".into()),
+                path: Some("/healthz/etcd".into()),
+            }),
+        );
+        assert!(status.allowed);
+        assert_eq!(
+            status.reason.as_deref(),
+            Some(
+                r#"RBAC: allowed by ClusterRoleBinding "health" of ClusterRole "health" to Group "system:authenticated""#
+            )
+        );
+
+        let status = authorizer().review(
+            &account,
+            Some(&ResourceAttributes {
+                verb: Some("get".into()),
+                resource: Some("pods".into()),
+                namespace: Some("app".into()),
+                ..Default::default()
+            }),
+            None,
+        );
+        assert_eq!(
+            status.reason.as_deref(),
+            Some(
+                r#"RBAC: allowed by RoleBinding "view/app" of ClusterRole "view" to ServiceAccount "deployer/app""#
+            )
+        );
+    }
+
+    #[test]
+    fn test_rules() {
+        let account = impersonate("system:serviceaccount:app:deployer", &[]);
+        let status = authorizer().rules(&account, "app");
+        let resources: Vec<_> = status
+            .resource_rules
+            .iter()
+            .flat_map(|rule| rule.resources.clone().unwrap_or_default())
+            .collect();
+        assert_eq!(
+            resources,
+            ["pods", "pods/log", "widgets", "configmaps", "*/status"]
+        );
+        assert_eq!(status.non_resource_rules.len(), 1);
+
+        let status = authorizer().rules(&account, "other");
+        assert!(status.resource_rules.is_empty());
+    }
+}
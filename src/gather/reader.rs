@@ -2,7 +2,7 @@ use std::{
     collections::HashMap,
     hash::Hash,
     io::{self, BufRead as _},
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
     sync::{Arc, Mutex},
     time::Duration,
@@ -38,6 +38,7 @@ use crate::{
 
 use super::{
     printers::{AGE_CEL, ColumnDefinition, TablePath, has_predefined_table, predefined_table},
+    rbac::Authorizer,
     representation::{
         ArchivePath, Container, LogGroup, NamespaceName, NamespacedName, TypeMetaGetter,
     },
@@ -49,6 +50,56 @@ const ADDED_PATH: [&str; 3] = ["metadata", "annotations", ADDED_ANNOTATION];
 const UPDATED_PATH: [&str; 3] = ["metadata", "annotations", UPDATED_ANNOTATION];
 const DELETED_PATH: [&str; 3] = ["metadata", "annotations", DELETED_ANNOTATION];
 
+/// Number of patches between two keyframes written during recording.
+pub const KEYFRAME_INTERVAL: usize = 100;
+
+/// `Keyframe` is a full object state stored next to a patch file, allowing the reader
+/// to skip the patches preceding it instead of replaying them from the base object.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Keyframe {
+    /// Number of patch lines applied to produce the object.
+    pub line: usize,
+    /// Timestamp of the last applied patch.
+    pub timestamp: DateTime<Utc>,
+    pub object: serde_json::Value,
+}
+
+/// Returns the latest sync timestamp recorded in the patch operations, if any.
+/// Sync annotations which do not hold a timestamp are skipped.
+pub fn patch_timestamp(patches: &[PatchOperation]) -> Option<DateTime<Utc>> {
+    let mut timestamp: Option<DateTime<Utc>> = None;
+    for p in patches {
+        match p {
+            PatchOperation::Replace(ReplaceOperation { path, value })
+            | PatchOperation::Add(AddOperation { path, value })
+                if *path == PointerBuf::from_tokens(UPDATED_PATH)
+                    || *path == PointerBuf::from_tokens(ADDED_PATH)
+                    || *path == PointerBuf::from_tokens(DELETED_PATH) =>
+            {
+                match serde_json::from_value::<DateTime<Utc>>(value.clone()) {
+                    Ok(sync_timestamp) => timestamp = timestamp.max(Some(sync_timestamp)),
+                    Err(error) => {
+                        tracing::debug!(
+                            "Skipping sync annotation {path} without a timestamp: {error}"
+                        )
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    timestamp
+}
+
+/// Returns the sync timestamp recorded in the object annotations, if any.
+pub fn object_timestamp(object: &serde_json::Value) -> Option<DateTime<Utc>> {
+    [UPDATED_PATH, DELETED_PATH, ADDED_PATH]
+        .into_iter()
+        .find_map(|path| object.pointer(PointerBuf::from_tokens(path).as_str()))
+        .and_then(|value| serde_json::from_value(value.clone()).ok())
+}
+
 #[derive(Deserialize, Clone)]
 pub struct Destination {
     server: String,
@@ -529,13 +580,15 @@ pub struct ArchiveReader {
     archive: Archive,
     named_resources: Arc<NamedResources>,
     buffer_size: usize,
+    storage: Storage,
+    authorizer: Arc<sync::OnceCell<Arc<Authorizer>>>,
 }
 
 impl ArchiveReader {
-    pub async fn new(archive: Archive, storage: &Storage, buffer_size: usize) -> Self {
+    pub async fn new(archive: Archive, storage: Storage, buffer_size: usize) -> Self {
         let mut named_resources = match NamedResources::from_discovery_file(
             archive.join(ArchivePath::Custom("apis.json".into())),
-            storage,
+            &storage,
         )
         .await
         {
@@ -548,7 +601,7 @@ impl ArchiveReader {
 
         match NamedResources::from_discovery_file(
             archive.join(ArchivePath::Custom("api.json".into())),
-            storage,
+            &storage,
         )
         .await
         {
@@ -562,18 +615,38 @@ impl ArchiveReader {
             archive,
             named_resources: Arc::new(named_resources),
             buffer_size: buffer_size.max(1),
+            storage,
+            authorizer: Default::default(),
         }
     }
 
+    /// Returns the storage the archive is read from.
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// Returns the RBAC authorizer of the archive, shared by all clones of the reader
+    /// so it is built once.
+    pub fn authorizer(&self) -> &sync::OnceCell<Arc<Authorizer>> {
+        &self.authorizer
+    }
+
     pub fn join(&self, path: ArchivePath) -> PathBuf {
         self.archive.join(path)
     }
 
-    /// Returns the archive's root path. Used to distinguish archives in hash/eq.
+    /// Returns the archive's root path.
     pub fn path(&self) -> PathBuf {
         self.archive.path()
     }
 
+    /// Returns the archive's root path and the source of OCI or zip storage, which
+    /// distinguishes archives in hash/eq. Archives from different sources may share
+    /// the same root path.
+    pub fn id(&self) -> (PathBuf, Option<String>) {
+        (self.path(), self.storage.source())
+    }
+
     pub fn named_object_from_list(&self, list: List) -> anyhow::Result<NamedObject> {
         let gvr = GroupVersionResource::gvr(
             &list.group.clone().unwrap_or_default(),
@@ -621,20 +694,21 @@ pub struct Reader {
 }
 
 impl Hash for Reader {
-    // Hash on the archive's path so that readers for different archives produce
-    // different cache keys in `#[cached]` functions. Previously this was a no-op,
-    // which made all Readers collide in the cache: the first archive's response
-    // for a given path/list/get would be returned for every other archive too.
+    // Hash on the archive's path and OCI reference so that readers for different
+    // archives produce different cache keys in `#[cached]` functions. Previously
+    // this was a no-op, which made all Readers collide in the cache: the first
+    // archive's response for a given path/list/get would be returned for every
+    // other archive too.
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.archive.path().hash(state);
+        self.archive.id().hash(state);
     }
 }
 
 impl PartialEq for Reader {
-    // Equal iff the underlying archive paths match. See `Hash` above for the
+    // Equal iff the underlying archive paths and OCI references match. See `Hash` above for the
     // rationale; both must agree for the cache to correctly distinguish archives.
     fn eq(&self, other: &Self) -> bool {
-        self.archive.path() == other.archive.path()
+        self.archive.id() == other.archive.id()
     }
 }
 
@@ -642,11 +716,8 @@ impl Eq for Reader {}
 
 impl Reader {
     #[instrument(skip_all, err)]
-    pub async fn new(
-        archive: ArchiveReader,
-        beginning: DateTime<Utc>,
-        storage: Storage,
-    ) -> anyhow::Result<Self> {
+    pub async fn new(archive: ArchiveReader, beginning: DateTime<Utc>) -> anyhow::Result<Self> {
+        let storage = archive.storage.clone();
         let path = ArchivePath::Custom(PathBuf::from_str("collected.timestamp")?);
         let path = archive.join(path);
         let diff = match storage.exist(&path) {
@@ -763,11 +834,18 @@ impl Reader {
             match objects.get(&path) {
                 Some(previous) if self.storage.exist(&path.with_extension("patch")) => {
                     new_objects.insert(path.clone(), previous.clone());
+                    let from = previous.last_sync_timestamp().unwrap_or_default();
+                    let skip = self
+                        .keyframe(&path, from + chrono::Duration::nanoseconds(1))
+                        .await?
+                        .map(|keyframe| keyframe.line)
+                        .unwrap_or_default();
                     let versions = self
                         .interpolate(
                             previous,
                             path.with_extension("patch"),
-                            previous.last_sync_timestamp().unwrap_or_default(),
+                            skip,
+                            from,
                             self.archive_time(),
                         )
                         .await?;
@@ -873,6 +951,20 @@ impl Reader {
         .map_err(Into::into)
     }
 
+    /// Reads all objects of the resource type, cluster-wide and in every namespace.
+    pub async fn resources<K>(&self) -> anyhow::Result<Vec<K>>
+    where
+        K: Resource<DynamicType = ()> + DeserializeOwned,
+    {
+        let path = ArchivePath::new_path(NamespaceName::new(None, None), TypeMeta::resource::<K>());
+
+        self.items(self.archive.join(path), Selector::default())
+            .await?
+            .filter(|obj| obj.older(self.archive_time()) && !obj.deleted())
+            .map(|obj| obj.try_parse().map_err(Into::into))
+            .collect()
+    }
+
     pub async fn read<R: DeserializeOwned + Clone>(&self, path: PathBuf) -> anyhow::Result<R> {
         self.versions(path)
             .await?
@@ -888,13 +980,17 @@ impl Reader {
         match self.storage.exist(&path.with_extension("patch")) {
             false => Ok(vec![serde_saphyr::from_slice(&object)?]),
             true => {
-                let original: serde_json::Value = serde_saphyr::from_slice(&object)?;
+                let (original, skip) = match self.keyframe(&path, self.archive_time()).await? {
+                    Some(keyframe) => (keyframe.object, keyframe.line),
+                    None => (serde_saphyr::from_slice(&object)?, 0),
+                };
                 Some(original.clone())
                     .into_iter()
                     .chain(
                         self.interpolate(
                             &original,
                             path.with_extension("patch"),
+                            skip,
                             Default::default(),
                             self.archive_time(),
                         )
@@ -906,6 +1002,29 @@ impl Reader {
         }
     }
 
+    // Finds the latest keyframe for the object recorded before the given time
+    async fn keyframe(
+        &self,
+        path: &Path,
+        until: DateTime<Utc>,
+    ) -> anyhow::Result<Option<Keyframe>> {
+        let keyframes = path.with_extension("keyframes");
+        if !self.storage.exist(&keyframes) {
+            return Ok(None);
+        }
+
+        let mut latest = None;
+        for line in self.read_lines(keyframes).await? {
+            let keyframe: Keyframe = serde_json::from_str(&line?)?;
+            if keyframe.timestamp >= until {
+                break;
+            }
+            latest = Some(keyframe);
+        }
+
+        Ok(latest)
+    }
+
     async fn read_lines(
         &self,
         filename: PathBuf,
@@ -915,49 +1034,35 @@ impl Reader {
         Ok(io::BufReader::new(io::Cursor::new(file)).lines())
     }
 
-    // Goes through all json patches and applies them on the resource in order
+    // Goes through all json patches and applies them on the resource in order,
+    // skipping the first lines already included in the target
     async fn interpolate<R: Serialize + DeserializeOwned>(
         &self,
         target: &R,
         patches_file: PathBuf,
+        skip: usize,
         from: DateTime<Utc>,
         until: DateTime<Utc>,
     ) -> anyhow::Result<Vec<R>> {
         let mut target = serde_json::to_value(target)?;
         let mut versions = vec![];
-        for list in self.read_lines(patches_file).await? {
+        for list in self.read_lines(patches_file).await?.skip(skip) {
             let patches: Vec<PatchOperation> = serde_json::from_str(&list?)?;
-            let mut do_apply = false;
-            for p in patches.clone() {
-                match p {
-                    PatchOperation::Replace(ReplaceOperation { path, value })
-                    | PatchOperation::Add(AddOperation { path, value })
-                        if path == PointerBuf::from_tokens(UPDATED_PATH)
-                            || path == PointerBuf::from_tokens(ADDED_PATH)
-                            || path == PointerBuf::from_tokens(DELETED_PATH) =>
-                    {
-                        let last_sync_timestamp: DateTime<Utc> = serde_json::from_value(value)?;
-                        if last_sync_timestamp >= until {
-                            let wait_duration = (last_sync_timestamp - until).to_std()?;
-                            let mut next_patch_time = self
-                                .next_patch_time
-                                .lock()
-                                .expect("next_patch_time lock poisoned");
-                            *next_patch_time = (*next_patch_time).min(wait_duration);
-                            return Ok(versions);
-                        } else if last_sync_timestamp <= from {
-                            break;
-                        } else {
-                            do_apply = true;
-                        }
-                    }
-                    _ => (),
-                };
-            }
-
-            if do_apply && !patches.is_empty() {
-                patch(&mut target, &patches)?;
-                versions.push(serde_json::from_value(target.clone())?)
+            match patch_timestamp(&patches) {
+                Some(last_sync_timestamp) if last_sync_timestamp >= until => {
+                    let wait_duration = (last_sync_timestamp - until).to_std()?;
+                    let mut next_patch_time = self
+                        .next_patch_time
+                        .lock()
+                        .expect("next_patch_time lock poisoned");
+                    *next_patch_time = (*next_patch_time).min(wait_duration);
+                    return Ok(versions);
+                }
+                Some(last_sync_timestamp) if last_sync_timestamp > from => {
+                    patch(&mut target, &patches)?;
+                    versions.push(serde_json::from_value(target.clone())?)
+                }
+                _ => (),
             }
         }
 
@@ -971,6 +1076,68 @@ mod tests {
     use chrono::Duration;
     use serde_json::json;
 
+    #[tokio::test]
+    async fn versions_from_keyframe() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let archive = tmp_dir.path().join("collected");
+        std::fs::create_dir_all(&archive).unwrap();
+
+        let now = Utc::now();
+        let timestamp = |offset: i64| (now - Duration::seconds(offset)).to_string();
+        let patch_line = |offset: i64, data: &str| {
+            json!([
+                {"op": "replace", "path": "/metadata/annotations/crust-gather.io~1updated", "value": timestamp(offset)},
+                {"op": "replace", "path": "/data/key", "value": data},
+            ])
+            .to_string()
+        };
+
+        std::fs::write(
+            archive.join("cm.yaml"),
+            format!(
+                "apiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: test\n  annotations:\n    {UPDATED_ANNOTATION}: \"{}\"\ndata:\n  key: base\n",
+                timestamp(40)
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            archive.join("cm.patch"),
+            [
+                patch_line(30, "first"),
+                patch_line(20, "second"),
+                patch_line(-20, "future"),
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        // The keyframe differs from the replayed state, which proves the reader seeks to it
+        let keyframe = Keyframe {
+            line: 1,
+            timestamp: now - Duration::seconds(30),
+            object: json!({
+                "apiVersion": "v1",
+                "kind": "ConfigMap",
+                "metadata": {"name": "test", "annotations": {UPDATED_ANNOTATION: timestamp(30)}},
+                "data": {"key": "first", "marker": "keyframe"},
+            }),
+        };
+        std::fs::write(
+            archive.join("cm.keyframes"),
+            serde_json::to_string(&keyframe).unwrap(),
+        )
+        .unwrap();
+
+        let reader = Reader::new(
+            ArchiveReader::new(Archive::new(archive.clone()), Storage::FS, 1).await,
+            now,
+        )
+        .await
+        .unwrap();
+
+        let obj: serde_json::Value = reader.read(archive.join("cm.yaml")).await.unwrap();
+        assert_eq!(obj["data"], json!({"key": "second", "marker": "keyframe"}));
+    }
+
     #[tokio::test]
     async fn table_columns() {
         let list = NamedObject {
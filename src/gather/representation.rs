@@ -201,6 +201,19 @@ impl ArchivePath {
             ArchivePath::ClusterList(path) => Some(path.as_path()),
         }
     }
+
+    /// Maps the path, keeping the path kind.
+    pub fn map_path(&self, f: impl Fn(&Path) -> PathBuf) -> Self {
+        match self {
+            ArchivePath::Empty => ArchivePath::Empty,
+            ArchivePath::Cluster(path) => ArchivePath::Cluster(f(path)),
+            ArchivePath::Namespaced(path) => ArchivePath::Namespaced(f(path)),
+            ArchivePath::NamespacedList(path) => ArchivePath::NamespacedList(f(path)),
+            ArchivePath::ClusterList(path) => ArchivePath::ClusterList(f(path)),
+            ArchivePath::Logs(path) => ArchivePath::Logs(f(path)),
+            ArchivePath::Custom(path) => ArchivePath::Custom(f(path)),
+        }
+    }
 }
 
 impl Display for ArchivePath {
@@ -7,7 +7,7 @@ use serde::{Deserialize, Serialize};
 
 use tracing::instrument;
 
-#[derive(Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+#[derive(Deserialize, Clone, Debug, Default, Hash, PartialEq, Eq)]
 pub struct Selector {
     #[serde(rename = "labelSelector")]
     label_selector: Option<String>,
@@ -1,15 +1,36 @@
 use std::{
-    collections::HashMap, fmt::Display, fs::File, future::pending, net::SocketAddr, ops::Deref,
-    path::PathBuf, str::FromStr, sync::Arc, time::Duration,
+    collections::HashMap,
+    fmt::Display,
+    future::pending,
+    io::ErrorKind,
+    net::{SocketAddr, TcpListener},
+    ops::Deref,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
 
+use actix_http::{
+    HttpService, Protocol, Request, Response, body::MessageBody, error::DispatchError,
+};
+use actix_service::{
+    IntoServiceFactory, Service, ServiceFactory, ServiceFactoryExt as _, fn_service, map_config,
+};
 use actix_web::{
-    App, HttpRequest, HttpResponse, HttpServer, Responder, error, get,
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
+    dev::{AppConfig, ServiceRequest, ServiceResponse},
+    error, get,
     http::{
         KeepAlive,
-        header::{Accept, CONTENT_TYPE, HeaderValue, QualityItem, SEC_WEBSOCKET_PROTOCOL, VARY},
+        header::{
+            AUTHORIZATION, Accept, CONTENT_TYPE, HeaderValue, QualityItem, SEC_WEBSOCKET_PROTOCOL,
+            VARY,
+        },
     },
+    middleware::{Next, from_fn},
     post,
+    rt::net::TcpStream,
     web::{self, Bytes, Header, Path, Payload, Query},
 };
 use anyhow::Context as _;
@@ -17,6 +38,10 @@ use async_stream::stream;
 use base64::{Engine as _, prelude::BASE64_STANDARD};
 use chrono::{DateTime, SecondsFormat, Utc};
 use clap::Parser;
+use ed25519_dalek::VerifyingKey;
+use k8s_openapi::api::authorization::v1::{
+    SelfSubjectAccessReview, SelfSubjectRulesReview, SubjectAccessReview,
+};
 use k8s_openapi::serde_json::{self, json};
 use kube::{
     api::{TypeMeta, WatchEvent},
@@ -26,23 +51,41 @@ use kube::{
         watch::{Bookmark, BookmarkMeta},
     },
 };
-use oci_client::{Client, Reference, manifest::OciImageManifest};
+use oci_client::Client;
+use rustls::ServerConfig;
 use serde::Deserialize;
+use tempfile::TempDir;
 use tokio::sync::oneshot;
-use tokio::time::{Instant, sleep};
+use tokio::time::{Instant, sleep, timeout};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
 
 use crate::{
-    cli::{DEFAULT_OCI_BUFFER_SIZE, OCISettings},
+    cli::{DEFAULT_OCI_BUFFER_SIZE, DecryptionSettings, OCIReference, OCISettings},
     gather::{
+        blob_cache::{BlobCache, CacheSettings},
+        encryption::Decryption,
+        github::GithubSettings,
+        http::{RemoteArchive, is_url},
+        kubeconfig::KubeconfigTarget,
+        manifest::{verify_archive, verify_oci, verify_storage, verifying_key},
+        rbac::{Authorizer, Impersonation, UserInfo},
         reader::{ArchiveReader, Destination, Get, List, Log, NamedObject, Reader, Watch},
         representation::TypeMetaGetter,
-        storage::{Descriptor, OCIState, Storage, pull_blob_cached},
-        writer::{Archive, YamlPath},
+        s3::{S3Settings, is_s3},
+        storage::{OCIState, Storage, ZipState},
+        tls::{Authentication, ClientCertificate, ServeSecurity, TlsSettings},
+        writer::Archive,
     },
 };
 
 use super::{representation::ArchivePath, selector::Selector, writer::ArchiveSearch};
 
+const KEEP_ALIVE: KeepAlive = KeepAlive::Timeout(Duration::from_secs(30));
+
+const CLIENT_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Clone, Deserialize)]
 pub struct Socket(SocketAddr);
 
@@ -77,20 +120,39 @@ pub struct Server {
     #[arg(short, long, value_name = "KUBECONFIG")]
     kubeconfig: Option<PathBuf>,
 
+    /// Write a standalone kubeconfig for the served archives to the path, instead of
+    /// adding contexts to the user's kubeconfig. Use "-" to print it to stdout, logs are
+    /// written to stderr. The file is removed once serving stops.
+    /// Contexts merged into the user's kubeconfig are left behind when the server is
+    /// killed, a standalone kubeconfig leaves the user's kubeconfig untouched.
+    ///
+    /// Example:
+    ///     --kubeconfig-output=./served.kubeconfig
+    ///     --kubeconfig-output=- > served.kubeconfig
+    #[arg(long, value_name = "PATH", conflicts_with = "kubeconfig")]
+    #[serde(default)]
+    kubeconfig_output: Option<PathBuf>,
+
     /// The input archive path. Will be used as a recursive search directory for
-    /// snapshot locations.
+    /// snapshot locations. Archives are served next to the OCI references.
     ///
-    /// Defaults to a new archive with name "crust-gather".
+    /// HTTP(S) URLs of zip archives are read lazily with range requests, other
+    /// archives such as tar.gz are downloaded once into the cache directory.
+    /// "s3://bucket/prefix" locations serve the snapshots uploaded with "--s3-upload",
+    /// reading the path layout object by object and archive objects like URLs.
+    ///
+    /// Defaults to a new archive with name "crust-gather" when no reference is given.
     ///
     /// Example:
     ///     --archive=./artifacts
-    #[arg(short, long, value_name = "PATH", default_value_t = Default::default())]
+    ///     --archive=https://example.com/snapshots/crust-gather.zip
+    ///     --archive=s3://snapshots/prod
+    #[arg(short, long, value_name = "PATH")]
     #[serde(default)]
-    #[arg(conflicts_with = "reference")]
-    archive: ArchiveSearch,
+    archive: Option<ArchiveSearch>,
 
-    /// OCI source for crust gather archive serving. Reads cluster state directly from the provided image reference
-    /// with optional authentication.
+    /// OCI source for crust gather archive serving. Reads cluster state directly from the provided image references
+    /// with optional authentication. Each reference is served in its own kubeconfig context.
     #[clap(flatten)]
     #[serde(flatten)]
     #[serde(default)]
@@ -106,217 +168,500 @@ pub struct Server {
         value_parser = |arg: &str| -> anyhow::Result<Socket> {Socket::try_from(arg)})]
     #[serde(default)]
     socket: Socket,
+
+    /// TLS and authentication of the served API.
+    #[clap(flatten)]
+    #[serde(flatten)]
+    #[serde(default)]
+    tls: TlsSettings,
+
+    /// Decryption options for encrypted archives.
+    #[clap(flatten)]
+    #[serde(flatten)]
+    #[serde(default)]
+    decryption: DecryptionSettings,
+
+    /// Verify the archive against its manifest before serving.
+    ///
+    /// Example:
+    ///     --verify
+    #[arg(long)]
+    #[serde(default)]
+    verify: bool,
+
+    /// Path to the ed25519 public key in PEM format to verify the manifest signature with.
+    /// Implies "--verify".
+    ///
+    /// Example:
+    ///     --verify-key=key.pub
+    #[arg(long, value_name = "PATH")]
+    #[serde(default)]
+    verify_key: Option<PathBuf>,
+
+    /// S3-compatible storage settings for "s3://" archive locations.
+    #[clap(flatten)]
+    #[serde(flatten)]
+    #[serde(default)]
+    s3: S3Settings,
+
+    /// GitHub Actions artifacts to serve snapshots from.
+    #[clap(flatten)]
+    #[serde(flatten)]
+    #[serde(default)]
+    github: GithubSettings,
+
+    /// Disk cache for OCI blobs, so restarted servers don't download layers again.
+    #[clap(flatten)]
+    #[serde(flatten)]
+    #[serde(default)]
+    cache: CacheSettings,
+
+    /// Download all OCI layers into the cache in the background once the index is loaded.
+    ///
+    /// Example:
+    ///     --prefetch
+    #[arg(long, requires = "reference", conflicts_with = "no_cache")]
+    #[serde(default)]
+    prefetch: bool,
 }
 
 impl Server {
-    pub async fn get_api(&self) -> anyhow::Result<Api> {
-        let archives: Vec<_> = self.archive.clone().into();
-        if self.oci.reference.is_some() {
-            Api::new_oci(
-                self.oci.clone(),
-                self.socket.clone(),
-                self.kubeconfig.clone(),
+    /// Returns true when the kubeconfig is printed to stdout.
+    pub fn streams_stdout(&self) -> bool {
+        self.kubeconfig_output
+            .as_ref()
+            .is_some_and(|output| output.as_os_str() == "-")
+    }
+
+    /// Verifies a remote archive when verification is requested and adds its reader
+    /// under the given name.
+    async fn push_verified_reader(
+        &self,
+        source: &str,
+        name: String,
+        archive: Archive,
+        storage: Storage,
+        verify_key: Option<&VerifyingKey>,
+        readers: &mut Vec<(String, ArchiveReader)>,
+    ) -> anyhow::Result<()> {
+        if self.verify || verify_key.is_some() {
+            let verification = verify_storage(
+                &storage,
+                &archive.path(),
+                verify_key,
+                DEFAULT_OCI_BUFFER_SIZE,
             )
-            .await
-        } else {
-            Api::new(archives, self.socket.clone(), self.kubeconfig.clone()).await
+            .await?;
+            verification.ensure_valid()?;
+            tracing::info!(source, archive = %archive, %verification, "Verified archive");
         }
+
+        readers.push((
+            name,
+            ArchiveReader::new(archive, storage, DEFAULT_OCI_BUFFER_SIZE).await,
+        ));
+        Ok(())
+    }
+
+    pub async fn get_api(&self) -> anyhow::Result<Api> {
+        let decryption = self.decryption.to_decryption()?;
+        let verify_key = self.verify_key.as_deref().map(verifying_key).transpose()?;
+        let verify = self.verify || verify_key.is_some();
+
+        let remote = !self.oci.references.is_empty() || !self.github.github_artifact.is_empty();
+        let search = match (&self.archive, remote) {
+            (Some(search), _) => Some(search.clone()),
+            (None, false) => Some(ArchiveSearch::default()),
+            (None, true) => None,
+        };
+
+        let cache = self.cache.to_cache()?;
+        let mut readers = vec![];
+        let mut unpacked = vec![];
+        if let Some(search) = search {
+            let mut archives: Vec<Archive> = vec![];
+            let mut remote = vec![];
+            let location = search.path().to_string_lossy().to_string();
+            if is_url(&location) {
+                remote.push(
+                    RemoteArchive::open(&location, cache.as_ref(), decryption.as_ref()).await?,
+                );
+            } else if is_s3(&location) {
+                let snapshots = self
+                    .s3
+                    .open(
+                        &location.as_str().try_into()?,
+                        cache.as_ref(),
+                        decryption.as_ref(),
+                    )
+                    .await?;
+                if let Some(objects) = snapshots.objects {
+                    for archive in objects.archives() {
+                        let name = archive.name().to_string_lossy().to_string();
+                        let storage = Storage::S3(objects.clone());
+                        self.push_verified_reader(
+                            &location,
+                            name,
+                            archive,
+                            storage,
+                            verify_key.as_ref(),
+                            &mut readers,
+                        )
+                        .await?;
+                    }
+                }
+                remote.extend(snapshots.archives);
+            } else {
+                archives = search.clone().into();
+                unpacked.extend(Storage::unpack(&search.path(), decryption.as_ref())?);
+            }
+
+            for remote in remote {
+                match remote {
+                    RemoteArchive::Zip(zip) => {
+                        let zip: Arc<ZipState> = Arc::from(zip);
+                        let zip_archives = zip.archives();
+                        if zip_archives.is_empty() {
+                            anyhow::bail!("{} contains no crust-gather snapshots", zip.source);
+                        }
+                        for archive in zip_archives {
+                            let name = archive.name().to_string_lossy().to_string();
+                            let storage = Storage::Zip(zip.clone());
+                            self.push_verified_reader(
+                                &zip.source,
+                                name,
+                                archive,
+                                storage,
+                                verify_key.as_ref(),
+                                &mut readers,
+                            )
+                            .await?;
+                        }
+                    }
+                    RemoteArchive::Unpacked(dir) => unpacked.push(dir),
+                }
+            }
+            for dir in &unpacked {
+                archives.extend(Vec::<Archive>::from(ArchiveSearch::from(
+                    dir.path().to_path_buf(),
+                )));
+            }
+            if is_s3(&location) && readers.is_empty() && archives.is_empty() {
+                anyhow::bail!("{location} contains no crust-gather snapshots");
+            }
+
+            for archive in archives {
+                if verify {
+                    let verification = verify_archive(&archive.path(), verify_key.as_ref())?;
+                    verification.ensure_valid()?;
+                    tracing::info!(archive = %archive, %verification, "Verified archive");
+                }
+
+                readers.push((
+                    archive.name().to_string_lossy().to_string(),
+                    ArchiveReader::new(archive, Storage::FS, DEFAULT_OCI_BUFFER_SIZE).await,
+                ));
+            }
+        }
+
+        for artifact in &self.github.github_artifact {
+            let zip = Arc::new(self.github.open(artifact, cache.as_ref()).await?);
+            let archives = zip.archives();
+            if archives.is_empty() {
+                anyhow::bail!("GitHub artifact {artifact} contains no crust-gather snapshots");
+            }
+
+            for archive in archives {
+                let name = match self.github.github_artifact.len() {
+                    1 => archive.name().to_string_lossy().to_string(),
+                    _ => format!("{}-{}", archive.name().to_string_lossy(), artifact.id),
+                };
+                let storage = Storage::Zip(zip.clone());
+                self.push_verified_reader(
+                    &artifact.to_string(),
+                    name,
+                    archive,
+                    storage,
+                    verify_key.as_ref(),
+                    &mut readers,
+                )
+                .await?;
+            }
+        }
+
+        let names = Api::reference_names(&self.oci.references);
+        for (reference, name) in self.oci.references.iter().zip(names) {
+            let oci = self.oci.with_reference(reference.clone());
+            let reader = Api::oci_reader(&oci, decryption.clone(), cache.clone()).await?;
+            if let Storage::OCI(state) = reader.storage() {
+                if verify {
+                    let verification =
+                        verify_oci(*state.clone(), verify_key.as_ref(), oci.buffer_size).await?;
+                    verification.ensure_valid()?;
+                    tracing::info!(reference = %state.reference, %verification, "Verified OCI archive");
+                }
+                if self.prefetch {
+                    state.prefetch(oci.buffer_size);
+                }
+            }
+            readers.push((name, reader));
+        }
+
+        let kubeconfig =
+            KubeconfigTarget::new(self.kubeconfig.clone(), self.kubeconfig_output.clone())?;
+        let security = self.tls.to_security(self.socket.0)?;
+        let mut server =
+            Api::with_readers(readers, self.socket.clone(), kubeconfig, security).await?;
+        server.unpacked = unpacked;
+        Ok(server)
     }
 }
 
 pub struct Api {
     state: ApiState,
     socket: SocketAddr,
+    // Plain HTTP is served without a TLS configuration.
+    tls: Option<Arc<ServerConfig>>,
+    // Decrypted archives, removed once the server is stopped.
+    unpacked: Vec<TempDir>,
 }
 
 #[derive(Clone)]
 struct ApiState {
     archives: HashMap<String, ArchiveReader>,
-    kubeconfig_path: PathBuf,
+    kubeconfig: KubeconfigTarget,
+    // Contexts of the served archives, and the current context they replaced.
+    served: Kubeconfig,
     previous_context: Option<String>,
+    authentication: Authentication,
     serve_time: DateTime<Utc>,
-    storage: Storage,
 }
 
 impl ApiState {
     pub async fn to_reader(&self, archive: ArchiveReader) -> anyhow::Result<Reader> {
-        Reader::new(archive, self.serve_time, self.storage.clone())
+        Reader::new(archive, self.serve_time)
             .await
             .context("failed to open storage reader")
     }
-}
-
-impl Api {
-    pub async fn new(
-        archives: impl IntoIterator<Item = Archive> + Clone,
-        socket: Socket,
-        kubeconfig: Option<PathBuf>,
-    ) -> anyhow::Result<Self> {
-        let Socket(socket) = socket;
-
-        let kubeconfig_path = kubeconfig.unwrap_or(std::path::PathBuf::from(
-            std::env::var("KUBECONFIG")
-                .unwrap_or(format!("{}/.kube/config", std::env::var("HOME")?).to_string()),
-        ));
 
-        let mut config = match File::open(&kubeconfig_path) {
-            Ok(file) => serde_saphyr::from_reader(file)?,
-            _ => Kubeconfig::default(),
+    /// Returns the user reviews are evaluated for: the requester, or the user it
+    /// impersonates when the snapshot RBAC allows the impersonation.
+    async fn user(&self, server: &str, request: &HttpRequest) -> actix_web::Result<UserInfo> {
+        let requester = UserInfo::requester(request.conn_data::<ClientCertificate>());
+        let Some(impersonation) = Impersonation::from_headers(request.headers()) else {
+            return Ok(requester);
         };
 
-        let previous_context = config.current_context;
-        config.current_context = None;
+        let authorizer = self.authorizer(server, &requester).await?;
+        if !authorizer.can_impersonate(&requester, &impersonation) {
+            return Err(error::ErrorForbidden(anyhow::anyhow!(
+                "user {:?} cannot impersonate {:?}",
+                requester.name,
+                impersonation.user
+            )));
+        }
+        Ok(impersonation.user_info())
+    }
 
-        let config = archives
-            .clone()
-            .into_iter()
-            .map(|a| Api::prepare_kubeconfig(a.name().to_string_lossy().to_string(), socket))
-            .try_fold(config, Kubeconfig::merge)?;
+    /// Loads the RBAC objects of the archive on first use. Privileged users are allowed
+    /// everything, so nothing is read for them.
+    async fn authorizer(
+        &self,
+        server: &str,
+        user: &UserInfo,
+    ) -> actix_web::Result<Arc<Authorizer>> {
+        if user.is_privileged() {
+            return Ok(Default::default());
+        }
 
-        serde_saphyr::to_io_writer(&mut File::create(&kubeconfig_path)?, &config)?;
+        let archive = self
+            .archives
+            .get(server)
+            .ok_or(error::ErrorNotFound(anyhow::anyhow!("Server not found")))?;
+        let authorizer = archive
+            .authorizer()
+            .get_or_try_init(|| async {
+                let reader = self
+                    .to_reader(archive.clone())
+                    .await
+                    .map_err(error::ErrorServiceUnavailable)?;
+                Ok::<_, actix_web::Error>(Arc::new(Authorizer::new(
+                    reader
+                        .resources()
+                        .await
+                        .map_err(error::ErrorInternalServerError)?,
+                    reader
+                        .resources()
+                        .await
+                        .map_err(error::ErrorInternalServerError)?,
+                    reader
+                        .resources()
+                        .await
+                        .map_err(error::ErrorInternalServerError)?,
+                    reader
+                        .resources()
+                        .await
+                        .map_err(error::ErrorInternalServerError)?,
+                )))
+            })
+            .await?;
+        Ok(authorizer.clone())
+    }
+}
 
-        let mut readers = HashMap::new();
+impl Api {
+    pub async fn new(
+        archives: impl IntoIterator<Item = Archive>,
+        socket: Socket,
+        kubeconfig: KubeconfigTarget,
+    ) -> anyhow::Result<Self> {
+        let mut readers = vec![];
         for archive in archives {
-            readers.insert(
-                Api::convert_name(archive.name().to_string_lossy().to_string()),
-                ArchiveReader::new(archive, &Storage::FS, DEFAULT_OCI_BUFFER_SIZE).await,
-            );
+            readers.push((
+                archive.name().to_string_lossy().to_string(),
+                ArchiveReader::new(archive, Storage::FS, DEFAULT_OCI_BUFFER_SIZE).await,
+            ));
         }
 
-        Ok(Self {
-            state: ApiState {
-                archives: readers,
-                kubeconfig_path,
-                previous_context,
-                serve_time: Utc::now(),
-                storage: Storage::FS,
-            },
-            socket,
-        })
+        Api::with_readers(readers, socket, kubeconfig, ServeSecurity::default()).await
     }
 
     pub(crate) async fn new_oci(
         oci: OCISettings,
         socket: Socket,
-        kubeconfig: Option<PathBuf>,
+        kubeconfig: KubeconfigTarget,
+        decryption: Option<Decryption>,
+        cache: Option<BlobCache>,
     ) -> anyhow::Result<Self> {
-        let Some(reference) = &oci.reference else {
+        let Some(reference) = oci.reference()? else {
             anyhow::bail!("missing reference");
         };
 
-        let reference: Reference = reference.clone().into();
+        let name = reference.repository.clone();
+        let reader = Api::oci_reader(&oci, decryption, cache).await?;
+        Api::with_readers(
+            vec![(name, reader)],
+            socket,
+            kubeconfig,
+            ServeSecurity::default(),
+        )
+        .await
+    }
 
+    /// Serves the archives, each in its own kubeconfig context named after the archive.
+    /// The contexts carry the credentials and CA of the server.
+    async fn with_readers(
+        readers: Vec<(String, ArchiveReader)>,
+        socket: Socket,
+        kubeconfig: KubeconfigTarget,
+        security: ServeSecurity,
+    ) -> anyhow::Result<Self> {
         let Socket(socket) = socket;
 
-        let kubeconfig_path = kubeconfig.unwrap_or(std::path::PathBuf::from(
-            std::env::var("KUBECONFIG")
-                .unwrap_or(format!("{}/.kube/config", std::env::var("HOME")?).to_string()),
-        ));
-
-        let mut config = match File::open(&kubeconfig_path) {
-            Result::Ok(file) => serde_saphyr::from_reader(file)?,
-            _ => Kubeconfig::default(),
-        };
-
-        let previous_context = config.current_context;
-        config.current_context = None;
-
-        let config = config.merge(Api::prepare_kubeconfig(
-            reference.repository().to_string(),
-            socket,
-        ))?;
-
-        serde_saphyr::to_io_writer(&mut File::create(&kubeconfig_path)?, &config)?;
-
-        let client = Client::new(oci.to_client_config());
-        let auth = oci.to_auth();
-        let (manifest, _) = client.pull_image_manifest(&reference, &auth).await?;
-        let config = pull_blob_cached(&client, &reference, &auth, &manifest.config, true).await?;
-        let config = serde_json::from_slice(&config)?;
-        let index = Arc::new(Self::collect_index(&client, &reference, &auth, manifest).await?);
-
-        let storage = Storage::new(Some(OCIState {
-            reference: reference.clone(),
-            client,
-            config,
-            index,
-            auth,
-        }));
+        let (names, readers): (Vec<String>, Vec<ArchiveReader>) = readers.into_iter().unzip();
+        let names = Api::unique_names(names.into_iter().map(Api::convert_name));
+        let archives = names.iter().cloned().zip(readers).collect();
 
-        let search = ArchiveSearch::default();
-        let mut archives = HashMap::new();
-        archives.insert(
-            Api::convert_name(reference.repository().to_string()),
-            ArchiveReader::new(Archive::new(search.path()), &storage, oci.buffer_size).await,
-        );
+        let served = names
+            .into_iter()
+            .map(|name| Api::prepare_kubeconfig(name, socket, &security))
+            .try_fold(Kubeconfig::default(), Kubeconfig::merge)?;
+        let previous_context = kubeconfig.install(&served).await?;
 
         Ok(Self {
             state: ApiState {
                 archives,
-                kubeconfig_path,
+                kubeconfig,
+                served,
                 previous_context,
+                authentication: security.authentication,
                 serve_time: Utc::now(),
-                storage,
             },
             socket,
+            tls: security.tls,
+            unpacked: vec![],
         })
     }
 
-    async fn collect_index(
-        client: &Client,
-        reference: &Reference,
-        auth: &oci_client::secrets::RegistryAuth,
-        manifest: OciImageManifest,
-    ) -> anyhow::Result<HashMap<PathBuf, Descriptor>> {
-        let mut index = HashMap::new();
-
-        for layer in manifest.layers {
-            let Some(annotations) = layer.annotations.clone() else {
-                anyhow::bail!("manifest layer contains no org.opencontainers.image.title annoation")
-            };
-            let path = &annotations["org.opencontainers.image.title"];
-            index.insert(PathBuf::from(path), Descriptor::OciDescriptor(layer));
-        }
-
-        let Some(index_layer) = index.get(&PathBuf::from("index.yaml")) else {
-            return Ok(index);
+    /// Pulls the index of the OCI archive.
+    async fn oci_reader(
+        oci: &OCISettings,
+        decryption: Option<Decryption>,
+        cache: Option<BlobCache>,
+    ) -> anyhow::Result<ArchiveReader> {
+        let Some(reference) = oci.reference()? else {
+            anyhow::bail!("missing reference");
         };
 
-        let data = pull_blob_cached(client, reference, auth, index_layer.deref(), true).await?;
-        let resource_paths: Vec<YamlPath> = serde_saphyr::from_slice(&data)?;
-        for yaml_path in resource_paths {
-            let resource_path = yaml_path.path;
-            let Some(parent_path) = resource_path.parent() else {
-                anyhow::bail!(format!(
-                    "index layer must reference a parent list object: {resource_path:?}"
-                ))
-            };
+        let storage = Storage::new(Some(
+            OCIState::pull(
+                Client::new(oci.to_client_config()),
+                reference.clone().into(),
+                oci.to_auth().await,
+                decryption,
+                cache,
+            )
+            .await?,
+        ));
 
-            let Some(parent) = index.get(&parent_path.with_extension("yaml")) else {
-                anyhow::bail!(format!(
-                    "index layer must reference a yaml list object: {resource_path:?}"
-                ))
-            };
+        let search = ArchiveSearch::default();
+        Ok(ArchiveReader::new(Archive::new(search.path()), storage, oci.buffer_size).await)
+    }
 
-            index.insert(
-                resource_path,
-                Descriptor::ListOciDescriptor(parent.deref().clone(), yaml_path.from, yaml_path.to),
-            );
-        }
+    /// Context names of the references. References to the same repository, such as
+    /// snapshots before and after a change, are suffixed with their tag or digest.
+    fn reference_names(references: &[OCIReference]) -> Vec<String> {
+        references
+            .iter()
+            .map(|reference| {
+                let repository = &reference.repository;
+                let shared = references
+                    .iter()
+                    .filter(|other| &other.repository == repository)
+                    .count()
+                    > 1;
+                match (shared, &reference.tag, &reference.digest) {
+                    (true, Some(tag), _) => format!("{repository}-{tag}"),
+                    (true, None, Some(digest)) => {
+                        let hex = digest.rsplit(':').next().unwrap_or(digest);
+                        format!("{repository}-{}", &hex[..hex.len().min(12)])
+                    }
+                    _ => repository.clone(),
+                }
+            })
+            .collect()
+    }
 
-        Ok(index)
+    /// Numbers archives served with the same name, such as the same repository in two
+    /// registries, keeping the first one unchanged.
+    fn unique_names(names: impl IntoIterator<Item = String>) -> Vec<String> {
+        let mut unique: Vec<String> = vec![];
+        for name in names {
+            let numbered = (2..).map(|n| format!("{name}-{n}"));
+            let name = std::iter::once(name.clone())
+                .chain(numbered)
+                .find(|candidate| !unique.contains(candidate))
+                .unwrap_or(name);
+            unique.push(name);
+        }
+        unique
     }
 
     fn convert_name(name: String) -> String {
         name.replace('/', "-")
     }
 
-    fn prepare_kubeconfig(name: String, socket: SocketAddr) -> Kubeconfig {
+    fn prepare_kubeconfig(
+        name: String,
+        socket: SocketAddr,
+        security: &ServeSecurity,
+    ) -> Kubeconfig {
         let name = Api::convert_name(name);
         Kubeconfig {
             current_context: Some(name.clone()),
             auth_infos: vec![NamedAuthInfo {
                 name: name.clone(),
+                auth_info: security.auth_info(),
                 ..Default::default()
             }],
             contexts: vec![NamedContext {
@@ -331,7 +676,8 @@ impl Api {
             clusters: vec![NamedCluster {
                 name: name.clone(),
                 cluster: Some(Cluster {
-                    server: Some(format!("http://{socket}/{name}")),
+                    server: Some(format!("{}://{socket}/{name}", security.scheme())),
+                    certificate_authority_data: security.certificate_authority_data(),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -340,29 +686,6 @@ impl Api {
         }
     }
 
-    fn clean_kubeconfig(state: ApiState) -> anyhow::Result<()> {
-        let mut config = Kubeconfig::read_from(&state.kubeconfig_path)?;
-
-        let contexts = state.archives.into_keys().collect::<Vec<_>>();
-
-        config.contexts.retain(|c| !contexts.contains(&c.name));
-        config.clusters.retain(|c| !contexts.contains(&c.name));
-        config.auth_infos.retain(|ai| !contexts.contains(&ai.name));
-
-        config.current_context = match config
-            .contexts
-            .iter()
-            .find(|c| Some(c.name.clone()) == state.previous_context)
-        {
-            Some(context) => Some(context.name.clone()),
-            None => config.contexts.first().map(|c| c.name.clone()),
-        };
-
-        serde_saphyr::to_io_writer(&mut File::create(state.kubeconfig_path)?, &config)?;
-
-        Ok(())
-    }
-
     pub async fn serve(self) -> anyhow::Result<()> {
         self.serve_with_shutdown(oneshot::channel::<()>().1).await
     }
@@ -370,13 +693,15 @@ impl Api {
     pub async fn serve_with_shutdown(self, shutdown: oneshot::Receiver<()>) -> anyhow::Result<()> {
         let state = self.state.clone();
 
-        let server = HttpServer::new(move || {
+        let app = move || {
             App::new()
                 .app_data(web::Data::new(self.state.clone()))
+                .wrap(from_fn(authenticate))
                 .service(version)
                 .service(healthz)
-                .service(ssar_stub)
-                .service(ssrr_stub)
+                .service(self_subject_access_review)
+                .service(subject_access_review)
+                .service(self_subject_rules_review)
                 .service(api)
                 .service(apis)
                 .service(api_list)
@@ -388,11 +713,16 @@ impl Api {
                 .service(namespaced_get)
                 .service(namespaced_apis_get)
                 .service(logs_get)
-        })
-        .keep_alive(KeepAlive::Timeout(Duration::from_secs(30)))
-        .client_disconnect_timeout(Duration::from_secs(5))
-        .bind_auto_h2c(self.socket)?
-        .run();
+        };
+
+        let server = match self.tls {
+            Some(config) => bind_tls(config, self.socket, app)?,
+            None => HttpServer::new(app)
+                .keep_alive(KEEP_ALIVE)
+                .client_disconnect_timeout(CLIENT_DISCONNECT_TIMEOUT)
+                .bind_auto_h2c(self.socket)?
+                .run(),
+        };
 
         let handle = server.handle();
 
@@ -403,12 +733,114 @@ impl Api {
 
         server.await?;
 
-        Self::clean_kubeconfig(state)?;
+        state
+            .kubeconfig
+            .uninstall(&state.served, state.previous_context)
+            .await?;
 
         Ok(())
     }
 }
 
+/// Serves the app over TLS. Like "bind_auto_h2c", HTTP/2 is negotiated per connection,
+/// here with ALPN, and connections with a verified client certificate are marked.
+fn bind_tls<F, I, S, B>(
+    config: Arc<ServerConfig>,
+    socket: SocketAddr,
+    factory: F,
+) -> std::io::Result<actix_web::dev::Server>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<actix_web::Error> + 'static,
+    S::InitError: std::fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service<Request>>::Future: 'static,
+    B: MessageBody + 'static,
+{
+    let acceptor = TlsAcceptor::from(config);
+    let listener = TcpListener::bind(socket)?;
+    let server =
+        actix_web::dev::Server::build().listen("crust-gather-tls", listener, move || {
+            let acceptor = acceptor.clone();
+            let handshake = fn_service(move |stream: TcpStream| {
+                let acceptor = acceptor.clone();
+                async move {
+                    let peer = stream.peer_addr().ok();
+                    let stream = timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                        .await
+                        .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()))
+                        .map_err(DispatchError::Io)?;
+                    let protocol = match stream.get_ref().1.alpn_protocol() {
+                        Some(b"h2") => Protocol::Http2,
+                        _ => Protocol::Http1,
+                    };
+                    Ok((stream, protocol, peer))
+                }
+            });
+
+            let app = factory()
+                .into_factory()
+                .map_err(|err| err.into().error_response());
+            handshake.and_then(
+                HttpService::build()
+                    .keep_alive(KEEP_ALIVE)
+                    .client_disconnect_timeout(CLIENT_DISCONNECT_TIMEOUT)
+                    .on_connect_ext(|stream: &TlsStream<TcpStream>, extensions| {
+                        if let Some(certificate) = stream
+                            .get_ref()
+                            .1
+                            .peer_certificates()
+                            .and_then(|certificates| certificates.first())
+                            .and_then(|certificate| ClientCertificate::from_der(certificate))
+                        {
+                            extensions.insert(certificate);
+                        }
+                    })
+                    .finish(map_config(app, |_| AppConfig::default())),
+            )
+        })?;
+    Ok(server.run())
+}
+
+/// Rejects requests without the credentials required by the server, health checks are
+/// answered without them.
+async fn authenticate(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    let segments: Vec<&str> = request.path().trim_start_matches('/').split('/').collect();
+    let authenticated = matches!(segments[..], ["healthz"] | [_, "healthz"])
+        || request
+            .app_data::<web::Data<ApiState>>()
+            .is_some_and(|state| {
+                state.authentication.authenticate(
+                    request
+                        .headers()
+                        .get(AUTHORIZATION)
+                        .map(HeaderValue::as_bytes),
+                    request.conn_data::<ClientCertificate>().is_some(),
+                )
+            });
+    if !authenticated {
+        let response = HttpResponse::Unauthorized().json(json!({
+            "kind": "Status",
+            "apiVersion": "v1",
+            "metadata": {},
+            "status": "Failure",
+            "message": "Unauthorized",
+            "reason": "Unauthorized",
+            "code": 401
+        }));
+        return Ok(request.into_response(response).map_into_right_body());
+    }
+
+    next.call(request)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
 #[get("{server}/healthz")]
 async fn healthz(_: Path<Destination>) -> actix_web::Result<HttpResponse> {
     Ok(HttpResponse::Ok().content_type("text/plain").body("ok"))
@@ -438,51 +870,59 @@ async fn version(
 }
 
 #[post("{server}/apis/authorization.k8s.io/v1/selfsubjectaccessreviews")]
-async fn ssar_stub() -> impl Responder {
-    web::Json(json!({
-        "kind": "SelfSubjectAccessReview",
-        "apiVersion": "authorization.k8s.io/v1",
-        "spec": {
-            "resourceAttributes": {
-                "verb": "*",
-                "resource": "*"
-            }
-        },
-        "status": {
-            "allowed": true
-        }
-    }))
+async fn self_subject_access_review(
+    server: Path<Destination>,
+    request: HttpRequest,
+    review: web::Json<SelfSubjectAccessReview>,
+    state: web::Data<ApiState>,
+) -> actix_web::Result<impl Responder> {
+    let user = state.user(server.get_server(), &request).await?;
+    let mut review = review.into_inner();
+    let authorizer = state.authorizer(server.get_server(), &user).await?;
+    review.status = Some(authorizer.review(
+        &user,
+        review.spec.resource_attributes.as_ref(),
+        review.spec.non_resource_attributes.as_ref(),
+    ));
+
+    Ok(web::Json(review))
+}
+
+#[post("{server}/apis/authorization.k8s.io/v1/subjectaccessreviews")]
+async fn subject_access_review(
+    server: Path<Destination>,
+    review: web::Json<SubjectAccessReview>,
+    state: web::Data<ApiState>,
+) -> actix_web::Result<impl Responder> {
+    let mut review = review.into_inner();
+    let user = UserInfo {
+        name: review.spec.user.clone().unwrap_or_default(),
+        groups: review.spec.groups.clone().unwrap_or_default(),
+    };
+    let authorizer = state.authorizer(server.get_server(), &user).await?;
+    review.status = Some(authorizer.review(
+        &user,
+        review.spec.resource_attributes.as_ref(),
+        review.spec.non_resource_attributes.as_ref(),
+    ));
+
+    Ok(web::Json(review))
 }
 
 #[post("{server}/apis/authorization.k8s.io/v1/selfsubjectrulesreviews")]
-async fn ssrr_stub() -> impl Responder {
-    web::Json(json!({
-      "kind": "SelfSubjectRulesReview",
-      "apiVersion": "authorization.k8s.io/v1",
-      "metadata": {
-        "creationTimestamp": null
-      },
-      "spec": {
-        "namespace": "default"
-      },
-      "status": {
-        "resourceRules": [
-          {
-            "verbs": ["*"],
-            "apiGroups": ["*"],
-            "resources": ["*"],
-            "resourceNames": []
-          }
-        ],
-        "nonResourceRules": [
-          {
-            "verbs": ["*"],
-            "nonResourceURLs": ["*"]
-          }
-        ],
-        "incomplete": false
-      }
-    }))
+async fn self_subject_rules_review(
+    server: Path<Destination>,
+    request: HttpRequest,
+    review: web::Json<SelfSubjectRulesReview>,
+    state: web::Data<ApiState>,
+) -> actix_web::Result<impl Responder> {
+    let user = state.user(server.get_server(), &request).await?;
+    let mut review = review.into_inner();
+    let authorizer = state.authorizer(server.get_server(), &user).await?;
+    let namespace = review.spec.namespace.clone().unwrap_or_default();
+    review.status = Some(authorizer.rules(&user, &namespace));
+
+    Ok(web::Json(review))
 }
 
 #[get("{server}/api")]
@@ -866,3 +1306,248 @@ async fn get_item(get: Path<Get>, state: web::Data<ApiState>) -> anyhow::Result<
     let get = archive.named_object_from_get(get.clone())?;
     reader.load(get).await
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::TcpStream};
+
+    use base64::{Engine as _, prelude::BASE64_STANDARD};
+    use k8s_openapi::api::{
+        authorization::v1::{
+            ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+        },
+        core::v1::Secret,
+    };
+    use kube::{
+        Client, Config,
+        api::PostParams,
+        config::{KubeConfigOptions, Kubeconfig},
+    };
+    use rustls::pki_types::{CertificateDer, pem::PemObject as _};
+    use tempfile::TempDir;
+    use tokio::sync::oneshot;
+
+    use super::{Api, Socket};
+    use crate::{
+        cli::{DEFAULT_OCI_BUFFER_SIZE, OCIReference},
+        gather::{
+            kubeconfig::KubeconfigTarget,
+            reader::ArchiveReader,
+            storage::Storage,
+            tls::{CLIENT_GROUP, CLIENT_USER, ClientCertificate, TlsSettings},
+            writer::Archive,
+        },
+    };
+
+    #[test]
+    fn test_reference_names() {
+        let references: Vec<OCIReference> = [
+            "ghcr.io/org/snapshot:before",
+            "ghcr.io/org/snapshot@sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+            "ghcr.io/org/other:latest",
+        ]
+        .into_iter()
+        .map(|reference| reference.try_into().unwrap())
+        .collect();
+
+        assert_eq!(
+            Api::reference_names(&references),
+            [
+                "org/snapshot-before",
+                "org/snapshot-0123456789ab",
+                "org/other"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unique_names() {
+        let references: Vec<OCIReference> = [
+            "ghcr.io/org/snapshot:latest",
+            "docker.io/org/snapshot:latest",
+            "quay.io/org/snapshot:latest",
+        ]
+        .into_iter()
+        .map(|reference| reference.try_into().unwrap())
+        .collect();
+        let mut names = Api::reference_names(&references);
+        names.push("org-snapshot-latest".into());
+
+        assert_eq!(
+            Api::unique_names(names.into_iter().map(Api::convert_name)),
+            [
+                "org-snapshot-latest",
+                "org-snapshot-latest-2",
+                "org-snapshot-latest-3",
+                "org-snapshot-latest-4"
+            ]
+        );
+    }
+
+    async fn server_version(served: &Kubeconfig) -> kube::Result<String> {
+        let config = Config::from_custom_kubeconfig(served.clone(), &KubeConfigOptions::default())
+            .await
+            .unwrap();
+        let client = Client::try_from(config).unwrap();
+        Ok(client.apiserver_version().await?.git_version)
+    }
+
+    async fn health(served: &Kubeconfig) -> kube::Result<String> {
+        let config = Config::from_custom_kubeconfig(served.clone(), &KubeConfigOptions::default())
+            .await
+            .unwrap();
+        let client = Client::try_from(config).unwrap();
+        client
+            .request_text(http::Request::get("/healthz").body(vec![]).unwrap())
+            .await
+    }
+
+    async fn secret(served: &Kubeconfig, name: &str) -> kube::Result<Secret> {
+        let config = Config::from_custom_kubeconfig(served.clone(), &KubeConfigOptions::default())
+            .await
+            .unwrap();
+        kube::Api::<Secret>::namespaced(Client::try_from(config).unwrap(), "default")
+            .get(name)
+            .await
+    }
+
+    /// Reviews "delete secrets" in "kube-system" for the user of the kubeconfig.
+    async fn can_delete_secrets(served: &Kubeconfig) -> bool {
+        let config = Config::from_custom_kubeconfig(served.clone(), &KubeConfigOptions::default())
+            .await
+            .unwrap();
+        let review = SelfSubjectAccessReview {
+            spec: SelfSubjectAccessReviewSpec {
+                resource_attributes: Some(ResourceAttributes {
+                    verb: Some("delete".into()),
+                    resource: Some("secrets".into()),
+                    namespace: Some("kube-system".into()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        kube::Api::<SelfSubjectAccessReview>::all(Client::try_from(config).unwrap())
+            .create(&PostParams::default(), &review)
+            .await
+            .unwrap()
+            .status
+            .unwrap()
+            .allowed
+    }
+
+    #[tokio::test]
+    async fn test_serve_tls_authentication() {
+        let dir = TempDir::new().unwrap();
+        let archive = dir.path().join("crust-gather");
+        fs::create_dir_all(&archive).unwrap();
+        fs::write(
+            archive.join("version.yaml"),
+            "major: \"1\"\nminor: \"31\"\ngitVersion: v1.31.0\ngitCommit: abc\n\
+             gitTreeState: clean\nbuildDate: \"2024-08-13T07:28:49Z\"\n\
+             goVersion: go1.22.5\ncompiler: gc\nplatform: linux/amd64\n",
+        )
+        .unwrap();
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let socket = Socket::try_from(format!("127.0.0.1:{port}").as_str()).unwrap();
+        let settings = TlsSettings {
+            auth_token: Some("".into()),
+            client_cert_auth: true,
+            ..Default::default()
+        };
+        let security = settings.to_security(socket.0).unwrap();
+        let path = dir.path().join("served.kubeconfig");
+        let reader =
+            ArchiveReader::new(Archive::new(archive), Storage::FS, DEFAULT_OCI_BUFFER_SIZE).await;
+        let api = Api::with_readers(
+            vec![("crust-gather".into(), reader)],
+            socket.clone(),
+            KubeconfigTarget::Standalone(path.clone()),
+            security,
+        )
+        .await
+        .unwrap();
+        let (stop, shutdown) = oneshot::channel();
+        let server = tokio::spawn(api.serve_with_shutdown(shutdown));
+        while TcpStream::connect(socket.0).is_err() {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+
+        let served: Kubeconfig =
+            serde_saphyr::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(
+            served.clusters[0]
+                .cluster
+                .as_ref()
+                .unwrap()
+                .server
+                .as_ref()
+                .unwrap()
+                .starts_with("https://")
+        );
+        assert_eq!(server_version(&served).await.unwrap(), "v1.31.0");
+
+        // Either the token or the client certificate authenticates.
+        let mut token = served.clone();
+        let user = token.auth_infos[0].auth_info.as_mut().unwrap();
+        user.client_certificate_data = None;
+        user.client_key_data = None;
+        assert_eq!(server_version(&token).await.unwrap(), "v1.31.0");
+
+        let mut certificate = served.clone();
+        certificate.auth_infos[0].auth_info.as_mut().unwrap().token = None;
+        assert_eq!(server_version(&certificate).await.unwrap(), "v1.31.0");
+
+        // Both identify the privileged user, the certificate by its subject.
+        let pem = BASE64_STANDARD
+            .decode(
+                certificate.auth_infos[0]
+                    .auth_info
+                    .as_ref()
+                    .unwrap()
+                    .client_certificate_data
+                    .as_ref()
+                    .unwrap(),
+            )
+            .unwrap();
+        let identity =
+            ClientCertificate::from_der(&CertificateDer::from_pem_slice(&pem).unwrap()).unwrap();
+        assert_eq!(identity.user, CLIENT_USER);
+        assert_eq!(identity.groups, [CLIENT_GROUP]);
+        assert!(can_delete_secrets(&token).await);
+        assert!(can_delete_secrets(&certificate).await);
+
+        let mut anonymous = token.clone();
+        anonymous.auth_infos[0].auth_info = None;
+        match server_version(&anonymous).await {
+            Err(kube::Error::Api(status)) => assert_eq!(status.code, 401),
+            result => panic!("expected unauthorized, got {result:?}"),
+        }
+
+        // Only the health endpoints are answered without credentials, not objects named healthz.
+        assert_eq!(health(&anonymous).await.unwrap(), "ok");
+        match secret(&anonymous, "healthz").await {
+            Err(kube::Error::Api(status)) => assert_eq!(status.code, 401),
+            result => panic!("expected unauthorized, got {result:?}"),
+        }
+
+        // Clients without the generated CA don't trust the server.
+        let mut untrusted = served.clone();
+        untrusted.clusters[0]
+            .cluster
+            .as_mut()
+            .unwrap()
+            .certificate_authority_data = None;
+        assert!(server_version(&untrusted).await.is_err());
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
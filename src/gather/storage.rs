@@ -1,22 +1,45 @@
 use std::{
-    collections::HashMap, fs::File, io::Read as _, ops::Deref as _, path::PathBuf, pin::pin,
+    collections::HashMap,
+    ffi::OsStr,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    ops::{Deref as _, Range},
+    path::{Path, PathBuf},
+    pin::pin,
     sync::Arc,
 };
 
-use anyhow::bail;
+use anyhow::{Context as _, bail};
 use base64::{Engine as _, prelude::BASE64_STANDARD};
 use cached::cached;
 use derive_more::Deref;
-use flate2::read::GzDecoder;
-use oci_client::{Client, Reference, manifest::OciDescriptor, secrets::RegistryAuth};
+use futures::{StreamExt as _, TryStreamExt as _, stream};
+use k8s_openapi::serde_json;
+use oci_client::{
+    Client, Reference,
+    manifest::{OciDescriptor, OciImageManifest},
+    secrets::RegistryAuth,
+};
+use tempfile::{NamedTempFile, TempDir};
 use tokio::io::{AsyncWrite, AsyncWriteExt as _};
+use walkdir::WalkDir;
+use zip::ZipArchive;
 
-use crate::gather::writer::ManifestConfig;
+use crate::gather::{
+    blob_cache::BlobCache,
+    compression::{LayerCompression, ZIP_EXTENSION, is_tar, tar_decoder},
+    encryption::{Decryption, ENCRYPTED_EXTENSION, is_encrypted},
+    http::{HttpSource, MissingRange, RangeReader},
+    s3::S3State,
+    writer::{Archive, ManifestConfig, YamlPath},
+};
 
 #[derive(Clone)]
 pub enum Storage {
     FS,
     OCI(Box<OCIState>),
+    Zip(Arc<ZipState>),
+    S3(Arc<S3State>),
 }
 
 #[derive(Clone)]
@@ -26,6 +49,62 @@ pub struct OCIState {
     pub client: Client,
     pub config: ManifestConfig,
     pub index: Arc<HashMap<PathBuf, Descriptor>>,
+    pub decryption: Option<Decryption>,
+    pub cache: Option<BlobCache>,
+}
+
+/// Signature of zip central directory file headers.
+const ZIP_CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+
+/// Signature of the zip end of central directory record.
+const ZIP_EOCD_SIGNATURE: u32 = 0x06054b50;
+
+/// Signature of the zip64 end of central directory locator.
+const ZIP64_EOCD_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+
+/// Size of the zip end of central directory record without the comment.
+const ZIP_EOCD_SIZE: u64 = 22;
+
+/// Size of the zip64 end of central directory locator.
+const ZIP64_EOCD_LOCATOR_SIZE: u64 = 20;
+
+/// Size of the zip64 end of central directory record without the extensible data.
+const ZIP64_EOCD_SIZE: u64 = 56;
+
+/// Size of zip local file headers without the file name and extra field.
+const ZIP_LOCAL_HEADER_SIZE: u64 = 30;
+
+/// Bytes fetched past the compressed data of remote zip entries, for local extra fields
+/// and data descriptors which are not described by the central directory.
+const ZIP_ENTRY_SLACK: u64 = 1024;
+
+/// Range requests made for a single remote zip entry before the read fails.
+const MAX_ENTRY_FETCHES: usize = 4;
+
+/// Archives read from the entries of a zip file, without extracting it.
+pub struct ZipState {
+    pub source: String,
+    // Cloned for every read, clones share the parsed central directory.
+    archive: ZipArchive<ZipReader>,
+    index: HashMap<PathBuf, usize>,
+    // Fetches missing ranges of zip files read over HTTP.
+    remote: Option<HttpSource>,
+    // Byte ranges of the remote entries, from the local header to the compressed data end.
+    ranges: HashMap<usize, Range<u64>>,
+}
+
+#[derive(Clone)]
+enum ZipReader {
+    File(FileReader),
+    Http(RangeReader),
+}
+
+/// Reader with its own position over a shared file, so clones read independently.
+#[derive(Clone)]
+struct FileReader {
+    file: Arc<File>,
+    len: u64,
+    position: u64,
 }
 
 #[derive(Clone, Deref)]
@@ -42,6 +121,16 @@ impl Storage {
         }
     }
 
+    /// Returns the image reference or the zip file source the archives are read from.
+    pub fn source(&self) -> Option<String> {
+        match self {
+            Storage::FS => None,
+            Storage::OCI(oci_state) => Some(oci_state.reference.whole()),
+            Storage::Zip(zip_state) => Some(zip_state.source.clone()),
+            Storage::S3(s3_state) => Some(s3_state.location.to_string()),
+        }
+    }
+
     pub async fn read_raw(&self, path: PathBuf) -> anyhow::Result<String> {
         match self {
             Storage::FS => {
@@ -51,6 +140,8 @@ impl Storage {
                 Ok(data)
             }
             Storage::OCI(oci_state) => Ok(oci_state.read_raw(path).await?),
+            Storage::Zip(zip_state) => zip_state.read_raw(&path).await,
+            Storage::S3(s3_state) => s3_state.read_raw(&path).await,
         }
     }
 
@@ -64,13 +155,75 @@ impl Storage {
                 Ok(out.write(data.as_bytes()).await?)
             }
             Storage::OCI(oci_state) => Ok(oci_state.read(path, out).await?),
+            Storage::Zip(_) | Storage::S3(_) => {
+                let data = self.read_raw(path).await?;
+                let mut out = pin!(out);
+                Ok(out.write(data.as_bytes()).await?)
+            }
         }
     }
 
+    /// Extracts archives into a temporary directory, which can be served as a regular
+    /// archive location. When the path is a compressed archive file, it is extracted.
+    /// Otherwise encrypted archives found under the path are decrypted and extracted.
+    pub fn unpack(path: &Path, decryption: Option<&Decryption>) -> anyhow::Result<Option<TempDir>> {
+        let archives: Vec<PathBuf> = match path.is_file() {
+            true => vec![path.to_path_buf()],
+            false if decryption.is_some() => WalkDir::new(path)
+                .max_depth(5)
+                .same_file_system(true)
+                .into_iter()
+                .filter_map(Result::ok)
+                .map(|entry| entry.into_path())
+                .filter(|path| path.extension() == Some(OsStr::new(ENCRYPTED_EXTENSION)))
+                .collect(),
+            false => vec![],
+        };
+        if archives.is_empty() {
+            return Ok(None);
+        }
+
+        let dir = TempDir::new()?;
+        for path in archives {
+            let file = BufReader::new(File::open(&path)?);
+            let name = path.to_string_lossy();
+            let (name, reader): (_, Box<dyn Read>) = match (
+                name.strip_suffix(&format!(".{ENCRYPTED_EXTENSION}")),
+                decryption,
+            ) {
+                (Some(name), Some(decryption)) => {
+                    tracing::info!(path = %path.display(), "Decrypting archive");
+                    (name, Box::new(decryption.reader(file)?))
+                }
+                (Some(_), None) => bail!(
+                    "archive {name} is encrypted, provide --identity or --decrypt-passphrase to decrypt it"
+                ),
+                (None, _) => (name.as_ref(), Box::new(file)),
+            };
+
+            if is_tar(name) {
+                tar::Archive::new(tar_decoder(name, reader)?).unpack(dir.path())?;
+            } else if name.ends_with(&format!(".{ZIP_EXTENSION}")) {
+                // Zip archives are read with random access, so decrypted ones are streamed
+                // into a temporary file first.
+                let mut decrypted = NamedTempFile::new()?;
+                io::copy(&mut { reader }, &mut decrypted)?;
+                decrypted.rewind()?;
+                zip::ZipArchive::new(BufReader::new(decrypted))?.extract(dir.path())?;
+            } else {
+                tracing::warn!(path = %path.display(), "Skipping unknown archive file");
+            }
+        }
+
+        Ok(Some(dir))
+    }
+
     pub fn exist(&self, path: &PathBuf) -> bool {
         match self {
             Storage::FS => path.exists(),
             Storage::OCI(ocistate) => ocistate.index.contains_key(path),
+            Storage::Zip(zip_state) => zip_state.index.contains_key(path),
+            Storage::S3(s3_state) => s3_state.contains(path),
         }
     }
 
@@ -86,71 +239,519 @@ impl Storage {
                 }
             }
             Storage::OCI(ocistate) => {
-                let pattern = glob::Pattern::new(path)?;
-                for path in ocistate.index.keys() {
-                    if pattern.matches(
-                        path.to_str().map_or_else(
-                            || bail!("Unable to convert path to string: {path:?}"),
-                            Ok,
-                        )?,
-                    ) {
-                        paths.push(path.clone());
+                paths = Self::matching_keys(path, ocistate.index.keys())?;
+            }
+            Storage::Zip(zip_state) => {
+                paths = Self::matching_keys(path, zip_state.index.keys())?;
+            }
+            Storage::S3(s3_state) => {
+                paths = Self::matching_keys(path, s3_state.paths())?;
+            }
+        };
+        Ok(paths)
+    }
+
+    fn matching_keys<'a>(
+        pattern: &str,
+        keys: impl Iterator<Item = &'a PathBuf>,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        let pattern = glob::Pattern::new(pattern)?;
+        for path in keys {
+            if pattern.matches(
+                path.to_str()
+                    .map_or_else(|| bail!("Unable to convert path to string: {path:?}"), Ok)?,
+            ) {
+                paths.push(path.clone());
+            }
+        }
+        Ok(paths)
+    }
+}
+
+impl ZipState {
+    /// Opens the zip file and indexes its file entries.
+    pub fn new(source: String, file: File) -> anyhow::Result<Self> {
+        let reader = FileReader {
+            len: file.metadata()?.len(),
+            file: Arc::new(file),
+            position: 0,
+        };
+        let archive = ZipArchive::new(ZipReader::File(reader))?;
+        Ok(Self::with_archive(source, archive, None, HashMap::new()))
+    }
+
+    /// Opens the zip file over HTTP. The central directory is located from the end of
+    /// central directory record in the already fetched tail, and fetched with a single
+    /// range request when the tail does not hold it.
+    pub async fn remote(source: HttpSource) -> anyhow::Result<Self> {
+        let central_directory = Self::central_directory(&source)
+            .await
+            .with_context(|| format!("{} is not a zip file", source.source))?;
+        if !source.reader.contains(&central_directory) {
+            source.fetch_range(central_directory).await?;
+        }
+
+        let archive = ZipArchive::new(ZipReader::Http(source.reader.clone()))
+            .with_context(|| format!("{} is not a zip file", source.source))?;
+        let ranges = Self::entry_ranges(&archive, source.reader.clone())
+            .inspect_err(|error| {
+                tracing::debug!(%error, source = source.source, "Unable to read zip entry ranges");
+            })
+            .unwrap_or_default();
+
+        Ok(Self::with_archive(
+            source.source.clone(),
+            archive,
+            Some(source),
+            ranges,
+        ))
+    }
+
+    /// Returns the byte range of the central directory, which ends where the zip64 or
+    /// the regular end of central directory record starts.
+    async fn central_directory(source: &HttpSource) -> anyhow::Result<Range<u64>> {
+        let mut reader = source.reader.clone();
+        let len = reader.seek(SeekFrom::End(0))?;
+        let tail_len = len.min(ZIP_EOCD_SIZE + u64::from(u16::MAX));
+        let mut tail = vec![0; tail_len as usize];
+        reader.seek(SeekFrom::Start(len - tail_len))?;
+        reader.read_exact(&mut tail)?;
+
+        let u32_at = |data: &[u8], i: usize| {
+            u32::from_le_bytes(data[i..i + 4].try_into().unwrap_or_default())
+        };
+        let u64_at = |data: &[u8], i: usize| {
+            u64::from_le_bytes(data[i..i + 8].try_into().unwrap_or_default())
+        };
+        let Some(eocd) = (0..=tail.len().saturating_sub(ZIP_EOCD_SIZE as usize))
+            .rev()
+            .find(|&i| u32_at(&tail, i) == ZIP_EOCD_SIGNATURE)
+        else {
+            bail!("end of central directory record not found");
+        };
+        let eocd_offset = len - tail_len + eocd as u64;
+
+        let locator = eocd.checked_sub(ZIP64_EOCD_LOCATOR_SIZE as usize);
+        if let Some(locator) = locator.filter(|&i| u32_at(&tail, i) == ZIP64_EOCD_LOCATOR_SIGNATURE)
+        {
+            let offset = u64_at(&tail, locator + 8);
+            let record = offset..offset.saturating_add(ZIP64_EOCD_SIZE);
+            if !source.reader.contains(&record) {
+                source.fetch_range(record).await?;
+            }
+            let mut record = [0; ZIP64_EOCD_SIZE as usize];
+            reader.seek(SeekFrom::Start(offset))?;
+            reader.read_exact(&mut record)?;
+            let size = u64_at(&record, 40);
+            return Ok(offset.saturating_sub(size)..offset);
+        }
+
+        let size = u64::from(u32_at(&tail, eocd + 12));
+        Ok(eocd_offset.saturating_sub(size)..eocd_offset)
+    }
+
+    fn with_archive(
+        source: String,
+        archive: ZipArchive<ZipReader>,
+        remote: Option<HttpSource>,
+        mut ranges: HashMap<String, Range<u64>>,
+    ) -> Self {
+        let index: HashMap<PathBuf, usize> = (0..archive.len())
+            .filter_map(|i| {
+                let name = archive.name_for_index(i)?;
+                (!name.ends_with('/')).then(|| (PathBuf::from(name), i))
+            })
+            .collect();
+        let ranges = (0..archive.len())
+            .filter_map(|i| Some((i, ranges.remove(archive.name_for_index(i)?)?)))
+            .collect();
+
+        Self {
+            source,
+            archive,
+            index,
+            remote,
+            ranges,
+        }
+    }
+
+    /// Returns the byte range of the entries by name, from the local header to the end
+    /// of the compressed data, parsed from the already fetched central directory.
+    fn entry_ranges(
+        archive: &ZipArchive<ZipReader>,
+        mut reader: RangeReader,
+    ) -> anyhow::Result<HashMap<String, Range<u64>>> {
+        let mut ranges = HashMap::new();
+        reader.seek(SeekFrom::Start(archive.central_directory_start()))?;
+        let mut header = [0u8; 46];
+        loop {
+            match reader.read_exact(&mut header) {
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+                result => result?,
+            }
+            let u16_at = |i: usize| u64::from(u16::from_le_bytes([header[i], header[i + 1]]));
+            let u32_at = |i: usize| {
+                u64::from(u32::from_le_bytes([
+                    header[i],
+                    header[i + 1],
+                    header[i + 2],
+                    header[i + 3],
+                ]))
+            };
+            if u32_at(0) != u64::from(ZIP_CENTRAL_HEADER_SIGNATURE) {
+                break;
+            }
+
+            let (mut compressed, uncompressed, mut offset) = (u32_at(20), u32_at(24), u32_at(42));
+            let mut name = vec![0; u16_at(28) as usize];
+            reader.read_exact(&mut name)?;
+            let mut extra = vec![0; u16_at(30) as usize];
+            reader.read_exact(&mut extra)?;
+            reader.seek(SeekFrom::Current(u16_at(32) as i64))?;
+
+            // Zip64 extended information holds the values which do not fit in 32 bits,
+            // in the order of the header fields.
+            let mut fields = extra.as_slice();
+            while let [id_low, id_high, len_low, len_high, rest @ ..] = fields {
+                let len = usize::from(u16::from_le_bytes([*len_low, *len_high])).min(rest.len());
+                if u16::from_le_bytes([*id_low, *id_high]) == 0x0001 {
+                    let mut values = rest[..len]
+                        .chunks_exact(8)
+                        .map(|value| u64::from_le_bytes(value.try_into().unwrap_or_default()));
+                    let max = u64::from(u32::MAX);
+                    if uncompressed == max {
+                        values.next();
+                    }
+                    if compressed == max {
+                        compressed = values.next().unwrap_or(compressed);
+                    }
+                    if offset == max {
+                        offset = values.next().unwrap_or(offset);
                     }
                 }
+                fields = &rest[len..];
             }
+
+            let start = offset.saturating_add(archive.offset());
+            let end = start
+                .saturating_add(ZIP_LOCAL_HEADER_SIZE + name.len() as u64 + extra.len() as u64)
+                .saturating_add(compressed)
+                .saturating_add(ZIP_ENTRY_SLACK);
+            ranges.insert(String::from_utf8_lossy(&name).into_owned(), start..end);
+        }
+
+        Ok(ranges)
+    }
+
+    /// Returns the snapshots in the zip file, found by their "version.yaml" as in
+    /// an archive search on the filesystem.
+    pub fn archives(&self) -> Vec<Archive> {
+        find_archives(self.index.keys())
+    }
+
+    /// Reads the entry. Entries of remote zip files are fetched on demand with a single
+    /// request for the whole entry, and the read is retried once it is fetched.
+    async fn read_raw(&self, path: &Path) -> anyhow::Result<String> {
+        let Some(index) = self.index.get(path) else {
+            bail!("{} not found in {}", path.display(), self.source);
         };
-        Ok(paths)
+
+        for _ in 0..MAX_ENTRY_FETCHES {
+            let error = match self.read_entry(*index).await {
+                Ok(data) => return Ok(data),
+                Err(error) => error,
+            };
+            let (Some(remote), Some(offset)) = (&self.remote, MissingRange::find(&error)) else {
+                return Err(error);
+            };
+            match self.ranges.get(index).filter(|range| range.end > offset) {
+                Some(range) => remote.fetch_range(offset..range.end).await?,
+                None => remote.fetch(offset).await?,
+            }
+        }
+
+        bail!(
+            "{} was not read from {} after {MAX_ENTRY_FETCHES} range requests",
+            path.display(),
+            self.source
+        )
+    }
+
+    /// Reads the entry on the blocking thread pool, with its own copy of the archive.
+    async fn read_entry(&self, index: usize) -> anyhow::Result<String> {
+        let mut archive = self.archive.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut data = String::new();
+            archive.by_index(index)?.read_to_string(&mut data)?;
+            Ok(data)
+        })
+        .await?
+    }
+}
+
+/// Returns the snapshots among the paths of a remote storage, found by their
+/// "version.yaml" as in an archive search on the filesystem.
+pub fn find_archives<'a>(paths: impl Iterator<Item = &'a PathBuf>) -> Vec<Archive> {
+    let mut archives: Vec<Archive> = paths
+        .filter(|path| path.components().count() <= 5)
+        .filter(|path| path.file_name() == Some(OsStr::new("version.yaml")))
+        .filter_map(|path| path.parent())
+        .map(|path| Archive::new(path.to_path_buf()))
+        .collect();
+    archives.sort_by_key(Archive::path);
+    archives
+}
+
+impl Read for ZipReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            ZipReader::File(file) => file.read(buf),
+            ZipReader::Http(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        #[cfg(unix)]
+        let read = std::os::unix::fs::FileExt::read_at(self.file.as_ref(), buf, self.position)?;
+        #[cfg(windows)]
+        let read =
+            std::os::windows::fs::FileExt::seek_read(self.file.as_ref(), buf, self.position)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for FileReader {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl Seek for ZipReader {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            ZipReader::File(file) => file.seek(position),
+            ZipReader::Http(reader) => reader.seek(position),
+        }
     }
 }
 
 impl OCIState {
-    async fn read_raw(&self, path: PathBuf) -> anyhow::Result<String> {
-        let layer = self
-            .index
-            .get(&path)
-            .ok_or_else(|| anyhow::anyhow!("missing OCI layer entry for path: {path:?}"))?;
-        let mut data = Vec::with_capacity(layer.size as usize);
-        self.pull_blob(layer, &mut data).await?;
-        Ok(String::from_utf8(data)?)
+    /// Pulls the image manifest and config, and builds the index of archive paths to layers.
+    pub async fn pull(
+        client: Client,
+        reference: Reference,
+        auth: RegistryAuth,
+        decryption: Option<Decryption>,
+        cache: Option<BlobCache>,
+    ) -> anyhow::Result<Self> {
+        let (manifest, _) = client.pull_image_manifest(&reference, &auth).await?;
+        let config = pull_blob_cached(
+            &client,
+            &reference,
+            &auth,
+            &manifest.config,
+            true,
+            None,
+            cache.as_ref(),
+        )
+        .await?;
+        let config: ManifestConfig = serde_json::from_slice(&config)?;
+        let decryption = match (&config.encryption_key, decryption) {
+            (Some(key), Some(decryption)) => Some(decryption.layer_key(key)?),
+            (Some(_), None) => bail!(
+                "archive is encrypted, provide --identity or --decrypt-passphrase to decrypt it"
+            ),
+            (None, _) => None,
+        };
+        let index = Arc::new(
+            Self::collect_index(
+                &client,
+                &reference,
+                &auth,
+                manifest,
+                decryption.as_ref(),
+                cache.as_ref(),
+            )
+            .await?,
+        );
+
+        Ok(Self {
+            reference,
+            auth,
+            client,
+            config,
+            index,
+            decryption,
+            cache,
+        })
     }
 
-    async fn read<W: AsyncWrite>(&self, path: PathBuf, out: W) -> anyhow::Result<usize> {
-        let layer = self
+    async fn collect_index(
+        client: &Client,
+        reference: &Reference,
+        auth: &RegistryAuth,
+        manifest: OciImageManifest,
+        decryption: Option<&Decryption>,
+        cache: Option<&BlobCache>,
+    ) -> anyhow::Result<HashMap<PathBuf, Descriptor>> {
+        let mut index = HashMap::new();
+
+        for layer in manifest.layers {
+            let Some(annotations) = layer.annotations.clone() else {
+                anyhow::bail!("manifest layer contains no org.opencontainers.image.title annoation")
+            };
+            let path = &annotations["org.opencontainers.image.title"];
+            index.insert(PathBuf::from(path), Descriptor::OciDescriptor(layer));
+        }
+
+        let Some(index_layer) = index.get(&PathBuf::from("index.yaml")) else {
+            return Ok(index);
+        };
+
+        let data = pull_blob_cached(
+            client,
+            reference,
+            auth,
+            index_layer.deref(),
+            true,
+            decryption,
+            cache,
+        )
+        .await?;
+        let resource_paths: Vec<YamlPath> = serde_saphyr::from_slice(&data)?;
+        for yaml_path in resource_paths {
+            let resource_path = yaml_path.path;
+            let layer_path = match yaml_path.layer {
+                Some(layer) => layer,
+                None => match resource_path.parent() {
+                    Some(parent_path) => parent_path.with_extension("yaml"),
+                    None => anyhow::bail!(format!(
+                        "index layer must reference a parent list object: {resource_path:?}"
+                    )),
+                },
+            };
+
+            let Some(parent) = index.get(&layer_path) else {
+                anyhow::bail!(format!(
+                    "index layer must reference a packed layer: {resource_path:?}"
+                ))
+            };
+
+            index.insert(
+                resource_path,
+                Descriptor::ListOciDescriptor(parent.deref().clone(), yaml_path.from, yaml_path.to),
+            );
+        }
+
+        Ok(index)
+    }
+
+    /// Downloads all layers into the disk cache in a background task.
+    pub fn prefetch(&self, buffer_size: usize) {
+        let Some(cache) = self.cache.clone() else {
+            tracing::warn!("Prefetching requires the blob cache, layers are pulled on demand");
+            return;
+        };
+
+        let layers: HashMap<&str, &OciDescriptor> = self
             .index
-            .get(&path)
-            .ok_or_else(|| anyhow::anyhow!("missing OCI layer entry for path: {path:?}"))?;
+            .values()
+            .map(|descriptor| (descriptor.digest.as_str(), descriptor.deref()))
+            .collect();
+        let layers: Vec<OciDescriptor> = layers.into_values().cloned().collect();
+        let state = self.clone();
+        tokio::spawn(async move {
+            let mut missing = Vec::with_capacity(layers.len());
+            for layer in layers {
+                if !cache.contains(&layer.digest).await {
+                    missing.push(layer);
+                }
+            }
+            let count = missing.len();
+            let result = stream::iter(missing)
+                .map(|layer| {
+                    let (state, cache) = (&state, &cache);
+                    async move {
+                        cache
+                            .fetch(&state.client, &state.reference, &state.auth, &layer)
+                            .await
+                    }
+                })
+                .buffer_unordered(buffer_size)
+                .try_for_each(|_| async { Ok(()) })
+                .await;
+            match result {
+                Ok(()) => tracing::info!(layers = count, "Prefetched OCI layers"),
+                Err(error) => tracing::warn!(%error, "Unable to prefetch OCI layers"),
+            }
+        });
+    }
+
+    async fn read_raw(&self, path: PathBuf) -> anyhow::Result<String> {
+        let layer = self.pull_layer(&path).await?;
+        Ok(String::from_utf8(self.layer_file(&layer, &path)?.to_vec())?)
+    }
 
-        let size = self.pull_blob(layer, out).await?;
-        Ok(size)
+    async fn read<W: AsyncWrite>(&self, path: PathBuf, out: W) -> anyhow::Result<usize> {
+        let layer = self.pull_layer(&path).await?;
+        let data = self.layer_file(&layer, &path)?;
+        pin!(out).write_all(data).await?;
+        Ok(data.len())
     }
 
-    async fn pull_blob<W: AsyncWrite>(
-        &self,
-        descriptor: &Descriptor,
-        out: W,
-    ) -> anyhow::Result<usize> {
-        let data = pull_blob_cached(
+    /// Pulls and decodes the layer holding the file. Files packed into the same layer
+    /// are taken from it with `layer_file`, so the layer is pulled once for all of them.
+    pub async fn pull_layer(&self, path: &Path) -> anyhow::Result<Vec<u8>> {
+        let descriptor = self.descriptor(path)?;
+        pull_blob_cached(
             &self.client,
             &self.reference,
             &self.auth,
             descriptor.deref(),
             self.config.compressed || matches!(descriptor, Descriptor::ListOciDescriptor(..)),
+            self.decryption.as_ref(),
+            self.cache.as_ref(),
         )
-        .await?;
-        let mut out = pin!(out);
+        .await
+    }
 
-        let Descriptor::ListOciDescriptor(_, from, to) = descriptor else {
-            out.write_all(&data).await?;
-            return Ok(data.len());
-        };
+    /// Returns the data of the file within its decoded layer.
+    pub fn layer_file<'a>(&self, layer: &'a [u8], path: &Path) -> anyhow::Result<&'a [u8]> {
+        match self.descriptor(path)? {
+            Descriptor::ListOciDescriptor(_, from, to) => layer
+                .get(*from..*to)
+                .with_context(|| format!("{} is outside of its packed layer", path.display())),
+            Descriptor::OciDescriptor(_) => Ok(layer),
+        }
+    }
 
-        out.write_all(&data[*from..*to]).await?;
-        Ok(*to - *from)
+    fn descriptor(&self, path: &Path) -> anyhow::Result<&Descriptor> {
+        self.index
+            .get(path)
+            .ok_or_else(|| anyhow::anyhow!("missing OCI layer entry for path: {path:?}"))
     }
 }
 
+/// Pulls and decodes the blob. The last decoded blobs are kept in memory, while
+/// the disk cache holds the blobs as stored in the registry.
 #[cached(
     result = true,
+    size = 16,
     key = "String",
     convert = r#"{ format!("{}@{}", reference, descriptor.digest) }"#
 )]
@@ -160,21 +761,33 @@ pub(crate) async fn pull_blob_cached(
     auth: &RegistryAuth,
     descriptor: &OciDescriptor,
     encoded: bool,
+    decryption: Option<&Decryption>,
+    blob_cache: Option<&BlobCache>,
 ) -> anyhow::Result<Vec<u8>> {
-    client
-        .store_auth_if_needed(reference.registry(), auth)
-        .await;
-    let mut out = Vec::with_capacity(descriptor.size as usize);
-    client.pull_blob(reference, &descriptor, &mut out).await?;
-
-    if !encoded {
-        return Ok(out);
-    }
-
-    let data = BASE64_STANDARD.decode(out)?;
-    let mut dec = GzDecoder::new(data.as_slice());
+    let out = match blob_cache {
+        Some(cache) => cache.fetch(client, reference, auth, descriptor).await?,
+        None => {
+            client
+                .store_auth_if_needed(reference.registry(), auth)
+                .await;
+            let mut out = Vec::with_capacity(descriptor.size as usize);
+            client.pull_blob(reference, &descriptor, &mut out).await?;
+            out
+        }
+    };
 
-    let mut objects = vec![];
-    dec.read_to_end(&mut objects)?;
-    Ok(objects)
+    let compression = LayerCompression::from_media_type(&descriptor.media_type);
+    let data = match compression {
+        LayerCompression::Gzip if !encoded => return Ok(out),
+        LayerCompression::Gzip => BASE64_STANDARD.decode(out)?,
+        LayerCompression::Zstd => out,
+    };
+    let data = match decryption {
+        Some(decryption) if is_encrypted(&data) => decryption.decrypt(&data)?,
+        None if is_encrypted(&data) => {
+            bail!("OCI layer is encrypted, a decryption key is required")
+        }
+        _ => data,
+    };
+    compression.decompress(&data)
 }
@@ -0,0 +1,383 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::Context as _;
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use clap::Parser;
+use kube::config::AuthInfo;
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    Issuer, KeyPair, KeyUsagePurpose, PKCS_ED25519, SanType,
+};
+use rustls::{
+    RootCertStore, ServerConfig,
+    crypto::{CryptoProvider, aws_lc_rs},
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use secrecy::SecretString;
+use serde::Deserialize;
+
+/// Validity of the generated certificates, which are regenerated on every start.
+const CERTIFICATE_VALIDITY: time::Duration = time::Duration::days(365);
+
+/// Subject of the generated client certificate, the user and group Kubernetes
+/// derives from its common name and organization.
+pub const CLIENT_USER: &str = "crust-gather";
+pub const CLIENT_GROUP: &str = "system:masters";
+
+#[derive(Parser, Clone, Default, Deserialize)]
+pub struct TlsSettings {
+    /// Serve HTTPS with a certificate issued by a generated self-signed CA. The CA is
+    /// written into the generated kubeconfig and regenerated on every start.
+    ///
+    /// Example:
+    ///     --tls
+    #[arg(long)]
+    #[serde(default)]
+    pub tls: bool,
+
+    /// Serve HTTPS with the PEM certificate chain instead of a generated certificate.
+    /// Clients need to trust its issuer.
+    ///
+    /// Example:
+    ///     --tls-cert-file=tls.crt --tls-private-key-file=tls.key
+    #[arg(long, value_name = "PATH", requires = "tls_private_key_file")]
+    #[serde(default)]
+    pub tls_cert_file: Option<PathBuf>,
+
+    /// PEM private key of the "--tls-cert-file" certificate.
+    #[arg(long, value_name = "PATH", requires = "tls_cert_file")]
+    #[serde(default)]
+    pub tls_private_key_file: Option<PathBuf>,
+
+    /// Additional host names and IP addresses of the generated serving certificate.
+    /// "localhost", the loopback addresses and the socket address are always included.
+    ///
+    /// Example:
+    ///     --tls-san=crust-gather.example.com --tls-san=10.0.0.5
+    #[arg(long, value_name = "NAME", conflicts_with = "tls_cert_file")]
+    #[serde(default)]
+    pub tls_san: Vec<String>,
+
+    /// Require the bearer token on every request. Without a value a random token is
+    /// generated. The token is written into the generated kubeconfig.
+    ///
+    /// Example:
+    ///     --auth-token
+    ///     --auth-token=$(openssl rand -hex 32)
+    #[arg(
+        long,
+        env = "CRUST_GATHER_AUTH_TOKEN",
+        hide_env_values = true,
+        value_name = "TOKEN",
+        num_args = 0..=1,
+        default_missing_value = ""
+    )]
+    #[serde(default)]
+    pub auth_token: Option<String>,
+
+    /// Require client certificates, implies HTTPS. Without "--client-ca-file" a client
+    /// certificate issued by the generated CA is written into the generated kubeconfig.
+    /// With "--auth-token" either the token or a client certificate is accepted.
+    ///
+    /// Example:
+    ///     --client-cert-auth
+    #[arg(long)]
+    #[serde(default)]
+    pub client_cert_auth: bool,
+
+    /// Accept client certificates signed by the PEM CA bundle, implies "--client-cert-auth".
+    /// Clients bring their own certificates, none is written into the generated kubeconfig.
+    /// "kubectl auth can-i" is answered for the user and groups in the certificate subject,
+    /// and impersonation requires the "impersonate" permission in the snapshot.
+    ///
+    /// Example:
+    ///     --client-ca-file=ca.crt
+    #[arg(long, value_name = "PATH")]
+    #[serde(default)]
+    pub client_ca_file: Option<PathBuf>,
+}
+
+impl TlsSettings {
+    /// Loads or generates the certificates of the server listening on the socket.
+    pub fn to_security(&self, socket: SocketAddr) -> anyhow::Result<ServeSecurity> {
+        let provider = Arc::new(aws_lc_rs::default_provider());
+        let token = match self.auth_token.as_deref() {
+            Some("") => Some(hex::encode(random::<32>(&provider)?)),
+            token => token.map(str::to_string),
+        };
+        let client_certificates = self.client_cert_auth || self.client_ca_file.is_some();
+        let authentication = Authentication {
+            token,
+            client_certificates,
+        };
+
+        if !self.tls && self.tls_cert_file.is_none() && !client_certificates {
+            if authentication.token.is_some() {
+                tracing::warn!("The bearer token is sent over plain HTTP, use --tls to encrypt it");
+            }
+            return Ok(ServeSecurity {
+                authentication,
+                ..Default::default()
+            });
+        }
+
+        let ca = CertificateAuthority::generate()?;
+        let mut security = ServeSecurity::default();
+        let (chain, key) = match (&self.tls_cert_file, &self.tls_private_key_file) {
+            (Some(cert), Some(key)) => (
+                CertificateDer::pem_file_iter(cert)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .with_context(|| format!("unable to read {}", cert.display()))?,
+                PrivateKeyDer::from_pem_file(key)
+                    .with_context(|| format!("unable to read {}", key.display()))?,
+            ),
+            _ => {
+                let mut params = leaf("crust-gather", None, ExtendedKeyUsagePurpose::ServerAuth);
+                params.subject_alt_names = subject_alt_names(socket.ip(), &self.tls_san)?;
+                let (cert, key) = ca.issue(params)?;
+                security.certificate_authority = Some(ca.certificate.pem());
+                (
+                    vec![cert.der().clone()],
+                    PrivateKeyDer::try_from(key.serialize_der())
+                        .map_err(|err| anyhow::anyhow!(err))?,
+                )
+            }
+        };
+
+        // A client certificate is issued even when it is not required, kubectl prompts
+        // for a username over HTTPS without any credentials.
+        let mut roots = RootCertStore::empty();
+        match &self.client_ca_file {
+            Some(path) => {
+                for cert in CertificateDer::pem_file_iter(path)
+                    .with_context(|| format!("unable to read {}", path.display()))?
+                {
+                    roots.add(cert?)?;
+                }
+            }
+            None => {
+                roots.add(ca.certificate.der().clone())?;
+                let (cert, key) = ca.issue(leaf(
+                    CLIENT_USER,
+                    Some(CLIENT_GROUP),
+                    ExtendedKeyUsagePurpose::ClientAuth,
+                ))?;
+                security.client_identity = Some((cert.pem(), key.serialize_pem()));
+            }
+        }
+
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider.clone());
+        let verifier = match (client_certificates, &authentication.token) {
+            (true, None) => verifier,
+            _ => verifier.allow_unauthenticated(),
+        };
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier.build()?)
+            .with_single_cert(chain, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        security.tls = Some(Arc::new(config));
+        security.authentication = authentication;
+        Ok(security)
+    }
+}
+
+/// TLS configuration and credentials of the served API.
+#[derive(Clone, Default)]
+pub struct ServeSecurity {
+    /// Plain HTTP is served without a TLS configuration.
+    pub tls: Option<Arc<ServerConfig>>,
+    pub authentication: Authentication,
+    // PEM CA of the generated serving certificate.
+    certificate_authority: Option<String>,
+    // PEM certificate and private key issued by the generated CA for the kubeconfig.
+    client_identity: Option<(String, String)>,
+}
+
+impl ServeSecurity {
+    pub fn scheme(&self) -> &'static str {
+        match self.tls {
+            Some(_) => "https",
+            None => "http",
+        }
+    }
+
+    /// Base64 encoded PEM for the "certificate-authority-data" of the kubeconfig cluster.
+    pub fn certificate_authority_data(&self) -> Option<String> {
+        self.certificate_authority
+            .as_ref()
+            .map(|ca| BASE64_STANDARD.encode(ca))
+    }
+
+    /// Credentials of the kubeconfig user, if there are any.
+    pub fn auth_info(&self) -> Option<AuthInfo> {
+        let client_identity = self.client_identity.as_ref();
+        if self.authentication.token.is_none() && client_identity.is_none() {
+            return None;
+        }
+
+        Some(AuthInfo {
+            token: self.authentication.token.clone().map(SecretString::from),
+            client_certificate_data: client_identity.map(|(cert, _)| BASE64_STANDARD.encode(cert)),
+            client_key_data: client_identity
+                .map(|(_, key)| SecretString::from(BASE64_STANDARD.encode(key))),
+            ..Default::default()
+        })
+    }
+}
+
+/// Identity of a verified client certificate on the connection. Like Kubernetes, the
+/// user is the subject common name and the groups are the subject organizations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientCertificate {
+    pub user: String,
+    pub groups: Vec<String>,
+}
+
+impl ClientCertificate {
+    /// Reads the identity from the DER certificate, None when it has no common name.
+    pub fn from_der(certificate: &[u8]) -> Option<Self> {
+        let (_, certificate) = x509_parser::parse_x509_certificate(certificate).ok()?;
+        let subject = certificate.subject();
+        let user = subject.iter_common_name().next()?.as_str().ok()?;
+        Some(Self {
+            user: user.to_string(),
+            groups: subject
+                .iter_organization()
+                .filter_map(|organization| organization.as_str().ok())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+/// Credentials accepted by the server. Requests are authenticated by either of them.
+#[derive(Clone, Default)]
+pub struct Authentication {
+    token: Option<String>,
+    client_certificates: bool,
+}
+
+impl Authentication {
+    pub fn enabled(&self) -> bool {
+        self.token.is_some() || self.client_certificates
+    }
+
+    /// Checks the "Authorization" header, or the client certificate of the connection.
+    pub fn authenticate(&self, authorization: Option<&[u8]>, client_certificate: bool) -> bool {
+        if !self.enabled() || (self.client_certificates && client_certificate) {
+            return true;
+        }
+
+        let bearer = authorization.and_then(|header| header.strip_prefix(b"Bearer "));
+        match (&self.token, bearer) {
+            (Some(token), Some(bearer)) => constant_time_eq(token.as_bytes(), bearer),
+            _ => false,
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn random<const N: usize>(provider: &CryptoProvider) -> anyhow::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    provider
+        .secure_random
+        .fill(&mut bytes)
+        .map_err(|_| anyhow::anyhow!("unable to generate random bytes"))?;
+    Ok(bytes)
+}
+
+/// Self-signed Ed25519 CA issuing the generated certificates.
+struct CertificateAuthority {
+    issuer: Issuer<'static, KeyPair>,
+    certificate: rcgen::Certificate,
+}
+
+impl CertificateAuthority {
+    fn generate() -> anyhow::Result<Self> {
+        let mut params = params("crust-gather-ca", None);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let key = KeyPair::generate_for(&PKCS_ED25519)?;
+        let certificate = params.self_signed(&key)?;
+        Ok(Self {
+            issuer: Issuer::new(params, key),
+            certificate,
+        })
+    }
+
+    /// Issues a certificate with a new key for the subject.
+    fn issue(&self, params: CertificateParams) -> anyhow::Result<(rcgen::Certificate, KeyPair)> {
+        let key = KeyPair::generate_for(&PKCS_ED25519)?;
+        let certificate = params.signed_by(&key, &self.issuer)?;
+        Ok((certificate, key))
+    }
+}
+
+/// Parameters of a certificate valid from an hour ago, to tolerate clock skew.
+fn params(common_name: &str, organization: Option<&str>) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    if let Some(organization) = organization {
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, organization);
+    }
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    let now = time::OffsetDateTime::now_utc();
+    params.not_before = now - time::Duration::hours(1);
+    params.not_after = now + CERTIFICATE_VALIDITY;
+    params
+}
+
+/// Parameters of a certificate issued by the CA for the extended key usage.
+fn leaf(
+    common_name: &str,
+    organization: Option<&str>,
+    usage: ExtendedKeyUsagePurpose,
+) -> CertificateParams {
+    let mut params = params(common_name, organization);
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![usage];
+    params
+}
+
+/// Names of the serving certificate. An unspecified socket address is included as
+/// well, since the generated kubeconfig points to the socket address.
+fn subject_alt_names(socket: IpAddr, additional: &[String]) -> anyhow::Result<Vec<SanType>> {
+    let mut ips = vec![
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(Ipv6Addr::LOCALHOST),
+    ];
+    let mut hosts = vec!["localhost"];
+    for name in additional {
+        match name.parse() {
+            Ok(ip) => ips.push(ip),
+            Err(_) => hosts.push(name),
+        }
+    }
+    if !ips.contains(&socket) {
+        ips.push(socket);
+    }
+
+    let mut names = hosts
+        .into_iter()
+        .map(|host| {
+            Ok(SanType::DnsName(host.try_into().with_context(|| {
+                format!("invalid certificate host name {host}")
+            })?))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    names.extend(ips.into_iter().map(SanType::IpAddress));
+    Ok(names)
+}
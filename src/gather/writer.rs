@@ -1,49 +1,61 @@
 use anyhow::Context;
 use backon::{ExponentialBuilder, Retryable};
 use base64::{Engine as _, prelude::BASE64_STANDARD};
-use flate2::{Compression, write::GzEncoder};
 
-use chrono::Utc;
-use futures::{
-    StreamExt as _, TryStreamExt as _,
-    future::{self},
-    stream, try_join,
-};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::SigningKey;
 use json_patch::diff;
-use k8s_openapi::serde_json;
+use k8s_openapi::{apimachinery::pkg::version::Info, serde_json};
 use oci_client::{
     Client, Reference,
     client::{ClientConfig, Config},
     errors::OciDistributionError,
-    manifest::{
-        IMAGE_LAYER_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE, OciDescriptor, OciImageManifest, OciManifest,
-    },
+    manifest::{OciDescriptor, OciImageManifest, OciManifest},
     secrets::RegistryAuth,
 };
 use serde::{Deserialize, Serialize};
 use sha2::Digest as _;
 use std::{
     borrow::Cow,
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap, HashSet, hash_map::Entry},
     ffi::OsStr,
     fmt::Display,
-    fs::{DirBuilder, File},
-    io::{Read as _, Write as _},
-    ops::Deref,
-    path::PathBuf,
+    fs::{self, DirBuilder, File},
+    io::{self, Read as _, Seek as _, SeekFrom, Write as _},
+    path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
 use tar::{Builder, Header};
-use tokio::sync::Mutex;
+use tokio::{
+    runtime::Handle,
+    sync::{Semaphore, mpsc, oneshot},
+    task::JoinSet,
+};
 use tokio_util::bytes;
 use tracing::{debug, info, instrument};
 use walkdir::WalkDir;
-use zip::{ZipWriter, result::ZipError, write::SimpleFileOptions};
+use zip::{
+    ZipWriter,
+    result::ZipError,
+    write::{SimpleFileOptions, StreamWriter},
+};
 
 use crate::cli::DEFAULT_OCI_BUFFER_SIZE;
 use crate::gather::{
-    reader::{ArchiveReader, Reader},
+    compression::{
+        DEFAULT_XZ_LEVEL, DEFAULT_ZSTD_LEVEL, GZIP_EXTENSION, LayerCompression, TarEncoder,
+        XZ_EXTENSION, ZIP_EXTENSION, ZSTD_EXTENSION,
+    },
+    config::Secrets,
+    encryption::{ArchiveFile, Encryption, encrypt_layer, encrypted_path},
+    ignore::{IgnoreRules, has_changes},
+    manifest::{MANIFEST_PATH, Manifest, SIGNATURE_PATH, digest},
+    reader::{
+        ArchiveReader, KEYFRAME_INTERVAL, Keyframe, Reader, object_timestamp, patch_timestamp,
+    },
+    redact::Redaction,
+    s3::{S3Client, S3Location, S3Settings},
     storage::Storage,
 };
 
@@ -76,6 +88,12 @@ impl Display for ArchiveSearch {
     }
 }
 
+impl From<PathBuf> for ArchiveSearch {
+    fn from(value: PathBuf) -> Self {
+        Self(value)
+    }
+}
+
 impl From<&str> for ArchiveSearch {
     fn from(value: &str) -> Self {
         Self(PathBuf::from(value))
@@ -122,6 +140,11 @@ impl Archive {
             .unwrap_or(OsStr::new("snapshot"))
     }
 
+    /// Returns true when the archive is streamed to stdout, requested with "-" as the path.
+    pub fn is_stdout(&self) -> bool {
+        self.0 == Path::new("-")
+    }
+
     pub fn join(&self, path: ArchivePath) -> PathBuf {
         match path {
             ArchivePath::Empty => self.path(),
@@ -169,51 +192,206 @@ impl From<&str> for Archive {
 /// The Encoding enum represents the supported archive encoding formats.
 /// - Path indicates no encoding.
 /// - Gzip indicates gzip compression should be used.
+/// - Zstd indicates zstd compression with the given level should be used.
+/// - Xz indicates xz compression with the given preset should be used.
 /// - Zip indicates zip compression should be used.
 pub enum Encoding {
     #[default]
     Path,
     Gzip,
+    Zstd(i32),
+    Xz(u32),
     Zip,
     Oci(Reference),
 }
 
-impl From<&str> for Encoding {
-    fn from(value: &str) -> Self {
-        match value {
-            "zip" => Self::Zip,
-            "gzip" => Self::Gzip,
-            _ => Self::Path,
-        }
+impl TryFrom<&str> for Encoding {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let (name, level) = match value.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (value, None),
+        };
+
+        Ok(match (name, level) {
+            ("path", None) => Self::Path,
+            ("zip", None) => Self::Zip,
+            ("gzip", None) => Self::Gzip,
+            ("zstd", level) => {
+                let level = level.map_or(Ok(DEFAULT_ZSTD_LEVEL), str::parse)?;
+                anyhow::ensure!(
+                    zstd::compression_level_range().contains(&level),
+                    "zstd level must be in {:?}",
+                    zstd::compression_level_range()
+                );
+                Self::Zstd(level)
+            }
+            ("xz", level) => {
+                let level = level.map_or(Ok(DEFAULT_XZ_LEVEL), str::parse)?;
+                anyhow::ensure!(level <= 9, "xz level must be in 0..=9");
+                Self::Xz(level)
+            }
+            (name, Some(_)) => anyhow::bail!("encoding {name} does not support levels"),
+            (name, None) => anyhow::bail!("unknown encoding: {name}"),
+        })
     }
 }
 
 /// The Writer enum represents the different archive writer implementations.
-/// Gzip uses the gzip compression format.
+/// Tar uses a gzip, zstd or xz compressed tar.
+/// Stdout streams a compressed tar to stdout as it is being built.
 /// Zip uses the zip compression format.
 /// Oci uses the remote image reference as a destination.
+/// Tar and Zip archives are encrypted while they are written, OCI layers before upload.
+/// S3 writes the archive with the inner writer and uploads it once finished.
 pub enum Writer {
-    Path(Archive),
-    Gzip(Archive, Box<Builder<GzEncoder<File>>>),
-    Zip(Archive, Box<ZipWriter<File>>),
-    Oci(OCIState),
+    Path(Archive, PatchCounts),
+    Tar(Archive, Box<Builder<TarEncoder<ArchiveFile>>>, ArchiveState),
+    Stdout(Archive, Box<Builder<TarEncoder<io::Stdout>>>, ArchiveState),
+    Zip(
+        Archive,
+        Box<ZipWriter<StreamWriter<ArchiveFile>>>,
+        ArchiveState,
+    ),
+    Oci(Box<OCIState>),
+    S3(Box<Writer>, S3Location, S3Client),
+}
+
+/// PatchCounts holds the number of patches recorded per object, so keyframes are written
+/// without re-reading the patch files. Counts are loaded from the disk on the first sync.
+pub type PatchCounts = HashMap<PathBuf, usize>;
+
+/// ArchiveState holds the encoding, the encryption and the digests of stored files for single
+/// file archives, which can't be read back before they are finished.
+#[derive(Default)]
+pub struct ArchiveState {
+    encoding: Encoding,
+    encrypted: bool,
+    files: BTreeMap<String, String>,
+}
+
+impl ArchiveState {
+    fn new(encoding: &Encoding, encryption: Option<&Encryption>) -> Self {
+        Self {
+            encoding: encoding.clone(),
+            encrypted: encryption.is_some(),
+            files: BTreeMap::new(),
+        }
+    }
 }
 
-// OCIState holds current OCI writer destination state
+/// Size of files kept in memory for the OCI packs. Once exceeded, the largest pack in
+/// memory is spilled to a temporary file.
+pub const OCI_MEMORY_LIMIT: usize = 128 * 1024 * 1024;
+
+/// Uncompressed size in MiB of the OCI layers uploaded at once. Every upload also holds
+/// the compressed and encrypted copies of its layer in memory.
+pub const OCI_UPLOAD_MEMORY_LIMIT_MIB: usize = 256;
+
+/// Default uncompressed size after which a packed OCI layer is closed and a new one started.
+pub const DEFAULT_OCI_MAX_LAYER_SIZE: usize = 64 * 1024 * 1024;
+
+/// Default maximum number of layers in an OCI image.
+pub const DEFAULT_OCI_MAX_LAYERS: usize = 1000;
+
+/// Directory of the packed layer titles in the archive, outside of the collected paths.
+const OCI_LAYERS_DIR: &str = ".layers";
+
+/// Size of the two zero blocks marking the end of a tar archive.
+const TAR_END_LEN: usize = 1024;
+
+// OCIState holds current OCI writer destination state. Files are packed per namespace,
+// kept in memory or spilled to temporary files over the memory limit. Once the collection
+// is finished, the files of every pack are ordered by path and split into layers of the
+// size limit, so layer boundaries don't depend on the order files were collected in.
 pub struct OCIState {
     archive: Archive,
-    client: Client,
     config: ManifestConfig,
-    image_ref: Box<Reference>,
-    auth: RegistryAuth,
+    registry: Registry,
     buffer_size: usize,
+    packing: LayerPacking,
+    files: BTreeMap<String, String>,
+    packs: BTreeMap<(PackFormat, String), LayerBuffer>,
+    buffered: usize,
+    sealed: usize,
+    index: Vec<YamlPath>,
+    uploads: JoinSet<anyhow::Result<OciDescriptor>>,
+    upload_memory: Arc<Semaphore>,
+    layers: Vec<OciDescriptor>,
+}
+
+/// Previous snapshot, with blobs mounted into the pushed image instead of being uploaded.
+struct BaseImage {
+    reference: Reference,
+    blobs: HashSet<String>,
+}
+
+/// Limits for packing archive files into OCI layers.
+#[derive(Clone, Copy)]
+pub struct LayerPacking {
+    pub max_layer_size: usize,
+    pub max_layers: usize,
+}
+
+impl Default for LayerPacking {
+    fn default() -> Self {
+        Self {
+            max_layer_size: DEFAULT_OCI_MAX_LAYER_SIZE,
+            max_layers: DEFAULT_OCI_MAX_LAYERS,
+        }
+    }
+}
+
+/// Format of a packed layer. Resource documents are concatenated into a YAML list,
+/// other files such as logs are bundled into a tar.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum PackFormat {
+    Yaml,
+    Tar,
+}
+
+/// Registry destination and layer encoding, shared with the upload tasks.
+#[derive(Clone)]
+struct Registry {
+    client: Client,
+    image_ref: Arc<Reference>,
+    auth: RegistryAuth,
+    base: Option<Arc<BaseImage>>,
+    layer_key: Option<age::x25519::Recipient>,
+    layer_compression: LayerCompression,
 }
 
-// YamlPath contains a full path in the yaml file in archive
-// and a range of bytes to extract yaml from a list
+/// Files packed into the layers of a pack. Files are kept in memory, until the buffer is
+/// spilled to a file over the memory limit. Once the collection is finished, files are
+/// ordered by path and split into layers, so identical files produce the same layers with
+/// the same digests in every snapshot, regardless of the order they were collected in.
+struct LayerBuffer {
+    format: PackFormat,
+    data: Vec<u8>,
+    spill: Option<File>,
+    len: usize,
+    entries: Vec<PackEntry>,
+}
+
+/// Position of a file in the layer buffer. The file data starts at the offset in the chunk,
+/// after the tar header.
+struct PackEntry {
+    path: PathBuf,
+    start: usize,
+    len: usize,
+    offset: usize,
+    size: usize,
+}
+
+// YamlPath contains a full path of the file in archive, the packed layer holding it,
+// and a range of bytes to extract the file from the layer. Archives without the layer
+// title combine documents into a yaml list layer per directory.
 #[derive(Serialize, Deserialize)]
 pub struct YamlPath {
     pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<PathBuf>,
     pub from: usize,
     pub to: usize,
 }
@@ -222,36 +400,492 @@ pub struct YamlPath {
 pub struct ManifestConfig {
     #[serde(default)]
     pub compressed: bool,
+    /// Archive key used to encrypt the layers, wrapped for the archive recipients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption_key: Option<String>,
+    /// Summary of the collection, absent in archives pushed by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<CollectionSummary>,
+}
+
+/// Artifact type of the OCI manifest of crust-gather archives.
+pub const OCI_ARTIFACT_TYPE: &str = "application/vnd.crust-gather.archive.v1";
+/// Media type of the [`ManifestConfig`] blob of crust-gather archives.
+pub const OCI_CONFIG_MEDIA_TYPE: &str = "application/vnd.crust-gather.config.v1+json";
+
+pub const CLUSTER_ANNOTATION: &str = "crust-gather.io/cluster";
+pub const SERVER_VERSION_ANNOTATION: &str = "crust-gather.io/server-version";
+pub const FILTERS_ANNOTATION: &str = "crust-gather.io/filters";
+pub const VERSION_ANNOTATION: &str = "crust-gather.io/version";
+pub const CREATED_ANNOTATION: &str = "org.opencontainers.image.created";
+
+/// Summary of the collection stored in the OCI config. The main fields are repeated
+/// in the manifest annotations, to list snapshots without pulling the config.
+/// Private summaries of encrypted or pseudonymized archives omit the cluster name and
+/// the collection parameters, as the config and annotations are stored in plaintext.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CollectionSummary {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_version: Option<String>,
+    pub created: DateTime<Utc>,
+    pub version: String,
+    #[serde(default)]
+    pub parameters: serde_json::Value,
+    #[serde(default)]
+    pub files: usize,
+    #[serde(default)]
+    pub resources: usize,
+    #[serde(skip)]
+    pub private: bool,
+}
+
+impl Default for CollectionSummary {
+    fn default() -> Self {
+        Self {
+            cluster: None,
+            server_version: None,
+            created: Utc::now(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            parameters: serde_json::Value::Null,
+            files: 0,
+            resources: 0,
+            private: false,
+        }
+    }
+}
+
+impl CollectionSummary {
+    /// Counts the stored file, taking the server version from "version.yaml".
+    fn record(&mut self, archive_path: &str, data: &str) {
+        self.files += 1;
+        if archive_path == "version.yaml" {
+            self.server_version = serde_saphyr::from_str::<Info>(data)
+                .ok()
+                .map(|info| info.git_version);
+        } else if archive_path.ends_with(".yaml")
+            && (archive_path.starts_with("namespaces/") || archive_path.starts_with("cluster/"))
+        {
+            self.resources += 1;
+        }
+    }
+
+    /// Drops the identifying fields of private summaries before publishing.
+    fn publish(&mut self) {
+        if self.private {
+            self.cluster = None;
+            self.parameters = serde_json::Value::Null;
+        }
+    }
+
+    /// Returns the manifest annotations of the summary.
+    pub fn annotations(&self) -> BTreeMap<String, String> {
+        let mut annotations: BTreeMap<String, String> = [
+            (CREATED_ANNOTATION, self.created.to_rfc3339()),
+            (VERSION_ANNOTATION, self.version.clone()),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+        for (key, value) in [
+            (CLUSTER_ANNOTATION, self.cluster.clone()),
+            (SERVER_VERSION_ANNOTATION, self.server_version.clone()),
+            (
+                FILTERS_ANNOTATION,
+                self.parameters
+                    .get("filters")
+                    .filter(|filters| !filters.as_array().is_some_and(Vec::is_empty))
+                    .map(ToString::to_string),
+            ),
+        ] {
+            if let Some(value) = value {
+                annotations.insert(key.to_string(), value);
+            }
+        }
+
+        annotations
+    }
+}
+
+/// Number of pending writes queued for the writer task before scanners are suspended.
+pub const WRITER_CHANNEL_CAPACITY: usize = 256;
+
+/// Requests processed by the writer task, each with a channel for the result.
+enum Command {
+    Store(Representation, oneshot::Sender<anyhow::Result<()>>),
+    Sync(
+        Representation,
+        IgnoreRules,
+        oneshot::Sender<anyhow::Result<()>>,
+    ),
+    StoreManifest(
+        serde_json::Value,
+        Option<SigningKey>,
+        oneshot::Sender<anyhow::Result<()>>,
+    ),
+    Finish(oneshot::Sender<anyhow::Result<()>>),
+}
+
+/// `WriterHandle` sends representations to a dedicated writer task over a bounded channel.
+///
+/// The writer task owns the `Writer` and runs on a blocking thread, so filesystem I/O and
+/// patch computation stay off the async executor. Scanners wait for a free slot in the
+/// channel when the writer falls behind, and for the result of their own writes.
+///
+/// Every representation is stripped of collected secret values, redacted and pseudonymized
+/// before it is sent to the writer task, so no scanner can bypass the redaction.
+#[derive(Clone)]
+pub struct WriterHandle {
+    commands: mpsc::Sender<Command>,
+    secrets: Secrets,
+    redaction: Redaction,
 }
 
-impl From<Writer> for Arc<Mutex<Writer>> {
+impl From<Writer> for WriterHandle {
     fn from(val: Writer) -> Self {
-        Self::new(Mutex::new(val))
+        Self::spawn(val, WRITER_CHANNEL_CAPACITY)
+    }
+}
+
+impl WriterHandle {
+    /// Starts the writer task. Must be called within a tokio runtime.
+    pub fn spawn(mut writer: Writer, capacity: usize) -> Self {
+        let (sender, mut receiver) = mpsc::channel(capacity.max(1));
+        let runtime = Handle::current();
+        tokio::task::spawn_blocking(move || {
+            while let Some(command) = receiver.blocking_recv() {
+                // A dropped result receiver only means the caller is gone.
+                let _ = match command {
+                    Command::Store(repr, result) => {
+                        result.send(runtime.block_on(writer.store(&repr)))
+                    }
+                    Command::Sync(repr, ignore, result) => {
+                        result.send(runtime.block_on(writer.sync(&repr, &ignore)))
+                    }
+                    Command::StoreManifest(parameters, key, result) => result
+                        .send(runtime.block_on(writer.store_manifest(parameters, key.as_ref()))),
+                    Command::Finish(result) => {
+                        let _ = result.send(runtime.block_on(writer.finish()));
+                        return;
+                    }
+                };
+            }
+        });
+
+        Self {
+            commands: sender,
+            secrets: Secrets::default(),
+            redaction: Redaction::default(),
+        }
+    }
+
+    /// Sets the secret values and the redaction applied to every stored representation.
+    pub fn with_redaction(mut self, secrets: Secrets, redaction: Redaction) -> Self {
+        self.secrets = secrets;
+        self.redaction = redaction;
+        self
+    }
+
+    async fn request(
+        &self,
+        command: impl FnOnce(oneshot::Sender<anyhow::Result<()>>) -> Command,
+    ) -> anyhow::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.commands
+            .send(command(sender))
+            .await
+            .map_err(|_| anyhow::anyhow!("writer is already finished"))?;
+        receiver.await.context("writer task stopped")?
+    }
+
+    /// Adds a representation data to the archive under the representation path.
+    pub async fn store(&self, repr: Representation) -> anyhow::Result<()> {
+        let repr = self.secrets.strip_and_redact(&repr, &self.redaction);
+        self.request(|result| Command::Store(repr, result)).await
+    }
+
+    /// Records the representation changes as a patch to the stored object.
+    pub async fn sync(&self, repr: Representation, ignore: IgnoreRules) -> anyhow::Result<()> {
+        let repr = self.secrets.strip_and_redact(&repr, &self.redaction);
+        self.request(|result| Command::Sync(repr, ignore, result))
+            .await
+    }
+
+    /// Stores the archive manifest, signed with the key if provided. The collection
+    /// parameters are pseudonymized like the collected files.
+    pub async fn store_manifest(
+        &self,
+        parameters: serde_json::Value,
+        key: Option<SigningKey>,
+    ) -> anyhow::Result<()> {
+        let parameters = serde_json::from_str(
+            &self
+                .redaction
+                .pseudonymizer()
+                .apply_text(&parameters.to_string()),
+        )?;
+        self.request(|result| Command::StoreManifest(parameters, key, result))
+            .await
+    }
+
+    /// Finishes the archive and stops the writer task. Later writes return an error.
+    pub async fn finish(&self) -> anyhow::Result<()> {
+        self.request(Command::Finish).await
     }
 }
 
 impl Writer {
-    /// Finish zip archive
-    pub fn finish_zip(self) -> anyhow::Result<()> {
-        let Self::Zip(_, builder) = self else {
+    /// Finish zip archive. The writer is left pointing to the archive path.
+    pub fn finish_zip(&mut self) -> anyhow::Result<()> {
+        let Self::Zip(Archive(archive), ..) = self else {
             return anyhow::Result::Ok(());
         };
+        let archive = Archive(archive.clone());
+        let Self::Zip(_, builder, _) =
+            std::mem::replace(self, Self::Path(archive, PatchCounts::new()))
+        else {
+            unreachable!("writer is a zip archive");
+        };
 
-        builder.finish()?;
+        builder.finish()?.into_inner().finish()?;
         Ok(())
     }
 
-    /// Finish gzip archive
-    pub fn finish_gzip(&mut self) -> anyhow::Result<()> {
-        let Self::Gzip(_, builder) = self else {
-            return anyhow::Result::Ok(());
+    /// Finish compressed tar archive. The writer is left pointing to the archive path.
+    pub fn finish_tar(&mut self) -> anyhow::Result<()> {
+        let archive = match self {
+            Self::Tar(Archive(archive), ..) => Archive(archive.clone()),
+            Self::Stdout(Archive(archive), ..) => Archive(archive.clone()),
+            _ => return anyhow::Result::Ok(()),
+        };
+        match std::mem::replace(self, Self::Path(archive, PatchCounts::new())) {
+            Self::Tar(_, builder, _) => {
+                builder.into_inner()?.finish()?.finish()?;
+            }
+            Self::Stdout(_, builder, _) => {
+                builder.into_inner()?.finish()?.flush()?;
+            }
+            _ => unreachable!("writer is a tar archive"),
+        };
+        Ok(())
+    }
+
+    /// Finish writing the archive: pushes the OCI image, or finalizes the tar or zip file.
+    /// Archives written for S3 are uploaded once finished.
+    pub async fn finish(&mut self) -> anyhow::Result<()> {
+        if let Self::S3(writer, location, client) = self {
+            let output = writer.output();
+            Box::pin(writer.finish()).await?;
+            return client.upload(location, &output?).await;
+        }
+
+        self.finish_oci().await?;
+        self.finish_tar()?;
+        self.finish_zip()
+    }
+
+    /// Returns the path of the finished archive file or directory.
+    fn output(&self) -> anyhow::Result<PathBuf> {
+        let (path, state) = match self {
+            Self::Path(archive, _) => return Ok(archive.path()),
+            Self::Tar(Archive(archive), builder, state) => {
+                (archive.with_extension(builder.get_ref().extension()), state)
+            }
+            Self::Zip(Archive(archive), _, state) => (archive.with_extension(ZIP_EXTENSION), state),
+            Self::Stdout(..) | Self::Oci(..) | Self::S3(..) => {
+                anyhow::bail!("Only path, tar and zip archives are stored as files")
+            }
+        };
+
+        Ok(match state.encrypted {
+            true => encrypted_path(&path),
+            false => path,
+        })
+    }
+
+    /// Stores the manifest with digests of all files in the archive, signed with the key if provided.
+    /// Path archives are hashed from the disk, to include files from previous runs
+    /// and recorded patches.
+    pub async fn store_manifest(
+        &mut self,
+        parameters: serde_json::Value,
+        key: Option<&SigningKey>,
+    ) -> anyhow::Result<()> {
+        let (manifest, signature) = match self {
+            Self::S3(writer, ..) => return Box::pin(writer.store_manifest(parameters, key)).await,
+            Self::Path(archive, _) => {
+                let (manifest, signature) =
+                    Manifest::new(parameters, Manifest::files_in(&archive.path())?).sign(key)?;
+                fs::write(archive.path().join(MANIFEST_PATH), &manifest)?;
+                if let Some(signature) = &signature {
+                    fs::write(archive.path().join(SIGNATURE_PATH), signature)?;
+                }
+                return Ok(());
+            }
+            Self::Tar(.., state) | Self::Stdout(.., state) | Self::Zip(.., state) => {
+                Manifest::new(parameters, state.files.clone()).sign(key)?
+            }
+            Self::Oci(state) => {
+                if let Some(summary) = &mut state.config.summary {
+                    summary.parameters = parameters.clone();
+                }
+                Manifest::new(parameters, state.files.clone()).sign(key)?
+            }
+        };
+
+        self.store(
+            &Representation::new()
+                .with_path(ArchivePath::Custom(MANIFEST_PATH.into()))
+                .with_data(&manifest),
+        )
+        .await?;
+        if let Some(signature) = signature {
+            self.store(
+                &Representation::new()
+                    .with_path(ArchivePath::Custom(SIGNATURE_PATH.into()))
+                    .with_data(&signature),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the encryption for the archive. Tar and zip archive files are created again,
+    /// written through the encryption. For OCI a random archive key is generated and stored
+    /// in the manifest config, wrapped for the configured recipients.
+    pub fn with_encryption(mut self, encryption: Option<Encryption>) -> anyhow::Result<Self> {
+        let Some(encryption) = encryption else {
+            return Ok(self);
+        };
+
+        match &mut self {
+            Self::Path(..) => {
+                anyhow::bail!("Encryption requires the zip, gzip, zstd, xz or OCI encoding")
+            }
+            Self::Stdout(..) => anyhow::bail!("Encryption is not supported for stdout streaming"),
+            Self::S3(..) => anyhow::bail!("Encryption must be set before the S3 upload"),
+            Self::Tar(archive, _, state) | Self::Zip(archive, _, state) => {
+                anyhow::ensure!(
+                    state.files.is_empty(),
+                    "Encryption must be set before files are stored"
+                );
+                let (archive, encoding) = (archive.clone(), state.encoding.clone());
+                let plaintext = self.output()?;
+                drop(self);
+                fs::remove_file(plaintext)?;
+                return Self::new_file(&archive, &encoding, Some(&encryption));
+            }
+            Self::Oci(state) => {
+                let (recipient, wrapped) = encryption.layer_key()?;
+                state.registry.layer_key = Some(recipient);
+                state.config.encryption_key = Some(wrapped);
+                if let Some(summary) = &mut state.config.summary {
+                    summary.private = true;
+                }
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Sets the compression of OCI layers. Other encodings are not affected.
+    pub fn with_layer_compression(mut self, compression: Option<LayerCompression>) -> Self {
+        if let (Self::Oci(state), Some(compression)) = (&mut self, compression) {
+            state.registry.layer_compression = compression;
+        }
+
+        self
+    }
+
+    /// Sets the limits for packing files into OCI layers. Other encodings are not affected.
+    pub fn with_layer_packing(mut self, packing: LayerPacking) -> Self {
+        if let Self::Oci(state) = &mut self {
+            state.packing = packing;
+        }
+
+        self
+    }
+
+    /// Sets the cluster name recorded in the OCI summary. Other encodings are not affected.
+    pub fn with_cluster_name(mut self, cluster: Option<String>) -> Self {
+        if let (Self::Oci(state), Some(cluster)) = (&mut self, cluster)
+            && let Some(summary) = &mut state.config.summary
+        {
+            summary.cluster = Some(cluster);
+        }
+
+        self
+    }
+
+    /// Marks the OCI summary as private, omitting the cluster name and the collection
+    /// parameters from the config and annotations. Encrypted archives are always private.
+    pub fn with_private_summary(mut self, private: bool) -> Self {
+        if let Self::Oci(state) = &mut self
+            && let Some(summary) = &mut state.config.summary
+        {
+            summary.private |= private;
+        }
+
+        self
+    }
+
+    /// Sets the previous snapshot for OCI pushes. Blobs of the base image are mounted
+    /// into the pushed image when unchanged. Layers of encrypted archives differ in every
+    /// snapshot, so they are never mounted. Other encodings are not affected.
+    pub async fn with_base(mut self, base: Option<Reference>) -> anyhow::Result<Self> {
+        let (Self::Oci(state), Some(base)) = (&mut self, base) else {
+            return Ok(self);
+        };
+        let registry = &mut state.registry;
+        anyhow::ensure!(
+            base.resolve_registry() == registry.image_ref.resolve_registry(),
+            "base image {base} must be in the same registry as {}",
+            registry.image_ref
+        );
+
+        let (manifest, _) = registry
+            .client
+            .pull_image_manifest(&base, &registry.auth)
+            .await
+            .with_context(|| format!("unable to pull base image manifest {base}"))?;
+        let blobs = manifest
+            .layers
+            .into_iter()
+            .chain([manifest.config])
+            .map(|descriptor| descriptor.digest)
+            .collect();
+        registry.base = Some(Arc::new(BaseImage {
+            reference: base,
+            blobs,
+        }));
+
+        Ok(self)
+    }
+
+    /// Uploads the finished archive to the S3 location. Applied after the other
+    /// settings, which configure the wrapped writer.
+    pub fn with_s3(
+        self,
+        location: Option<S3Location>,
+        settings: &S3Settings,
+    ) -> anyhow::Result<Self> {
+        let Some(location) = location else {
+            return Ok(self);
         };
 
-        Ok(builder.finish()?)
+        match self {
+            Self::Stdout(..) => {
+                anyhow::bail!("Archives streamed to stdout can't be uploaded to S3")
+            }
+            Self::Oci(..) | Self::S3(..) => anyhow::bail!("OCI archives can't be uploaded to S3"),
+            writer => Ok(Self::S3(Box::new(writer), location, settings.to_client()?)),
+        }
     }
 
     /// Finish writing the archive, finalizing any compression and flushing buffers.
-    pub async fn finish_oci(&self) -> anyhow::Result<()> {
+    pub async fn finish_oci(&mut self) -> anyhow::Result<()> {
         if let Writer::Oci(ocistate) = self {
             return ocistate.publish_image().await;
         }
@@ -268,11 +902,9 @@ impl Writer {
         let data = repr.data();
 
         match self {
-            Self::Path(Archive(archive))
-            | Self::Oci(OCIState {
-                archive: Archive(archive),
-                ..
-            }) => {
+            Self::S3(writer, ..) => Box::pin(writer.store(repr)).await?,
+            Self::Oci(state) => state.store(archive_path, data).await?,
+            Self::Path(Archive(archive), _) => {
                 let file = archive.join(archive_path);
                 if !file.exists() {
                     DirBuilder::new()
@@ -282,17 +914,22 @@ impl Writer {
                     file.write_all(data.as_bytes())?;
                 }
             }
-            Self::Gzip(Archive(archive), builder) => {
-                let mut header = Header::new_gnu();
-                header.set_size(data.len() as u64 + 1);
-                header.set_cksum();
-                header.set_mode(0o644);
-
-                let root_prefix = archive.file_stem().unwrap();
-                let file = PathBuf::from(root_prefix).join(archive_path);
-                builder.append_data(&mut header, file, data.as_bytes())?;
+            Self::Tar(Archive(archive), builder, state) => {
+                state
+                    .files
+                    .insert(archive_path.clone(), digest(data.as_bytes()));
+                Self::append_tar(builder, archive.file_stem().unwrap(), archive_path, data)?;
+            }
+            Self::Stdout(archive, builder, state) => {
+                state
+                    .files
+                    .insert(archive_path.clone(), digest(data.as_bytes()));
+                Self::append_tar(builder, archive.name(), archive_path, data)?;
             }
-            Self::Zip(Archive(archive), writer) => {
+            Self::Zip(Archive(archive), writer, state) => {
+                state
+                    .files
+                    .insert(archive_path.clone(), digest(data.as_bytes()));
                 let path = repr.path();
                 let path = path.parent().unwrap().to_str().unwrap();
                 writer
@@ -319,50 +956,119 @@ impl Writer {
 
     /// Adds a representation data to the archive under the representation path
     #[instrument(skip_all, fields(repr = repr.path().to_string()))]
-    pub async fn sync(&mut self, repr: &Representation) -> anyhow::Result<()> {
+    pub async fn sync(
+        &mut self,
+        repr: &Representation,
+        ignore: &IgnoreRules,
+    ) -> anyhow::Result<()> {
         tracing::debug!("Writing...");
 
         let archive_path: String = repr.path().try_into()?;
 
         match self {
-            Self::Path(archive) => {
+            Self::Path(archive, counts) => {
                 let file_path = archive.0.join(archive_path);
 
                 // generate diff and write
-                let original = Reader::new(
-                    ArchiveReader::new(archive.clone(), &Storage::FS, DEFAULT_OCI_BUFFER_SIZE)
-                        .await,
+                let original: serde_json::Value = Reader::new(
+                    ArchiveReader::new(archive.clone(), Storage::FS, DEFAULT_OCI_BUFFER_SIZE).await,
                     Utc::now(),
-                    Storage::FS,
                 )
                 .await?
                 .read(file_path.clone())
                 .await?;
                 let updated = serde_saphyr::from_str(repr.data())?;
-                let patch = &diff(&original, &updated);
-                if !patch.deref().is_empty() {
+                let (stripped, updated) = (ignore.apply(original.clone()), ignore.apply(updated));
+                let patch = &diff(&stripped, &updated);
+                if has_changes(&stripped, &updated) {
+                    let count = match counts.entry(file_path.clone()) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => entry.insert(Self::patch_count(&file_path)?),
+                    };
                     let mut patches = File::options()
                         .create(true)
                         .append(true)
                         .open(file_path.with_extension("patch"))?;
                     serde_json::to_writer(patches.try_clone()?, patch)?;
                     patches.write_all(b"\n")?;
+                    *count += 1;
+                    Self::keyframe(&file_path, original, patch, *count)?;
                 }
                 self.store(repr).await?;
             }
-            Self::Gzip(Archive(_archive), _builder) => {
-                unimplemented!();
-            }
-            Self::Zip(Archive(_archive), _writer) => {
-                unimplemented!();
-            }
-            Self::Oci(..) => {
-                unimplemented!();
+            Self::S3(writer, ..) => Box::pin(writer.sync(repr, ignore)).await?,
+            Self::Tar(..) | Self::Stdout(..) | Self::Zip(..) | Self::Oci(..) => {
+                anyhow::bail!("Recording is only supported for path archives")
             }
         }
         Ok(())
     }
 
+    /// Appends a file to the tar archive under the root directory.
+    fn append_tar<W: io::Write>(
+        builder: &mut Builder<W>,
+        root_prefix: &OsStr,
+        archive_path: String,
+        data: &str,
+    ) -> anyhow::Result<()> {
+        let mut header = Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        header.set_mode(0o644);
+
+        let file = PathBuf::from(root_prefix).join(archive_path);
+        builder.append_data(&mut header, file, data.as_bytes())?;
+        Ok(())
+    }
+
+    /// Counts the patches recorded for the object by a previous run.
+    fn patch_count(file_path: &Path) -> anyhow::Result<usize> {
+        match fs::read(file_path.with_extension("patch")) {
+            Ok(patches) => Ok(patches.iter().filter(|b| **b == b'\n').count()),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Appends a keyframe with the full object state after every `KEYFRAME_INTERVAL` patches,
+    /// so the reader does not have to replay the whole patch file.
+    fn keyframe(
+        file_path: &Path,
+        mut object: serde_json::Value,
+        patch: &json_patch::Patch,
+        lines: usize,
+    ) -> anyhow::Result<()> {
+        if !lines.is_multiple_of(KEYFRAME_INTERVAL) {
+            return Ok(());
+        }
+
+        // The reader does not replay patches without a sync timestamp, so the keyframe
+        // keeps the previous state and its timestamp for them.
+        let timestamp = match patch_timestamp(patch) {
+            Some(timestamp) => {
+                json_patch::patch(&mut object, patch)?;
+                timestamp
+            }
+            None => object_timestamp(&object).unwrap_or_default(),
+        };
+
+        let mut keyframes = File::options()
+            .create(true)
+            .append(true)
+            .open(file_path.with_extension("keyframes"))?;
+        serde_json::to_writer(
+            keyframes.try_clone()?,
+            &Keyframe {
+                line: lines,
+                timestamp,
+                object,
+            },
+        )?;
+        keyframes.write_all(b"\n")?;
+
+        Ok(())
+    }
+
     /// Creates a new `Writer` for the given `Archive` and `Encoding`.
     pub async fn new(
         archive: &Archive,
@@ -372,6 +1078,38 @@ impl Writer {
         buffer_size: usize,
     ) -> anyhow::Result<Self> {
         let buffer_size = buffer_size.max(1);
+        if archive.is_stdout() {
+            return Self::new_stdout(encoding);
+        }
+        if let Encoding::Oci(image_ref) = encoding {
+            return Ok(Self::Oci(Box::new(OCIState {
+                archive: archive.clone(),
+                config: ManifestConfig {
+                    compressed: true,
+                    encryption_key: None,
+                    summary: Some(CollectionSummary::default()),
+                },
+                registry: Registry {
+                    client: Client::new(client_config.unwrap_or_default()),
+                    image_ref: image_ref.clone().into(),
+                    auth: auth.unwrap_or(RegistryAuth::Anonymous),
+                    base: None,
+                    layer_key: None,
+                    layer_compression: LayerCompression::default(),
+                },
+                buffer_size,
+                packing: LayerPacking::default(),
+                files: Default::default(),
+                packs: Default::default(),
+                buffered: 0,
+                sealed: 0,
+                index: vec![],
+                uploads: JoinSet::new(),
+                upload_memory: Arc::new(Semaphore::new(OCI_UPLOAD_MEMORY_LIMIT_MIB)),
+                layers: vec![],
+            })));
+        }
+
         match archive.0.parent() {
             Some(parent) if !parent.as_os_str().is_empty() => {
                 DirBuilder::new().recursive(true).create(parent)?;
@@ -379,104 +1117,267 @@ impl Writer {
             Some(_) | None => (),
         };
 
-        Ok(match encoding {
-            Encoding::Path => Self::Path(archive.clone()),
-            Encoding::Gzip => Self::Gzip(
-                archive.clone(),
-                Box::new(Builder::new(GzEncoder::new(
-                    File::create(archive.0.with_extension("tar.gz"))?,
-                    Compression::default(),
-                ))),
-            ),
-            Encoding::Zip => Self::Zip(
-                archive.clone(),
-                Box::new(ZipWriter::new(File::create(
-                    archive.0.with_extension("zip"),
-                )?)),
-            ),
-            Encoding::Oci(image_ref) => Self::Oci(OCIState {
-                archive: archive.clone(),
-                config: ManifestConfig { compressed: true },
-                client: Client::new(client_config.unwrap_or_default()),
-                image_ref: image_ref.clone().into(),
-                auth: auth.unwrap_or(RegistryAuth::Anonymous),
-                buffer_size,
-            }),
-        })
+        match encoding {
+            Encoding::Path => Ok(Self::Path(archive.clone(), PatchCounts::new())),
+            encoding => Self::new_file(archive, encoding, None),
+        }
     }
-}
 
-impl OCIState {
-    #[instrument(skip_all, err)]
-    async fn publish_image(&self) -> anyhow::Result<()> {
-        info!("Pushing image: {:?}", self.image_ref);
-        self.client
-            .store_auth_if_needed(self.image_ref.resolve_registry(), &self.auth)
-            .await;
+    /// Creates a `Writer` for a single file archive, written through the encryption if set.
+    /// Zip archives are written as a stream, as the encrypted file can't be seeked.
+    fn new_file(
+        archive: &Archive,
+        encoding: &Encoding,
+        encryption: Option<&Encryption>,
+    ) -> anyhow::Result<Self> {
+        let file =
+            |extension| ArchiveFile::create(&archive.0.with_extension(extension), encryption);
+        let encoder = match encoding {
+            Encoding::Gzip => TarEncoder::gzip(file(GZIP_EXTENSION)?),
+            Encoding::Zstd(level) => TarEncoder::zstd(file(ZSTD_EXTENSION)?, *level)?,
+            Encoding::Xz(level) => TarEncoder::xz(file(XZ_EXTENSION)?, *level)?,
+            Encoding::Zip => {
+                return Ok(Self::Zip(
+                    archive.clone(),
+                    Box::new(ZipWriter::new_stream(file(ZIP_EXTENSION)?)),
+                    ArchiveState::new(encoding, encryption),
+                ));
+            }
+            Encoding::Path | Encoding::Oci(_) => {
+                unreachable!("path and OCI archives are not single files")
+            }
+        };
 
-        let config = Config::new(
-            serde_json::to_vec(&self.config)?,
-            OCI_IMAGE_MEDIA_TYPE.to_string(),
-            None,
-        );
-        let paths = glob::glob(&format!(
-            "{}/**/*",
-            self.archive
-                .path()
-                .to_str()
-                .ok_or(anyhow::anyhow!("archive path is not convertable to string"))?,
-        ))?;
-
-        let resource_paths = Arc::new(Mutex::new(Default::default()));
-        let non_resource_paths: Vec<PathBuf> = stream::iter(paths.into_iter())
-            .filter_map(|path| Self::prepare_resource_layer(path, resource_paths.clone()))
-            .collect()
-            .await;
+        Ok(Self::Tar(
+            archive.clone(),
+            Box::new(Builder::new(encoder)),
+            ArchiveState::new(encoding, encryption),
+        ))
+    }
 
-        // Upload layers
-        let layers = Arc::new(Mutex::new(vec![]));
-        let raw_layers = stream::iter(non_resource_paths.into_iter())
-            .map(|path| self.push_oci_archive_layer(path, layers.clone()))
-            .buffer_unordered(self.buffer_size)
-            .try_for_each(future::ok::<(), anyhow::Error>);
-
-        let resource_layer_entries = { resource_paths.lock().await.clone() };
-        let resources = stream::iter(resource_layer_entries)
-            .filter_map(|(p, yamls)| {
-                future::ready(
-                    Self::combined_oci_archive_layer(&yamls)
-                        .ok()
-                        .map(|data| (p + ".yaml", data)),
-                )
-            })
-            .map(|(p, data)| self.push_oci_layer(p, data, layers.clone()))
-            .buffer_unordered(self.buffer_size)
-            .try_for_each(future::ok::<(), anyhow::Error>);
+    /// Creates a `Writer` streaming a compressed tar to stdout. Gzip is used unless
+    /// zstd or xz encoding is requested.
+    fn new_stdout(encoding: &Encoding) -> anyhow::Result<Self> {
+        let stdout = io::stdout();
+        let encoder = match encoding {
+            Encoding::Path | Encoding::Gzip => TarEncoder::gzip(stdout),
+            Encoding::Zstd(level) => TarEncoder::zstd(stdout, *level)?,
+            Encoding::Xz(level) => TarEncoder::xz(stdout, *level)?,
+            Encoding::Zip => {
+                anyhow::bail!("Zip archives can't be streamed to stdout, use a tar encoding")
+            }
+            Encoding::Oci(_) => anyhow::bail!("OCI archives can't be streamed to stdout"),
+        };
 
-        let yamls = Self::prepare_index(resource_paths.lock().await.values().cloned().collect())?;
-        let yamls =
-            serde_saphyr::to_string(&yamls).context("unable to collect yamls index file")?;
+        Ok(Self::Stdout(
+            Archive::default(),
+            Box::new(Builder::new(encoder)),
+            ArchiveState::default(),
+        ))
+    }
+}
 
-        let index_layer = self.push_oci_layer("index.yaml".to_string(), yamls, layers.clone());
+impl OCIState {
+    /// Adds the file to its namespace pack. The largest packs in memory are spilled to
+    /// temporary files over the memory limit. The first stored version of a file is kept,
+    /// as for path archives.
+    async fn store(&mut self, archive_path: String, data: &str) -> anyhow::Result<()> {
+        if self.files.contains_key(&archive_path) {
+            return Ok(());
+        }
+        self.files
+            .insert(archive_path.clone(), digest(data.as_bytes()));
+        if let Some(summary) = &mut self.config.summary {
+            summary.record(&archive_path, data);
+        }
 
-        try_join!(resources, raw_layers, index_layer).context("failed to upload OCI layers")?;
+        let path = self.archive.path().join(&archive_path);
+        let format = match path.extension() {
+            Some(extension) if extension == "yaml" => PackFormat::Yaml,
+            _ => PackFormat::Tar,
+        };
+        let key = (format, Self::pack_group(&archive_path));
+        if !self.packs.contains_key(&key) {
+            self.ensure_layers(1)?;
+        }
 
-        info!("Pushing config: {}", self.image_ref);
-        let (digest, size) = self.push_blob(config.data).await?;
+        let buffer = self
+            .packs
+            .entry(key)
+            .or_insert_with(|| LayerBuffer::new(format));
+        let before = buffer.data.len();
+        buffer.append(path, &archive_path, data)?;
+        self.buffered = self.buffered - before + buffer.data.len();
+        self.ensure_layers(0)?;
+
+        while self.buffered > OCI_MEMORY_LIMIT {
+            let Some(largest) = self.packs.values_mut().max_by_key(|pack| pack.data.len()) else {
+                break;
+            };
+            self.buffered -= largest.data.len();
+            largest.spill()?;
+        }
+        Ok(())
+    }
 
-        let mut manifest = OciImageManifest::default();
-        manifest.config.media_type = config.media_type.to_string();
-        manifest.layers = layers.lock().await.clone();
-        manifest.layers.sort_by(|a, b| a.digest.cmp(&b.digest));
-        manifest.config.digest = digest;
-        manifest.config.size = size as i64;
+    /// Returns the pack of the file: a namespace, cluster scoped files, or other archive files.
+    fn pack_group(archive_path: &str) -> String {
+        let mut components = archive_path.split('/');
+        match (components.next(), components.next(), components.next()) {
+            (Some("namespaces"), Some(namespace), Some(_)) => format!("namespaces-{namespace}"),
+            (Some("cluster"), _, Some(_)) => "cluster".to_string(),
+            _ => "archive".to_string(),
+        }
+    }
+
+    /// Returns the layer title stored in the manifest. Titles of encrypted archives are
+    /// numbered in upload order, as the manifest is not encrypted and the pack names
+    /// contain the namespaces.
+    fn pack_title(
+        &self,
+        (format, group): &(PackFormat, String),
+        sequence: usize,
+    ) -> anyhow::Result<String> {
+        let extension = match format {
+            PackFormat::Yaml => "yaml",
+            PackFormat::Tar => "tar",
+        };
+        let name = match self.registry.layer_key {
+            Some(_) => format!("{}.{extension}", self.sealed),
+            None => format!("{group}-{sequence}.{extension}"),
+        };
+        let path = self.archive.path().join(OCI_LAYERS_DIR).join(name);
+        path.to_str()
+            .ok_or(anyhow::anyhow!("file path is not convertable to string"))
+            .map(ToString::to_string)
+    }
+
+    /// Checks the image stays within the layer limit with additional layers, counting
+    /// the layers of the buffered packs and the index.
+    fn ensure_layers(&self, additional: usize) -> anyhow::Result<()> {
+        let packed: usize = self
+            .packs
+            .values()
+            .map(|pack| pack.len.div_ceil(self.packing.max_layer_size).max(1))
+            .sum();
+        let layers = packed + additional + 1;
+        anyhow::ensure!(
+            layers <= self.packing.max_layers,
+            "archive requires more than {} OCI layers, increase --oci-max-layers or --oci-max-layer-size",
+            self.packing.max_layers
+        );
+        Ok(())
+    }
 
-        info!("Pushing manifest: {}", self.image_ref);
+    /// Uploads the layers of the pack, adding their files to the index.
+    async fn upload_pack(
+        &mut self,
+        key: &(PackFormat, String),
+        mut buffer: LayerBuffer,
+    ) -> anyhow::Result<()> {
+        buffer.sort();
+        let (mut first, mut sequence) = (0, 0);
+        while first < buffer.entries.len() {
+            let title = self.pack_title(key, sequence)?;
+            let (data, paths, next) = buffer.layer(first, self.packing.max_layer_size, &title)?;
+            self.index.extend(paths);
+            self.sealed += 1;
+            self.upload(title, data).await?;
+            (first, sequence) = (next, sequence + 1);
+        }
+        Ok(())
+    }
+
+    /// Starts the layer upload, waiting for running uploads to finish when the concurrency
+    /// limit is reached, or the layer doesn't fit into the upload memory limit. A layer above
+    /// the limit is uploaded alone.
+    async fn upload(&mut self, title: String, data: impl Into<Vec<u8>>) -> anyhow::Result<()> {
+        while self.uploads.len() >= self.buffer_size {
+            self.join_upload().await?;
+        }
+
+        let data = data.into();
+        let size = data
+            .len()
+            .div_ceil(1024 * 1024)
+            .clamp(1, OCI_UPLOAD_MEMORY_LIMIT_MIB);
+        let permit = self
+            .upload_memory
+            .clone()
+            .acquire_many_owned(size as u32)
+            .await?;
+        let registry = self.registry.clone();
+        self.uploads.spawn(async move {
+            let _permit = permit;
+            registry.push_layer(title, data).await
+        });
+        Ok(())
+    }
+
+    async fn join_upload(&mut self) -> anyhow::Result<()> {
+        if let Some(layer) = self.uploads.join_next().await {
+            self.layers
+                .push(layer?.context("failed to upload OCI layers")?);
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all, err)]
+    async fn publish_image(&mut self) -> anyhow::Result<()> {
+        info!("Pushing image: {:?}", self.registry.image_ref);
+        for (key, buffer) in std::mem::take(&mut self.packs) {
+            self.upload_pack(&key, buffer).await?;
+        }
+        self.buffered = 0;
+
+        self.index.sort_by(|a, b| a.path.cmp(&b.path));
+        let index = serde_saphyr::to_string(&std::mem::take(&mut self.index))
+            .context("unable to collect yamls index file")?;
+        self.upload("index.yaml".to_string(), index).await?;
+        while !self.uploads.is_empty() {
+            self.join_upload().await?;
+        }
+
+        info!("Pushing config: {}", self.registry.image_ref);
+        if let Some(summary) = &mut self.config.summary {
+            summary.publish();
+        }
+        let config = Config::new(
+            serde_json::to_vec(&self.config)?,
+            OCI_CONFIG_MEDIA_TYPE.to_string(),
+            None,
+        );
+        let (digest, size) = self
+            .registry
+            .push_blob(config.data, None, LayerCompression::Gzip)
+            .await?;
+
+        let mut manifest = OciImageManifest {
+            artifact_type: Some(OCI_ARTIFACT_TYPE.to_string()),
+            annotations: self
+                .config
+                .summary
+                .as_ref()
+                .map(CollectionSummary::annotations),
+            ..Default::default()
+        };
+        manifest.config.media_type = config.media_type.to_string();
+        manifest.layers = std::mem::take(&mut self.layers);
+        manifest.layers.sort_by(|a, b| a.digest.cmp(&b.digest));
+        manifest.config.digest = digest;
+        manifest.config.size = size as i64;
+
+        info!("Pushing manifest: {}", self.registry.image_ref);
         let manifest = manifest.into();
-        self.push_manifest(&manifest).await
+        self.registry.push_manifest(&manifest).await
     }
+}
 
+impl Registry {
     async fn push_manifest(&self, manifest: &OciManifest) -> anyhow::Result<()> {
+        self.client
+            .store_auth_if_needed(self.image_ref.resolve_registry(), &self.auth)
+            .await;
         let push = || self.client.push_manifest(&self.image_ref, manifest);
         push.retry(
             ExponentialBuilder::default()
@@ -493,12 +1394,28 @@ impl OCIState {
     async fn push_blob(
         &self,
         data: impl Into<bytes::Bytes> + Clone,
+        layer_key: Option<&age::x25519::Recipient>,
+        compression: LayerCompression,
     ) -> anyhow::Result<(String, usize)> {
-        let mut enc = GzEncoder::new(vec![], Compression::best());
-        enc.write_all(&data.into())?;
-        let data = BASE64_STANDARD.encode(enc.finish()?);
+        let data = compression.compress(&data.into())?;
+        let data = match layer_key {
+            Some(recipient) => encrypt_layer(recipient, &data)?,
+            None => data,
+        };
+        // Gzip layers are base64 encoded for compatibility with existing archives.
+        let data = match compression {
+            LayerCompression::Gzip => BASE64_STANDARD.encode(data).into_bytes(),
+            LayerCompression::Zstd => data,
+        };
 
+        self.client
+            .store_auth_if_needed(self.image_ref.resolve_registry(), &self.auth)
+            .await;
         let digest = format!("sha256:{}", hex::encode(sha2::Sha256::digest(&data)));
+        if self.reuse_blob(&digest).await {
+            return Ok((digest, data.len()));
+        }
+
         let push = || {
             let data = data.clone();
             self.client.push_blob(&self.image_ref, data, &digest)
@@ -515,152 +1432,210 @@ impl OCIState {
         Ok((digest, data.len()))
     }
 
-    fn prepare_index(yamls: Vec<Vec<PathBuf>>) -> anyhow::Result<Vec<YamlPath>> {
-        let mut list = vec![];
-        for yaml_list in yamls {
-            let mut index = 0;
-            for yaml in yaml_list {
-                let path = yaml.to_string_lossy();
-                let mut file = File::open(&yaml).context(format!("failed to open file {path}"))?;
-                list.push(YamlPath {
-                    path: yaml,
-                    from: index,
-                    to: {
-                        let mut data = vec![];
-                        file.read_to_end(&mut data)?;
-                        index += data.len();
-                        index
-                    },
-                });
-                index += 4
+    /// Returns true when the blob is already in the repository, or is mounted from the
+    /// base image. Failed checks fall back to the upload.
+    async fn reuse_blob(&self, digest: &str) -> bool {
+        match self.client.blob_exists(&self.image_ref, digest).await {
+            Ok(true) => {
+                debug!("Blob {digest} exists, skipping upload");
+                return true;
             }
+            Ok(false) => (),
+            Err(err) => debug!("Unable to check blob {digest}: {err}"),
         }
 
-        Ok(list)
-    }
-
-    async fn prepare_resource_layer(
-        path: glob::GlobResult,
-        resource_layers: Arc<Mutex<BTreeMap<String, Vec<PathBuf>>>>,
-    ) -> Option<PathBuf> {
-        let path = path.ok()?;
-
-        if path.is_dir() {
-            return Some(path);
+        let Some(base) = self
+            .base
+            .as_ref()
+            .filter(|base| base.blobs.contains(digest))
+        else {
+            return false;
+        };
+        match self
+            .client
+            .mount_blob(&self.image_ref, &base.reference, digest)
+            .await
+        {
+            Ok(()) => {
+                debug!("Mounted blob {digest} from {}", base.reference);
+                true
+            }
+            Err(err) => {
+                debug!("Unable to mount blob {digest}: {err}");
+                false
+            }
         }
+    }
 
-        let Some(parent) = path.parent() else {
-            return Some(path);
+    async fn push_layer(
+        self,
+        archive_path: String,
+        mut data: Vec<u8>,
+    ) -> anyhow::Result<OciDescriptor> {
+        if data.is_empty() {
+            // Publish an empty json instead, as ghcr doesn't allow empty layers
+            data = b"{}".to_vec();
         };
 
-        let Some(ext) = path.extension() else {
-            return Some(path);
-        };
+        info!("Pushing layer: {:?}", archive_path);
+        let (digest, size) = self
+            .push_blob(data, self.layer_key.as_ref(), self.layer_compression)
+            .await?;
+
+        Ok(OciDescriptor {
+            artifact_type: None,
+            urls: None,
+            media_type: self.layer_compression.media_type().to_string(),
+            digest,
+            size: size as i64,
+            annotations: Some(
+                [(
+                    "org.opencontainers.image.title".to_string(),
+                    archive_path.to_string(),
+                )]
+                .into(),
+            ),
+        })
+    }
+}
 
-        if ext != "yaml" || path.to_string_lossy().ends_with("version.yaml") {
-            return Some(path);
+impl LayerBuffer {
+    fn new(format: PackFormat) -> Self {
+        Self {
+            format,
+            data: vec![],
+            spill: None,
+            len: 0,
+            entries: vec![],
         }
-
-        let parent = parent.to_str()?;
-
-        debug!("Inserting path {:?} with parent {:?}", path, parent);
-        resource_layers
-            .lock()
-            .await
-            .entry(parent.to_string())
-            .or_default()
-            .push(path);
-
-        None
     }
 
-    async fn push_oci_layer(
-        &self,
-        archive_path: String,
-        mut data: String,
-        layers: Arc<Mutex<Vec<OciDescriptor>>>,
-    ) -> anyhow::Result<()> {
-        if data.is_empty() {
-            // That could only happen for empty logs, so we publish an empty json instead
-            // as ghcr doesn't allow empty layers
-            data = "{}".to_string();
+    /// Appends the file to the buffer. Other files are added as tar entries under
+    /// the archive path.
+    fn append(&mut self, path: PathBuf, archive_path: &str, data: &str) -> anyhow::Result<()> {
+        let (chunk, offset) = match self.format {
+            PackFormat::Yaml => (data.as_bytes().to_vec(), 0),
+            PackFormat::Tar => {
+                let mut header = Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                let mut builder = Builder::new(vec![]);
+                builder.append_data(&mut header, archive_path, data.as_bytes())?;
+                // Drop the end of archive blocks, they are added when the layer is built.
+                let mut entry = builder.into_inner()?;
+                entry.truncate(entry.len() - TAR_END_LEN);
+                let offset = entry.len() - data.len().div_ceil(512) * 512;
+                (entry, offset)
+            }
         };
 
-        info!("Pushing layer: {:?}", archive_path);
-        let (digest, size) = self.push_blob(data).await?;
-        {
-            layers.lock().await.push(OciDescriptor {
-                artifact_type: None,
-                urls: None,
-                media_type: IMAGE_LAYER_MEDIA_TYPE.to_string(),
-                digest,
-                size: size as i64,
-                annotations: Some(
-                    [(
-                        "org.opencontainers.image.title".to_string(),
-                        archive_path.to_string(),
-                    )]
-                    .into(),
-                ),
-            });
+        match &mut self.spill {
+            Some(file) => file.write_all(&chunk)?,
+            None => self.data.extend_from_slice(&chunk),
         }
+        self.entries.push(PackEntry {
+            path,
+            start: self.len,
+            len: chunk.len(),
+            offset,
+            size: data.len(),
+        });
+        self.len += chunk.len();
 
         Ok(())
     }
 
-    #[instrument(skip_all, err)]
-    fn combined_oci_archive_layer(yamls: &Vec<PathBuf>) -> anyhow::Result<String> {
-        let mut files: Vec<serde_json::Value> = vec![];
-        for yaml in yamls {
-            let path = yaml.to_string_lossy();
-            let file = File::open(yaml).context(format!("failed to open file {path}"))?;
-            files.push(
-                serde_saphyr::from_reader(file).context(format!("failed to read file {path}"))?,
-            );
-        }
+    /// Moves the buffered files to a temporary file, later files are appended to it.
+    fn spill(&mut self) -> anyhow::Result<()> {
+        let file = match &mut self.spill {
+            Some(file) => file,
+            None => self.spill.insert(tempfile::tempfile()?),
+        };
+        file.write_all(&std::mem::take(&mut self.data))?;
+        Ok(())
+    }
 
-        let data = serde_saphyr::to_string_multiple(&files)
-            .context("failed to serialize a list of yamls")?;
-        Ok(data)
+    /// Orders the files by path.
+    fn sort(&mut self) {
+        self.entries.sort_by(|a, b| a.path.cmp(&b.path));
     }
 
-    async fn push_oci_archive_layer(
-        &self,
-        path: PathBuf,
-        layers: Arc<Mutex<Vec<OciDescriptor>>>,
-    ) -> anyhow::Result<()> {
-        if path.is_dir() {
-            return anyhow::Result::Ok(());
+    /// Returns the layer data starting with the file at `first`, the file ranges and the
+    /// first file of the next layer. Files are added until the layer reaches `max_size`.
+    /// YAML documents are separated by a document start marker.
+    fn layer(
+        &mut self,
+        first: usize,
+        max_size: usize,
+        title: &str,
+    ) -> anyhow::Result<(Vec<u8>, Vec<YamlPath>, usize)> {
+        let mut data = vec![];
+        let mut paths = vec![];
+        let mut newline = true;
+        let mut next = first;
+        while next < self.entries.len() && data.len() < max_size {
+            let entry = &self.entries[next];
+            let chunk = match &mut self.spill {
+                Some(file) => {
+                    let mut chunk = vec![0; entry.len];
+                    file.seek(SeekFrom::Start(entry.start as u64))?;
+                    file.read_exact(&mut chunk)?;
+                    chunk
+                }
+                None => self.data[entry.start..entry.start + entry.len].to_vec(),
+            };
+            if self.format == PackFormat::Yaml && next > first {
+                data.extend_from_slice(if newline { b"---\n" } else { b"\n---\n" });
+            }
+            newline = chunk.ends_with(b"\n");
+
+            let from = data.len() + entry.offset;
+            data.extend_from_slice(&chunk);
+            paths.push(YamlPath {
+                path: entry.path.clone(),
+                layer: Some(title.into()),
+                from,
+                to: from + entry.size,
+            });
+            next += 1;
+        }
+        if self.format == PackFormat::Tar {
+            data.extend_from_slice(&[0; TAR_END_LEN]);
         }
-        let archive_path = path.clone();
-        let archive_path = archive_path
-            .to_str()
-            .ok_or(anyhow::anyhow!("file path is not convertable to string"))?;
-        let mut file = File::open(path).context(format!("failed to open file {archive_path}"))?;
-        let mut data = String::new();
-        File::read_to_string(&mut file, &mut data)
-            .context(format!("failed to read file {archive_path}"))?;
 
-        self.push_oci_layer(archive_path.to_string(), data, layers)
-            .await
+        Ok((data, paths, next))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::{
+        collections::HashMap,
         env,
         fs::{self},
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicUsize, Ordering},
+        },
     };
 
+    use oci_client::manifest::OciImageManifest;
+
     use tempfile::TempDir;
 
     use crate::{
         cli::DEFAULT_OCI_BUFFER_SIZE,
-        gather::{config::Secrets, representation::ArchivePath, writer::Representation},
+        gather::{
+            config::Secrets, ignore::IgnoreRules, representation::ArchivePath,
+            writer::Representation,
+        },
+        scanners::interface::UPDATED_ANNOTATION,
     };
 
-    use super::{Archive, Encoding, Writer};
+    use super::{
+        Archive, ArchiveReader, Encoding, KEYFRAME_INTERVAL, Keyframe, Reader, Storage, Utc,
+        Writer, serde_json,
+    };
 
     #[tokio::test]
     async fn test_new_gzip() {
@@ -694,6 +1669,599 @@ mod tests {
         assert!(result.await.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_writer_handle() {
+        use futures::future::try_join_all;
+
+        use super::WriterHandle;
+
+        let tmp_dir = TempDir::new().expect("failed to create temp dir");
+        let archive = tmp_dir.path().join("test");
+        let writer = Writer::new(
+            &Archive::new(archive.clone()),
+            &Encoding::Path,
+            None,
+            None,
+            DEFAULT_OCI_BUFFER_SIZE,
+        )
+        .await
+        .unwrap();
+        let handle = WriterHandle::spawn(writer, 1);
+
+        try_join_all((0..20).map(|i| {
+            handle.store(
+                Representation::new()
+                    .with_data(&format!("content {i}"))
+                    .with_path(ArchivePath::Custom(format!("{i}.txt").into())),
+            )
+        }))
+        .await
+        .unwrap();
+        handle.finish().await.unwrap();
+
+        for i in 0..20 {
+            assert_eq!(
+                fs::read_to_string(archive.join(format!("{i}.txt"))).unwrap(),
+                format!("content {i}")
+            );
+        }
+        assert!(
+            handle
+                .store(Representation::new().with_path(ArchivePath::Custom("late.txt".into())))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_writer_handle_redaction() {
+        use crate::gather::{config::Secrets, pseudonym::Pseudonymizer, redact::Redaction};
+
+        use super::WriterHandle;
+
+        let tmp_dir = TempDir::new().expect("failed to create temp dir");
+        let archive = tmp_dir.path().join("test");
+        let writer = Writer::new(
+            &Archive::new(archive.clone()),
+            &Encoding::Path,
+            None,
+            None,
+            DEFAULT_OCI_BUFFER_SIZE,
+        )
+        .await
+        .unwrap();
+        let mut secrets: Secrets = vec![].into();
+        secrets.0.push("password-for-customer".into());
+        let pseudonymizer =
+            Pseudonymizer::new("key", vec!["customer".into()], vec![], vec![], None);
+        let handle = WriterHandle::spawn(writer, 1).with_redaction(
+            secrets.clone(),
+            Redaction::default().with_pseudonymizer(pseudonymizer.clone()),
+        );
+
+        // Secret values are stripped before the namespace in them is pseudonymized.
+        handle
+            .store(
+                Representation::new()
+                    .with_path(ArchivePath::Logs(
+                        "namespaces/customer/v1/pod/web/app/current.log".into(),
+                    ))
+                    .with_data("login password-for-customer in customer"),
+            )
+            .await
+            .unwrap();
+        handle.finish().await.unwrap();
+
+        let ns = pseudonymizer.apply_text("customer");
+        let path = format!("namespaces/{ns}/v1/pod/web/app/current.log");
+        assert_eq!(
+            fs::read_to_string(archive.join(&path)).unwrap(),
+            format!("login xxx in {ns}")
+        );
+        assert_eq!(secrets.replacements().counts()[&path], 1);
+    }
+
+    #[test]
+    fn test_layer_buffer() {
+        use std::io::Read as _;
+
+        use super::{LayerBuffer, OCIState, PackFormat};
+
+        let docs = ["a: 1\n", "b: 2", "c: 3\n"];
+        for spill in [false, true] {
+            let mut buffer = LayerBuffer::new(PackFormat::Yaml);
+            for (i, doc) in docs.iter().enumerate().rev() {
+                let path = format!("dir/{i}.yaml");
+                buffer.append(path.clone().into(), &path, doc).unwrap();
+                if spill && i == 1 {
+                    buffer.spill().unwrap();
+                }
+            }
+
+            buffer.sort();
+            let (data, paths, next) = buffer.layer(0, usize::MAX, "pack.yaml").unwrap();
+            assert_eq!(next, docs.len());
+            assert_eq!(data, b"a: 1\n---\nb: 2\n---\nc: 3\n");
+            for (doc, path) in docs.iter().zip(paths) {
+                assert_eq!(path.layer, Some("pack.yaml".into()));
+                assert_eq!(&data[path.from..path.to], doc.as_bytes());
+            }
+
+            // Layers are split on path boundaries once they reach the size.
+            let (data, _, next) = buffer.layer(0, 6, "pack.yaml").unwrap();
+            assert_eq!((data.as_slice(), next), (b"a: 1\n---\nb: 2".as_slice(), 2));
+
+            let mut buffer = LayerBuffer::new(PackFormat::Tar);
+            for (i, doc) in docs.iter().enumerate().rev() {
+                let path = format!("logs/{i}.log");
+                buffer.append(path.clone().into(), &path, doc).unwrap();
+                if spill && i == 1 {
+                    buffer.spill().unwrap();
+                }
+            }
+
+            buffer.sort();
+            let (data, paths, _) = buffer.layer(0, usize::MAX, "pack.tar").unwrap();
+            for (doc, path) in docs.iter().zip(paths) {
+                assert_eq!(&data[path.from..path.to], doc.as_bytes());
+            }
+            let mut archive = tar::Archive::new(data.as_slice());
+            for (i, entry) in archive.entries().unwrap().enumerate() {
+                let mut entry = entry.unwrap();
+                let mut content = String::new();
+                entry.read_to_string(&mut content).unwrap();
+                assert_eq!(
+                    entry.path().unwrap().to_str(),
+                    Some(format!("logs/{i}.log").as_str())
+                );
+                assert_eq!(content, docs[i]);
+            }
+        }
+
+        assert_eq!(
+            OCIState::pack_group("namespaces/default/v1/pod/a.yaml"),
+            "namespaces-default"
+        );
+        assert_eq!(OCIState::pack_group("cluster/v1/node/a.yaml"), "cluster");
+        assert_eq!(OCIState::pack_group("version.yaml"), "archive");
+    }
+
+    #[test]
+    fn test_collection_summary() {
+        use super::{
+            CLUSTER_ANNOTATION, CollectionSummary, FILTERS_ANNOTATION, SERVER_VERSION_ANNOTATION,
+        };
+
+        let mut summary = CollectionSummary {
+            cluster: Some("kind".to_string()),
+            parameters: serde_json::json!({"filters": []}),
+            ..Default::default()
+        };
+        summary.record("version.yaml", "gitVersion: v1.34.0\nmajor: '1'\nminor: '34'\nplatform: linux/amd64\ncompiler: gc\ngoVersion: go1.24\ngitCommit: abc\ngitTreeState: clean\nbuildDate: '2025-01-01'\n");
+        summary.record("namespaces/default/v1/pod/a.yaml", "a: 1");
+        summary.record("namespaces/default/v1/pod/a/c/current.log", "log");
+        assert_eq!(summary.server_version.as_deref(), Some("v1.34.0"));
+        assert_eq!((summary.files, summary.resources), (3, 1));
+
+        let annotations = summary.annotations();
+        assert_eq!(annotations[CLUSTER_ANNOTATION], "kind");
+        assert_eq!(annotations[SERVER_VERSION_ANNOTATION], "v1.34.0");
+        assert!(!annotations.contains_key(FILTERS_ANNOTATION));
+
+        summary.parameters =
+            serde_json::json!({"filters": [[{"namespaceInclude": [{"namespace": "default"}]}]]});
+        assert_eq!(
+            summary.annotations()[FILTERS_ANNOTATION],
+            r#"[[{"namespaceInclude":[{"namespace":"default"}]}]]"#
+        );
+
+        summary.private = true;
+        summary.publish();
+        let annotations = summary.annotations();
+        assert!(!annotations.contains_key(CLUSTER_ANNOTATION));
+        assert!(!annotations.contains_key(FILTERS_ANNOTATION));
+        assert_eq!(annotations[SERVER_VERSION_ANNOTATION], "v1.34.0");
+    }
+
+    #[tokio::test]
+    async fn test_oci_max_layers() {
+        use super::LayerPacking;
+
+        let writer = Writer::new(
+            &Archive::default(),
+            &Encoding::Oci("localhost:5000/crust-gather:test".parse().unwrap()),
+            None,
+            None,
+            DEFAULT_OCI_BUFFER_SIZE,
+        )
+        .await
+        .unwrap()
+        .with_layer_packing(LayerPacking {
+            max_layers: 3,
+            ..Default::default()
+        });
+        let Writer::Oci(mut state) = writer else {
+            panic!("writer is not an OCI writer");
+        };
+
+        for path in [
+            "namespaces/default/v1/pod/a.yaml",
+            "namespaces/default/v1/pod/b.yaml",
+            "cluster/v1/node/a.yaml",
+        ] {
+            state.store(path.to_string(), "a: 1").await.unwrap();
+        }
+        let err = state
+            .store("namespaces/other/v1/pod/a.yaml".to_string(), "a: 1")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("more than 3 OCI layers"));
+    }
+
+    /// Serves the OCI distribution endpoints used for pushing, counting blob uploads and mounts.
+    #[derive(Default)]
+    struct MockRegistry {
+        blobs: Mutex<HashMap<(String, String), Vec<u8>>>,
+        manifests: Mutex<HashMap<(String, String), Vec<u8>>>,
+        uploads: AtomicUsize,
+        mounts: AtomicUsize,
+    }
+
+    impl MockRegistry {
+        fn start() -> (Arc<Self>, String, actix_web::dev::ServerHandle) {
+            use actix_web::{App, HttpResponse, HttpServer, web};
+
+            type Registry = web::Data<Arc<MockRegistry>>;
+            type Query = web::Query<HashMap<String, String>>;
+
+            let registry = Arc::new(Self::default());
+            let state = registry.clone();
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::new(state.clone()))
+                    .route("/v2/", web::get().to(HttpResponse::Ok))
+                    .route(
+                        "/v2/{repo}/blobs/uploads/",
+                        web::post().to(
+                            |path: web::Path<String>, query: Query, registry: Registry| async move {
+                                let repo = path.into_inner();
+                                let mut blobs = registry.blobs.lock().unwrap();
+                                if let (Some(digest), Some(from)) =
+                                    (query.get("mount"), query.get("from"))
+                                    && let Some(blob) =
+                                        blobs.get(&(from.clone(), digest.clone())).cloned()
+                                {
+                                    blobs.insert((repo.clone(), digest.clone()), blob);
+                                    registry.mounts.fetch_add(1, Ordering::SeqCst);
+                                    return HttpResponse::Created()
+                                        .insert_header((
+                                            "location",
+                                            format!("/v2/{repo}/blobs/{digest}"),
+                                        ))
+                                        .finish();
+                                }
+                                HttpResponse::Accepted()
+                                    .insert_header((
+                                        "location",
+                                        format!("/v2/{repo}/blobs/uploads/session"),
+                                    ))
+                                    .finish()
+                            },
+                        ),
+                    )
+                    .route(
+                        "/v2/{repo}/blobs/uploads/session",
+                        web::put().to(
+                            |path: web::Path<String>,
+                             query: Query,
+                             body: web::Bytes,
+                             registry: Registry| async move {
+                                let (repo, digest) = (path.into_inner(), query["digest"].clone());
+                                registry
+                                    .blobs
+                                    .lock()
+                                    .unwrap()
+                                    .insert((repo.clone(), digest.clone()), body.to_vec());
+                                registry.uploads.fetch_add(1, Ordering::SeqCst);
+                                HttpResponse::Created()
+                                    .insert_header((
+                                        "location",
+                                        format!("/v2/{repo}/blobs/{digest}"),
+                                    ))
+                                    .finish()
+                            },
+                        ),
+                    )
+                    .route(
+                        "/v2/{repo}/blobs/{digest}",
+                        web::head().to(
+                            |path: web::Path<(String, String)>, registry: Registry| async move {
+                                match registry
+                                    .blobs
+                                    .lock()
+                                    .unwrap()
+                                    .contains_key(&path.into_inner())
+                                {
+                                    true => HttpResponse::Ok().finish(),
+                                    false => HttpResponse::NotFound().finish(),
+                                }
+                            },
+                        ),
+                    )
+                    .route(
+                        "/v2/{repo}/manifests/{tag}",
+                        web::put().to(
+                            |path: web::Path<(String, String)>,
+                             body: web::Bytes,
+                             registry: Registry| async move {
+                                let (repo, tag) = path.into_inner();
+                                registry
+                                    .manifests
+                                    .lock()
+                                    .unwrap()
+                                    .insert((repo.clone(), tag.clone()), body.to_vec());
+                                HttpResponse::Created()
+                                    .insert_header((
+                                        "location",
+                                        format!("/v2/{repo}/manifests/{tag}"),
+                                    ))
+                                    .finish()
+                            },
+                        ),
+                    )
+                    .route(
+                        "/v2/{repo}/manifests/{tag}",
+                        web::get().to(
+                            |path: web::Path<(String, String)>, registry: Registry| async move {
+                                match registry.manifests.lock().unwrap().get(&path.into_inner()) {
+                                    Some(manifest) => HttpResponse::Ok()
+                                        .content_type("application/vnd.oci.image.manifest.v1+json")
+                                        .body(manifest.clone()),
+                                    None => HttpResponse::NotFound().finish(),
+                                }
+                            },
+                        ),
+                    )
+            })
+            .bind("127.0.0.1:0")
+            .unwrap();
+            let address = server.addrs()[0].to_string();
+            let server = server.run();
+            let handle = server.handle();
+            tokio::spawn(server);
+
+            (registry, address, handle)
+        }
+
+        /// Creates an OCI writer for the image in the registry.
+        async fn writer(address: &str, image: &str, base: Option<&str>) -> Writer {
+            use oci_client::client::{ClientConfig, ClientProtocol};
+
+            let reference = |image: &str| format!("{address}/{image}").parse().unwrap();
+            Writer::new(
+                &Archive::default(),
+                &Encoding::Oci(reference(image)),
+                Some(ClientConfig {
+                    protocol: ClientProtocol::Http,
+                    use_monolithic_push: true,
+                    ..Default::default()
+                }),
+                None,
+                DEFAULT_OCI_BUFFER_SIZE,
+            )
+            .await
+            .unwrap()
+            .with_base(base.map(reference))
+            .await
+            .unwrap()
+        }
+
+        fn manifest(&self, repo: &str, tag: &str) -> OciImageManifest {
+            let manifests = self.manifests.lock().unwrap();
+            serde_json::from_slice(&manifests[&(repo.to_string(), tag.to_string())]).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oci_incremental_push() {
+        let (registry, address, stop) = MockRegistry::start();
+
+        let paths = [
+            "version.yaml",
+            "cluster/v1/node/a.yaml",
+            "cluster/v1/node/b.yaml",
+            "namespaces/default/v1/pod/a.yaml",
+            "namespaces/default/v1/pod/b.yaml",
+            "namespaces/default/v1/pod/a/app/current.log",
+            "namespaces/other/v1/pod/a.yaml",
+        ];
+        let push =
+            async |image: &str, base: Option<&str>, order: &mut dyn Iterator<Item = &&str>| {
+                let mut writer = MockRegistry::writer(&address, image, base).await;
+                for path in order {
+                    writer
+                        .store(
+                            &Representation::new()
+                                .with_path(ArchivePath::Custom(path.into()))
+                                .with_data(&format!("name: {path}\n")),
+                        )
+                        .await
+                        .unwrap();
+                }
+                writer.finish().await.unwrap();
+            };
+        let layers = |repo: &str, tag: &str| -> Vec<(String, String)> {
+            registry
+                .manifest(repo, tag)
+                .layers
+                .into_iter()
+                .map(|layer| {
+                    let title =
+                        layer.annotations.unwrap()["org.opencontainers.image.title"].clone();
+                    (title, layer.digest)
+                })
+                .collect()
+        };
+
+        push("snapshots:a", None, &mut paths.iter()).await;
+        let uploads = registry.uploads.load(Ordering::SeqCst);
+        let layers_a = layers("snapshots", "a");
+        assert!(layers_a.iter().any(|(title, _)| title == "index.yaml"));
+        assert_eq!(
+            uploads,
+            layers_a.len() + 1,
+            "layers and config are uploaded"
+        );
+
+        // The same files collected in another order produce the same layers and index,
+        // which are found in the repository, so only the new config is uploaded.
+        push("snapshots:b", None, &mut paths.iter().rev()).await;
+        assert_eq!(layers("snapshots", "b"), layers_a);
+        assert_eq!(registry.uploads.load(Ordering::SeqCst), uploads + 1);
+        assert_eq!(registry.mounts.load(Ordering::SeqCst), 0);
+
+        // Layers missing in another repository are mounted from the base image.
+        push("copies:c", Some("snapshots:a"), &mut paths.iter()).await;
+        assert_eq!(layers("copies", "c"), layers_a);
+        assert_eq!(registry.uploads.load(Ordering::SeqCst), uploads + 2);
+        assert_eq!(registry.mounts.load(Ordering::SeqCst), layers_a.len());
+
+        stop.stop(false).await;
+    }
+
+    #[tokio::test]
+    async fn test_oci_layers_split_in_path_order() {
+        use super::LayerPacking;
+
+        let (registry, address, stop) = MockRegistry::start();
+        let paths = [
+            "namespaces/default/v1/pod/a.yaml",
+            "namespaces/default/v1/pod/b.yaml",
+            "namespaces/default/v1/pod/c.yaml",
+            "namespaces/default/v1/pod/d.yaml",
+        ];
+        let push = async |tag: &str, order: &mut dyn Iterator<Item = &&str>| {
+            let writer = MockRegistry::writer(&address, &format!("snapshots:{tag}"), None)
+                .await
+                .with_layer_packing(LayerPacking {
+                    max_layer_size: 16,
+                    ..Default::default()
+                });
+            let Writer::Oci(mut state) = writer else {
+                panic!("writer is not an OCI writer");
+            };
+            for path in order {
+                state
+                    .store(path.to_string(), &format!("name: {path}"))
+                    .await
+                    .unwrap();
+            }
+            state.publish_image().await.unwrap();
+            registry
+                .manifest("snapshots", tag)
+                .layers
+                .into_iter()
+                .map(|layer| (layer.annotations, layer.digest, layer.size))
+                .collect::<Vec<_>>()
+        };
+
+        // A namespace over the layer size is split into the same layers in any order.
+        let layers = push("a", &mut paths.iter()).await;
+        let packs = layers
+            .iter()
+            .filter_map(|(annotations, ..)| {
+                annotations.as_ref()?.get("org.opencontainers.image.title")
+            })
+            .filter(|title| title.contains(".layers/namespaces-default-"))
+            .count();
+        assert_eq!(packs, paths.len());
+        assert_eq!(push("b", &mut paths.iter().rev()).await, layers);
+        assert_eq!(
+            push("c", &mut [paths[2], paths[0], paths[3], paths[1]].iter()).await,
+            layers
+        );
+
+        stop.stop(false).await;
+    }
+
+    #[tokio::test]
+    async fn test_oci_encrypted_titles() {
+        use crate::gather::encryption::Encryption;
+
+        let (registry, address, stop) = MockRegistry::start();
+        let identity = age::x25519::Identity::generate();
+        let mut writer = MockRegistry::writer(&address, "snapshots:a", None)
+            .await
+            .with_encryption(Some(Encryption::Recipients(vec![identity.to_public()])))
+            .unwrap();
+        for path in [
+            "namespaces/customer/v1/pod/a.yaml",
+            "namespaces/customer/v1/pod/a/app/current.log",
+        ] {
+            writer
+                .store(
+                    &Representation::new()
+                        .with_path(ArchivePath::Custom(path.into()))
+                        .with_data("name: a"),
+                )
+                .await
+                .unwrap();
+        }
+        writer.finish().await.unwrap();
+
+        // Namespaces are not exposed in the plaintext manifest.
+        let manifest = registry.manifest("snapshots", "a");
+        assert_eq!(manifest.layers.len(), 3);
+        let manifest = serde_json::to_string(&manifest).unwrap();
+        assert!(!manifest.contains("customer"), "{manifest}");
+
+        stop.stop(false).await;
+    }
+
+    #[tokio::test]
+    async fn test_oci_upload_memory_limit() {
+        use super::OCI_UPLOAD_MEMORY_LIMIT_MIB;
+
+        let (registry, address, stop) = MockRegistry::start();
+        let Writer::Oci(mut state) = MockRegistry::writer(&address, "snapshots:a", None).await
+        else {
+            panic!("writer is not an OCI writer");
+        };
+
+        // In flight layers hold their uncompressed size in MiB until they are uploaded.
+        state
+            .upload("small".to_string(), vec![1; 3 * 1024 * 1024 - 1])
+            .await
+            .unwrap();
+        assert_eq!(
+            state.upload_memory.available_permits(),
+            OCI_UPLOAD_MEMORY_LIMIT_MIB - 3
+        );
+        state.join_upload().await.unwrap();
+        assert_eq!(
+            state.upload_memory.available_permits(),
+            OCI_UPLOAD_MEMORY_LIMIT_MIB
+        );
+        assert_eq!(registry.uploads.load(Ordering::SeqCst), 1);
+
+        stop.stop(false).await;
+    }
+
+    #[tokio::test]
+    async fn test_new_stdout_zip() {
+        let archive = Archive::from("-");
+        assert!(archive.is_stdout());
+
+        let result = Writer::new(
+            &archive,
+            &Encoding::Zip,
+            None,
+            None,
+            DEFAULT_OCI_BUFFER_SIZE,
+        );
+        assert!(result.await.is_err());
+    }
+
     #[tokio::test]
     async fn test_add_gzip() {
         use crate::gather::representation::ArchivePath;
@@ -715,10 +2283,137 @@ mod tests {
             .with_path(ArchivePath::Custom("test.txt".into()));
 
         assert!(writer.store(&repr).await.is_ok());
-        assert!(writer.finish_gzip().is_ok());
+        assert!(writer.finish_tar().is_ok());
         assert!(archive.with_file_name("test.tar.gz").exists());
     }
 
+    #[tokio::test]
+    async fn test_add_encrypted() {
+        use crate::gather::{
+            encryption::{Decryption, Encryption},
+            representation::ArchivePath,
+        };
+
+        for (encoding, name) in [(Encoding::Gzip, "test.tar.gz"), (Encoding::Zip, "test.zip")] {
+            let tmp_dir = TempDir::new().expect("failed to create temp dir");
+            let archive = tmp_dir.path().join("test");
+            let identity = age::x25519::Identity::generate();
+            let mut writer = Writer::new(
+                &Archive::new(archive.clone()),
+                &encoding,
+                None,
+                None,
+                DEFAULT_OCI_BUFFER_SIZE,
+            )
+            .await
+            .unwrap()
+            .with_encryption(Some(Encryption::Recipients(vec![identity.to_public()])))
+            .unwrap();
+
+            let repr = Representation::new()
+                .with_data("content")
+                .with_path(ArchivePath::Custom("test.txt".into()));
+
+            // Only the encrypted archive is written to the disk.
+            assert!(writer.store(&repr).await.is_ok());
+            assert!(!archive.with_file_name(name).exists());
+            assert!(writer.finish().await.is_ok());
+            assert!(!archive.with_file_name(name).exists());
+            assert!(archive.with_file_name(format!("{name}.age")).exists());
+
+            let unpacked = Storage::unpack(
+                tmp_dir.path(),
+                Some(&Decryption::Identities(vec![identity].into())),
+            )
+            .unwrap()
+            .unwrap();
+            assert_eq!(
+                fs::read_to_string(unpacked.path().join("test/test.txt")).unwrap(),
+                "content"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_zstd_xz() {
+        use crate::gather::representation::ArchivePath;
+
+        let tmp_dir = TempDir::new().expect("failed to create temp dir");
+        for (encoding, name) in [
+            ("zstd:19", "test.tar.zst"),
+            ("xz", "test.tar.xz"),
+            ("gzip", "test.tar.gz"),
+        ] {
+            let archive = tmp_dir.path().join("test");
+            let mut writer = Writer::new(
+                &Archive::new(archive.clone()),
+                &Encoding::try_from(encoding).unwrap(),
+                None,
+                None,
+                DEFAULT_OCI_BUFFER_SIZE,
+            )
+            .await
+            .unwrap();
+
+            let repr = Representation::new()
+                .with_data("content")
+                .with_path(ArchivePath::Custom("test.txt".into()));
+
+            writer.store(&repr).await.unwrap();
+            writer.finish_tar().unwrap();
+
+            let unpacked = Storage::unpack(&archive.with_file_name(name), None)
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                fs::read_to_string(unpacked.path().join("test/test.txt")).unwrap(),
+                "content"
+            );
+        }
+
+        assert!(Encoding::try_from("zstd:23").is_err());
+        assert!(Encoding::try_from("gzip:1").is_err());
+        assert!(Encoding::try_from("rar").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_gzip_manifest() {
+        use crate::gather::{manifest::verify_archive, representation::ArchivePath};
+
+        let tmp_dir = TempDir::new().expect("failed to create temp dir");
+        let archive = tmp_dir.path().join("test");
+        let key = ed25519_dalek::SigningKey::from_bytes(&[1; 32]);
+        let mut writer = Writer::new(
+            &Archive::new(archive.clone()),
+            &Encoding::Gzip,
+            None,
+            None,
+            DEFAULT_OCI_BUFFER_SIZE,
+        )
+        .await
+        .unwrap();
+
+        let repr = Representation::new()
+            .with_data("content")
+            .with_path(ArchivePath::Custom("test.txt".into()));
+
+        writer.store(&repr).await.unwrap();
+        writer
+            .store_manifest(serde_json::Value::Null, Some(&key))
+            .await
+            .unwrap();
+        writer.finish_tar().unwrap();
+
+        let verification = verify_archive(
+            &archive.with_file_name("test.tar.gz"),
+            Some(&key.verifying_key()),
+        )
+        .unwrap();
+        assert!(verification.is_valid());
+        assert!(verification.signed);
+        assert_eq!(verification.files, 1);
+    }
+
     #[tokio::test]
     async fn test_add_zip() {
         use std::{
@@ -793,6 +2488,105 @@ mod tests {
         assert_eq!(data, "content with xxx");
     }
 
+    #[tokio::test]
+    async fn test_sync_ignored_fields() {
+        let tmp_dir = TempDir::new().expect("failed to create temp dir");
+        let archive = tmp_dir.path().join("collected");
+        let mut writer = Writer::new(
+            &Archive::new(archive.clone()),
+            &Encoding::Path,
+            None,
+            None,
+            DEFAULT_OCI_BUFFER_SIZE,
+        )
+        .await
+        .unwrap();
+
+        let object = |resource_version: &str, data: &str| {
+            Representation::new()
+                .with_path(ArchivePath::Custom("cm.yaml".into()))
+                .with_data(
+                    format!(
+                        "apiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: test\n  resourceVersion: \"{resource_version}\"\n  annotations:\n    {UPDATED_ANNOTATION}: \"{resource_version}\"\ndata:\n  key: {data}\n"
+                    )
+                    .as_str(),
+                )
+        };
+
+        let ignore = IgnoreRules::defaults();
+        writer.store(&object("1", "a")).await.unwrap();
+        writer.sync(&object("2", "a"), &ignore).await.unwrap();
+        assert!(!archive.join("cm.patch").exists());
+
+        writer.sync(&object("3", "b"), &ignore).await.unwrap();
+        let patches = fs::read_to_string(archive.join("cm.patch")).unwrap();
+        assert_eq!(patches.lines().count(), 1);
+        assert!(!patches.contains("resourceVersion"));
+    }
+
+    #[tokio::test]
+    async fn test_sync_keyframes() {
+        // A patch without a sync timestamp is not replayed, but still gets its keyframe.
+        for timestamped in [true, false] {
+            let tmp_dir = TempDir::new().expect("failed to create temp dir");
+            let archive = tmp_dir.path().join("collected");
+            let mut writer = Writer::new(
+                &Archive::new(archive.clone()),
+                &Encoding::Path,
+                None,
+                None,
+                DEFAULT_OCI_BUFFER_SIZE,
+            )
+            .await
+            .unwrap();
+
+            let object = |data: usize| {
+                let timestamp = match timestamped || data != KEYFRAME_INTERVAL {
+                    true => (Utc::now() - chrono::Duration::seconds(1)).to_string(),
+                    false => "pending".to_string(),
+                };
+                Representation::new()
+                    .with_path(ArchivePath::Custom("cm.yaml".into()))
+                    .with_data(
+                        format!(
+                            "apiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: test\n  annotations:\n    {UPDATED_ANNOTATION}: \"{timestamp}\"\ndata:\n  key: \"{data}\"\n",
+                        )
+                        .as_str(),
+                    )
+            };
+
+            writer.store(&object(0)).await.unwrap();
+            for data in 1..=KEYFRAME_INTERVAL + 1 {
+                writer
+                    .sync(&object(data), &IgnoreRules::default())
+                    .await
+                    .unwrap();
+            }
+
+            let keyframes = fs::read_to_string(archive.join("cm.keyframes")).unwrap();
+            assert_eq!(keyframes.lines().count(), 1);
+
+            let keyframe: Keyframe = serde_json::from_str(keyframes.trim()).unwrap();
+            let replayed = match timestamped {
+                true => KEYFRAME_INTERVAL,
+                false => KEYFRAME_INTERVAL - 1,
+            };
+            assert_eq!(keyframe.line, KEYFRAME_INTERVAL);
+            assert_eq!(keyframe.object["data"]["key"], replayed.to_string());
+
+            let obj: serde_json::Value = Reader::new(
+                ArchiveReader::new(Archive::new(archive.clone()), Storage::FS, 1).await,
+                Utc::now(),
+            )
+            .await
+            .unwrap()
+            .read(archive.join("cm.yaml"))
+            .await
+            .unwrap();
+            assert_eq!(obj["data"]["key"], (KEYFRAME_INTERVAL + 1).to_string());
+        }
+    }
+
     #[tokio::test]
     async fn test_try_into_nested_file_success() {
         let tmp_dir = TempDir::new().expect("failed to create temp dir");
@@ -19,7 +19,10 @@ use tokio::{
 use crate::{
     cli::{Filters, GatherCommands, GatherSettings, OCIReference, OCISettings},
     gather::{
+        blob_cache::CacheSettings,
         config::{GatherMode, KubeconfigFile, RunDuration, SecretsFile},
+        kubeconfig::KubeconfigTarget,
+        redact::RedactMode,
         server::{Api, Socket},
         writer::{Archive, ArchiveSearch, Encoding},
     },
@@ -30,6 +33,11 @@ pub struct RedactionOptions {
     #[serde(default)]
     secret_values: Vec<String>,
     secrets_file: Option<String>,
+    /// How to redact the data of collected Secret objects. Defaults to hash.
+    secret_data: Option<RedactMode>,
+    /// Keep the data of collected Secret objects as-is.
+    #[serde(default)]
+    keep_secret_data: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, schemars::JsonSchema)]
@@ -139,6 +147,7 @@ enum ServeDescriptor {
 struct NormalizedRedaction {
     secret_value_count: usize,
     secrets_file: Option<String>,
+    secret_data: Option<RedactMode>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -178,7 +187,8 @@ const SERVER_INSTRUCTIONS: &str = concat!(
     "Use collect_archive to create a local snapshot artifact, or collect_oci to collect and ",
     "publish a snapshot to an OCI image. Both collection tools accept selectors to narrow scope ",
     "and redaction settings such as secret_values or secrets_file so sensitive values can be ",
-    "excluded before saving or upload. Use serve_archive or serve_oci to expose a collected ",
+    "excluded before saving or upload. Secret object data is redacted by default, set ",
+    "redaction.keep_secret_data to keep it. Use serve_archive or serve_oci to expose a collected ",
     "snapshot as a read-only Kubernetes-like API. The serve tools always create a temporary ",
     "client kubeconfig for the active snapshot. In serve responses and serving_status, ",
     "details.source describes what is being served, details.kubeconfig is the client kubeconfig ",
@@ -263,6 +273,7 @@ impl CrustGatherMcp {
             insecure_skip_tls_verify: Some(request.insecure_skip_tls_verify),
             file: Some(Archive::from(archive_path.as_str())),
             secrets_file: redaction.secrets_file.clone(),
+            redact_secrets: redaction.normalized.secret_data,
             encoding: Some(Encoding::Path),
             ..Default::default()
         };
@@ -298,7 +309,7 @@ impl CrustGatherMcp {
         let duration = parse_duration(request.duration)?;
         let redaction = prepare_redaction(request.redaction)?;
         let mut registry = request.registry_auth.unwrap_or_default();
-        registry.reference = Some(image_reference.clone());
+        registry.references = vec![image_reference.clone()];
         let image_reference_value: Reference = image_reference.clone().into();
 
         let settings = GatherSettings {
@@ -306,6 +317,7 @@ impl CrustGatherMcp {
             insecure_skip_tls_verify: Some(request.insecure_skip_tls_verify),
             file: Some(Archive::from(archive_path.as_str())),
             secrets_file: redaction.secrets_file.clone(),
+            redact_secrets: redaction.normalized.secret_data,
             encoding: Some(Encoding::Oci(image_reference_value.clone())),
             oci: registry,
             duration,
@@ -346,7 +358,7 @@ impl CrustGatherMcp {
         let api = Api::new(
             archives,
             Socket::try_from(socket.as_str())?,
-            Some(temp_kubeconfig.path().to_path_buf()),
+            KubeconfigTarget::Standalone(temp_kubeconfig.path().to_path_buf()),
         )
         .await?;
 
@@ -395,22 +407,25 @@ impl CrustGatherMcp {
             normalize_optional_string(request.socket)?.unwrap_or_else(|| "0.0.0.0:9095".into());
         let socket_value = parse_socket(&socket)?;
         let mut registry = request.registry_auth.unwrap_or_default();
-        registry.reference = Some(image_reference.clone());
+        registry.references = vec![image_reference.clone()];
         let image_reference_value: Reference = image_reference.clone().into();
+        let auth = registry.to_auth().await;
         let temp_kubeconfig = NamedTempFile::new()?;
         let client_kubeconfig = temp_kubeconfig.path().display().to_string();
         let descriptor = ServeDescriptor::Oci {
             image_reference: image_reference_value.to_string(),
             socket: socket_value.to_string(),
             insecure_registry: registry.insecure,
-            has_basic_auth: matches!(registry.to_auth(), RegistryAuth::Basic(..)),
-            has_token_auth: matches!(registry.to_auth(), RegistryAuth::Bearer(..)),
+            has_basic_auth: matches!(auth, RegistryAuth::Basic(..)),
+            has_token_auth: matches!(auth, RegistryAuth::Bearer(..)),
             has_custom_ca: registry.ca_file.is_some(),
         };
         let api = Api::new_oci(
             registry,
             Socket::try_from(socket.as_str())?,
-            Some(temp_kubeconfig.path().to_path_buf()),
+            KubeconfigTarget::Standalone(temp_kubeconfig.path().to_path_buf()),
+            None,
+            CacheSettings::default().to_cache()?,
         )
         .await?;
 
@@ -578,7 +593,18 @@ async fn finalize_running_server(running: RunningServer, clean_shutdown: bool) -
 
 fn prepare_redaction(redaction: Option<RedactionOptions>) -> Result<PreparedRedaction> {
     let Some(redaction) = redaction else {
-        return Ok(PreparedRedaction::default());
+        return Ok(PreparedRedaction {
+            normalized: NormalizedRedaction {
+                secret_data: Some(RedactMode::default()),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+    };
+
+    let secret_data = match redaction.keep_secret_data {
+        true => None,
+        false => Some(redaction.secret_data.unwrap_or_default()),
     };
 
     let mut secrets = redaction
@@ -605,6 +631,7 @@ fn prepare_redaction(redaction: Option<RedactionOptions>) -> Result<PreparedReda
             normalized: NormalizedRedaction {
                 secret_value_count: 0,
                 secrets_file,
+                secret_data,
             },
             ..Default::default()
         });
@@ -624,6 +651,7 @@ fn prepare_redaction(redaction: Option<RedactionOptions>) -> Result<PreparedReda
         normalized: NormalizedRedaction {
             secret_value_count: secrets.len(),
             secrets_file,
+            secret_data,
         },
     })
 }
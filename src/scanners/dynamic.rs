@@ -1,15 +1,15 @@
-use std::{fmt::Debug, sync::Arc};
+use std::fmt::Debug;
 
 use async_trait::async_trait;
 use kube::Api;
 use kube::core::{ApiResource, DynamicObject, ResourceExt};
-use tokio::sync::Mutex;
 use tracing::instrument;
 
 use crate::gather::{
-    config::{Config, Secrets},
+    config::Config,
+    ignore::IgnoreRules,
     representation::{Representation, TypeMetaGetter},
-    writer::Writer,
+    writer::WriterHandle,
 };
 
 use super::{
@@ -32,11 +32,11 @@ impl Dynamic {
 
 #[async_trait]
 impl Collect<DynamicObject> for Dynamic {
-    fn get_secrets(&self) -> Secrets {
-        self.collectable.get_secrets()
+    fn get_ignore_rules(&self) -> IgnoreRules {
+        self.collectable.get_ignore_rules()
     }
 
-    fn get_writer(&self) -> Arc<Mutex<Writer>> {
+    fn get_writer(&self) -> WriterHandle {
         self.collectable.get_writer()
     }
 
@@ -88,6 +88,8 @@ mod test {
     use tempfile::TempDir;
     use tokio::time::timeout;
 
+    use std::sync::Arc;
+
     use crate::cli::DEFAULT_OCI_BUFFER_SIZE;
     use crate::filters::filter::Include;
     use crate::gather::config::GatherMode;
@@ -172,6 +174,9 @@ mod test {
                     .expect("failed to create builder")
                     .into(),
                     secrets: Default::default(),
+                    ignore: Default::default(),
+                    redaction: Default::default(),
+                    signing_key: None,
                     mode: GatherMode::Collect,
                     additional_logs: Default::default(),
                     duration: "1m".try_into().unwrap(),
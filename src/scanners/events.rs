@@ -7,14 +7,14 @@ use k8s_openapi::{
 };
 use kube::Api;
 use kube::core::ApiResource;
-use std::{fmt::Debug, sync::Arc};
-use tokio::sync::Mutex;
+use std::fmt::Debug;
 use tracing::instrument;
 
 use crate::gather::{
-    config::{Config, Secrets},
+    config::Config,
+    ignore::IgnoreRules,
     representation::{ArchivePath, Representation},
-    writer::Writer,
+    writer::WriterHandle,
 };
 
 use super::{
@@ -45,11 +45,11 @@ impl From<Config> for Events {
 
 #[async_trait]
 impl Collect<Event> for Events {
-    fn get_secrets(&self) -> Secrets {
-        self.collectable.get_secrets()
+    fn get_ignore_rules(&self) -> IgnoreRules {
+        self.collectable.get_ignore_rules()
     }
 
-    fn get_writer(&self) -> Arc<Mutex<Writer>> {
+    fn get_writer(&self) -> WriterHandle {
         self.collectable.get_writer()
     }
 
@@ -152,10 +152,8 @@ impl Collect<Event> for Events {
         }
 
         self.get_writer()
-            .lock()
-            .await
             .store(
-                &Representation::new()
+                Representation::new()
                     .with_path(self.path(&Event::default()))
                     .with_data(format!(include_str!("templates/event-filter.html"), data).as_str()),
             )
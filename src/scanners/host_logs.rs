@@ -1,7 +1,6 @@
 use std::{
     fmt::{self, Debug},
     ops::Deref,
-    sync::Arc,
 };
 
 use async_trait::async_trait;
@@ -28,15 +27,15 @@ use scopeguard::defer;
 use serde::{Serialize, Serializer};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt};
-use tokio::sync::Mutex;
 use tracing::instrument;
 
 use crate::{
     cli::DebugPod,
     gather::{
-        config::{Config, Secrets},
+        config::Config,
+        ignore::IgnoreRules,
         representation::{self, ArchivePath, CustomLog, LogGroup, Representation},
-        writer::Writer,
+        writer::WriterHandle,
     },
 };
 
@@ -126,11 +125,11 @@ impl From<Config> for HostLogs {
 
 #[async_trait]
 impl Collect<Node> for HostLogs {
-    fn get_secrets(&self) -> Secrets {
-        self.collectable.get_secrets()
+    fn get_ignore_rules(&self) -> IgnoreRules {
+        self.collectable.get_ignore_rules()
     }
 
-    fn get_writer(&self) -> Arc<Mutex<Writer>> {
+    fn get_writer(&self) -> WriterHandle {
         self.collectable.get_writer()
     }
 
@@ -1,4 +1,4 @@
-use std::{fmt::Debug, sync::Arc};
+use std::fmt::Debug;
 
 use async_trait::async_trait;
 use chrono::Utc;
@@ -6,13 +6,13 @@ use http::Request;
 use k8s_openapi::api::core::v1::Node;
 use kube::core::ApiResource;
 use kube::{Api, core::discovery::v2};
-use tokio::sync::Mutex;
 use tracing::instrument;
 
 use crate::gather::{
-    config::{Config, Secrets},
+    config::Config,
+    ignore::IgnoreRules,
     representation::{ArchivePath, Representation},
-    writer::Writer,
+    writer::WriterHandle,
 };
 
 use super::{
@@ -35,11 +35,11 @@ impl Info {
 
 #[async_trait]
 impl Collect<Node> for Info {
-    fn get_secrets(&self) -> Secrets {
-        Secrets::default()
+    fn get_ignore_rules(&self) -> IgnoreRules {
+        IgnoreRules::default()
     }
 
-    fn get_writer(&self) -> Arc<Mutex<Writer>> {
+    fn get_writer(&self) -> WriterHandle {
         self.collectable.get_writer()
     }
 
@@ -86,7 +86,7 @@ impl Collect<Node> for Info {
         ];
 
         for repr in reprs {
-            self.get_writer().lock().await.store(&repr).await?;
+            self.get_writer().store(repr).await?;
         }
 
         Ok(())
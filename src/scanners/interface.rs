@@ -15,16 +15,14 @@ use serde::de::DeserializeOwned;
 use std::fmt::Debug;
 use std::future::Future;
 use thiserror::Error;
-use tokio::sync::Mutex;
 use tracing::instrument;
 
-use std::sync::Arc;
 use std::time::Duration;
 use trait_set::trait_set;
 
-use crate::gather::config::Secrets;
+use crate::gather::ignore::IgnoreRules;
 use crate::gather::representation::{ArchivePath, Representation, TypeMetaGetter};
-use crate::gather::writer::Writer;
+use crate::gather::writer::WriterHandle;
 
 trait_set! {
     pub trait Base = Clone + Debug;
@@ -84,12 +82,12 @@ pub trait Collect<R: ResourceThreadSafe>: Send {
         action.retry(Self::retry_policy()).await
     }
 
-    /// Returns the Secrets instance to filter any secrets in the representation
-    fn get_secrets(&self) -> Secrets;
+    /// Returns the rules for fields which are ignored when recording patches
+    fn get_ignore_rules(&self) -> IgnoreRules;
 
     /// Returns the Writer instance for this scanner to write object
     /// representations to.
-    fn get_writer(&self) -> Arc<Mutex<Writer>>;
+    fn get_writer(&self) -> WriterHandle;
 
     /// Constructs the path for storing the collected Kubernetes object.
     ///
@@ -195,11 +193,7 @@ pub trait Collect<R: ResourceThreadSafe>: Send {
 
         let writer = self.get_writer();
         for repr in representations {
-            writer
-                .lock()
-                .await
-                .store(&self.get_secrets().strip(&repr))
-                .await?;
+            writer.store(repr).await?;
         }
 
         Ok(())
@@ -255,11 +249,7 @@ pub trait Collect<R: ResourceThreadSafe>: Send {
 
         let writer = self.get_writer();
         for repr in representations {
-            writer
-                .lock()
-                .await
-                .sync(&self.get_secrets().strip(&repr))
-                .await?;
+            writer.sync(repr, self.get_ignore_rules()).await?;
         }
 
         Ok(())
@@ -1,7 +1,4 @@
-use std::{
-    fmt::{self, Debug, Display},
-    sync::Arc,
-};
+use std::fmt::{self, Debug, Display};
 
 use async_trait::async_trait;
 use k8s_openapi::api::core::v1::Pod;
@@ -11,13 +8,13 @@ use kube::{
     core::{ApiResource, ResourceExt, subresource::LogParams},
 };
 use thiserror::Error;
-use tokio::sync::Mutex;
 use tracing::instrument;
 
 use crate::gather::{
-    config::{Config, Secrets},
+    config::Config,
+    ignore::IgnoreRules,
     representation::{ArchivePath, Container, LogGroup, Representation},
-    writer::Writer,
+    writer::WriterHandle,
 };
 
 use super::{
@@ -82,11 +79,11 @@ impl Logs {
 
 #[async_trait]
 impl Collect<Pod> for Logs {
-    fn get_secrets(&self) -> Secrets {
-        self.collectable.get_secrets()
+    fn get_ignore_rules(&self) -> IgnoreRules {
+        self.collectable.get_ignore_rules()
     }
 
-    fn get_writer(&self) -> Arc<Mutex<Writer>> {
+    fn get_writer(&self) -> WriterHandle {
         self.collectable.get_writer()
     }
 
@@ -245,6 +242,9 @@ mod test {
                 .expect("failed to create builder")
                 .into(),
                 secrets: Default::default(),
+                ignore: Default::default(),
+                redaction: Default::default(),
+                signing_key: None,
                 mode: GatherMode::Collect,
                 additional_logs: Default::default(),
                 duration: "1m".try_into().unwrap(),
@@ -1,16 +1,13 @@
 use crate::{
     filters::filter::Filter,
     gather::{
-        config::{Config, Secrets},
-        representation::TypeMetaGetter,
-        writer::Writer,
+        config::Config, ignore::IgnoreRules, representation::TypeMetaGetter, writer::WriterHandle,
     },
 };
 use async_trait::async_trait;
 
 use kube::Api;
 use kube::core::{ApiResource, GroupVersionKind, Resource};
-use tokio::sync::Mutex;
 use tracing::instrument;
 
 use std::{fmt::Debug, sync::Arc};
@@ -22,8 +19,8 @@ pub struct Objects<R: Resource> {
     pub api: Api<R>,
     pub filter: Arc<dyn Filter<R>>,
     pub resource: ApiResource,
-    secrets: Secrets,
-    writer: Arc<Mutex<Writer>>,
+    ignore: IgnoreRules,
+    writer: WriterHandle,
 }
 
 impl<R: ResourceThreadSafe> Debug for Objects<R> {
@@ -43,7 +40,7 @@ where
             api: Api::all_with(config.client, &resource),
             filter: config.filter,
             writer: config.writer,
-            secrets: config.secrets,
+            ignore: config.ignore,
             resource,
         }
     }
@@ -59,7 +56,7 @@ where
             api: Api::all(config.client),
             filter: config.filter,
             writer: config.writer,
-            secrets: config.secrets,
+            ignore: config.ignore,
             resource: ApiResource::erase::<R>(&Default::default()),
         }
     }
@@ -68,11 +65,11 @@ where
 #[async_trait]
 /// Collects default representations for Kubernetes API objects of any type.
 impl<R: ResourceThreadSafe> Collect<R> for Objects<R> {
-    fn get_secrets(&self) -> Secrets {
-        self.secrets.clone()
+    fn get_ignore_rules(&self) -> IgnoreRules {
+        self.ignore.clone()
     }
 
-    fn get_writer(&self) -> Arc<Mutex<Writer>> {
+    fn get_writer(&self) -> WriterHandle {
         self.writer.clone()
     }
 
@@ -186,6 +183,9 @@ mod test {
                 .expect("failed to create builder")
                 .into(),
                 secrets: Default::default(),
+                ignore: Default::default(),
+                redaction: Default::default(),
+                signing_key: None,
                 mode: GatherMode::Collect,
                 additional_logs: Default::default(),
                 duration: "1m".try_into().unwrap(),
@@ -232,6 +232,9 @@ mod test {
                 .expect("failed to create builder")
                 .into(),
                 secrets: Default::default(),
+                ignore: Default::default(),
+                redaction: Default::default(),
+                signing_key: None,
                 mode: GatherMode::Collect,
                 additional_logs: Default::default(),
                 duration: "1m".try_into().unwrap(),
@@ -275,6 +278,9 @@ mod test {
                 .expect("failed to create builder")
                 .into(),
                 secrets: Default::default(),
+                ignore: Default::default(),
+                redaction: Default::default(),
+                signing_key: None,
                 mode: GatherMode::Collect,
                 additional_logs: Default::default(),
                 duration: "1m".try_into().unwrap(),
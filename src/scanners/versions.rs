@@ -1,17 +1,17 @@
-use std::{fmt::Debug, sync::Arc};
+use std::fmt::Debug;
 
 use async_trait::async_trait;
 use k8s_openapi::api::core::v1::Pod;
 use kube::core::ApiResource;
 use kube::{Api, Resource};
 use serde::Serialize;
-use tokio::sync::Mutex;
 use tracing::instrument;
 
 use crate::gather::{
-    config::{Config, Secrets},
+    config::Config,
+    ignore::IgnoreRules,
     representation::{ArchivePath, Representation},
-    writer::Writer,
+    writer::WriterHandle,
 };
 
 use super::{
@@ -42,11 +42,11 @@ impl Versions {
 
 #[async_trait]
 impl Collect<Pod> for Versions {
-    fn get_secrets(&self) -> Secrets {
-        self.collectable.get_secrets()
+    fn get_ignore_rules(&self) -> IgnoreRules {
+        self.collectable.get_ignore_rules()
     }
 
-    fn get_writer(&self) -> Arc<Mutex<Writer>> {
+    fn get_writer(&self) -> WriterHandle {
         self.collectable.get_writer()
     }
 
@@ -72,10 +72,8 @@ impl Collect<Pod> for Versions {
             .collect::<Vec<_>>();
 
         self.get_writer()
-            .lock()
-            .await
             .store(
-                &Representation::new()
+                Representation::new()
                     .with_path(ArchivePath::Custom("app-versions.yaml".into()))
                     .with_data(serde_saphyr::to_string(&data)?.as_str()),
             )